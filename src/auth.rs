@@ -0,0 +1,123 @@
+//! Authentication and access control for `kvs-server`.
+//!
+//! Users and their permissions are read from a JSON file:
+//!
+//! ```json
+//! {
+//!   "users": [
+//!     { "name": "admin", "password": "hunter2", "rules": [{ "prefix": "", "ops": ["read", "write"] }] },
+//!     { "name": "deployer", "token": "t0ken", "rules": [{ "prefix": "config/", "ops": ["read"] }] }
+//!   ]
+//! }
+//! ```
+//!
+//! A client authenticates either with a user name and password, or with a token alone. Each rule
+//! grants operations on the keys starting with `prefix`; anything not granted by a rule is denied.
+
+use std::{fs, path::Path, sync::Arc};
+
+use serde::Deserialize;
+
+use crate::{KvsError, Result};
+
+/// The kind of access an operation needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Reading values
+    Read,
+    /// Setting or removing values
+    Write,
+}
+
+/// Grants `ops` on every key starting with `prefix`
+#[derive(Debug, Deserialize)]
+struct Rule {
+    prefix: String,
+    ops: Vec<Operation>,
+}
+
+/// A user allowed to connect to the server
+#[derive(Debug, Deserialize)]
+pub struct User {
+    name: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+impl User {
+    /// The name of the user
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether any of the user's rules grants `op` on `key`
+    pub fn allows(&self, op: Operation, key: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| key.starts_with(&rule.prefix) && rule.ops.contains(&op))
+    }
+}
+
+/// The set of users known to the server and their permissions
+#[derive(Debug, Deserialize)]
+pub struct Acl {
+    users: Vec<Arc<User>>,
+}
+
+impl Acl {
+    /// Reads an [`Acl`] from a JSON file
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::auth::Acl;
+    ///
+    /// let acl = Acl::from_file("acl.json").unwrap();
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Acl> {
+        let acl: Acl = serde_json::from_slice(&fs::read(path)?)?;
+        if let Some(user) = acl
+            .users
+            .iter()
+            .find(|user| user.password.is_none() && user.token.is_none())
+        {
+            return Err(KvsError::Message(format!(
+                "User {} has neither a password nor a token",
+                user.name
+            )));
+        }
+        Ok(acl)
+    }
+
+    /// Finds the user matching the credentials.
+    ///
+    /// With a `username`, `secret` is checked against that user's password; without one, it is
+    /// checked against the users' tokens.
+    pub fn authenticate(&self, username: Option<&str>, secret: &str) -> Option<Arc<User>> {
+        self.users
+            .iter()
+            .find(|user| {
+                let expected = match username {
+                    Some(name) if name == user.name => user.password.as_deref(),
+                    Some(_) => None,
+                    None => user.token.as_deref(),
+                };
+                expected.is_some_and(|expected| constant_time_eq(expected, secret))
+            })
+            .cloned()
+    }
+}
+
+/// Compares secrets without returning early, so response timing doesn't leak how much matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
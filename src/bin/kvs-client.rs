@@ -0,0 +1,184 @@
+use std::net::SocketAddr;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::process::exit;
+
+use clap::crate_version;
+use clap::{value_parser, Arg, ArgMatches, Command};
+use kvs::{KvsClient, Result};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    let addr_arg = Arg::new("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .help("Address of the server")
+        .default_value(DEFAULT_ADDR)
+        .value_parser(value_parser!(SocketAddr));
+    let key_arg = Arg::new("key").help("A string key").required(true);
+    let to_arg = Arg::new("to")
+        .help("The key to move or copy the value to")
+        .required(true);
+
+    let command = Command::new("kvs-client")
+        .version(crate_version!())
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("get")
+                .about("Get the string value of a given string key")
+                .args([key_arg.clone(), addr_arg.clone()]),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Set the value of a string key to a string")
+                .args([
+                    key_arg.clone(),
+                    Arg::new("value")
+                        .help("The string value of the key")
+                        .required(true),
+                    addr_arg.clone(),
+                ]),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a given key")
+                .args([key_arg.clone(), addr_arg.clone()]),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Move the value of a key to another key")
+                .args([key_arg.clone(), to_arg.clone(), addr_arg.clone()]),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy the value of a key to another key")
+                .args([key_arg, to_arg, addr_arg.clone()]),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("List the keys, a page at a time")
+                .args([
+                    Arg::new("match")
+                        .long("match")
+                        .value_name("PATTERN")
+                        .help("Only list the keys matching a glob pattern, e.g. 'user:*'"),
+                    Arg::new("count")
+                        .long("count")
+                        .value_name("N")
+                        .help("Keys to go through per request")
+                        .default_value("100")
+                        .value_parser(value_parser!(usize)),
+                    addr_arg,
+                ]),
+        )
+        .args([
+            Arg::new("user")
+                .long("user")
+                .value_name("NAME")
+                .help("User to authenticate as, together with --password")
+                .requires("password")
+                .global(true),
+            Arg::new("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .help("Password of --user")
+                .env("KVS_PASSWORD")
+                .hide_env_values(true)
+                .global(true),
+            Arg::new("token")
+                .long("token")
+                .value_name("TOKEN")
+                .help("Token to authenticate with")
+                .env("KVS_TOKEN")
+                .hide_env_values(true)
+                .conflicts_with("user")
+                .global(true),
+        ]);
+    #[cfg(feature = "tls")]
+    let command = command.args([
+        Arg::new("ca")
+            .long("ca")
+            .value_name("FILE")
+            .help("Connect over TLS, trusting the PEM certificates in FILE")
+            .global(true)
+            .value_parser(value_parser!(PathBuf)),
+        Arg::new("server-name")
+            .long("server-name")
+            .value_name("NAME")
+            .help("Name the server certificate must be issued for")
+            .global(true)
+            .default_value("localhost"),
+    ]);
+    let matches = command.get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let (name, sub_matches) = matches.subcommand().unwrap();
+    let addr = sub_matches.get_one::<SocketAddr>("addr").unwrap();
+    let key = || sub_matches.get_one::<String>("key").unwrap().to_string();
+    let mut client = connect(sub_matches, addr)?;
+    if let Some(user) = sub_matches.get_one::<String>("user") {
+        let password = sub_matches.get_one::<String>("password").unwrap();
+        client.authenticate(Some(user), password)?;
+    } else if let Some(token) = sub_matches.get_one::<String>("token") {
+        client.authenticate(None, token)?;
+    }
+    match name {
+        "get" => match client.get(key())? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        "set" => {
+            let value = sub_matches.get_one::<String>("value").unwrap().to_string();
+            client.set(key(), value)?;
+        }
+        "rm" => client.remove(key())?,
+        "rename" => {
+            let to = sub_matches.get_one::<String>("to").unwrap().to_string();
+            client.rename(key(), to)?;
+        }
+        "copy" => {
+            let to = sub_matches.get_one::<String>("to").unwrap().to_string();
+            client.copy(key(), to)?;
+        }
+        "scan" => {
+            let pattern = sub_matches.get_one::<String>("match");
+            let count = *sub_matches.get_one::<usize>("count").unwrap();
+            let mut cursor = None;
+            loop {
+                let (keys, next) = client.scan(cursor, pattern.map(String::as_str), count)?;
+                for key in keys {
+                    println!("{}", key);
+                }
+                if next.is_none() {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn connect(matches: &ArgMatches, addr: &SocketAddr) -> Result<KvsClient> {
+    match matches.get_one::<PathBuf>("ca") {
+        Some(ca) => {
+            let server_name = matches.get_one::<String>("server-name").unwrap();
+            KvsClient::connect_tls(addr, server_name, ca)
+        }
+        None => KvsClient::connect(addr),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn connect(_matches: &ArgMatches, addr: &SocketAddr) -> Result<KvsClient> {
+    KvsClient::connect(addr)
+}
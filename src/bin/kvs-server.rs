@@ -0,0 +1,304 @@
+use std::{
+    env::current_dir, net::SocketAddr, path::PathBuf, process::exit, thread, time::Duration,
+};
+
+use clap::crate_version;
+use clap::{value_parser, Arg, ArgMatches, Command};
+use kvs::{
+    auth::Acl,
+    engine_of,
+    thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool},
+    CompactionEnd, CompactionListener, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer,
+    LsmKvStore, Protocol, Result, SledKvsEngine, WalArchive,
+};
+use log::{info, LevelFilter};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    let command = Command::new("kvs-server")
+        .version(crate_version!())
+        .arg(
+            Arg::new("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("Address to listen on")
+                .default_value(DEFAULT_ADDR)
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("protocol")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .help("Wire protocol spoken to clients")
+                .default_value("kvs")
+                .value_parser([
+                    "kvs",
+                    "resp",
+                    #[cfg(feature = "http")]
+                    "http",
+                    #[cfg(feature = "grpc")]
+                    "grpc",
+                ]),
+        )
+        .arg(
+            Arg::new("acl")
+                .long("acl")
+                .value_name("FILE")
+                .help("JSON file with the users allowed to connect and their permissions")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Storage engine; defaults to the one that created the store, or kvs")
+                .value_parser(["kvs", "sled", "lsm"]),
+        )
+        .arg(
+            Arg::new("leader")
+                .long("leader")
+                .value_name("IP:PORT")
+                .help("Leader to replicate as a read-only follower of; kvs engine only")
+                .value_parser(value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("slow-op-threshold")
+                .long("slow-op-threshold")
+                .value_name("MS")
+                .help("Log store operations taking longer than MS milliseconds; kvs engine only")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("compaction-rate-limit")
+                .long("compaction-rate-limit")
+                .value_name("BYTES")
+                .help("Read and write at most BYTES per second when compacting; kvs engine only")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("wal-archive")
+                .long("wal-archive")
+                .value_name("DIR")
+                .num_args(0..=1)
+                .help("Archive replaced log files into DIR, archive/ in the store by default; kvs engine only")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("wal-archive-max-segments")
+                .long("wal-archive-max-segments")
+                .value_name("N")
+                .help("Keep at most N archived log files")
+                .requires("wal-archive")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("wal-archive-max-bytes")
+                .long("wal-archive-max-bytes")
+                .value_name("BYTES")
+                .help("Keep at most BYTES of archived log files")
+                .requires("wal-archive")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("wal-archive-max-age")
+                .long("wal-archive-max-age")
+                .value_name("SECONDS")
+                .help("Keep archived log files for at most SECONDS after they were last written to")
+                .requires("wal-archive")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("thread-pool")
+                .long("thread-pool")
+                .value_name("POOL")
+                .help("Serve connections concurrently from a thread pool instead of one after another")
+                .value_parser(["naive", "shared-queue"]),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Threads of the pool; defaults to the number of CPUs")
+                .requires("thread-pool")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Most verbose level logged; `debug` traces every request and store operation")
+                .env("KVS_LOG_LEVEL")
+                .default_value("info")
+                .value_parser(value_parser!(LevelFilter)),
+        );
+    #[cfg(feature = "tls")]
+    let command = command.args([
+        Arg::new("cert")
+            .long("cert")
+            .value_name("FILE")
+            .help("PEM certificate chain to terminate TLS with")
+            .requires("key")
+            .value_parser(value_parser!(PathBuf)),
+        Arg::new("key")
+            .long("key")
+            .value_name("FILE")
+            .help("PEM private key of the TLS certificate")
+            .requires("cert")
+            .value_parser(value_parser!(PathBuf)),
+    ]);
+    #[cfg(feature = "http")]
+    let command = command.arg(
+        Arg::new("metrics-addr")
+            .long("metrics-addr")
+            .value_name("IP:PORT")
+            .help("Address to also serve Prometheus metrics on, at /metrics")
+            .value_parser(value_parser!(SocketAddr)),
+    );
+    #[cfg(feature = "tokio")]
+    let command = command.arg(
+        Arg::new("async")
+            .long("async")
+            .help("Serve every connection from a tokio task instead of one after another")
+            .action(clap::ArgAction::SetTrue),
+    );
+    let matches = command.get_matches();
+    env_logger::builder()
+        .filter_level(*matches.get_one::<LevelFilter>("log-level").unwrap())
+        .init();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let addr = *matches.get_one::<SocketAddr>("addr").unwrap();
+    let protocol = match matches.get_one::<String>("protocol").unwrap().as_str() {
+        "resp" => Protocol::Resp,
+        #[cfg(feature = "http")]
+        "http" => Protocol::Http,
+        #[cfg(feature = "grpc")]
+        "grpc" => Protocol::Grpc,
+        _ => Protocol::Kvs,
+    };
+
+    let dir = current_dir()?;
+    // opening the store fails if it was created by another engine than the one asked for
+    let engine = match matches.get_one::<String>("engine") {
+        Some(engine) => engine.to_string(),
+        None => engine_of(&dir)?.unwrap_or_else(|| "kvs".to_string()),
+    };
+
+    info!("kvs-server {}", crate_version!());
+    info!("Storage engine: {}", engine);
+    info!("Listening on {} ({:?} protocol)", addr, protocol);
+
+    let leader = matches.get_one::<SocketAddr>("leader");
+    match engine.as_str() {
+        "sled" | "lsm" if leader.is_some() => Err(KvsError::Message(
+            "Only the kvs engine can follow a leader".to_owned(),
+        )),
+        "sled" => serve(
+            KvsServer::new(SledKvsEngine::open(&dir)?),
+            matches,
+            protocol,
+            addr,
+        ),
+        "lsm" => serve(
+            KvsServer::new(LsmKvStore::open(&dir)?),
+            matches,
+            protocol,
+            addr,
+        ),
+        _ => {
+            let mut options = KvStoreOptions::new().compaction_listener(LogCompactions);
+            if let Some(ms) = matches.get_one::<u64>("slow-op-threshold") {
+                options = options.slow_op_threshold(Duration::from_millis(*ms));
+            }
+            if let Some(bytes) = matches.get_one::<u64>("compaction-rate-limit") {
+                options = options.compaction_rate_limit(*bytes);
+            }
+            if matches.contains_id("wal-archive") {
+                options = options.wal_archive(WalArchive {
+                    dir: matches.get_one::<PathBuf>("wal-archive").cloned(),
+                    max_segments: matches.get_one("wal-archive-max-segments").copied(),
+                    max_bytes: matches.get_one("wal-archive-max-bytes").copied(),
+                    max_age: matches
+                        .get_one("wal-archive-max-age")
+                        .map(|&secs| Duration::from_secs(secs)),
+                });
+            }
+            let mut server = KvsServer::new(KvStore::open_with(&dir, options)?);
+            if let Some(leader) = leader {
+                info!("Following leader {}", leader);
+                server = server.with_leader(*leader);
+            }
+            serve(server, matches, protocol, addr)
+        }
+    }
+}
+
+fn serve<E: KvsEngine>(
+    server: KvsServer<E>,
+    matches: &ArgMatches,
+    protocol: Protocol,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut server = server.with_protocol(protocol);
+    if let Some(acl) = matches.get_one::<PathBuf>("acl") {
+        info!("Requiring authentication with users from {}", acl.display());
+        server = server.with_acl(Acl::from_file(acl)?);
+    }
+    #[cfg(feature = "http")]
+    if let Some(metrics_addr) = matches.get_one::<SocketAddr>("metrics-addr") {
+        info!("Serving metrics on {}", metrics_addr);
+        server = server.with_metrics_addr(*metrics_addr);
+    }
+    if let Some(pool) = matches.get_one::<String>("thread-pool") {
+        let threads = match matches.get_one::<u32>("threads") {
+            Some(threads) => *threads,
+            None => thread::available_parallelism().map_or(1, |n| n.get() as u32),
+        };
+        info!(
+            "Serving connections from a {} pool of {} threads",
+            pool, threads
+        );
+        server = match pool.as_str() {
+            "naive" => server.with_thread_pool(NaiveThreadPool::new(threads)?),
+            _ => server.with_thread_pool(SharedQueueThreadPool::new(threads)?),
+        };
+    }
+    #[cfg(feature = "tokio")]
+    if matches.get_flag("async") {
+        info!("Serving connections from async tasks");
+        server = server.with_async();
+    }
+    #[cfg(feature = "tls")]
+    let server = match (
+        matches.get_one::<PathBuf>("cert"),
+        matches.get_one::<PathBuf>("key"),
+    ) {
+        (Some(cert), Some(key)) => {
+            info!("Terminating TLS with certificate {}", cert.display());
+            server.with_tls(cert, key)?
+        }
+        _ => server,
+    };
+    server.run(addr)
+}
+
+/// Logs the compactions of a kvs store, which log their failures themselves
+struct LogCompactions;
+
+impl CompactionListener for LogCompactions {
+    fn on_compaction_end(&self, event: &CompactionEnd) {
+        if let Ok(reclaimed) = event.result {
+            info!(
+                "Compacted the log into generation {} in {:?}, reclaiming {} bytes",
+                event.gen, event.duration, reclaimed
+            );
+        }
+    }
+}
@@ -1,66 +1,802 @@
-use std::process::exit;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use clap::crate_version;
-use clap::{Arg, Command};
-use kvs::{KvStore, Result};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use kvs::{
+    ExportFormat, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvStore, OnConflict,
+    RecordFormat, Result, SledKvsEngine,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
 
-fn main() -> Result<()> {
-    let matches = Command::new("kvs")
+fn main() {
+    if let Err(e) = run(&cli().get_matches()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+/// The command line interface of `kvs`, whose subcommands are also read by `kvs shell`
+fn cli() -> Command {
+    let key_arg = Arg::new("key").help("A string key").required(true);
+    let to_arg = Arg::new("to")
+        .help("The key to move or copy the value to")
+        .required(true);
+
+    Command::new("kvs")
         .version(crate_version!())
-        .args([Arg::new("arg1"), Arg::new("arg2"), Arg::new("arg3")])
-        .get_matches();
-    if !matches.args_present() {
-        exit(-1)
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("get")
+                .about("Get the string value of a given string key")
+                .arg(key_arg.clone()),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Set the value of a string key to a string")
+                .args([
+                    key_arg.clone(),
+                    Arg::new("value")
+                        .help("The string value of the key")
+                        .required(true),
+                ]),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a given key")
+                .arg(key_arg.clone()),
+        )
+        .subcommand(
+            Command::new("rename")
+                .about("Move the value of a key to another key")
+                .args([key_arg.clone(), to_arg.clone()]),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy the value of a key to another key")
+                .args([key_arg, to_arg]),
+        )
+        .subcommand(
+            Command::new("clear").about("Delete every key").arg(
+                Arg::new("yes")
+                    .long("yes")
+                    .help("Confirm clearing the store")
+                    .action(ArgAction::SetTrue),
+            ),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Print the keys starting with a prefix and their values")
+                .arg(
+                    Arg::new("prefix")
+                        .help("Prefix of the keys to print, all of them by default")
+                        .default_value(""),
+                ),
+        )
+        .subcommand(
+            Command::new("keys")
+                .about("Print the keys matching a glob pattern, e.g. 'user:*:profile'")
+                .arg(
+                    Arg::new("pattern")
+                        .help("Pattern where * matches anything and ? a single character")
+                        .default_value("*"),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Print every key and value")
+                .args([
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Format of the export")
+                        .default_value("jsonl")
+                        .value_parser(["jsonl", "csv"]),
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Only export the keys starting with PREFIX")
+                        .default_value(""),
+                ]),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Set the keys and values of a JSON lines export")
+                .args([
+                    Arg::new("file")
+                        .help("The export to import, - for stdin")
+                        .required(true),
+                    Arg::new("on-conflict")
+                        .long("on-conflict")
+                        .value_name("POLICY")
+                        .help("What importing a key that already exists does")
+                        .default_value("overwrite")
+                        .value_parser(["skip", "overwrite", "fail"]),
+                ]),
+        )
+        .subcommand(
+            Command::new("load")
+                .about("Set the tab-separated or JSON lines pairs read from stdin in one batch"),
+        )
+        .subcommand(
+            Command::new("log")
+                .about("Inspect the log")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("dump").about("Print every record of the log and its status"),
+                ),
+        )
+        .subcommand(Command::new("compact").about("Compact the log now"))
+        .subcommand(Command::new("stats").about("Print the size of the store and its log"))
+        .subcommand(
+            Command::new("fsck")
+                .about("Check the framing and checksum of every record of the log")
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .help("Rebuild the log from the recoverable records if fsck finds damage")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Write a consistent copy of the store into an empty directory")
+                .args([
+                    Arg::new("backup")
+                        .help("Directory to write the backup to [default: backups/<unix millis> in the store]")
+                        .value_parser(value_parser!(PathBuf)),
+                    Arg::new("previous")
+                        .help("Earlier backup to only write the changes since")
+                        .value_parser(value_parser!(PathBuf)),
+                ]),
+        )
+        .subcommand(
+            Command::new("shell")
+                .about("Open the store once and run the commands read from stdin, one per line"),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure throughput and latency on a new store, deleted afterwards")
+                .args([
+                    Arg::new("writes")
+                        .long("writes")
+                        .value_name("N")
+                        .help("Number of keys set")
+                        .default_value("10000")
+                        .value_parser(value_parser!(usize)),
+                    Arg::new("reads")
+                        .long("reads")
+                        .value_name("N")
+                        .help("Number of gets of the keys set")
+                        .default_value("10000")
+                        .value_parser(value_parser!(usize)),
+                    Arg::new("value-size")
+                        .long("value-size")
+                        .value_name("BYTES")
+                        .help("Size of the values set")
+                        .default_value("100")
+                        .value_parser(value_parser!(usize)),
+                    Arg::new("threads")
+                        .long("threads")
+                        .value_name("N")
+                        .help("Number of threads sharing the operations")
+                        .default_value("1")
+                        .value_parser(value_parser!(u64).range(1..)),
+                    Arg::new("engine")
+                        .long("engine")
+                        .value_name("ENGINE-NAME")
+                        .help("Storage engine to measure")
+                        .default_value("kvs")
+                        .value_parser(["kvs", "sled", "lsm"]),
+                ]),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Check a backup and restore it into an empty directory")
+                .args([
+                    Arg::new("backup")
+                        .help("Directory of the backup")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                    Arg::new("target")
+                        .help("Directory to restore the store into, the data directory by default")
+                        .value_parser(value_parser!(PathBuf)),
+                ]),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Rewrite the log of a closed store with records in another format")
+                .args([
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("FORMAT")
+                        .help("Format of the records the store holds")
+                        .required(true)
+                        .value_parser(["bincode", "json", "msgpack", "cbor"]),
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .help("Format to rewrite the records in")
+                        .required(true)
+                        .value_parser(["bincode", "json", "msgpack", "cbor"]),
+                    Arg::new("store")
+                        .help("Directory of the store, the data directory by default")
+                        .value_parser(value_parser!(PathBuf)),
+                ]),
+        )
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .value_name("PATH")
+                .help("Data directory of the store, created if missing")
+                .env("KVS_DIR")
+                .default_value(".")
+                .global(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .args([
+            Arg::new("output")
+                .long("output")
+                .value_name("FORMAT")
+                .help("How get, scan, stats and bench print what they report")
+                .default_value("raw")
+                .value_parser(["raw", "json", "table"])
+                .global(true),
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Don't print what get and scan read; get fails if the key is missing")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        ])
+}
+
+/// How read commands print what they read, picked by `--output` and `--quiet`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// Values as they are, and `name: value` lines for stats
+    Raw,
+    /// A single JSON document
+    Json,
+    /// Aligned columns under a header
+    Table,
+    /// Nothing
+    Quiet,
+}
+
+impl Output {
+    fn from_matches(matches: &ArgMatches) -> Output {
+        if matches.get_flag("quiet") {
+            return Output::Quiet;
+        }
+        match matches.get_one::<String>("output").unwrap().as_str() {
+            "json" => Output::Json,
+            "table" => Output::Table,
+            _ => Output::Raw,
+        }
     }
+}
 
-    if let Some(arg1) = matches.get_one::<String>("arg1") {
-        if arg1 == &"get".to_string() {
-            let extra_field = matches.contains_id("arg3");
-            if extra_field {
-                panic!()
-            }
-            match matches.get_one::<String>("arg2") {
-                Some(arg2) => {
-                    let mut store = KvStore::open(".").unwrap();
-                    match store.get(arg2.to_string()) {
-                        Ok(value) => match value {
-                            Some(_) => (),
-                            None => println!("Key not found"),
-                        },
-                        Err(_) => (),
-                    }
+fn run(matches: &ArgMatches) -> Result<()> {
+    let (name, sub_matches) = matches.subcommand().unwrap();
+    let dir = sub_matches.get_one::<PathBuf>("dir").unwrap();
+    match name {
+        "fsck" => {
+            let repair = sub_matches.get_flag("repair");
+            let report = if repair {
+                KvStore::repair(dir, KvStoreOptions::new())?
+            } else {
+                KvStore::fsck(dir, KvStoreOptions::new())?
+            };
+            println!("live: {}", report.live);
+            println!("stale: {}", report.stale);
+            println!("corrupt: {}", report.corrupt);
+            println!("torn_bytes: {}", report.torn_bytes);
+            match (report.is_clean(), repair) {
+                (true, _) => println!("status: clean"),
+                (false, true) => println!("status: repaired"),
+                (false, false) => {
+                    println!("status: damaged, run `kvs fsck --repair` to rebuild the log");
+                    exit(1)
                 }
-                None => panic!(),
-            }
-        } else if arg1 == &"set".to_string() {
-            match matches.get_one::<String>("arg2") {
-                Some(arg2) => match matches.get_one::<String>("arg3") {
-                    Some(arg3) => {
-                        let mut store = KvStore::open(".").unwrap();
-                        store.set(arg2.to_string(), arg3.to_string()).unwrap();
+            }
+        }
+        "restore" => {
+            let backup_dir = sub_matches.get_one::<PathBuf>("backup").unwrap();
+            let target_dir = sub_matches.get_one::<PathBuf>("target").unwrap_or(dir);
+            KvStore::restore(backup_dir, target_dir)?
+        }
+        "migrate" => {
+            let format = |name| record_format(sub_matches.get_one::<String>(name).unwrap());
+            let store_dir = sub_matches.get_one::<PathBuf>("store").unwrap_or(dir);
+            KvStore::migrate(
+                store_dir,
+                format("from"),
+                format("to"),
+                KvStoreOptions::new(),
+            )?
+        }
+        "shell" => shell(&open(dir)?)?,
+        "bench" => bench(sub_matches)?,
+        _ => {
+            if !execute(&open(dir)?, name, sub_matches)? {
+                exit(1)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The record format named `name` on the command line
+fn record_format(name: &str) -> RecordFormat {
+    match name {
+        "json" => RecordFormat::Json,
+        "msgpack" => RecordFormat::MessagePack,
+        "cbor" => RecordFormat::Cbor,
+        _ => RecordFormat::Bincode,
+    }
+}
+
+/// Runs the subcommand `name` on `store`, and returns `false` if it failed because a key was
+/// missing, which it reports on stdout rather than as an error
+fn execute(store: &KvStore, name: &str, sub_matches: &ArgMatches) -> Result<bool> {
+    let key = || sub_matches.get_one::<String>("key").unwrap().to_string();
+    let to = || sub_matches.get_one::<String>("to").unwrap().to_string();
+    let output = Output::from_matches(sub_matches);
+    match name {
+        "get" => {
+            let key = key();
+            let value = store.get(key.clone())?;
+            match (output, value) {
+                (Output::Json, value) => println!("{}", json!({ "key": key, "value": value })),
+                (Output::Quiet, value) => return Ok(value.is_some()),
+                (_, None) => println!("Key not found"),
+                (Output::Table, Some(value)) => print_table(["KEY", "VALUE"], &[(key, value)]),
+                (_, Some(value)) => println!("{}", value),
+            }
+        }
+        "set" => {
+            let value = sub_matches.get_one::<String>("value").unwrap().to_string();
+            store.set(key(), value)?;
+        }
+        "rm" | "rename" | "copy" => {
+            let result = match name {
+                "rm" => store.remove(key()),
+                "rename" => store.rename(key(), to()),
+                _ => store.copy(key(), to()),
+            };
+            match result {
+                Err(KvsError::KeyNotFound) => {
+                    println!("Key not found");
+                    return Ok(false);
+                }
+                result => result?,
+            }
+        }
+        "clear" => {
+            if !sub_matches.get_flag("yes") {
+                return Err(KvsError::Message(
+                    "Refusing to delete every key without --yes".to_owned(),
+                ));
+            }
+            store.clear()?;
+        }
+        "scan" => {
+            let prefix = sub_matches.get_one::<String>("prefix").unwrap();
+            match output {
+                Output::Raw => {
+                    for entry in store.scan_prefix(prefix) {
+                        let (key, value) = entry?;
+                        println!("{}\t{}", key, value);
                     }
-                    None => panic!(),
-                },
-                None => panic!(),
-            }
-        } else if arg1 == &"rm".to_string() {
-            match matches.get_one::<String>("arg2") {
-                Some(arg2) => {
-                    let mut store = KvStore::open(".").unwrap();
-                    match store.remove(arg2.to_string()) {
-                        Ok(_) => (),
-                        Err(_) => {
-                            println!("Key not found");
-                            exit(1)
-                        }
+                }
+                Output::Json => {
+                    let entries = store
+                        .scan_prefix(prefix)
+                        .map(|entry| {
+                            entry.map(|(key, value)| json!({ "key": key, "value": value }))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    println!("{}", Value::Array(entries));
+                }
+                Output::Table => {
+                    let entries = store.scan_prefix(prefix).collect::<Result<Vec<_>>>()?;
+                    print_table(["KEY", "VALUE"], &entries);
+                }
+                Output::Quiet => {}
+            }
+        }
+        "keys" => {
+            let pattern = sub_matches.get_one::<String>("pattern").unwrap();
+            match output {
+                Output::Json => {
+                    let keys: Vec<String> = store.keys_matching(pattern).collect();
+                    println!("{}", json!(keys));
+                }
+                Output::Quiet => {}
+                _ => {
+                    for key in store.keys_matching(pattern) {
+                        println!("{}", key);
                     }
                 }
-                None => panic!(),
             }
-        } else {
-            panic!()
+        }
+        "export" => {
+            let format = match sub_matches.get_one::<String>("format").unwrap().as_str() {
+                "csv" => ExportFormat::Csv,
+                _ => ExportFormat::JsonLines,
+            };
+            let prefix = sub_matches.get_one::<String>("prefix").unwrap();
+            store.export(prefix, format, io::stdout().lock())?;
+        }
+        "import" => {
+            let on_conflict = match sub_matches
+                .get_one::<String>("on-conflict")
+                .unwrap()
+                .as_str()
+            {
+                "skip" => OnConflict::Skip,
+                "fail" => OnConflict::Fail,
+                _ => OnConflict::Overwrite,
+            };
+            match sub_matches.get_one::<String>("file").unwrap().as_str() {
+                "-" => store.import_from_reader(io::stdin().lock(), on_conflict)?,
+                file => store.import_from_reader(BufReader::new(File::open(file)?), on_conflict)?,
+            };
+        }
+        "load" => {
+            store.load(io::stdin().lock())?;
+        }
+        "log" => {
+            println!("gen\toffset\tlen\ttype\tkey\texpires_at\tstatus");
+            for record in store.log_records()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    record.gen,
+                    record.offset,
+                    record.len,
+                    record.kind,
+                    record.key.escape_ascii(),
+                    record
+                        .expires_at
+                        .map_or_else(|| "-".to_string(), |expires_at| expires_at.to_string()),
+                    if record.live { "live" } else { "stale" }
+                );
+            }
+        }
+        "compact" => {
+            let reclaimed = store.compact()?;
+            println!("Reclaimed {} bytes", reclaimed);
+        }
+        "stats" => {
+            let stats = store.stats()?;
+            let last_compaction = match stats.last_compaction {
+                Some(time) => Some(time.duration_since(UNIX_EPOCH)?.as_millis()),
+                None => None,
+            };
+            if output == Output::Json {
+                let stats = json!({
+                    "keys": stats.keys,
+                    "live_bytes": stats.live_bytes,
+                    "stale_bytes": stats.stale_bytes,
+                    "log_bytes": stats.log_bytes,
+                    "segments": stats.segments,
+                    "last_compaction": last_compaction,
+                });
+                println!("{}", stats);
+                return Ok(true);
+            }
+            let rows = [
+                ("keys", stats.keys.to_string()),
+                ("live_bytes", stats.live_bytes.to_string()),
+                ("stale_bytes", stats.stale_bytes.to_string()),
+                ("log_bytes", stats.log_bytes.to_string()),
+                ("segments", stats.segments.to_string()),
+                (
+                    "last_compaction",
+                    last_compaction.map_or_else(|| "never".to_string(), |time| time.to_string()),
+                ),
+            ]
+            .map(|(name, value)| (name.to_string(), value));
+            if output == Output::Table {
+                print_table(["STAT", "VALUE"], &rows);
+            } else {
+                for (name, value) in rows {
+                    println!("{}: {}", name, value);
+                }
+            }
+        }
+        "backup" => {
+            let backup_dir = match sub_matches.get_one::<PathBuf>("backup") {
+                Some(backup_dir) => backup_dir.clone(),
+                None => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                    let backup_dir = store.backups_dir().join(now.as_millis().to_string());
+                    // the directory is named after the time, so it is printed to be found again
+                    println!("{}", backup_dir.display());
+                    backup_dir
+                }
+            };
+            match sub_matches.get_one::<PathBuf>("previous") {
+                Some(previous) => store.backup_incremental(previous, backup_dir)?,
+                None => store.backup(backup_dir)?,
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(true)
+}
+
+/// Prints `rows` in two columns aligned under `header`
+fn print_table(header: [&str; 2], rows: &[(String, String)]) {
+    let width = rows
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .chain([header[0].len()])
+        .max()
+        .unwrap_or_default();
+    println!("{:width$}  {}", header[0], header[1]);
+    for (name, value) in rows {
+        println!("{:width$}  {}", name, value);
+    }
+}
+
+/// Runs the subcommands read from stdin, one per line, on `store` until `exit` or the end of
+/// the input
+///
+/// A failing command is reported without stopping the shell. Subcommands that need the store
+/// closed, like `fsck`, or that read stdin themselves, like `load`, aren't available.
+fn shell(store: &KvStore) -> Result<()> {
+    let interactive = io::stdin().is_terminal();
+    let mut line = String::new();
+    loop {
+        if interactive {
+            print!("kvs> ");
+            io::stdout().flush()?;
+        }
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit" | "quit") => return Ok(()),
+            Some("fsck" | "restore" | "migrate" | "load" | "shell") => {
+                eprintln!("{} is not available in the shell", words[0]);
+                continue;
+            }
+            Some(_) => {}
+        }
+        let matches = match cli().no_binary_name(true).try_get_matches_from(words) {
+            Ok(matches) => matches,
+            Err(e) => {
+                e.print()?;
+                continue;
+            }
+        };
+        let (name, sub_matches) = matches.subcommand().unwrap();
+        if let Err(e) = execute(store, name, sub_matches) {
+            eprintln!("{}", e);
         }
     }
+}
 
+/// Splits a shell line into words separated by whitespace, where double quotes group words and
+/// a backslash escapes the next character
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or_else(|| {
+                    KvsError::Message("Nothing to escape at the end of the line".to_owned())
+                })?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(KvsError::Message("Unterminated quote".to_owned()));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// The operations of one phase of `kvs bench` and how long they took
+struct Phase {
+    /// Time from the first operation started to the last one done
+    elapsed: Duration,
+    /// Time each operation took, shortest first
+    latencies: Vec<Duration>,
+}
+
+impl Phase {
+    /// Runs `op` for `0..ops`, shared between `threads` threads with a clone of `engine` each
+    fn run<E, F>(engine: &E, ops: usize, threads: usize, op: F) -> Result<Phase>
+    where
+        E: KvsEngine,
+        F: Fn(&E, usize) -> Result<()> + Sync,
+    {
+        let start = Instant::now();
+        let latencies = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|first| {
+                    let engine = engine.clone();
+                    let op = &op;
+                    scope.spawn(move || {
+                        let mut latencies = Vec::new();
+                        for i in (first..ops).step_by(threads) {
+                            let start = Instant::now();
+                            op(&engine, i)?;
+                            latencies.push(start.elapsed());
+                        }
+                        Ok(latencies)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("benchmark thread panicked"))
+                .collect::<Result<Vec<Vec<Duration>>>>()
+        })?;
+        let elapsed = start.elapsed();
+        let mut latencies: Vec<Duration> = latencies.into_iter().flatten().collect();
+        latencies.sort();
+        Ok(Phase { elapsed, latencies })
+    }
+
+    fn ops_per_sec(&self) -> u64 {
+        (self.latencies.len() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+    }
+
+    /// Latency in microseconds that `percent`% of the operations didn't exceed
+    fn percentile_us(&self, percent: f64) -> u128 {
+        let rank = (self.latencies.len() as f64 * percent / 100.0).ceil() as usize;
+        self.latencies
+            .get(rank.saturating_sub(1))
+            .map_or(0, Duration::as_micros)
+    }
+
+    /// The statistics printed for the phase, each name prefixed with `name`
+    fn rows(&self, name: &str) -> Vec<(String, String)> {
+        vec![
+            (name.to_string(), self.latencies.len().to_string()),
+            (format!("{}_per_sec", name), self.ops_per_sec().to_string()),
+            (
+                format!("{}_p50_us", name),
+                self.percentile_us(50.0).to_string(),
+            ),
+            (
+                format!("{}_p95_us", name),
+                self.percentile_us(95.0).to_string(),
+            ),
+            (
+                format!("{}_p99_us", name),
+                self.percentile_us(99.0).to_string(),
+            ),
+            (
+                format!("{}_max_us", name),
+                self.percentile_us(100.0).to_string(),
+            ),
+        ]
+    }
+}
+
+/// Runs `kvs bench`: sets keys, then gets them, on a store in a new temporary directory
+fn bench(sub_matches: &ArgMatches) -> Result<()> {
+    let writes = *sub_matches.get_one::<usize>("writes").unwrap();
+    let reads = *sub_matches.get_one::<usize>("reads").unwrap();
+    let value_size = *sub_matches.get_one::<usize>("value-size").unwrap();
+    let threads = *sub_matches.get_one::<u64>("threads").unwrap() as usize;
+    let engine = sub_matches.get_one::<String>("engine").unwrap();
+
+    let dir = env::temp_dir().join(format!("kvs-bench-{}", Uuid::new_v4()));
+    fs::create_dir(&dir)?;
+    let phases = match engine.as_str() {
+        "sled" => SledKvsEngine::open(&dir)
+            .and_then(|store| bench_engine(store, writes, reads, value_size, threads)),
+        "lsm" => LsmKvStore::open(&dir)
+            .and_then(|store| bench_engine(store, writes, reads, value_size, threads)),
+        _ => KvStore::open(&dir)
+            .and_then(|store| bench_engine(store, writes, reads, value_size, threads)),
+    };
+    fs::remove_dir_all(&dir)?;
+    let (write, read) = phases?;
+
+    let output = Output::from_matches(sub_matches);
+    let mut rows = vec![("engine".to_string(), engine.to_string())];
+    rows.extend(write.rows("writes"));
+    rows.extend(read.rows("reads"));
+    match output {
+        Output::Json => {
+            let stats: serde_json::Map<String, Value> = rows
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .parse::<u64>()
+                        .map_or(Value::String(value), Value::from);
+                    (name, value)
+                })
+                .collect();
+            println!("{}", Value::Object(stats));
+        }
+        Output::Table => print_table(["STAT", "VALUE"], &rows),
+        Output::Raw => {
+            for (name, value) in rows {
+                println!("{}: {}", name, value);
+            }
+        }
+        Output::Quiet => {}
+    }
     Ok(())
 }
+
+/// Sets `writes` keys to values of `value_size` bytes, then gets `reads` of them, and returns how
+/// both phases went
+fn bench_engine<E: KvsEngine>(
+    engine: E,
+    writes: usize,
+    reads: usize,
+    value_size: usize,
+    threads: usize,
+) -> Result<(Phase, Phase)> {
+    let key = |i: usize| format!("key{:010}", i);
+    let value = "x".repeat(value_size);
+    let write = Phase::run(&engine, writes, threads, |engine, i| {
+        engine.set(key(i), value.clone())
+    })?;
+    engine.flush()?;
+    let read = Phase::run(&engine, reads, threads, |engine, i| {
+        engine.get(key(i % writes.max(1))).map(|_| ())
+    })?;
+    Ok((write, read))
+}
+
+/// Opens the store in `dir`
+fn open(dir: &Path) -> Result<KvStore> {
+    KvStore::open(data_dir(dir)?).map_err(|e| not_writable(dir, e))
+}
+
+/// Creates `dir` if it doesn't exist yet, and returns it
+fn data_dir(dir: &Path) -> Result<&Path> {
+    fs::create_dir_all(dir).map_err(|e| not_writable(dir, e.into()))?;
+    Ok(dir)
+}
+
+/// Explains a permission error `e` raised writing to the data directory `dir`
+fn not_writable(dir: &Path, e: KvsError) -> KvsError {
+    match &e {
+        KvsError::Io(io_error) if io_error.kind() == io::ErrorKind::PermissionDenied => {
+            KvsError::Message(format!(
+                "Data directory {} is not writable: {}",
+                dir.display(),
+                io_error
+            ))
+        }
+        _ => e,
+    }
+}
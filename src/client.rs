@@ -0,0 +1,492 @@
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{
+    io::{BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    protocol::{read_frame, write_frame, ErrorKind, Request, Response},
+    ChangeBatch, KvsError, LogPosition, Result,
+};
+
+/// Size of the requests of a pipeline sent before reading the responses to them, small enough for
+/// the sockets to buffer so the server never waits for the client to read while it is writing
+const PIPELINE_WINDOW: usize = 64 * 1024;
+
+/// A bidirectional byte stream to the server, either plain TCP or TLS
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// A client connected to a `kvs-server` over TCP.
+///
+/// A single connection is kept open and reused for every request.
+pub struct KvsClient {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl KvsClient {
+    /// Connects to a `kvs-server` listening at `addr`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// ```
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            stream: BufReader::new(Box::new(stream)),
+        })
+    }
+
+    /// Connects to a `kvs-server` listening at `addr` over TLS.
+    ///
+    /// The server certificate must be issued for `server_name` and signed by one of the
+    /// certificates in the PEM file `ca`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect_tls("127.0.0.1:4000", "localhost", "ca.pem").unwrap();
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        ca: impl AsRef<Path>,
+    ) -> Result<KvsClient> {
+        let config = crate::tls::client_config(ca.as_ref())?;
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_string())?;
+        let connection = rustls::ClientConnection::new(config, server_name)?;
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            stream: BufReader::new(Box::new(rustls::StreamOwned::new(connection, stream))),
+        })
+    }
+
+    /// Gets the value of a key from the server
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.get(String::from("key1")).unwrap();
+    /// ```
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Gets the values of several keys from the server in a single request, in the order of `keys`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.multi_get(vec![String::from("key1"), String::from("key2")]).unwrap();
+    /// ```
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.send(Request::MultiGet { keys })? {
+            Response::Values(values) => Ok(values),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+
+    /// Sets the value of a key on the server
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.set(String::from("key1"), String::from("value1")).unwrap();
+    /// ```
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Sets the value of a key on the server unless it already exists, and tells whether it did
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.set_if_absent(String::from("key1"), String::from("value1")).unwrap();
+    /// ```
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        match self.send(Request::SetIfAbsent { key, value })? {
+            Response::Applied(applied) => Ok(applied),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+
+    /// Moves the value of `from` to the key `to` on the server
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.rename(String::from("draft"), String::from("published")).unwrap();
+    /// ```
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        self.request(Request::Rename { from, to }).map(|_| ())
+    }
+
+    /// Copies the value of `from` to the key `to` on the server
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.copy(String::from("config"), String::from("config.bak")).unwrap();
+    /// ```
+    pub fn copy(&mut self, from: String, to: String) -> Result<()> {
+        self.request(Request::Copy { from, to }).map(|_| ())
+    }
+
+    /// Removes a key from the server if its value is `expected`, and tells whether it did
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.remove_if(String::from("lock"), String::from("owner1")).unwrap();
+    /// ```
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        match self.send(Request::RemoveIf { key, expected })? {
+            Response::Applied(applied) => Ok(applied),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+
+    /// Removes a key from the server
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.remove(String::from("key1")).unwrap();
+    /// ```
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Remove { key }).map(|_| ())
+    }
+
+    /// Lists a page of the keys on the server: goes through up to `count` keys after `cursor`, or
+    /// from the first key if it is `None`, and returns the ones matching the glob `pattern`, if
+    /// any, along with the cursor to pass for the next page, or `None` once every key was listed.
+    ///
+    /// A page may hold fewer keys than `count`, or none, before the last one. Keys added or
+    /// removed during the scan may or may not be listed, but every other key is listed once.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let mut cursor = None;
+    /// loop {
+    ///     let (keys, next) = client.scan(cursor, Some("user:*"), 100).unwrap();
+    ///     for key in keys {
+    ///         println!("{}", key);
+    ///     }
+    ///     if next.is_none() {
+    ///         break;
+    ///     }
+    ///     cursor = next;
+    /// }
+    /// ```
+    pub fn scan(
+        &mut self,
+        cursor: Option<String>,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        match self.send(Request::Scan {
+            cursor,
+            pattern: pattern.map(str::to_string),
+            count,
+        })? {
+            Response::Keys { keys, cursor } => Ok((keys, cursor)),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+
+    /// Starts a pipeline: the requests queued in the returned [`Pipeline`] are sent together when
+    /// it is executed, without waiting for the response to each before sending the next, which
+    /// saves a round trip per request for bulk loads.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let mut pipeline = client.pipeline();
+    /// pipeline
+    ///     .set(String::from("key1"), String::from("value1"))
+    ///     .get(String::from("key1"));
+    /// let results = pipeline.execute().unwrap();
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Starts a transaction on the server: the sets and removals made through the returned
+    /// [`Transaction`] are queued on the server, and only applied, all together, once it is
+    /// committed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let mut transaction = client.transaction().unwrap();
+    /// transaction.remove(String::from("from")).unwrap();
+    /// transaction.set(String::from("to"), String::from("100")).unwrap();
+    /// transaction.commit().unwrap();
+    /// ```
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        self.request(Request::Multi)?;
+        Ok(Transaction {
+            client: self,
+            open: true,
+        })
+    }
+
+    /// Fetches up to `limit` of the changes made to the store of the server from `from` on, as a
+    /// follower that replicated the store with UUID `store_id` up to there
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvsClient, LogPosition};
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// let batch = client.changes(None, LogPosition::START, 1000).unwrap();
+    /// ```
+    pub fn changes(
+        &mut self,
+        store_id: Option<String>,
+        from: LogPosition,
+        limit: usize,
+    ) -> Result<ChangeBatch> {
+        match self.send(Request::Replicate {
+            store_id,
+            from,
+            limit,
+        })? {
+            Response::Changes(batch) => Ok(batch),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+
+    /// Authenticates the connection with a user name and password, or with a token alone when
+    /// `username` is `None`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvsClient;
+    ///
+    /// let mut client = KvsClient::connect("127.0.0.1:4000").unwrap();
+    /// client.authenticate(Some("admin"), "hunter2").unwrap();
+    /// ```
+    pub fn authenticate(&mut self, username: Option<&str>, password: &str) -> Result<()> {
+        self.request(Request::Auth {
+            username: username.map(str::to_string),
+            password: password.to_string(),
+        })
+        .map(|_| ())
+    }
+
+    /// Sends a request expecting a single value or none in response
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        self.send(request).and_then(value)
+    }
+
+    /// Sends a request and waits for its response, turning protocol errors into [`Err`]
+    fn send(&mut self, request: Request) -> Result<Response> {
+        write_frame(self.stream.get_mut(), &request)?;
+        check(self.receive()?)
+    }
+
+    /// Waits for the response to the oldest request not answered yet
+    fn receive(&mut self) -> Result<Response> {
+        read_frame(&mut self.stream)?
+            .ok_or_else(|| KvsError::Protocol("Connection closed by server".to_owned()))
+    }
+}
+
+/// Turns an error response into [`Err`]
+fn check(response: Response) -> Result<Response> {
+    match response {
+        Response::Err {
+            kind: ErrorKind::KeyNotFound,
+            ..
+        } => Err(KvsError::KeyNotFound),
+        Response::Err { kind, message } => Err(KvsError::Server { kind, message }),
+        response => Ok(response),
+    }
+}
+
+/// Takes the single value or none from the response to a request expecting one
+fn value(response: Response) -> Result<Option<String>> {
+    match response {
+        Response::Ok(value) => Ok(value),
+        _ => Err(KvsError::Protocol(
+            "Unexpected response from server".to_owned(),
+        )),
+    }
+}
+
+/// Requests queued to be sent together, see [`KvsClient::pipeline`]
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queues getting the value of a key
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Queues setting the value of a key
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Queues removing a key
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Number of requests queued
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether no request is queued
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the queued requests and returns the result of each, in the order they were queued.
+    ///
+    /// The server runs every request, whether the ones before it failed or not. Only a failure of
+    /// the connection itself fails the whole pipeline.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        let mut results = Vec::with_capacity(self.requests.len());
+        let mut requests = self.requests.into_iter().peekable();
+        let mut frames = Vec::new();
+        while requests.peek().is_some() {
+            let mut sent = 0;
+            while frames.len() < PIPELINE_WINDOW {
+                match requests.next() {
+                    Some(request) => write_frame(&mut frames, &request)?,
+                    None => break,
+                }
+                sent += 1;
+            }
+            self.client.stream.get_mut().write_all(&frames)?;
+            self.client.stream.get_mut().flush()?;
+            frames.clear();
+            for _ in 0..sent {
+                results.push(check(self.client.receive()?).and_then(value));
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// A transaction on the server, see [`KvsClient::transaction`].
+///
+/// Its writes are applied atomically by [`Transaction::commit`], which fails without applying any
+/// if one of them removes a key that doesn't exist at that point. Dropping the transaction without
+/// committing it discards them.
+pub struct Transaction<'a> {
+    client: &'a mut KvsClient,
+    /// Whether the transaction wasn't committed or discarded yet
+    open: bool,
+}
+
+impl Transaction<'_> {
+    /// Queues setting the value of a key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.queue(Request::Set { key, value })
+    }
+
+    /// Queues removing a key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.queue(Request::Remove { key })
+    }
+
+    /// Applies the queued writes atomically
+    pub fn commit(mut self) -> Result<()> {
+        self.open = false;
+        self.client.request(Request::Exec).map(|_| ())
+    }
+
+    /// Drops the queued writes
+    pub fn discard(mut self) -> Result<()> {
+        self.open = false;
+        self.client.request(Request::Discard).map(|_| ())
+    }
+
+    /// Sends a write to be queued in the transaction
+    fn queue(&mut self, request: Request) -> Result<()> {
+        match self.client.send(request)? {
+            Response::Queued => Ok(()),
+            _ => Err(KvsError::Protocol(
+                "Unexpected response from server".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            // the connection is unusable anyway if this fails
+            let _ = self.client.request(Request::Discard);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Archiving the generations of a [`KvStore`](super::KvStore) that are no longer needed instead
+//! of deleting them, see [`KvStoreOptions::wal_archive`](super::KvStoreOptions::wal_archive).
+//!
+//! A generation is renamed into the archive, or copied there and deleted if the archive is on
+//! another file system, so a crash leaves it in the store, to be archived again on open, or in
+//! the archive.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::{
+    files::sync_dir,
+    layout::{self, log_path},
+    options::WalArchive,
+};
+use crate::Result;
+
+/// The archive of a store, and the retention of the generations in it
+#[derive(Debug, Clone)]
+pub(super) struct Archive {
+    dir: PathBuf,
+    retention: WalArchive,
+}
+
+impl Archive {
+    /// The archive `options` sets for the store in `dir`
+    pub(super) fn new(dir: &Path, options: &WalArchive) -> Archive {
+        Archive {
+            dir: options
+                .dir
+                .clone()
+                .unwrap_or_else(|| layout::archive_dir(dir)),
+            retention: options.clone(),
+        }
+    }
+
+    /// Deletes the oldest archived generations until the rest are within the retention
+    pub(super) fn enforce_retention(&self) -> Result<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+        let mut archived = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let gen = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if let (Some(gen), Some("log")) = (gen, path.extension().and_then(OsStr::to_str)) {
+                let metadata = path.metadata()?;
+                archived.push((gen, path, metadata.len(), metadata.modified()?));
+            }
+        }
+        archived.sort_unstable();
+
+        let mut count = archived.len();
+        let mut bytes: u64 = archived.iter().map(|&(_, _, len, _)| len).sum();
+        let now = SystemTime::now();
+        for (_, path, len, modified) in archived {
+            let too_old = self
+                .retention
+                .max_age
+                .is_some_and(|max_age| now.duration_since(modified).is_ok_and(|age| age > max_age));
+            let too_many = self
+                .retention
+                .max_segments
+                .is_some_and(|max_segments| count > max_segments);
+            let too_large = self
+                .retention
+                .max_bytes
+                .is_some_and(|max_bytes| bytes > max_bytes);
+            if !(too_old || too_many || too_large) {
+                break;
+            }
+            fs::remove_file(&path)?;
+            count -= 1;
+            bytes -= len;
+        }
+        sync_dir(&self.dir)
+    }
+
+    /// Moves generation `gen` of the store in `dir` into the archive
+    fn take(&self, dir: &Path, gen: u64) -> Result<()> {
+        let from = log_path(dir, gen);
+        let to = self.dir.join(format!("{}.log", gen));
+        if fs::rename(&from, &to).is_err() {
+            // the archive is on another file system
+            fs::copy(&from, &to)?;
+            File::open(&to)?.sync_all()?;
+            fs::remove_file(&from)?;
+        }
+        Ok(())
+    }
+}
+
+/// Deletes the log files of the generations `gens` of the store in `dir` that are left, or moves
+/// them into `archive` and deletes the archived generations past its retention
+pub(super) fn retire(dir: &Path, gens: &[u64], archive: Option<&Archive>) -> Result<()> {
+    let gens: Vec<u64> = gens
+        .iter()
+        .copied()
+        .filter(|&gen| log_path(dir, gen).exists())
+        .collect();
+    if gens.is_empty() {
+        return Ok(());
+    }
+    match archive {
+        Some(archive) => {
+            fs::create_dir_all(&archive.dir)?;
+            for gen in gens {
+                archive.take(dir, gen)?;
+            }
+            sync_dir(&archive.dir)?;
+            archive.enforce_retention()
+        }
+        None => {
+            for gen in gens {
+                fs::remove_file(log_path(dir, gen))?;
+            }
+            Ok(())
+        }
+    }
+}
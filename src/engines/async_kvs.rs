@@ -0,0 +1,254 @@
+use std::{future::Future, path::PathBuf};
+
+use tokio::task;
+
+use super::{KvStore, KvsEngine, LsmKvStore, SledKvsEngine, WriteBatch};
+use crate::Result;
+
+/// The async side of an engine, which [`AsyncKvStore`] runs operations through.
+///
+/// [`KvStore`] reads and writes its log with `tokio::fs`. Engines without async IO of their own
+/// keep the provided methods, which run the blocking ones on tokio's blocking thread pool.
+///
+/// The blocking methods of a [`KvStore`] wait for the records it is writing out with `tokio::fs`.
+/// On the current-thread runtime, don't call them from a task while others write through an
+/// [`AsyncKvStore`]: that write can't finish while the runtime's only thread is blocked.
+pub trait AsyncEngine: KvsEngine {
+    /// Gets the value of a key, see [`KvsEngine::get`]
+    fn get_async(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        blocking(self.clone(), move |engine| engine.get(key))
+    }
+
+    /// Gets the values of several keys at once, see [`KvsEngine::multi_get`]
+    fn multi_get_async(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<String>>>> + Send {
+        blocking(self.clone(), move |engine| engine.multi_get(&keys))
+    }
+
+    /// Lists the keys starting with `prefix`, see [`KvsEngine::keys_with_prefix`]
+    fn keys_with_prefix_async(
+        &self,
+        prefix: String,
+    ) -> impl Future<Output = Result<Vec<String>>> + Send {
+        blocking(self.clone(), move |engine| engine.keys_with_prefix(&prefix))
+    }
+
+    /// Runs `op`, which writes through a handle to the engine
+    fn write_async<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(Self) -> Result<T> + Send + 'static,
+    ) -> impl Future<Output = Result<T>> + Send {
+        blocking(self.clone(), op)
+    }
+
+    /// Hands buffered writes to the operating system, see [`KvsEngine::flush`]
+    fn flush_async(&self) -> impl Future<Output = Result<()>> + Send {
+        blocking(self.clone(), |engine| engine.flush())
+    }
+}
+
+impl AsyncEngine for SledKvsEngine {}
+
+impl AsyncEngine for LsmKvStore {}
+
+/// Runs `op` against `engine` on tokio's blocking thread pool
+async fn blocking<E: KvsEngine, T: Send + 'static>(
+    engine: E,
+    op: impl FnOnce(E) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    task::spawn_blocking(move || op(engine)).await?
+}
+
+/// An async handle to an engine, for use from tokio tasks.
+///
+/// Operations never block the executor on the disk: a [`KvStore`] reads and writes its log with
+/// `tokio::fs`, see [`AsyncEngine`].
+pub struct AsyncKvStore<E: AsyncEngine = KvStore> {
+    engine: E,
+}
+
+impl<E: AsyncEngine> Clone for AsyncKvStore<E> {
+    fn clone(&self) -> Self {
+        AsyncKvStore {
+            engine: self.engine.clone(),
+        }
+    }
+}
+
+impl AsyncKvStore {
+    /// Opens a [`KvStore`] at the specified path without blocking the executor
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::AsyncKvStore;
+    /// # use tempfile::TempDir;
+    /// # async fn example() {
+    ///
+    /// let store = AsyncKvStore::open(TempDir::new().unwrap().path()).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn open(path: impl Into<PathBuf>) -> Result<AsyncKvStore> {
+        let path = path.into();
+        let store = task::spawn_blocking(move || KvStore::open(path)).await??;
+        Ok(AsyncKvStore::new(store))
+    }
+}
+
+impl<E: AsyncEngine> AsyncKvStore<E> {
+    /// Wraps an open engine
+    pub fn new(engine: E) -> Self {
+        AsyncKvStore { engine }
+    }
+
+    /// Sets the value of a key, overwriting any previous value
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::AsyncKvStore;
+    /// # use tempfile::TempDir;
+    /// # async fn example() {
+    ///
+    /// let store = AsyncKvStore::open(TempDir::new().unwrap().path()).await.unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine
+            .write_async(move |engine| engine.set(key, value))
+            .await
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::AsyncKvStore;
+    /// # use tempfile::TempDir;
+    /// # async fn example() {
+    ///
+    /// let store = AsyncKvStore::open(TempDir::new().unwrap().path()).await.unwrap();
+    /// store.get(String::from("key1")).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get_async(key).await
+    }
+
+    /// Gets the values of several keys at once, in the order of `keys`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::AsyncKvStore;
+    /// # use tempfile::TempDir;
+    /// # async fn example() {
+    ///
+    /// let store = AsyncKvStore::open(TempDir::new().unwrap().path()).await.unwrap();
+    /// store.multi_get(vec![String::from("key1"), String::from("key2")]).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn multi_get(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine.multi_get_async(keys).await
+    }
+
+    /// Removes a key. Fails if the key does not exist.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::AsyncKvStore;
+    /// # use tempfile::TempDir;
+    /// # async fn example() {
+    ///
+    /// let store = AsyncKvStore::open(TempDir::new().unwrap().path()).await.unwrap();
+    /// store.remove(String::from("key1")).await.unwrap();
+    /// # }
+    /// ```
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.engine
+            .write_async(move |engine| engine.remove(key))
+            .await
+    }
+
+    /// Replaces the value of `key` with `new` if it is `expected`, returning the current value
+    /// as the error otherwise
+    pub async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        self.engine
+            .write_async(move |engine| engine.compare_and_swap(key, expected, new))
+            .await
+    }
+
+    /// Moves the value of `from` to the key `to`. Fails if `from` does not exist.
+    pub async fn rename(&self, from: String, to: String) -> Result<()> {
+        self.engine
+            .write_async(move |engine| engine.rename(from, to))
+            .await
+    }
+
+    /// Copies the value of `from` to the key `to`. Fails if `from` does not exist.
+    pub async fn copy(&self, from: String, to: String) -> Result<()> {
+        self.engine
+            .write_async(move |engine| engine.copy(from, to))
+            .await
+    }
+
+    /// Sets the value of a key and returns the value it replaced
+    pub async fn get_and_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.engine
+            .write_async(move |engine| engine.get_and_set(key, value))
+            .await
+    }
+
+    /// Removes a key if its value is `expected`, and tells whether it did
+    pub async fn remove_if(&self, key: String, expected: String) -> Result<bool> {
+        self.engine
+            .write_async(move |engine| engine.remove_if(key, expected))
+            .await
+    }
+
+    /// Sets the value of a key unless it already exists, and tells whether it did
+    pub async fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.engine
+            .write_async(move |engine| engine.set_if_absent(key, value))
+            .await
+    }
+
+    /// Gets the value of a key, setting it to the value returned by `f` first if the key does not
+    /// exist
+    pub async fn get_or_insert_with(
+        &self,
+        key: String,
+        f: impl FnOnce() -> String + Send + 'static,
+    ) -> Result<String> {
+        self.engine
+            .write_async(move |engine| engine.get_or_insert_with(key, f))
+            .await
+    }
+
+    /// Lists the keys starting with `prefix` in lexical order
+    pub async fn keys_with_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        self.engine.keys_with_prefix_async(prefix).await
+    }
+
+    /// Applies every write in `batch` atomically
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.engine
+            .write_async(move |engine| engine.write_batch(batch))
+            .await
+    }
+
+    /// Hands buffered writes to the operating system
+    pub async fn flush(&self) -> Result<()> {
+        self.engine.flush_async().await
+    }
+}
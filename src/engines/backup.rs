@@ -0,0 +1,195 @@
+//! The manifest of a backup made by [`KvStore::backup`](super::KvStore::backup).
+//!
+//! A backup directory holds log files laid out like those of a store, in `segments/`, so it can be
+//! opened as a store itself, and a JSON `MANIFEST` listing them with their length and CRC32. The manifest is written
+//! last, so a directory without one holds a backup that didn't complete.
+//!
+//! The manifest also records where the log of the store ended when the backup was made, its
+//! watermark. An incremental backup only holds the changes since the backup named as its `base`
+//! in the manifest, so restoring it replays the chain of backups from the full one at its root.
+//!
+//! The namespaces of the store are backed up the same way, each in the `namespaces/<name>`
+//! subdirectory of the backup.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{kvs::NAMESPACES_DIR, layout, record};
+use crate::{KvsError, Result};
+
+/// Name of the manifest file in a backup directory
+pub(super) const MANIFEST_NAME: &str = "MANIFEST";
+
+/// The contents of a backup directory
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    /// UUID of the store the backup was made from
+    pub(super) store_id: String,
+    /// When the backup was made, in milliseconds since the Unix epoch
+    pub(super) created_at: u64,
+    /// Where the log of the store ended when the backup was made. Missing from backups made
+    /// before incremental backups.
+    #[serde(default)]
+    pub(super) watermark: Option<Watermark>,
+    /// The backup this one holds the changes since, unless it is a full backup
+    #[serde(default)]
+    pub(super) base: Option<PathBuf>,
+    /// The log files of the backup
+    pub(super) files: Vec<BackupFile>,
+}
+
+/// A position in the log of a store: every record written after it is at a greater position
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct Watermark {
+    pub(super) gen: u64,
+    pub(super) offset: u64,
+}
+
+/// A log file of a backup
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct BackupFile {
+    /// File name, relative to the backup directory
+    pub(super) name: String,
+    pub(super) len: u64,
+    pub(super) crc32: u32,
+}
+
+impl Manifest {
+    /// Writes the manifest into `dir`, replacing the file in one step so it is never seen half
+    /// written
+    pub(super) fn write(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_NAME));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(MANIFEST_NAME))?;
+        Ok(())
+    }
+
+    /// Reads the manifest of the backup in `dir`
+    fn read(dir: &Path) -> Result<Manifest> {
+        let file = File::open(dir.join(MANIFEST_NAME)).map_err(|_| {
+            KvsError::Message(format!("{} holds no complete backup", dir.display()))
+        })?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+/// The backups that the backup in `dir` builds on, and itself, with their manifests: the full
+/// backup first and `dir` last
+pub(super) fn chain(dir: &Path) -> Result<Vec<(PathBuf, Manifest)>> {
+    let mut chain: Vec<(PathBuf, Manifest)> = Vec::new();
+    let mut dir = dir.to_path_buf();
+    loop {
+        let manifest = Manifest::read(&dir)?;
+        let base = manifest.base.clone();
+        chain.push((dir, manifest));
+        match base {
+            Some(base) if chain.iter().any(|(dir, _)| *dir == base) => {
+                return Err(KvsError::Message(format!(
+                    "The backups in {} build on each other",
+                    base.display()
+                )))
+            }
+            Some(base) => dir = base,
+            None => break,
+        }
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// Checks the backup in `dir`, the backups it builds on and the backups of its namespaces against
+/// their manifests
+pub(super) fn verify(dir: &Path) -> Result<()> {
+    let chain = chain(dir)?;
+    let store_id = Uuid::parse_str(&chain[0].1.store_id)?;
+    for (dir, manifest) in &chain {
+        for file in &manifest.files {
+            // the manifest only ever names files in the backup directory
+            let name = Path::new(&file.name);
+            if name
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+            {
+                return Err(KvsError::Message(format!(
+                    "Invalid file name {:?} in the backup manifest",
+                    file.name
+                )));
+            }
+            let path = dir.join(&file.name);
+            let data = fs::read(&path)?;
+            if data.len() as u64 != file.len || crc32fast::hash(&data) != file.crc32 {
+                return Err(KvsError::Message(format!(
+                    "{} doesn't match the backup manifest",
+                    path.display()
+                )));
+            }
+            if record::read_file_header(&path)?.store_id != store_id {
+                return Err(KvsError::Message(format!(
+                    "{} belongs to another store",
+                    path.display()
+                )));
+            }
+        }
+    }
+    for namespace in namespaces(dir)? {
+        verify(&namespace)?;
+    }
+    Ok(())
+}
+
+/// Copies the log files of the verified backup in `dir`, and of the backups it builds on, into
+/// the store directory `target` as its generations, oldest first. The backups of its namespaces
+/// are copied the same way.
+///
+/// The copy is given a new store UUID: it is a store of its own from now on.
+pub(super) fn copy(dir: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(layout::segments_dir(target))?;
+    let store_id = Uuid::new_v4();
+    let mut gen = 1;
+    for (dir, manifest) in chain(dir)? {
+        for file in manifest.files {
+            let path = dir.join(&file.name);
+            let format = record::read_file_header(&path)?.format;
+            let data = fs::read(&path)?;
+            let mut log = File::create(layout::segment_path(target, gen))?;
+            log.write_all(&record::file_header(store_id, format))?;
+            log.write_all(&data[record::FILE_HEADER_LEN as usize..])?;
+            log.sync_all()?;
+            gen += 1;
+        }
+    }
+    for namespace in namespaces(dir)? {
+        copy(
+            &namespace,
+            &target
+                .join(NAMESPACES_DIR)
+                .join(namespace.file_name().unwrap()),
+        )?;
+    }
+    Ok(())
+}
+
+/// Directories of the namespaces backed up in `dir`
+fn namespaces(dir: &Path) -> Result<Vec<PathBuf>> {
+    let dir = dir.join(NAMESPACES_DIR);
+    let mut namespaces = Vec::new();
+    if !dir.exists() {
+        return Ok(namespaces);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            namespaces.push(entry.path());
+        }
+    }
+    Ok(namespaces)
+}
@@ -0,0 +1,53 @@
+use super::record::Command;
+
+/// Writes collected to be applied to a [`KvStore`](super::KvStore) together with
+/// [`KvStore::write_batch`](super::KvStore::write_batch)
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// # use tempfile::TempDir;
+///
+/// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+/// store.set(String::from("from"), String::from("100")).unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.remove(String::from("from"));
+/// batch.set(String::from("to"), String::from("100"));
+/// store.write_batch(batch).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(super) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds setting `key` to `value` to the batch
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.commands
+            .push(Command::set(key.into_bytes(), value.into_bytes()));
+        self
+    }
+
+    /// Adds removing `key` to the batch
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.commands.push(Command::remove(key.into_bytes()));
+        self
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Whether the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
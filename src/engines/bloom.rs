@@ -0,0 +1,194 @@
+//! Bloom filters, which tell that a key is missing from a set of keys without looking at them.
+//!
+//! Every SSTable of an [`LsmKvStore`](super::LsmKvStore) holds the filter of its keys. The
+//! generations of the log of a [`KvStore`](super::KvStore) that are no longer appended to get one
+//! too, in `hints/<gen>.bloom`, so that looking a key up through the log, as
+//! [`KvStore::history`](super::KvStore::history) and snapshots do, skips the generations that
+//! don't hold it. Reading the current value of a key never needs one: the index in memory knows
+//! where every key is, or that it isn't there.
+//!
+//! A filter file holds the CRC32 of its payload, then the payload encoded with bincode: the length
+//! of the generation the filter was built from, which is checked so that a filter outliving its
+//! generation is rebuilt, and the bits of the filter. Filters are built the first time a
+//! generation is looked through for a key.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{
+    files::{self, ReplaceStrategy},
+    layout,
+};
+use crate::Result;
+
+/// Bits of a bloom filter per key, for about 1% of false positives
+const BITS_PER_KEY: usize = 10;
+
+/// Number of bits of a bloom filter set per key
+const HASHES: u64 = 7;
+
+/// Extension of the filter files of the generations
+const FILTER_EXTENSION: &str = "bloom";
+
+/// 64-bit FNV-1a hash of a key, stable across builds since bloom filters are stored
+pub(super) fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The bits of the bloom filter probed for a key hash, in a filter of `bits` bits
+fn bits(hash: u64, bits: u64) -> impl Iterator<Item = u64> {
+    let delta = hash.rotate_left(32) | 1;
+    (0..HASHES).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % bits)
+}
+
+/// A bloom filter of the keys with the given hashes
+pub(super) fn build(key_hashes: &[u64]) -> Vec<u8> {
+    let len = (key_hashes.len() * BITS_PER_KEY).max(64) as u64;
+    let mut bloom = vec![0; len.div_ceil(8) as usize];
+    for &hash in key_hashes {
+        for bit in bits(hash, bloom.len() as u64 * 8) {
+            bloom[bit as usize / 8] |= 1 << (bit % 8);
+        }
+    }
+    bloom
+}
+
+/// Whether the bloom filter may hold the key with the given hash; `false` is always right. An
+/// empty filter may hold every key.
+pub(super) fn may_contain(bloom: &[u8], hash: u64) -> bool {
+    bloom.is_empty()
+        || bits(hash, bloom.len() as u64 * 8)
+            .all(|bit| bloom[bit as usize / 8] & (1 << (bit % 8)) != 0)
+}
+
+/// The filter of the keys of a generation of the log
+#[derive(Serialize, Deserialize)]
+pub(super) struct GenFilter {
+    /// Where the records the filter was built from end
+    len: u64,
+    bloom: Vec<u8>,
+}
+
+impl GenFilter {
+    /// Whether the generation may hold a record about `key`
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        may_contain(&self.bloom, hash(key))
+    }
+}
+
+/// The filters of the generations of the log of a store that are no longer appended to, kept in
+/// memory once read or built
+pub(super) struct GenFilters {
+    dir: PathBuf,
+    /// Whether the filters built are only kept in memory, as the store never touches its files
+    read_only: bool,
+    strategy: ReplaceStrategy,
+    filters: SkipMap<u64, Arc<GenFilter>>,
+}
+
+impl GenFilters {
+    pub(super) fn new(dir: &Path, read_only: bool, strategy: ReplaceStrategy) -> GenFilters {
+        GenFilters {
+            dir: dir.to_owned(),
+            read_only,
+            strategy,
+            filters: SkipMap::new(),
+        }
+    }
+
+    /// The filter of generation `gen`, whose records end at `len`, unless none was built yet
+    pub(super) fn get(&self, gen: u64, len: u64) -> Option<Arc<GenFilter>> {
+        if let Some(entry) = self.filters.get(&gen) {
+            if entry.value().len == len {
+                return Some(entry.value().clone());
+            }
+        }
+        let data = fs::read(filter_path(&self.dir, gen)).ok()?;
+        let filter = (data.len() >= 4)
+            .then(|| data.split_at(4))
+            .filter(|(crc, payload)| crc32fast::hash(payload).to_le_bytes() == **crc)
+            .and_then(|(_, payload)| bincode::deserialize::<GenFilter>(payload).ok())
+            .filter(|filter| filter.len == len)?;
+        let filter = Arc::new(filter);
+        self.filters.insert(gen, filter.clone());
+        Some(filter)
+    }
+
+    /// Keeps the filter of generation `gen`, whose records end at `len`, built from the hashes of
+    /// the keys of its records, or holding every key if some of its records, like range removals,
+    /// aren't about a single key
+    pub(super) fn insert(&self, gen: u64, len: u64, key_hashes: Option<&[u64]>) {
+        if self
+            .filters
+            .get(&gen)
+            .is_some_and(|entry| entry.value().len == len)
+        {
+            // built by another lookup meanwhile
+            return;
+        }
+        let filter = Arc::new(GenFilter {
+            len,
+            bloom: key_hashes.map_or_else(Vec::new, build),
+        });
+        self.filters.insert(gen, filter.clone());
+        if self.read_only {
+            return;
+        }
+        // the log is still read fine without the file, it's only looked through in full
+        if let Err(e) = self.write(gen, &filter) {
+            warn!(
+                "Failed to write the bloom filter of generation {}: {}",
+                gen, e
+            );
+        }
+    }
+
+    /// Writes the filter of generation `gen`, replacing the file in one step
+    fn write(&self, gen: u64, filter: &GenFilter) -> Result<()> {
+        let payload = bincode::serialize(filter)?;
+        let tmp = layout::tmp_path(&self.dir, &format!("{}.{}.tmp", gen, FILTER_EXTENSION));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        files::replace_file(&tmp, &filter_path(&self.dir, gen), self.strategy)
+    }
+
+    /// Deletes the filter files of the store in `dir` whose generations aren't among `gens`
+    pub(super) fn remove_stale(dir: &Path, gens: &[u64]) -> Result<()> {
+        let entries = match fs::read_dir(layout::hints_dir(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(FILTER_EXTENSION.as_ref()) {
+                continue;
+            }
+            let gen = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if gen.is_none_or(|gen| !gens.contains(&gen)) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of the filter file of generation `gen` of the store in `dir`
+fn filter_path(dir: &Path, gen: u64) -> PathBuf {
+    layout::hint_path(dir, &format!("{}.{}", gen, FILTER_EXTENSION))
+}
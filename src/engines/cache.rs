@@ -0,0 +1,111 @@
+//! The read cache of a [`KvStore`](super::KvStore), see
+//! [`KvStoreOptions::cache_capacity`](super::KvStoreOptions::cache_capacity).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// A bounded cache of the values read from the log, evicting the least recently used first.
+///
+/// Values are cached along with the position of the record they were read from, and only served
+/// while the index still points at that record, so writes and compaction never have to invalidate
+/// them: an overwritten or moved value simply misses.
+#[derive(Default)]
+pub(super) struct ReadCache {
+    /// Bytes of keys and values the cache holds at most; `0` disables it
+    capacity: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    /// Keys by the tick they were last used at, least recently used first
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    bytes: u64,
+}
+
+struct CacheEntry {
+    /// Generation and offset of the record the value was read from
+    record: (u64, u64),
+    value: Vec<u8>,
+    tick: u64,
+}
+
+impl ReadCache {
+    /// A cache holding up to `capacity` bytes of keys and values
+    pub(super) fn new(capacity: u64) -> Self {
+        ReadCache {
+            capacity,
+            ..ReadCache::default()
+        }
+    }
+
+    /// The value of `key` if it was cached from the record at `record`, counting a hit or a miss
+    pub(super) fn get(&self, key: &[u8], record: (u64, u64)) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (value, last_used) = match lru.entries.get_mut(key) {
+            Some(entry) if entry.record == record => {
+                let last_used = entry.tick;
+                entry.tick = tick;
+                (entry.value.clone(), last_used)
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let key = lru.order.remove(&last_used).unwrap();
+        lru.order.insert(tick, key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Caches `value`, read for `key` from the record at `record`, evicting the least recently
+    /// used values to make room
+    pub(super) fn insert(&self, key: &[u8], record: (u64, u64), value: &[u8]) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.capacity {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let entry = CacheEntry {
+            record,
+            value: value.to_vec(),
+            tick,
+        };
+        if let Some(old) = lru.entries.insert(key.to_vec(), entry) {
+            lru.order.remove(&old.tick);
+            lru.bytes -= (key.len() + old.value.len()) as u64;
+        }
+        lru.order.insert(tick, key.to_vec());
+        lru.bytes += size;
+        while lru.bytes > self.capacity {
+            let (_, key) = lru.order.pop_first().unwrap();
+            let evicted = lru.entries.remove(&key).unwrap();
+            lru.bytes -= (key.len() + evicted.value.len()) as u64;
+        }
+    }
+
+    /// Hits and misses since the store was opened
+    pub(super) fn counters(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,115 @@
+//! Checkpoints of the index of a [`KvStore`](super::KvStore), so that opening it only replays the
+//! log written since.
+//!
+//! The `hints/checkpoint` file in the store directory holds the CRC32 of its payload, then the payload
+//! encoded with bincode: the UUID of the store, the position in the log up to which the index is
+//! checkpointed, the lengths of the log files at that point, the entries of the index, the
+//! links of its merge records and the version of the last write. It is
+//! written when the store is closed, and deleted when the store is opened for writing, so that a
+//! checkpoint is never trusted after the log was written by a process that didn't checkpoint it
+//! again.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{
+    files::{self, ReplaceStrategy},
+    kvs::RecordPos,
+    layout,
+    merge::Link,
+    LogPosition,
+};
+use crate::Result;
+
+/// Name of the checkpoint file in a store directory
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// The index of a store as it was at a position of its log
+#[derive(Serialize, Deserialize)]
+pub(super) struct Checkpoint {
+    pub(super) store_id: u128,
+    /// Where the records the index doesn't account for start
+    pub(super) position: LogPosition,
+    /// Generations of the log and the lengths of their files
+    pub(super) lens: Vec<(u64, u64)>,
+    pub(super) entries: Vec<(Vec<u8>, RecordPos)>,
+    /// Links of the merge records the entries are made of
+    pub(super) links: Vec<Link>,
+    /// Version of the last write
+    pub(super) version: u64,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint into `dir`, replacing the file in one step so it is never seen half
+    /// written
+    pub(super) fn write(&self, dir: &Path, strategy: ReplaceStrategy) -> Result<()> {
+        let payload = bincode::serialize(self)?;
+        let tmp = layout::tmp_path(dir, &format!("{}.tmp", CHECKPOINT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        files::replace_file(&tmp, &layout::hint_path(dir, CHECKPOINT_FILE), strategy)
+    }
+
+    /// Reads the checkpoint in `dir`, unless there is none or it doesn't match the log of the
+    /// store `store_id`, whose files have the lengths in `gens`
+    pub(super) fn read(
+        dir: &Path,
+        store_id: Uuid,
+        gens: &HashMap<u64, u64>,
+    ) -> Result<Option<Checkpoint>> {
+        let data = match fs::read(layout::hint_path(dir, CHECKPOINT_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let checkpoint = (data.len() >= 4)
+            .then(|| data.split_at(4))
+            .filter(|(crc, payload)| crc32fast::hash(payload).to_le_bytes() == **crc)
+            .and_then(|(_, payload)| bincode::deserialize::<Checkpoint>(payload).ok());
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => {
+                warn!("Ignoring the corrupt checkpoint of the index");
+                return Ok(None);
+            }
+        };
+        // the log may have been compacted, cleared or cut short since, but only the generation
+        // written to at the checkpoint may have grown
+        let position = checkpoint.position;
+        let matches = checkpoint.store_id == store_id.as_u128()
+            && checkpoint
+                .lens
+                .iter()
+                .all(|&(gen, len)| match gens.get(&gen) {
+                    Some(&current) if gen == position.gen => current >= position.offset,
+                    Some(&current) => current == len,
+                    None => false,
+                })
+            && gens
+                .keys()
+                .all(|&gen| gen > position.gen || checkpoint.lens.iter().any(|&(g, _)| g == gen));
+        if !matches {
+            warn!("Ignoring the checkpoint of the index, which doesn't match the log");
+            return Ok(None);
+        }
+        Ok(Some(checkpoint))
+    }
+
+    /// Deletes the checkpoint in `dir`, if there is one
+    pub(super) fn remove(dir: &Path) -> Result<()> {
+        match fs::remove_file(layout::hint_path(dir, CHECKPOINT_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
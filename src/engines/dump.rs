@@ -0,0 +1,91 @@
+//! The text formats [`KvStore::export`](super::KvStore::export) writes key-value pairs in, and
+//! [`KvStore::import_from_reader`](super::KvStore::import_from_reader) and
+//! [`KvStore::load`](super::KvStore::load) read them from.
+
+use std::{borrow::Cow, io::Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// The format of a dump of the store's keys and values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// One JSON object per line, e.g. `{"key":"key1","value":"value1"}`
+    #[default]
+    JsonLines,
+    /// A `key,value` header line followed by one line per key, quoted as described in RFC 4180
+    Csv,
+}
+
+/// What [`KvStore::import_from_reader`](super::KvStore::import_from_reader) does with a key that
+/// already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConflict {
+    /// Keep the value in the store
+    Skip,
+    /// Replace the value in the store
+    #[default]
+    Overwrite,
+    /// Stop the import with an error
+    Fail,
+}
+
+/// A key-value pair as it is written in JSON lines
+#[derive(Serialize, Deserialize)]
+struct JsonEntry<'a> {
+    key: Cow<'a, str>,
+    value: Cow<'a, str>,
+}
+
+/// Parses a line of JSON lines into a key-value pair
+pub(super) fn parse_entry(line: &str) -> Result<(String, String)> {
+    let entry: JsonEntry<'_> = serde_json::from_str(line)?;
+    Ok((entry.key.into_owned(), entry.value.into_owned()))
+}
+
+/// Parses a line of JSON lines, or a key and a value separated by a tab, into a key-value pair
+pub(super) fn parse_line(line: &str) -> Result<(String, String)> {
+    if line.starts_with('{') {
+        return parse_entry(line);
+    }
+    let (key, value) = line
+        .split_once('\t')
+        .ok_or_else(|| KvsError::Message("no tab between the key and the value".to_owned()))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+impl ExportFormat {
+    /// Writes whatever comes before the entries
+    pub(super) fn write_header(self, out: &mut impl Write) -> Result<()> {
+        if self == ExportFormat::Csv {
+            out.write_all(b"key,value\r\n")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the line of one key-value pair
+    pub(super) fn write_entry(self, out: &mut impl Write, key: &str, value: &str) -> Result<()> {
+        match self {
+            ExportFormat::JsonLines => {
+                let entry = JsonEntry {
+                    key: key.into(),
+                    value: value.into(),
+                };
+                serde_json::to_writer(&mut *out, &entry)?;
+                out.write_all(b"\n")?;
+            }
+            ExportFormat::Csv => write!(out, "{},{}\r\n", csv_field(key), csv_field(value))?,
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field if it holds a separator, a quote or a line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
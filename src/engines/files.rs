@@ -0,0 +1,100 @@
+//! Replacing and syncing the files of a store the same way on every platform.
+//!
+//! POSIX systems rename a file over another one atomically, even while the one replaced is open.
+//! Windows refuses to rename over a file that is open or still being deleted, so there the file
+//! replaced is first moved aside to `<name>.replaced`, which opening the store cleans up after a
+//! crash.
+
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{KvsError, Result};
+
+/// Extension of a file moved aside while it is replaced
+const REPLACED_EXTENSION: &str = "replaced";
+
+/// How a file of a store is replaced by a new version written next to it.
+///
+/// The default suits the platform; the other one can be chosen with
+/// [`KvStoreOptions::replace_strategy`](super::KvStoreOptions::replace_strategy), e.g. to test the
+/// Windows code path elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplaceStrategy {
+    /// The new file is renamed over the old one in one step
+    #[cfg_attr(not(windows), default)]
+    Rename,
+    /// The old file is renamed to `<name>.replaced`, the new one takes its name, and the old one
+    /// is deleted. A crash in between leaves the old file aside, to be put back on open.
+    #[cfg_attr(windows, default)]
+    MoveAside,
+}
+
+/// Replaces the file `to` with `from`, or only renames `from` if there is no `to` yet
+pub(super) fn replace_file(from: &Path, to: &Path, strategy: ReplaceStrategy) -> Result<()> {
+    match strategy {
+        ReplaceStrategy::Rename => fs::rename(from, to)?,
+        ReplaceStrategy::MoveAside => {
+            let aside = aside_path(to);
+            let moved = match fs::rename(to, &aside) {
+                Ok(()) => true,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+            };
+            fs::rename(from, to)?;
+            if moved {
+                fs::remove_file(&aside)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Puts back the files of `dir` moved aside by a replacement that a crash interrupted before the
+/// new file took their name, and deletes the ones it had replaced already.
+///
+/// A store opened read-only can't be recovered, so it fails if there is anything to recover.
+pub(super) fn recover_replaced(dir: &Path, read_only: bool) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let aside = entry?.path();
+        if !aside.is_file() || aside.extension() != Some(OsStr::new(REPLACED_EXTENSION)) {
+            continue;
+        }
+        if read_only {
+            return Err(KvsError::Message(format!(
+                "{} has to be recovered by opening it for writing first",
+                dir.display()
+            )));
+        }
+        let original = aside.with_extension("");
+        if original.exists() {
+            fs::remove_file(&aside)?;
+        } else {
+            fs::rename(&aside, &original)?;
+        }
+    }
+    Ok(())
+}
+
+/// Path `path` is moved aside to while it is replaced
+fn aside_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(REPLACED_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Syncs the directory `dir`, so that the files created, renamed or deleted in it last a crash
+#[cfg(unix)]
+pub(super) fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Syncs the directory `dir`, which Windows does along with the files in it
+#[cfg(windows)]
+pub(super) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Glob patterns for listing keys: `*` matches any run of bytes, `?` any single byte, and `\`
+//! makes the byte after it match only itself.
+
+/// Whether `key` matches the glob `pattern`
+pub(crate) fn matches(pattern: &[u8], key: &[u8]) -> bool {
+    // where to resume after the last `*` if the rest fails to match: the pattern past the star,
+    // and the key one byte further than the star was tried for last
+    let (mut p, mut k) = (0, 0);
+    let mut star = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&key[k]) => {
+                p += 2;
+                k += 1;
+                continue;
+            }
+            Some(&byte) if byte != b'\\' && byte == key[k] => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((after_star, tried)) => {
+                p = after_star;
+                k = tried + 1;
+                star = Some((after_star, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
@@ -0,0 +1,129 @@
+//! What [`KvStore::log_records`](super::KvStore::log_records),
+//! [`KvStore::stats`](super::KvStore::stats), [`KvStore::fsck`](super::KvStore::fsck) and
+//! [`KvsEngine::metrics`](super::KvsEngine::metrics) report about a store, for debugging and
+//! monitoring.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// How much of the log of a [`KvStore`](super::KvStore) is taken by live records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of keys, like [`KvStore::len`](super::KvStore::len)
+    pub keys: usize,
+    /// Bytes taken by the records holding the current values of the keys
+    pub live_bytes: u64,
+    /// Bytes taken by overwritten, removed and expired records, and by markers
+    pub stale_bytes: u64,
+    /// Size of the log files, headers included
+    pub log_bytes: u64,
+    /// Number of log files, one per generation
+    pub segments: usize,
+    /// When the last compaction finished, unless none did since the store was opened
+    pub last_compaction: Option<SystemTime>,
+    /// Reads served by the read cache since the store was opened, see
+    /// [`KvStoreOptions::cache_capacity`](super::KvStoreOptions::cache_capacity)
+    pub cache_hits: u64,
+    /// Reads the read cache, when enabled, couldn't serve since the store was opened
+    pub cache_misses: u64,
+}
+
+/// What [`KvStore::fsck`](super::KvStore::fsck) found in the log of a store
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Records holding the current values of the keys
+    pub live: usize,
+    /// Overwritten, removed and expired records, and markers
+    pub stale: usize,
+    /// Records failing their checksum or not decoding
+    pub corrupt: usize,
+    /// Bytes of records cut short at the end of log files, by a crash in the middle of a write
+    pub torn_bytes: u64,
+}
+
+impl FsckReport {
+    /// Whether every record of the log is whole and valid
+    pub fn is_clean(&self) -> bool {
+        self.corrupt == 0 && self.torn_bytes == 0
+    }
+}
+
+/// Counters of the work an engine did since it was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineMetrics {
+    /// Number of compactions that finished
+    pub compactions: u64,
+    /// Bytes appended to the log
+    pub bytes_written: u64,
+    /// Bytes compactions freed on disk
+    pub bytes_reclaimed: u64,
+    /// Writes held back by [`KvStoreOptions::write_stall`](super::KvStoreOptions::write_stall)
+    pub write_stalls: u64,
+    /// Time the writes held back waited in total
+    pub write_stall_time: Duration,
+}
+
+/// A record of the log of a [`KvStore`](super::KvStore)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Generation of the log file holding the record
+    pub gen: u64,
+    /// Byte offset of the record in its log file
+    pub offset: u64,
+    /// Length of the record, framing included
+    pub len: u64,
+    /// What the record does
+    pub kind: RecordKind,
+    /// The key the record is about, or the start of the range it removes; empty for markers and
+    /// records that can't be decoded
+    pub key: Vec<u8>,
+    /// When the record expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    /// Whether the record holds the current value of its key, or one of the merges or appends it
+    /// is made of
+    pub live: bool,
+}
+
+/// What a [`LogRecord`] does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// Sets a key
+    Set,
+    /// Removes a key
+    Remove,
+    /// Opens a batch
+    Begin,
+    /// Closes a batch
+    Commit,
+    /// Removes every key written before it
+    Clear,
+    /// Merges an operand into the value of a key
+    Merge,
+    /// Appends bytes to the value of a key
+    Append,
+    /// Removes the keys in a range
+    RemoveRange,
+    /// Fails its checksum or doesn't decode
+    Corrupt,
+    /// Is encrypted with a key the store wasn't opened with
+    Unreadable,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecordKind::Set => "SET",
+            RecordKind::Remove => "RM",
+            RecordKind::Begin => "BEGIN",
+            RecordKind::Commit => "COMMIT",
+            RecordKind::Clear => "CLEAR",
+            RecordKind::Merge => "MERGE",
+            RecordKind::Append => "APPEND",
+            RecordKind::RemoveRange => "RMRANGE",
+            RecordKind::Corrupt => "CORRUPT",
+            RecordKind::Unreadable => "UNREADABLE",
+        })
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use uuid::Uuid;
+
+use super::{
+    kvs::{utf8, utf8_string},
+    KvStore,
+};
+use crate::{KvsError, Result};
+
+/// A type that keys can be made of, encoded into bytes ordered the same way as its values.
+///
+/// Integers are encoded big-endian, with the sign bit of signed ones flipped so negative numbers
+/// come first. Strings and byte vectors end with a terminator, so a tuple of them is ordered by
+/// its first element, then by the next one, like the tuple itself.
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::KeyCodec;
+///
+/// assert!(9u64.to_key_bytes() < 10u64.to_key_bytes());
+/// assert!((-1i32).to_key_bytes() < 0i32.to_key_bytes());
+/// let key = (String::from("user"), 42u64);
+/// assert_eq!(<(String, u64)>::from_key_bytes(&key.to_key_bytes()).unwrap(), key);
+/// ```
+pub trait KeyCodec: Sized {
+    /// Appends the encoding of the key to `out`
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decodes a key from the start of `bytes`, moving `bytes` past it
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self>;
+
+    /// The encoding of the key
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_key(&mut out);
+        out
+    }
+
+    /// Decodes a key from `bytes`, failing unless they hold exactly one
+    fn from_key_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let key = Self::decode_key(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid_key("trailing bytes"));
+        }
+        Ok(key)
+    }
+}
+
+/// Takes the first `len` bytes of `bytes`
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(invalid_key("too short"));
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
+
+/// The error for bytes that aren't the encoding of a key
+fn invalid_key(reason: &str) -> KvsError {
+    KvsError::Message(format!("Invalid key encoding: {}", reason))
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                let bytes = take(bytes, std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyCodec for $t {
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                // with the sign bit flipped, negative numbers sort before the others
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(out);
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                Ok((<$u>::decode_key(bytes)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+/// Escapes the zero bytes of `bytes` as `00 ff` and ends them with `00 00`, which sorts before
+/// any byte that could follow, so a shorter key comes before the longer ones it starts.
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        for &byte in self {
+            out.push(byte);
+            if byte == 0 {
+                out.push(0xff);
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        let mut key = Vec::new();
+        loop {
+            match take(bytes, 1)?[0] {
+                0 => match take(bytes, 1)?[0] {
+                    0 => return Ok(key),
+                    0xff => key.push(0),
+                    _ => return Err(invalid_key("bad escape")),
+                },
+                byte => key.push(byte),
+            }
+        }
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().encode_key(out)
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::decode_key(bytes)?).map_err(|_| invalid_key("not UTF-8"))
+    }
+}
+
+impl KeyCodec for Uuid {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+        Ok(Uuid::from_slice(take(bytes, 16)?).unwrap())
+    }
+}
+
+macro_rules! tuple_key {
+    ($($name:ident),*) => {
+        impl<$($name: KeyCodec),*> KeyCodec for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($name,)*) = self;
+                $($name.encode_key(out);)*
+            }
+
+            fn decode_key(bytes: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_key(bytes)?,)*))
+            }
+        }
+    };
+}
+
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+
+/// A handle to a [`KvStore`] whose keys are of type `K`, encoded with [`KeyCodec`] so that they
+/// are stored, and iterated, in the order of their values.
+///
+/// The store should only hold keys of type `K`: the others fail to decode when iterating.
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{KeyedKvStore, KvStore};
+/// # use tempfile::TempDir;
+///
+/// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+/// let orders = KeyedKvStore::<u64>::new(store);
+/// for id in 0..300 {
+///     orders.set(&id, String::from("pending")).unwrap();
+/// }
+/// let ids: Vec<u64> = orders.range(100..200).map(|entry| entry.unwrap().0).collect();
+/// assert_eq!(ids, (100..200).collect::<Vec<_>>());
+/// ```
+pub struct KeyedKvStore<K> {
+    store: KvStore,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K> Clone for KeyedKvStore<K> {
+    fn clone(&self) -> Self {
+        KeyedKvStore {
+            store: self.store.clone(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: KeyCodec> KeyedKvStore<K> {
+    /// Wraps an open store
+    pub fn new(store: KvStore) -> Self {
+        KeyedKvStore {
+            store,
+            key: PhantomData,
+        }
+    }
+
+    /// Sets the value of a key, overwriting any previous value
+    pub fn set(&self, key: &K, value: String) -> Result<()> {
+        self.store.set_raw(key.to_key_bytes(), value.into_bytes())
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist
+    pub fn get(&self, key: &K) -> Result<Option<String>> {
+        utf8(self.store.get_raw(&key.to_key_bytes())?)
+    }
+
+    /// Removes a key. Fails if the key does not exist.
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.store.remove_raw(&key.to_key_bytes())
+    }
+
+    /// Iterates over the keys and their values, in the order of the keys
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, String)>> + '_ {
+        self.range(..)
+    }
+
+    /// Iterates over the keys between two bounds and their values, in the order of the keys.
+    /// Values are read as the iterator advances.
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(K, String)>> + '_ {
+        let bound = |bound: Bound<&K>| bound.map(KeyCodec::to_key_bytes);
+        let range = (bound(range.start_bound()), bound(range.end_bound()));
+        self.store.range_raw(range).map(|entry| {
+            let (key, value) = entry?;
+            Ok((K::from_key_bytes(&key)?, utf8_string(value)?))
+        })
+    }
+
+    /// The wrapped store
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+}
@@ -0,0 +1,4886 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    error::Error,
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex, MutexGuard, OnceLock, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "tokio")]
+use super::AsyncEngine;
+#[cfg(feature = "metrics")]
+use super::LatencyStats;
+use super::{
+    archive::{self, Archive},
+    backup::{self, BackupFile, Manifest, Watermark},
+    bloom::{self, GenFilters},
+    cache::ReadCache,
+    checkpoint::Checkpoint,
+    dump,
+    files::{self, sync_dir, ReplaceStrategy},
+    glob,
+    latency::{Latencies, StoreOp},
+    layout::{self, log_path},
+    listener::{CompactionEnd, CompactionListener, CompactionStart},
+    log_writer::{self, LogWriter},
+    marker,
+    merge::Merges,
+    migrate,
+    rate_limit::RateLimiter,
+    record::{self, Codec, Command, CommandType, DecodeError},
+    secondary::SecondaryIndexes,
+    watch::Watchers,
+    ChangeBatch, ChangeRecord, CompactionStrategy, CompactionTrigger, EngineMetrics, ExportFormat,
+    FsckReport, KeyVersion, KvStoreOptions, KvsEngine, LogPosition, LogRecord, OnConflict,
+    RecordFormat, RecordKind, StoreStats, SyncPolicy, VersionRetention, WatchEvent, WatchOp,
+    WriteBatch, WriteStall,
+};
+use crate::{KvsError, Result};
+use crossbeam_skiplist::{map::Entry, SkipMap};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "tokio")]
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    task,
+};
+use tracing::{debug, error, instrument, warn};
+use uuid::Uuid;
+
+/// Records a [`Tail`] reads ahead at most, not counting the rest of a batch
+const TAIL_READ_AHEAD: usize = 1000;
+
+/// Name of the single log file used by stores written before the log was split into generations
+const LEGACY_STORE_NAME: &str = "kvs.store";
+
+/// Number of entries [`KvStore::import_from_reader`] writes in one batch
+const IMPORT_BATCH_LEN: usize = 1000;
+
+/// Name of the directory holding a store's namespaces
+pub(super) const NAMESPACES_DIR: &str = "namespaces";
+
+/// Name of the file locked by the processes that have a store open
+const LOCK_FILE: &str = "LOCK";
+
+/// Extension of the file compaction writes a generation into, renamed to `<gen>.log` once the
+/// generation is complete and synced
+const COMPACTING_EXTENSION: &str = "compacting";
+
+/// Name of the file listing the generations a compaction replaced, until they are deleted
+const COMPACTION_MANIFEST: &str = "compaction";
+
+/// How often a write stopped by [`KvStoreOptions::write_stall`] checks whether it can go on
+const STALL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A container for storing key-value pairs in memory.
+///
+/// Cloning a [`KvStore`] is cheap and gives another handle to the same store, so it can be shared
+/// between threads. Writes are serialized on the log writer. Reads of existing keys take no locks:
+/// the index is a concurrent skip list and records are read with positional reads on shared file
+/// handles.
+///
+/// The log is made of generations, files named `<gen>.log` in the store directory. Writes go to
+/// the newest generation. Once enough of the log is stale, as set by
+/// [`KvStoreOptions::compaction_trigger`], a background thread copies the live
+/// records into a new generation and deletes the older ones, while reads and writes carry on.
+///
+/// Keys are bytes ordered lexicographically: string keys are their UTF-8 bytes, and the `_raw`
+/// methods take any bytes, e.g. big-endian integers that iterate in numeric order.
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<SkipMap<Vec<u8>, RecordPos>>,
+    readers: Arc<SkipMap<u64, Arc<File>>>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    compaction: Arc<Compaction>,
+    codec: Arc<Codec>,
+    namespaces: Arc<Namespaces>,
+    cache: Arc<ReadCache>,
+    indexes: Arc<SecondaryIndexes>,
+    merges: Arc<Merges>,
+    latencies: Arc<Latencies>,
+    filters: Arc<GenFilters>,
+    /// The directory of the store, for the async handles to open its log files with `tokio::fs`
+    #[cfg(feature = "tokio")]
+    path: Arc<Path>,
+}
+
+/// A [`KvStore`] handle that doesn't keep the store open, for background threads
+struct WeakKvStore {
+    index: Weak<SkipMap<Vec<u8>, RecordPos>>,
+    readers: Weak<SkipMap<u64, Arc<File>>>,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    compaction: Weak<Compaction>,
+    codec: Weak<Codec>,
+    namespaces: Weak<Namespaces>,
+    cache: Weak<ReadCache>,
+    indexes: Weak<SecondaryIndexes>,
+    merges: Weak<Merges>,
+    latencies: Weak<Latencies>,
+    filters: Weak<GenFilters>,
+    #[cfg(feature = "tokio")]
+    path: Arc<Path>,
+}
+
+impl WeakKvStore {
+    /// A handle to the store, unless every [`KvStore`] handle was dropped
+    fn upgrade(&self) -> Option<KvStore> {
+        Some(KvStore {
+            index: self.index.upgrade()?,
+            readers: self.readers.upgrade()?,
+            writer: self.writer.upgrade()?,
+            compaction: self.compaction.upgrade()?,
+            codec: self.codec.upgrade()?,
+            namespaces: self.namespaces.upgrade()?,
+            cache: self.cache.upgrade()?,
+            indexes: self.indexes.upgrade()?,
+            merges: self.merges.upgrade()?,
+            latencies: self.latencies.upgrade()?,
+            filters: self.filters.upgrade()?,
+            #[cfg(feature = "tokio")]
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// The namespaces of a [`KvStore`], each a store of its own in a subdirectory
+struct Namespaces {
+    dir: PathBuf,
+    /// The options the store was opened with, which its namespaces are opened with too
+    options: KvStoreOptions,
+    open: Mutex<HashMap<String, KvStore>>,
+}
+
+/// The record in the log that failed its checksum, see [`KvsError::Corruption`]
+#[derive(Debug)]
+pub struct CorruptRecord {
+    /// Generation of the log file holding the record
+    pub gen: u64,
+    /// Byte offset of the record in its log file
+    pub offset: u64,
+}
+
+impl fmt::Display for CorruptRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Corrupt record in generation {} at offset {}",
+            self.gen, self.offset
+        )
+    }
+}
+
+impl Error for CorruptRecord {}
+
+/// The background compaction thread, if one has been started.
+///
+/// Only [`KvStore`] handles hold on to it, so dropping the last handle waits for a running
+/// compaction to finish instead of leaving it to race with the next `open()`.
+struct Compaction {
+    /// Returns how many bytes the compaction reclaimed
+    thread: Mutex<Option<JoinHandle<Result<u64>>>>,
+    /// Notified of every compaction, see [`KvStoreOptions::compaction_listener`]
+    listener: Option<Arc<dyn CompactionListener>>,
+    /// Bytes per second a compaction reads and writes at most, see
+    /// [`KvStoreOptions::compaction_rate_limit`]
+    rate_limit: Option<u64>,
+    /// How much of the log the compactions started on their own rewrite
+    strategy: CompactionStrategy,
+}
+
+impl Drop for Compaction {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.get_mut().unwrap().take() {
+            join_compaction(thread);
+        }
+    }
+}
+
+/// The write side of a [`KvStore`]: the log is only appended to while holding it.
+///
+/// Writes are buffered, and `offset` is where the next record will land in the active generation.
+struct KvStoreWriter {
+    log: LogWriter,
+    offset: u64,
+    gen: u64,
+    /// Bytes of the records in the log, headers excluded
+    log_bytes: u64,
+    /// Bytes of the records in the log that are known to be stale
+    stale_bytes: u64,
+    compaction_trigger: CompactionTrigger,
+    /// When writes are held back while a compaction runs
+    write_stall: Option<WriteStall>,
+    /// Size of the active generation past which writes move on to a new one
+    segment_size: Option<u64>,
+    /// Generation the last compaction started wrote the live records to
+    compaction_gen: u64,
+    path: PathBuf,
+    store_id: Uuid,
+    /// Whether the store was opened with [`KvStore::open_read_only`], so `log` is never written
+    read_only: bool,
+    /// The locked [`LOCK_FILE`], released once the store and its compaction are done with
+    _lock: Option<File>,
+    sync_policy: SyncPolicy,
+    /// Writes since the log was last synced
+    unsynced: u64,
+    /// When the last compaction finished
+    last_compaction: Option<SystemTime>,
+    /// What the store did since it was opened
+    metrics: EngineMetrics,
+    /// Subscribers to the changes of the store
+    watchers: Watchers,
+    /// The index of the store, checkpointed when the store is closed
+    index: Arc<SkipMap<Vec<u8>, RecordPos>>,
+    /// The chains of the merge records of the store, checkpointed along with the index
+    merges: Arc<Merges>,
+    /// Version of the last write
+    version: u64,
+    /// Which overwritten versions compaction keeps
+    version_retention: VersionRetention,
+    /// How the checkpoint and the compaction manifest replace their previous version
+    replace_strategy: ReplaceStrategy,
+    /// Where the generations no longer needed go instead of being deleted
+    archive: Option<Archive>,
+}
+
+impl KvStoreWriter {
+    /// Flushes the log and waits until it is on disk
+    fn sync(&mut self) -> Result<()> {
+        self.log.flush()?;
+        self.log.get_ref().sync_all()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Counts a write of `len` bytes to the log, syncing it if the sync policy asks for it
+    fn written(&mut self, len: u64) -> Result<()> {
+        self.unsynced += 1;
+        self.log_bytes += len;
+        self.metrics.bytes_written += len;
+        let due = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryNWrites(n) => self.unsynced >= n,
+            _ => false,
+        };
+        if !due {
+            Ok(())
+        } else if log_writer::is_deferred() {
+            // the async handle writing the log out syncs it once it's written
+            self.log.sync_later();
+            self.unsynced = 0;
+            Ok(())
+        } else {
+            self.sync()
+        }
+    }
+
+    /// Versions the next write: microseconds since the Unix epoch, unless the last version isn't
+    /// older, so that versions keep increasing when the clock doesn't
+    fn next_version(&mut self) -> u64 {
+        self.version = now_micros().max(self.version + 1);
+        self.version
+    }
+
+    /// Whether enough of the log is stale to compact it
+    fn wants_compaction(&self) -> bool {
+        let trigger = self.compaction_trigger;
+        self.log_bytes >= trigger.min_log_bytes
+            && self.stale_bytes as f64 > trigger.stale_ratio * self.log_bytes as f64
+    }
+
+    /// Syncs the log and checkpoints the index up to its end
+    fn checkpoint(&mut self) -> Result<()> {
+        self.sync()?;
+        let mut lens = Vec::new();
+        for gen in layout::gens(&self.path)? {
+            lens.push((gen, fs::metadata(log_path(&self.path, gen))?.len()));
+        }
+        Checkpoint {
+            store_id: self.store_id.as_u128(),
+            position: LogPosition {
+                gen: self.gen,
+                offset: self.offset,
+            },
+            lens,
+            entries: self
+                .index
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            links: self
+                .merges
+                .links(self.index.iter().map(|entry| *entry.value())),
+            version: self.version,
+        }
+        .write(&self.path, self.replace_strategy)
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.checkpoint() {
+            error!("Failed to checkpoint the index: {}", e);
+        }
+    }
+}
+
+/// Where a record lives in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct RecordPos {
+    pub(super) gen: u64,
+    pub(super) offset: u64,
+    pub(super) len: u64,
+    /// When the record expires, in milliseconds since the Unix epoch
+    pub(super) expires_at: Option<u64>,
+}
+
+impl RecordPos {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Implementation of [`KvStore`]
+impl KvStore {
+    /// Opens a [`KvStore`] backed by a WAL at specified path
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a [`KvStore`] backed by a WAL at specified path with the given options.
+    ///
+    /// The store is locked for as long as it is open, so opening it again, from this process or
+    /// another one, fails until every handle is dropped.
+    ///
+    /// Fails with [`KvsError::Corruption`] if a record in the log fails its checksum, unless
+    /// [`KvStoreOptions::skip_corrupt`] is set.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().skip_corrupt(true);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_dir(path.into(), options)
+    }
+
+    /// Opens the [`KvStore`] at `path` for reading only, e.g. from a backup job or an analytics
+    /// tool.
+    ///
+    /// The log is replayed as it is at that point, and nothing in the directory is created,
+    /// renamed or written: writes, [`KvStore::clear`] and compaction fail. The store is locked
+    /// shared, so any number of processes can read it at once, but it can't be opened for writing
+    /// meanwhile, and this fails while it is. Fails as well if `path` holds no store, or a store
+    /// that needs upgrading by a read-write open first.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// drop(store);
+    /// let store = KvStore::open_read_only(temp_dir.path()).unwrap();
+    /// assert_eq!(store.get(String::from("key1")).unwrap(), Some(String::from("value1")));
+    /// assert!(store.set(String::from("key1"), String::from("value2")).is_err());
+    /// ```
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// Opens the store in `path`, creating or upgrading it on disk unless it is opened read-only
+    fn open_dir(path: PathBuf, mut options: KvStoreOptions) -> Result<KvStore> {
+        let read_only = options.read_only;
+        if !read_only {
+            fs::create_dir_all(&path)?;
+        }
+        marker::claim(&path, "kvs", read_only)?;
+        let lock = lock_dir(&path, read_only)?;
+        // the files of a store from before its directories move into them
+        files::recover_replaced(&path, read_only)?;
+        layout::upgrade(&path, read_only, options.replace_strategy)?;
+        let legacy = path.join(LEGACY_STORE_NAME);
+        if read_only {
+            if legacy.exists() {
+                return Err(KvsError::Message(format!(
+                    "{} has to be upgraded by opening it for writing first",
+                    path.display()
+                )));
+            }
+        } else {
+            // a store from before generations becomes the oldest generation
+            if legacy.exists() {
+                fs::rename(&legacy, layout::segment_path(&path, 0))?;
+            }
+        }
+
+        for dir in layout::replaced_dirs(&path) {
+            if dir.exists() {
+                files::recover_replaced(&dir, read_only)?;
+            }
+        }
+        migrate::recover(&path, read_only, options.replace_strategy)?;
+        let archive = options
+            .wal_archive
+            .as_ref()
+            .map(|options| Archive::new(&path, options));
+        let replaced = recover_compaction(&path, read_only, archive.as_ref())?;
+        if let Some(archive) = archive.as_ref().filter(|_| !read_only) {
+            // archived generations age even while nothing is archived
+            archive.enforce_retention()?;
+        }
+        let mut gens = layout::gens(&path)?;
+        gens.retain(|gen| !replaced.contains(gen));
+        // every log file must belong to the same store
+        let mut header = None;
+        for gen in gens.clone() {
+            let log = log_path(&path, gen);
+            if fs::metadata(&log)?.len() == 0 {
+                // created just before a crash, before its header was written
+                if !read_only {
+                    fs::remove_file(&log)?;
+                }
+                gens.retain(|&g| g != gen);
+            } else if !record::is_legacy(&log)? {
+                let file = record::read_file_header(&log)?;
+                let first = *header.get_or_insert(file);
+                if first.store_id != file.store_id {
+                    return Err(KvsError::Message(format!(
+                        "{} belongs to another store",
+                        log.display()
+                    )));
+                }
+                if first.format != file.format {
+                    return Err(KvsError::Message(format!(
+                        "{} holds {:?} records, but the store holds {:?} records",
+                        log.display(),
+                        file.format,
+                        first.format
+                    )));
+                }
+            }
+        }
+        let store_id = header.map_or_else(Uuid::new_v4, |header| header.store_id);
+        // the records are read and written in the format the store was created with
+        if let Some(header) = header {
+            options.record_format = header.format;
+        }
+
+        // start from the checkpoint of the index, unless the log is being recovered
+        let index = SkipMap::new();
+        let merges = Merges::new(options.merge_operator.clone());
+        let mut lens = HashMap::new();
+        for &gen in &gens {
+            lens.insert(gen, fs::metadata(log_path(&path, gen))?.len());
+        }
+        let checkpoint = if options.skip_corrupt {
+            None
+        } else {
+            Checkpoint::read(&path, store_id, &lens)?
+        };
+        let mut version = 0;
+        let start = checkpoint.map(|checkpoint| {
+            let now = now_millis();
+            for (key, pos) in checkpoint.entries {
+                if !pos.expired(now) {
+                    index.insert(key, pos);
+                }
+            }
+            merges.load(checkpoint.links);
+            version = checkpoint.version;
+            checkpoint.position
+        });
+
+        // replay the log past the checkpoint
+        let codec = Codec::new(&options);
+        let readers = SkipMap::new();
+        let mut log_bytes = 0;
+        for &gen in &gens {
+            if record::is_legacy(&log_path(&path, gen))? {
+                if read_only {
+                    return Err(KvsError::Message(format!(
+                        "{} has to be upgraded by opening it for writing first",
+                        path.display()
+                    )));
+                }
+                record::upgrade_legacy(
+                    &log_path(&path, gen),
+                    store_id,
+                    &codec,
+                    options.replace_strategy,
+                )?;
+            }
+            let file = File::open(log_path(&path, gen))?;
+            let len = file.metadata()?.len();
+            let (end, last_version) = match start {
+                Some(start) if gen < start.gen => (len, 0),
+                Some(start) if gen == start.gen => {
+                    replay(gen, &file, start.offset, &index, &merges, &codec, &options)?
+                }
+                _ => replay(
+                    gen,
+                    &file,
+                    record::FILE_HEADER_LEN,
+                    &index,
+                    &merges,
+                    &codec,
+                    &options,
+                )?,
+            };
+            version = version.max(last_version);
+            if end < len {
+                // only the newest generation is written to, so only its last record can have been
+                // torn by a crash: a sealed generation cut short was damaged after it was written
+                let newest = gens.last() == Some(&gen);
+                if options.strict || (!newest && !options.skip_corrupt) {
+                    return Err(CorruptRecord { gen, offset: end }.into());
+                }
+                if !newest {
+                    warn!(
+                        "Skipping the damaged end of sealed generation {}: {} bytes at offset {}",
+                        gen,
+                        len - end,
+                        end
+                    );
+                } else {
+                    warn!(
+                        "Truncating the torn record at the end of generation {}: {} bytes at offset {}",
+                        gen,
+                        len - end,
+                        end
+                    );
+                }
+                if newest && !read_only {
+                    let log = OpenOptions::new().write(true).open(log_path(&path, gen))?;
+                    log.set_len(end)?;
+                    log.sync_all()?;
+                }
+            }
+            log_bytes += end - record::FILE_HEADER_LEN;
+            readers.insert(gen, Arc::new(file));
+        }
+        if start.is_some() {
+            // the records before the checkpoint weren't decoded, so one is, to fail on a wrong key
+            if let Some(entry) = index.front() {
+                let pos = *entry.value();
+                let mut buf = vec![0; pos.len as usize];
+                read_exact_at(readers.get(&pos.gen).unwrap().value(), &mut buf, pos.offset)?;
+                decode(&codec, &buf, pos)?;
+            }
+        }
+        if !read_only {
+            // the log is about to be written without the checkpoint accounting for it
+            Checkpoint::remove(&path)?;
+            GenFilters::remove_stale(&path, &gens)?;
+        }
+        let filters = Arc::new(GenFilters::new(&path, read_only, options.replace_strategy));
+
+        // the newest generation is appended to until it reaches the segment size, so opening the
+        // store, e.g. for a single `kvs get`, doesn't leave a generation behind every time
+        let mut reopened = None;
+        if let Some(&gen) = gens.last().filter(|_| !read_only) {
+            let wal = layout::wal_path(&path, gen);
+            if wal.exists() {
+                let len = fs::metadata(&wal)?.len();
+                if options
+                    .segment_size
+                    .is_none_or(|size| len < record::FILE_HEADER_LEN + size)
+                {
+                    let log = OpenOptions::new().append(true).open(&wal)?;
+                    // a crash may have left a generation before it in `wal/`
+                    layout::seal(&path, gen)?;
+                    reopened = Some((gen, log, len));
+                }
+            }
+        }
+
+        // a read-only store keeps the newest generation as its log, which it never writes to
+        let (gen, log, offset) = match (gens.last(), reopened) {
+            (Some(&gen), _) if read_only => {
+                let log = File::open(log_path(&path, gen))?;
+                let len = log.metadata()?.len();
+                (gen, log, len)
+            }
+            (None, _) if read_only => {
+                return Err(KvsError::Message(format!("No store in {}", path.display())))
+            }
+            (_, Some(reopened)) => reopened,
+            (last, None) => {
+                let gen = last.map_or(1, |gen| gen + 1);
+                let log = new_log(&path, gen, store_id, codec.format(), &readers)?;
+                (gen, log, record::FILE_HEADER_LEN)
+            }
+        };
+        let namespaces = Namespaces {
+            dir: path.join(NAMESPACES_DIR),
+            options: options.clone(),
+            open: Mutex::new(HashMap::new()),
+        };
+
+        // whatever the live records don't take is stale: the tally of the handles that wrote it
+        // was lost when they closed
+        let now = now_millis();
+        let live_bytes: u64 = index
+            .iter()
+            .filter(|entry| !entry.value().expired(now))
+            .map(|entry| merges.chain_len(*entry.value()))
+            .sum();
+        let stale_bytes = log_bytes.saturating_sub(live_bytes);
+
+        let index = Arc::new(index);
+        let merges = Arc::new(merges);
+        #[cfg(feature = "tokio")]
+        let dir = Arc::from(path.as_path());
+        let store = KvStore {
+            index: index.clone(),
+            readers: Arc::new(readers),
+            writer: Arc::new(Mutex::new(KvStoreWriter {
+                log: LogWriter::new(log),
+                offset,
+                gen,
+                log_bytes,
+                stale_bytes,
+                compaction_trigger: options.compaction_trigger,
+                write_stall: options.write_stall,
+                segment_size: options.segment_size,
+                compaction_gen: 0,
+                path,
+                store_id,
+                read_only,
+                _lock: lock,
+                sync_policy: options.sync_policy,
+                unsynced: 0,
+                last_compaction: None,
+                metrics: EngineMetrics::default(),
+                watchers: Watchers::default(),
+                index,
+                merges: merges.clone(),
+                version,
+                version_retention: options.version_retention,
+                replace_strategy: options.replace_strategy,
+                archive,
+            })),
+            compaction: Arc::new(Compaction {
+                thread: Mutex::new(None),
+                listener: options
+                    .compaction_listener
+                    .as_ref()
+                    .map(|listener| listener.0.clone()),
+                rate_limit: options.compaction_rate_limit,
+                strategy: options.compaction_strategy,
+            }),
+            codec: Arc::new(codec),
+            namespaces: Arc::new(namespaces),
+            cache: Arc::new(ReadCache::new(options.cache_capacity)),
+            indexes: Arc::new(SecondaryIndexes::default()),
+            merges,
+            latencies: Arc::new(Latencies::new(options.slow_op_threshold)),
+            filters,
+            #[cfg(feature = "tokio")]
+            path: dir,
+        };
+        if read_only {
+            return Ok(store);
+        }
+        if let SyncPolicy::Interval(interval) = options.sync_policy {
+            spawn_syncer(Arc::downgrade(&store.writer), interval);
+        }
+        if let Some(interval) = options.expiry_sweep_interval {
+            spawn_sweeper(store.downgrade(), interval);
+        }
+        if let Some(interval) = options.checkpoint_interval {
+            spawn_checkpointer(Arc::downgrade(&store.writer), interval);
+        }
+        Ok(store)
+    }
+
+    /// Syncs the log and writes a checkpoint of the index covering it, so that the next open only
+    /// replays the records written after this point. Writes wait while the index is written out.
+    ///
+    /// The index is also checkpointed when the store is closed, and periodically with
+    /// [`KvStoreOptions::checkpoint_interval`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.checkpoint().unwrap();
+    /// ```
+    pub fn checkpoint(&self) -> Result<()> {
+        self.write_lock()?.checkpoint()
+    }
+
+    /// Flushes buffered records and waits until the operating system has written them to disk
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1"));
+    /// store.sync_all().unwrap();
+    /// ```
+    pub fn sync_all(&self) -> Result<()> {
+        self.writer().sync()
+    }
+
+    /// Closes this handle, syncing the log and checkpointing the index like dropping the last
+    /// handle does, but failing if they fail rather than only logging it.
+    ///
+    /// The store stays open for the other handles, and the files are only closed once every
+    /// handle is gone.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.close().unwrap();
+    /// ```
+    pub fn close(self) -> Result<()> {
+        let mut writer = self.writer();
+        if writer.read_only {
+            return Ok(());
+        }
+        writer.checkpoint()
+    }
+
+    /// Applies every write in `batch`, so that either all of them or none survive a crash.
+    ///
+    /// Fails with "Key not found", without writing anything, if the batch removes a key that
+    /// doesn't exist at that point of the batch.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, WriteBatch};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let mut batch = WriteBatch::new();
+    /// batch.set(String::from("key1"), String::from("value1"));
+    /// batch.set(String::from("key2"), String::from("value2"));
+    /// store.write_batch(batch).unwrap();
+    /// assert_eq!(store.get(String::from("key2")).unwrap(), Some(String::from("value2")));
+    /// ```
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.write_lock()?;
+        self.append_batch(&mut writer, batch.commands)
+    }
+
+    /// Appends the sets and removals in `commands` as a single batch, see [`KvStore::write_batch`]
+    fn append_batch(&self, writer: &mut KvStoreWriter, mut commands: Vec<Command>) -> Result<()> {
+        // the writes of a batch make up a single version
+        let version = writer.next_version();
+        for command in &mut commands {
+            command.version = Some(version);
+        }
+
+        let mut exists = HashMap::new();
+        for command in &commands {
+            let key = command.key.as_slice();
+            let present = *exists
+                .entry(key)
+                .or_insert_with(|| self.lookup(key, true).is_some());
+            if command.command_type == CommandType::RM && !present {
+                return Err(KvsError::KeyNotFound);
+            }
+            exists.insert(key, command.command_type == CommandType::SET);
+        }
+
+        // what the watched keys held before each write, with the earlier writes of the batch applied
+        let mut old_values = Vec::new();
+        if commands
+            .iter()
+            .any(|command| writer.watchers.watching(&command.key))
+        {
+            let mut values: HashMap<&[u8], Option<Vec<u8>>> = HashMap::new();
+            for command in &commands {
+                let key = command.key.as_slice();
+                let old = match values.get(key) {
+                    Some(value) => value.clone(),
+                    None => self.read(key, Some(writer))?,
+                };
+                values.insert(key, command.value.clone());
+                old_values.push(old);
+            }
+        }
+        let mut old_values = old_values.into_iter();
+
+        // encode everything up front so a failure can't leave half a batch in the log
+        let begin = self.codec.encode(&Command::begin())?;
+        let frames = commands
+            .iter()
+            .map(|command| self.codec.encode(command))
+            .collect::<Result<Vec<_>>>()?;
+        let commit = self.codec.encode(&Command::commit())?;
+
+        let start = writer.offset;
+        writer.log.write_all(&begin)?;
+        writer.offset += begin.len() as u64;
+        let mut positions = Vec::with_capacity(frames.len());
+        for (command, frame) in commands.iter().zip(&frames) {
+            writer.log.write_all(frame)?;
+            positions.push(RecordPos {
+                gen: writer.gen,
+                offset: writer.offset,
+                len: frame.len() as u64,
+                expires_at: command.expires_at,
+            });
+            writer.offset += frame.len() as u64;
+        }
+        writer.log.write_all(&commit)?;
+        writer.offset += commit.len() as u64;
+        let len = writer.offset - start;
+        writer.written(len)?;
+
+        // the markers are garbage as soon as they're written
+        writer.stale_bytes += (begin.len() + commit.len()) as u64;
+        for (command, pos) in commands.into_iter().zip(positions) {
+            let old_value = old_values.next().flatten();
+            let old = self.index.get(&command.key).map(|entry| *entry.value());
+            if command.command_type == CommandType::RM {
+                self.index.remove(&command.key);
+                self.indexes.remove(&command.key);
+                writer.stale_bytes += pos.len;
+            } else {
+                self.index.insert(command.key.clone(), pos);
+                if let Some(value) = &command.value {
+                    self.indexes.set(&command.key, value);
+                }
+            }
+            if let Some(old) = old {
+                writer.stale_bytes += old.len + self.merges.unlink(old);
+            }
+            if writer.watchers.watching(&command.key) {
+                writer.watchers.send(WatchEvent {
+                    op: match command.command_type {
+                        CommandType::RM => WatchOp::Remove,
+                        _ => WatchOp::Set,
+                    },
+                    key: command.key,
+                    old_value,
+                    new_value: command.value,
+                });
+            }
+        }
+        self.appended(writer)
+    }
+
+    /// Sets the value of a key to arbitrary bytes, overwriting any previous value.
+    ///
+    /// Values set this way that aren't valid UTF-8 can only be read back with
+    /// [`KvStore::get_bytes`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set_bytes(String::from("avatar"), vec![0x89, b'P', b'N', b'G']).unwrap();
+    /// ```
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_raw(key.into_bytes(), value)
+    }
+
+    /// Gets the value of a key as bytes, or `None` if the key does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set_bytes(String::from("avatar"), vec![0x89, b'P', b'N', b'G']).unwrap();
+    /// assert_eq!(store.get_bytes(String::from("avatar")).unwrap().unwrap().len(), 4);
+    /// ```
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.get_raw(key.as_bytes())
+    }
+
+    /// Sets the value of a key made of arbitrary bytes, overwriting any previous value.
+    ///
+    /// Keys that aren't valid UTF-8 are left out by the methods taking and returning `String`
+    /// keys, like [`KvStore::keys`] and [`KvStore::iter`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let mut key = b"order:".to_vec();
+    /// key.extend_from_slice(&42u64.to_be_bytes());
+    /// store.set_raw(key, b"pending".to_vec()).unwrap();
+    /// ```
+    pub fn set_raw(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let command = Command::set(key, value);
+        let mut writer = self.write_lock()?;
+        self.append_set(&mut writer, command)
+    }
+
+    /// Gets the value of a key made of arbitrary bytes, or `None` if the key does not exist
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set_raw(vec![0xff, 0x00], vec![1]).unwrap();
+    /// assert_eq!(store.get_raw(&[0xff, 0x00]).unwrap(), Some(vec![1]));
+    /// ```
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read(key, None)
+    }
+
+    /// Removes a key made of arbitrary bytes. Fails if the key does not exist.
+    pub fn remove_raw(&self, key: &[u8]) -> Result<()> {
+        let mut writer = self.write_lock()?;
+        if self.lookup(key, true).is_some() {
+            self.append_remove(&mut writer, key.to_vec())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// Removes every key starting with `prefix` and returns how many there were.
+    ///
+    /// A single record is written to the log, whatever the number of keys, see
+    /// [`KvStore::delete_range`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("session:1"), String::from("alice")).unwrap();
+    /// store.set(String::from("session:2"), String::from("bob")).unwrap();
+    /// store.set(String::from("user:1"), String::from("alice")).unwrap();
+    /// assert_eq!(store.delete_prefix("session:").unwrap(), 2);
+    /// assert_eq!(store.len(), 1);
+    /// ```
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize> {
+        // the first key past the ones starting with the prefix, unless only 0xff bytes follow it
+        let mut end = prefix.as_bytes().to_vec();
+        let end = match end.iter().rposition(|&byte| byte != 0xff) {
+            Some(last) => {
+                end.truncate(last + 1);
+                end[last] += 1;
+                Some(end)
+            }
+            None => None,
+        };
+        self.delete_between(prefix.as_bytes().to_vec(), end)
+    }
+
+    /// Removes every key in `range` and returns how many there were.
+    ///
+    /// A single range removal record is written to the log, whatever the number of keys, and the
+    /// removed records are dropped by the next compaction like overwritten ones. Watchers still
+    /// get a removal of each key.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// for day in 1..=9 {
+    ///     store.set(format!("log:2024-01-0{}", day), String::new()).unwrap();
+    /// }
+    /// assert_eq!(store.delete_range("log:2024-01-01"..="log:2024-01-05").unwrap(), 5);
+    /// ```
+    pub fn delete_range<K, R>(&self, range: R) -> Result<usize>
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().as_bytes().to_vec());
+        self.delete_range_raw((bound(range.start_bound()), bound(range.end_bound())))
+    }
+
+    /// Removes every key made of arbitrary bytes in `range`, see [`KvStore::delete_range`]
+    pub fn delete_range_raw<K, R>(&self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        // a range removal record covers the keys from its start on, up to its end excluded, and
+        // the first key past a key is the key followed by a zero byte
+        let after = |key: &K| [key.as_ref(), &[0]].concat();
+        let start = match range.start_bound() {
+            Bound::Included(start) => start.as_ref().to_vec(),
+            Bound::Excluded(start) => after(start),
+            Bound::Unbounded => Vec::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Some(after(end)),
+            Bound::Excluded(end) => Some(end.as_ref().to_vec()),
+            Bound::Unbounded => None,
+        };
+        self.delete_between(start, end)
+    }
+
+    /// Removes the keys from `start` on, up to `end` excluded if there is one, with a single record
+    fn delete_between(&self, start: Vec<u8>, end: Option<Vec<u8>>) -> Result<usize> {
+        let mut writer = self.write_lock()?;
+        let mut command = Command::remove_range(start, end);
+        let keys: Vec<(Vec<u8>, RecordPos)> = self
+            .index
+            .range(removed_range(&command))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        command.version = Some(writer.next_version());
+        let frame = self.codec.encode(&command)?;
+        let mut events = Vec::new();
+        for (key, _) in &keys {
+            if writer.watchers.watching(key) {
+                let old_value = self.read(key, Some(&mut writer))?;
+                events.push(WatchEvent {
+                    op: WatchOp::Remove,
+                    key: key.clone(),
+                    old_value,
+                    new_value: None,
+                });
+            }
+        }
+        writer.log.write_all(&frame)?;
+        writer.offset += frame.len() as u64;
+        writer.written(frame.len() as u64)?;
+
+        let now = now_millis();
+        let mut removed = 0;
+        for (key, pos) in keys {
+            if !pos.expired(now) {
+                removed += 1;
+            }
+            self.index.remove(&key);
+            self.indexes.remove(&key);
+            writer.stale_bytes += pos.len + self.merges.unlink(pos);
+        }
+        for event in events {
+            writer.watchers.send(event);
+        }
+        // like a removal, the record is garbage as soon as it is written
+        writer.stale_bytes += frame.len() as u64;
+        self.appended(&mut writer)?;
+        Ok(removed)
+    }
+
+    /// Merges `operand` into the value of a key with the function set by
+    /// [`KvStoreOptions::merge_operator`], which fails without one.
+    ///
+    /// Only the operand is appended to the log: it is folded into the value when the key is read,
+    /// and compaction replaces the merges with a set of the value. This makes read-modify-write
+    /// updates like counters or appending to lists as cheap as a set. A key that expires keeps its
+    /// expiry time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// // adds the operand to a counter
+    /// let options = KvStoreOptions::new().merge_operator(|_key, existing, operand| {
+    ///     let count = |value: &[u8]| String::from_utf8_lossy(value).parse::<i64>().unwrap_or(0);
+    ///     (existing.map_or(0, count) + count(operand)).to_string().into_bytes()
+    /// });
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// store.merge(String::from("visits"), String::from("1")).unwrap();
+    /// store.merge(String::from("visits"), String::from("2")).unwrap();
+    /// assert_eq!(store.get(String::from("visits")).unwrap(), Some(String::from("3")));
+    /// ```
+    pub fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.merge_raw(key.into_bytes(), operand.into_bytes())
+    }
+
+    /// Merges an operand of arbitrary bytes into the value of a key made of arbitrary bytes, see
+    /// [`KvStore::merge`]
+    pub fn merge_raw(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.merges.check_operator()?;
+        self.append_operand(Command::merge(key, operand), WatchOp::Merge)
+    }
+
+    /// Appends `suffix` to the value of a key, which is set to it if the key does not exist.
+    ///
+    /// Like a [`KvStore::merge`], only the suffix is appended to the log and compaction replaces
+    /// the appends with a set of the whole value, but no merge operator is needed. A key that
+    /// expires keeps its expiry time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.append(String::from("log"), String::from("started;")).unwrap();
+    /// store.append(String::from("log"), String::from("stopped;")).unwrap();
+    /// assert_eq!(
+    ///     store.get(String::from("log")).unwrap(),
+    ///     Some(String::from("started;stopped;"))
+    /// );
+    /// ```
+    pub fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.append_raw(key.into_bytes(), suffix.into_bytes())
+    }
+
+    /// Appends arbitrary bytes to the value of a key made of arbitrary bytes, see
+    /// [`KvStore::append`]
+    pub fn append_raw(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<()> {
+        self.append_operand(Command::append(key, suffix), WatchOp::Append)
+    }
+
+    /// Writes a merge or append `command`, linking it to the value of its key it builds on
+    fn append_operand(&self, mut command: Command, op: WatchOp) -> Result<()> {
+        let mut writer = self.write_lock()?;
+        let prev = self.lookup(&command.key, true);
+        command.expires_at = prev.and_then(|prev| prev.expires_at);
+        command.version = Some(writer.next_version());
+        let frame = self.codec.encode(&command)?;
+
+        // watchers and secondary indexes see the value the record results in
+        let values = if writer.watchers.watching(&command.key) || !self.indexes.is_empty() {
+            let old_value = self.read(&command.key, Some(&mut writer))?;
+            let new_value = self.merges.apply(&command, old_value.as_deref())?;
+            Some((old_value, new_value))
+        } else {
+            None
+        };
+        let Command {
+            key, expires_at, ..
+        } = command;
+
+        writer.log.write_all(&frame)?;
+        let pos = RecordPos {
+            gen: writer.gen,
+            offset: writer.offset,
+            len: frame.len() as u64,
+            expires_at,
+        };
+        writer.offset += pos.len;
+        writer.written(pos.len)?;
+        let old = self.index.get(&key).map(|entry| *entry.value());
+        self.merges.link(pos, prev);
+        self.index.insert(key.clone(), pos);
+        // an expired value is overwritten rather than built on
+        if let (Some(old), None) = (old, prev) {
+            writer.stale_bytes += old.len + self.merges.unlink(old);
+        }
+        if let Some((old_value, new_value)) = values {
+            self.indexes.set(&key, &new_value);
+            if writer.watchers.watching(&key) {
+                writer.watchers.send(WatchEvent {
+                    op,
+                    key,
+                    old_value,
+                    new_value: Some(new_value),
+                });
+            }
+        }
+        self.appended(&mut writer)
+    }
+
+    /// Whether the store holds a value for `key`, without reading it from the log
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// assert!(store.contains_key("key1"));
+    /// assert!(!store.contains_key("key2"));
+    /// ```
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lookup(key.as_bytes(), false).is_some()
+    }
+
+    /// Number of keys in the store, counted from the index.
+    ///
+    /// Keys with a TTL are only counted until they expire, which makes this linear in the number
+    /// of keys.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// assert_eq!(store.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.keys_raw().count()
+    }
+
+    /// Whether the store holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.keys_raw().next().is_none()
+    }
+
+    /// Iterates over the keys in the store in lexical order, leaving out keys that aren't valid
+    /// UTF-8.
+    ///
+    /// The iterator walks the live index, so keys set or removed while iterating may or may not
+    /// be seen.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key2"), String::from("value2")).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1", "key2"]);
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.keys_raw()
+            .filter_map(|key| String::from_utf8(key).ok())
+    }
+
+    /// Iterates over the keys matching the glob `pattern`, in lexical order: `*` matches anything,
+    /// `?` a single byte, and `\` makes the character after it match only itself.
+    ///
+    /// Only the keys starting with the part of the pattern before its first wildcard are looked
+    /// at, so `user:*:profile` doesn't go through the whole store. Like [`KvStore::keys`], the
+    /// iterator walks the live index.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("user:1:profile"), String::from("alice")).unwrap();
+    /// store.set(String::from("user:1:session"), String::from("1234")).unwrap();
+    /// store.set(String::from("user:2:profile"), String::from("bob")).unwrap();
+    /// let keys: Vec<String> = store.keys_matching("user:*:profile").collect();
+    /// assert_eq!(keys, vec!["user:1:profile", "user:2:profile"]);
+    /// ```
+    pub fn keys_matching(&self, pattern: &str) -> impl Iterator<Item = String> + '_ {
+        let pattern = pattern.as_bytes().to_vec();
+        let literal = pattern
+            .iter()
+            .position(|byte| matches!(byte, b'*' | b'?' | b'\\'))
+            .unwrap_or(pattern.len());
+        let prefix = pattern[..literal].to_vec();
+        self.index
+            .range(prefix.clone()..)
+            .take_while(move |entry| entry.key().starts_with(&prefix))
+            .filter(move |entry| glob::matches(&pattern, entry.key()))
+            .filter(move |entry| !entry.value().expired(now_millis()))
+            .filter_map(|entry| String::from_utf8(entry.key().clone()).ok())
+    }
+
+    /// Iterates over every key in the store as bytes, in lexical order.
+    ///
+    /// Like [`KvStore::keys`], the iterator walks the live index.
+    pub fn keys_raw(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.index
+            .iter()
+            .filter(move |entry| !entry.value().expired(now_millis()))
+            .map(|entry| entry.key().clone())
+    }
+
+    /// Iterates over the keys and values in the store in lexical order of the keys.
+    ///
+    /// Values are read from the log as the iterator advances, and keys removed before their value
+    /// is read are skipped. Keys that aren't valid UTF-8 are left out, and values that aren't are
+    /// returned as errors.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// for entry in store.iter() {
+    ///     let (key, value) = entry.unwrap();
+    ///     println!("{} = {}", key, value);
+    /// }
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.str_entries(self.index.iter())
+    }
+
+    /// Iterates over the keys in `range` and their values, in lexical order of the keys.
+    ///
+    /// Like [`KvStore::iter`], values are read as the iterator advances.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// for id in 999..=2000 {
+    ///     store.set(format!("user:{}", id), id.to_string()).unwrap();
+    /// }
+    /// let users = store.range("user:1000".."user:2000");
+    /// assert_eq!(users.count(), 1000);
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> impl Iterator<Item = Result<(String, String)>> + '_
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().as_bytes().to_vec());
+        let range = (bound(range.start_bound()), bound(range.end_bound()));
+        self.str_entries(self.index.range(range))
+    }
+
+    /// Iterates over the keys in `range` and their values as bytes, in lexical order of the keys.
+    ///
+    /// Unlike [`KvStore::range`], keys and values don't have to be valid UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// for id in 0u32..300 {
+    ///     store.set_raw(id.to_be_bytes().to_vec(), vec![]).unwrap();
+    /// }
+    /// let ids = store.range_raw(100u32.to_be_bytes()..200u32.to_be_bytes());
+    /// assert_eq!(ids.count(), 100);
+    /// ```
+    pub fn range_raw<K, R>(&self, range: R) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+        self.index
+            .range((bound(range.start_bound()), bound(range.end_bound())))
+            .filter_map(move |entry| self.read_entry(entry))
+    }
+
+    /// Iterates over the keys starting with `prefix` and their values, in lexical order of the keys.
+    ///
+    /// Like [`KvStore::iter`], values are read as the iterator advances.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("user:1"), String::from("alice")).unwrap();
+    /// store.set(String::from("group:1"), String::from("admins")).unwrap();
+    /// let users: Vec<_> = store.scan_prefix("user:").collect();
+    /// assert_eq!(users.len(), 1);
+    /// ```
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let prefix = prefix.as_bytes().to_vec();
+        self.str_entries(
+            self.index
+                .range(prefix.clone()..)
+                .take_while(move |entry| entry.key().starts_with(&prefix)),
+        )
+    }
+
+    /// Iterates over the keys starting with the bytes `prefix` and their values as bytes, in
+    /// lexical order of the keys.
+    pub fn scan_prefix_raw(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let prefix = prefix.to_vec();
+        self.index
+            .range(prefix.clone()..)
+            .take_while(move |entry| entry.key().starts_with(&prefix))
+            .filter_map(move |entry| self.read_entry(entry))
+    }
+
+    /// Writes the keys starting with `prefix` and their values to `out` in `format`, in lexical
+    /// order of the keys, and returns how many were written.
+    ///
+    /// Like [`KvStore::iter`], keys that aren't valid UTF-8 are left out and values that aren't
+    /// fail the export.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{ExportFormat, KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let mut dump = Vec::new();
+    /// store.export("", ExportFormat::JsonLines, &mut dump).unwrap();
+    /// assert_eq!(dump, b"{\"key\":\"key1\",\"value\":\"value1\"}\n");
+    /// ```
+    pub fn export(&self, prefix: &str, format: ExportFormat, out: impl Write) -> Result<usize> {
+        let mut out = BufWriter::new(out);
+        format.write_header(&mut out)?;
+        let mut exported = 0;
+        for entry in self.scan_prefix(prefix) {
+            let (key, value) = entry?;
+            format.write_entry(&mut out, &key, &value)?;
+            exported += 1;
+        }
+        out.flush()?;
+        Ok(exported)
+    }
+
+    /// Sets the key-value pairs read from `reader`, JSON lines as written by [`KvStore::export`],
+    /// and returns how many keys were set.
+    ///
+    /// Entries are written in batches whose keys are added to the index together, and the log is
+    /// synced once at the end. Keys that already exist, in the store or earlier in the input, are
+    /// handled as `on_conflict` says. With [`OnConflict::Fail`], the entries before the first
+    /// such key are imported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, OnConflict};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let dump = "{\"key\":\"key1\",\"value\":\"value1\"}\n";
+    /// store.import_from_reader(dump.as_bytes(), OnConflict::Fail).unwrap();
+    /// assert_eq!(store.get(String::from("key1")).unwrap(), Some(String::from("value1")));
+    /// ```
+    pub fn import_from_reader(
+        &self,
+        reader: impl BufRead,
+        on_conflict: OnConflict,
+    ) -> Result<usize> {
+        let mut batch = WriteBatch::new();
+        // keys of the batch, which aren't in the store yet
+        let mut pending = HashSet::new();
+        let mut imported = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = dump::parse_entry(&line).map_err(|e| {
+                KvsError::Message(format!("Line {} is not a key-value pair: {}", i + 1, e))
+            })?;
+            if on_conflict != OnConflict::Overwrite
+                && (pending.contains(&key) || self.contains_key(&key))
+            {
+                if on_conflict == OnConflict::Skip {
+                    continue;
+                }
+                self.write_batch(batch)?;
+                self.sync_all()?;
+                return Err(KvsError::Message(format!("Key {} already exists", key)));
+            }
+            if on_conflict != OnConflict::Overwrite {
+                pending.insert(key.clone());
+            }
+            batch.set(key, value);
+            imported += 1;
+            if batch.len() == IMPORT_BATCH_LEN {
+                self.write_batch(std::mem::take(&mut batch))?;
+                pending.clear();
+            }
+        }
+        self.write_batch(batch)?;
+        self.sync_all()?;
+        Ok(imported)
+    }
+
+    /// Sets the key-value pairs read from `reader`, one per line as a key and a value separated by
+    /// a tab or as JSON lines, and returns how many pairs were read.
+    ///
+    /// Unlike [`KvStore::import_from_reader`], every pair is written in a single batch, synced
+    /// once: if a line can't be parsed, nothing is set. The first tab of a line ends its key, so
+    /// values may hold tabs. A key given more than once is left with its last value.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let lines = "key1\tvalue1\n{\"key\":\"key2\",\"value\":\"value2\"}\n";
+    /// assert_eq!(store.load(lines.as_bytes()).unwrap(), 2);
+    /// assert_eq!(store.get(String::from("key2")).unwrap(), Some(String::from("value2")));
+    /// ```
+    pub fn load(&self, reader: impl BufRead) -> Result<usize> {
+        let mut batch = WriteBatch::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = dump::parse_line(line).map_err(|e| {
+                KvsError::Message(format!("Line {} is not a key-value pair: {}", i + 1, e))
+            })?;
+            batch.set(key, value);
+        }
+        let loaded = batch.len();
+        self.write_batch(batch)?;
+        self.sync_all()?;
+        Ok(loaded)
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// Once expired, the key can't be read and doesn't count as existing; compaction reclaims its
+    /// record. Setting the key again without a TTL makes it persistent.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use std::time::Duration;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let ttl = Duration::from_secs(60);
+    /// store.set_with_ttl(String::from("session"), String::from("token"), ttl).unwrap();
+    /// assert!(store.ttl(String::from("session")).unwrap().unwrap() <= ttl);
+    /// ```
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let mut command = Command::set(key.into_bytes(), value.into_bytes());
+        command.expires_at = Some(expires_at);
+        let mut writer = self.write_lock()?;
+        self.append_set(&mut writer, command)
+    }
+
+    /// Time left before a key expires, or `None` if it doesn't expire. Fails if the key does not
+    /// exist.
+    pub fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        let pos = self
+            .lookup(key.as_bytes(), false)
+            .ok_or_else(|| KvsError::KeyNotFound)?;
+        Ok(pos
+            .expires_at
+            .map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now_millis()))))
+    }
+
+    /// Makes a key that was set with a TTL persistent. Fails if the key does not exist.
+    pub fn persist(&self, key: String) -> Result<()> {
+        let key = key.into_bytes();
+        let mut writer = self.write_lock()?;
+        let pos = self
+            .lookup(&key, true)
+            .ok_or_else(|| KvsError::KeyNotFound)?;
+        if pos.expires_at.is_none() {
+            return Ok(());
+        }
+        // the key may expire between the lookup and the read
+        let value = self
+            .read(&key, Some(&mut writer))?
+            .ok_or_else(|| KvsError::KeyNotFound)?;
+        let command = Command::set(key, value);
+        self.append_set(&mut writer, command)
+    }
+
+    /// Removes the keys whose TTL has elapsed, writing a removal record for each, and returns how
+    /// many were removed.
+    ///
+    /// Expired keys are invisible anyway; this only reclaims them before compaction does.
+    /// [`KvStoreOptions::expiry_sweep_interval`] runs it periodically.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = self
+            .index
+            .iter()
+            .filter(|entry| entry.value().expired(now))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut removed = 0;
+        for key in expired {
+            let mut writer = self.write_lock()?;
+            // the key may have been set again in the meantime
+            if self
+                .index
+                .get(&key)
+                .is_some_and(|entry| entry.value().expired(now))
+            {
+                self.append_remove(&mut writer, key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes every key, deleting the log files and syncing the removal to disk.
+    ///
+    /// Namespaces have logs of their own and are left alone.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.clear().unwrap();
+    /// assert!(store.is_empty());
+    /// ```
+    pub fn clear(&self) -> Result<()> {
+        // a running compaction would copy the records it already read into a new generation
+        let mut writer = loop {
+            let writer = self.write_lock()?;
+            let running = self.compaction.thread.lock().unwrap().take();
+            match running {
+                Some(running) if !running.is_finished() => {
+                    drop(writer);
+                    join_compaction(running);
+                }
+                _ => break writer,
+            }
+        };
+
+        // the marker clears the index on replay until the old generations are gone
+        let mut command = Command::clear();
+        command.version = Some(writer.next_version());
+        let frame = self.codec.encode(&command)?;
+        writer.log.write_all(&frame)?;
+        writer.metrics.bytes_written += frame.len() as u64;
+        writer.sync()?;
+        self.index.clear();
+        self.indexes.clear();
+        self.merges.clear();
+
+        let old_gens: Vec<u64> = self.readers.iter().map(|entry| *entry.key()).collect();
+        writer.gen += 1;
+        writer.log = LogWriter::new(new_log(
+            &writer.path,
+            writer.gen,
+            writer.store_id,
+            self.codec.format(),
+            &self.readers,
+        )?);
+        writer.offset = record::FILE_HEADER_LEN;
+        writer.log_bytes = 0;
+        writer.stale_bytes = 0;
+        writer.sync()?;
+        // oldest first, so the marker goes last
+        for &gen in &old_gens {
+            self.readers.remove(&gen);
+        }
+        archive::retire(&writer.path, &old_gens, writer.archive.as_ref())?;
+        writer.watchers.send(WatchEvent {
+            op: WatchOp::Clear,
+            key: Vec::new(),
+            old_value: None,
+            new_value: None,
+        });
+        Ok(())
+    }
+
+    /// The version of the last write.
+    ///
+    /// Every write gets a version greater than the ones before it: the time it was written at, in
+    /// microseconds since the Unix epoch, unless that isn't past the last version. The writes of a
+    /// batch share a version. Keep the version of a point in time to read the store as it was then
+    /// with [`KvStore::get_at`].
+    pub fn version(&self) -> u64 {
+        self.writer().version
+    }
+
+    /// The value a key had at `version`, if that version is still in the log: compaction drops the
+    /// versions [`KvStoreOptions::version_retention`] doesn't keep, after which the key reads as
+    /// it was at the oldest version left. Like [`KvStore::history`], this reads the whole log.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let version = store.version();
+    /// store.set(String::from("key1"), String::from("value2")).unwrap();
+    /// assert_eq!(store.get_at("key1", version).unwrap(), Some(String::from("value1")));
+    /// ```
+    pub fn get_at(&self, key: &str, version: u64) -> Result<Option<String>> {
+        let value = self
+            .history(key)?
+            .into_iter()
+            .rev()
+            .find(|entry| entry.version <= version)
+            .filter(|entry| !expired_at(entry, version))
+            .and_then(|entry| entry.value);
+        utf8(value)
+    }
+
+    /// The versions of a key still in the log, oldest first, with the value each write left it
+    /// with. Removals, including [`KvStore::clear`], leave no value.
+    ///
+    /// This reads the whole log, but for the generations in `segments/` whose bloom filter tells
+    /// they don't hold the key, so it is meant for auditing and debugging rather than hot paths.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.remove(String::from("key1")).unwrap();
+    /// let history = store.history("key1").unwrap();
+    /// assert_eq!(history[0].value, Some(b"value1".to_vec()));
+    /// assert_eq!(history[1].value, None);
+    /// ```
+    pub fn history(&self, key: &str) -> Result<Vec<KeyVersion>> {
+        let files = {
+            let mut writer = self.writer();
+            writer.log.flush()?;
+            self.log_files(&writer)?
+        };
+        self.key_versions(key.as_bytes(), &files)
+    }
+
+    /// The generations of the log, with where each ends, leaving out the one a running compaction
+    /// writes: like in a tail, it only holds copies.
+    ///
+    /// Called with the writer held and flushed, so the log doesn't move on meanwhile.
+    fn log_files(&self, writer: &KvStoreWriter) -> Result<Vec<(u64, Arc<File>, u64)>> {
+        let compacting = self
+            .compaction
+            .thread
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+            .then_some(writer.compaction_gen);
+        self.readers
+            .iter()
+            .filter(|entry| Some(*entry.key()) != compacting)
+            .map(|entry| {
+                Ok((
+                    *entry.key(),
+                    entry.value().clone(),
+                    entry.value().metadata()?.len(),
+                ))
+            })
+            .collect()
+    }
+
+    /// The versions of `key` in `files`, oldest first, see [`KvStore::history`]
+    ///
+    /// The generations before the last one are no longer appended to, so the ones whose bloom
+    /// filter doesn't hold the key are skipped, and the ones without a filter get one.
+    fn key_versions(&self, key: &[u8], files: &[(u64, Arc<File>, u64)]) -> Result<Vec<KeyVersion>> {
+        let mut versions: Vec<KeyVersion> = Vec::new();
+        for (i, (gen, file, end)) in files.iter().enumerate() {
+            let sealed = i + 1 < files.len();
+            let filter = self.filters.get(*gen, *end).filter(|_| sealed);
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.may_contain(key))
+            {
+                continue;
+            }
+            // the hashes of the keys of the generation, unless a record is about many keys
+            let mut key_hashes = (sealed && filter.is_none()).then(Vec::new);
+            let building = key_hashes.is_some();
+            for_each_committed(*gen, file, *end, &self.codec, |command, _| {
+                if matches!(
+                    command.command_type,
+                    CommandType::CLEAR | CommandType::RMRANGE
+                ) {
+                    key_hashes = None;
+                } else if let Some(hashes) = key_hashes.as_mut() {
+                    hashes.push(bloom::hash(&command.key));
+                }
+                let about_key = match command.command_type {
+                    CommandType::CLEAR => true,
+                    CommandType::RMRANGE => removed_range(&command).contains(&key.to_vec()),
+                    _ => command.key == key,
+                };
+                if !about_key {
+                    return Ok(());
+                }
+                let version = command.version.unwrap_or(0);
+                let existing = versions
+                    .last()
+                    .filter(|last| !expired_at(last, version))
+                    .and_then(|last| last.value.as_deref());
+                let value = match command.command_type {
+                    CommandType::SET => command.value,
+                    command_type if command_type.is_operand() => {
+                        Some(self.merges.apply(&command, existing)?)
+                    }
+                    CommandType::CLEAR | CommandType::RMRANGE if existing.is_none() => {
+                        return Ok(())
+                    }
+                    _ => None,
+                };
+                versions.push(KeyVersion {
+                    version,
+                    value,
+                    expires_at: command.expires_at,
+                });
+                Ok(())
+            })?;
+            if building {
+                self.filters.insert(*gen, *end, key_hashes.as_deref());
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Subscribes to the changes of the keys starting with `prefix`: every set and removal of one
+    /// of them, and every [`KvStore::clear`], sends a [`WatchEvent`] to the returned channel, in the
+    /// order the changes were written.
+    ///
+    /// Keys that expire are reported once [`KvStore::sweep_expired`] removes them, without their
+    /// old value. Events queue up in the channel until they are received, and the subscription
+    /// ends when the receiver is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, WatchOp};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let events = store.watch("config:");
+    /// store.set(String::from("config:level"), String::from("debug")).unwrap();
+    /// let event = events.recv().unwrap();
+    /// assert_eq!(event.op, WatchOp::Set);
+    /// assert_eq!(event.new_value, Some(b"debug".to_vec()));
+    /// ```
+    pub fn watch(&self, prefix: &str) -> Receiver<WatchEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.writer()
+            .watchers
+            .add(prefix.as_bytes().to_vec(), sender);
+        receiver
+    }
+
+    /// Adds a secondary index named `name`, or replaces it: `extract` pulls the indexed value out of
+    /// the value of a key, if it has one, and [`KvStore::get_by_index`] finds the keys by it.
+    ///
+    /// The index is built from the keys of the store, then kept up to date by every write. It
+    /// lives in memory only, so it has to be added again whenever the store is opened.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.add_index("email", |value| {
+    ///     let user: serde_json::Value = serde_json::from_slice(value).ok()?;
+    ///     Some(user.get("email")?.as_str()?.as_bytes().to_vec())
+    /// }).unwrap();
+    /// let user = String::from(r#"{"name": "alice", "email": "a@b.com"}"#);
+    /// store.set(String::from("user:1"), user).unwrap();
+    /// assert_eq!(store.get_by_index("email", "a@b.com").unwrap(), vec!["user:1"]);
+    /// ```
+    pub fn add_index<F>(&self, name: &str, extract: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        // no write can slip in between reading the values and the index taking over
+        let mut writer = self.writer();
+        let pairs = self.index.iter().filter_map(|entry| {
+            let key = entry.key().clone();
+            let value = self.read(&key, Some(&mut writer));
+            value
+                .map(|value| value.map(|value| (key, value)))
+                .transpose()
+        });
+        self.indexes.add(name, Box::new(extract), pairs)
+    }
+
+    /// The keys, in order, whose value has `value` as the value of the secondary index `name`,
+    /// added by [`KvStore::add_index`]. Fails if there is no such index.
+    pub fn get_by_index(&self, name: &str, value: &str) -> Result<Vec<String>> {
+        let keys = self
+            .indexes
+            .get(name, value.as_bytes())
+            .ok_or_else(|| KvsError::Message(format!("No index named {}", name)))?;
+        keys.into_iter()
+            // expired keys stay indexed until they are swept
+            .filter(|key| self.lookup(key, false).is_some())
+            .map(|key| Ok(String::from_utf8(key)?))
+            .collect()
+    }
+
+    /// The committed records of the log from `from` on, in the order they were written, for
+    /// change data capture.
+    ///
+    /// The returned [`Tail`] yields records until it catches up with the end of the log, and then
+    /// the records written since whenever it is iterated again. [`Tail::position`] is where to
+    /// resume from with a new tail later on. The records of a batch are only yielded once the
+    /// whole batch is in the log.
+    ///
+    /// Compaction copies the live records into a new generation, so a tail reading the log as it
+    /// is compacted yields them again, as sets of the values the keys already have. Fails if
+    /// compaction, or [`KvStore::clear`], deleted the generation of `from` since: the consumer
+    /// has to start over from [`LogPosition::START`] or from a copy of the store.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, LogPosition};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let mut tail = store.tail(LogPosition::START).unwrap();
+    /// assert_eq!(tail.next().unwrap().unwrap().key, b"key1");
+    /// assert!(tail.next().is_none());
+    /// store.set(String::from("key2"), String::from("value2")).unwrap();
+    /// assert_eq!(tail.next().unwrap().unwrap().key, b"key2");
+    /// ```
+    pub fn tail(&self, from: LogPosition) -> Result<Tail> {
+        let (gen, file) = if from == LogPosition::START {
+            let first = self.readers.front().unwrap();
+            (*first.key(), first.value().clone())
+        } else {
+            let entry = self.readers.get(&from.gen).ok_or_else(|| {
+                KvsError::Message(format!("Position {} is no longer in the log", from))
+            })?;
+            (from.gen, entry.value().clone())
+        };
+        Ok(Tail {
+            store: self.clone(),
+            position: LogPosition {
+                gen,
+                offset: from.offset.max(record::FILE_HEADER_LEN),
+            },
+            file,
+            records: VecDeque::new(),
+        })
+    }
+
+    /// A consistent view of the store as it is now, for reading several keys, or iterating over
+    /// them, while writers carry on.
+    ///
+    /// Reads through the snapshot ignore every later write. Taking it only pins where the log
+    /// ends and the version of the last write, and keeps the log files open so that compaction
+    /// deleting them doesn't affect it: the disk space they take is only freed once it is
+    /// dropped. A key not written since reads as it is in the store, and one written since from
+    /// its history in the files up to the pinned position, like [`KvStore::get_at`]. Iterating
+    /// replays those files, the first time only.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let snapshot = store.snapshot().unwrap();
+    /// store.set(String::from("key1"), String::from("value2")).unwrap();
+    /// store.set(String::from("key2"), String::from("value2")).unwrap();
+    /// assert_eq!(snapshot.get("key1").unwrap(), Some(String::from("value1")));
+    /// assert_eq!(snapshot.get("key2").unwrap(), None);
+    /// ```
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let mut writer = self.writer();
+        writer.log.flush()?;
+        let files = self.log_files(&writer)?;
+        Ok(Snapshot {
+            store: self.clone(),
+            position: LogPosition {
+                gen: writer.gen,
+                offset: writer.offset,
+            },
+            version: writer.version,
+            taken_at: now_millis(),
+            files,
+            entries: OnceLock::new(),
+        })
+    }
+
+    /// Applies a change read from the [`KvStore::tail`] of another store, e.g. to replicate it.
+    ///
+    /// Changes can be applied more than once: removing a key that doesn't exist does nothing, and
+    /// a set that already expired removes the key.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, LogPosition};
+    /// # use tempfile::TempDir;
+    ///
+    /// let leader = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let follower = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// leader.set(String::from("key1"), String::from("value1")).unwrap();
+    /// for record in leader.tail(LogPosition::START).unwrap() {
+    ///     follower.apply_change(record.unwrap()).unwrap();
+    /// }
+    /// assert_eq!(follower.get(String::from("key1")).unwrap(), Some(String::from("value1")));
+    /// ```
+    pub fn apply_change(&self, record: ChangeRecord) -> Result<()> {
+        match (record.op, record.value) {
+            (WatchOp::Set, Some(value))
+                if record
+                    .expires_at
+                    .is_none_or(|expires_at| expires_at > now_millis()) =>
+            {
+                let mut command = Command::set(record.key, value);
+                command.expires_at = record.expires_at;
+                let mut writer = self.write_lock()?;
+                self.append_set(&mut writer, command)
+            }
+            (WatchOp::Merge, operand) => self.merge_raw(record.key, operand.unwrap_or_default()),
+            (WatchOp::Append, suffix) => self.append_raw(record.key, suffix.unwrap_or_default()),
+            (WatchOp::Set | WatchOp::Remove, _) => {
+                let mut writer = self.write_lock()?;
+                if self.lookup(&record.key, true).is_some() {
+                    self.append_remove(&mut writer, record.key)?;
+                }
+                Ok(())
+            }
+            (WatchOp::RemoveRange, end) => self.delete_between(record.key, end).map(|_| ()),
+            (WatchOp::Clear, _) => self.clear(),
+        }
+    }
+
+    /// The directory of the store
+    pub(crate) fn dir(&self) -> PathBuf {
+        self.writer().path.clone()
+    }
+
+    /// Opens the namespace `name`, creating it if needed.
+    ///
+    /// A namespace is a keyspace of its own, stored in the `namespaces/<name>` subdirectory of the
+    /// store with its own log, so its keys, compaction and [`KvStore::clear`] don't affect the
+    /// store or other namespaces. It is opened with the options the store was opened with, and
+    /// every call with the same name returns a handle to the same namespace. Names are made of
+    /// ASCII letters, digits, `-` and `_`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let sessions = store.namespace("sessions").unwrap();
+    /// sessions.set(String::from("key1"), String::from("token")).unwrap();
+    /// assert_eq!(store.get(String::from("key1")).unwrap(), None);
+    /// ```
+    pub fn namespace(&self, name: &str) -> Result<KvStore> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(KvsError::Message(format!(
+                "Invalid namespace name {:?}",
+                name
+            )));
+        }
+        let mut open = self.namespaces.open.lock().unwrap();
+        if let Some(namespace) = open.get(name) {
+            return Ok(namespace.clone());
+        }
+        let mut options = self.namespaces.options.clone();
+        // the generations of the namespace are numbered apart from the ones of the store
+        if let Some(dir) = options
+            .wal_archive
+            .as_mut()
+            .and_then(|archive| archive.dir.as_mut())
+        {
+            *dir = dir.join(NAMESPACES_DIR).join(name);
+        }
+        let namespace = KvStore::open_with(self.namespaces.dir.join(name), options)?;
+        open.insert(name.to_owned(), namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Names of the namespaces of the store, in lexical order
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        if !self.namespaces.dir.exists() {
+            return Ok(names);
+        }
+        for entry in fs::read_dir(&self.namespaces.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Writes a consistent copy of the store, and of its namespaces, into `dir`, which must be
+    /// empty or not exist yet.
+    ///
+    /// The live records are copied into a single log file, listed with its checksum in a
+    /// `MANIFEST` written last. Writers are only held up while the index is snapshotted, not while
+    /// records are copied, and the backup directory can be opened as a store as it is. Records
+    /// are copied as they are, so an encrypted store's backup needs its key.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let backup_dir = TempDir::new().unwrap();
+    /// store.backup(backup_dir.path()).unwrap();
+    /// ```
+    pub fn backup(&self, dir: impl AsRef<Path>) -> Result<()> {
+        self.write_backup(dir.as_ref(), None)
+    }
+
+    /// The `backups/` directory of the store, where backups can go without another directory
+    /// being set aside for them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.backup(store.backups_dir().join("nightly")).unwrap();
+    /// ```
+    pub fn backups_dir(&self) -> PathBuf {
+        layout::backups_dir(&self.writer().path)
+    }
+
+    /// Writes an incremental backup into `dir`, which must be empty or not exist yet, holding
+    /// only the changes since the backup in `previous`, full or incremental itself.
+    ///
+    /// The backup copies the records written since `previous` was made, and records removing the
+    /// keys that were removed since. Records moved by compaction in the meantime count as
+    /// written. The manifest names `previous` as its base, by absolute path, so the chain of
+    /// backups must stay where it is; [`KvStore::restore`] restores the whole chain.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let backup_dir = TempDir::new().unwrap();
+    /// store.backup(backup_dir.path().join("full")).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store
+    ///     .backup_incremental(backup_dir.path().join("full"), backup_dir.path().join("1"))
+    ///     .unwrap();
+    /// ```
+    pub fn backup_incremental(
+        &self,
+        previous: impl AsRef<Path>,
+        dir: impl AsRef<Path>,
+    ) -> Result<()> {
+        self.write_backup(dir.as_ref(), Some(previous.as_ref()))
+    }
+
+    /// Writes a backup of the store into `dir`, holding the changes since the backup in `previous`
+    /// if one is given, then backs its namespaces up the same way
+    fn write_backup(&self, dir: &Path, previous: Option<&Path>) -> Result<()> {
+        if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+            return Err(KvsError::Message(format!("{} is not empty", dir.display())));
+        }
+
+        // the keys the previous backups hold, and where the last of them left off
+        let since = match previous {
+            Some(previous) => {
+                let chain = backup::chain(previous)?;
+                let (_, last) = chain.last().unwrap();
+                let watermark = last.watermark.ok_or_else(|| {
+                    KvsError::Message(format!(
+                        "{} predates incremental backups",
+                        previous.display()
+                    ))
+                })?;
+                let store_id = Uuid::parse_str(&last.store_id)?;
+                let keys = SkipMap::new();
+                for (dir, manifest) in &chain {
+                    for file in &manifest.files {
+                        let file = File::open(dir.join(&file.name))?;
+                        let from = record::FILE_HEADER_LEN;
+                        replay(
+                            0,
+                            &file,
+                            from,
+                            &keys,
+                            &Merges::new(None),
+                            &self.codec,
+                            &KvStoreOptions::default(),
+                        )?;
+                    }
+                }
+                Some((fs::canonicalize(previous)?, watermark, store_id, keys))
+            }
+            None => None,
+        };
+
+        // the open files stay readable even if compaction deletes them while we copy
+        let (records, removed, files, store_id, watermark) = {
+            let mut writer = self.writer();
+            if let Some((previous, _, store_id, _)) = &since {
+                if *store_id != writer.store_id {
+                    return Err(KvsError::Message(format!(
+                        "{} is a backup of another store",
+                        previous.display()
+                    )));
+                }
+            }
+            writer.log.flush()?;
+            let now = now_millis();
+            // with the chains of the merges, which are unlinked once their keys are written again
+            let records: Vec<(Vec<u8>, RecordPos, Option<Vec<RecordPos>>)> = self
+                .index
+                .iter()
+                .filter(|entry| !entry.value().expired(now))
+                .filter(|entry| {
+                    let pos = entry.value();
+                    since.as_ref().is_none_or(|(_, watermark, _, _)| {
+                        (pos.gen, pos.offset) >= (watermark.gen, watermark.offset)
+                    })
+                })
+                .map(|entry| {
+                    let pos = *entry.value();
+                    (entry.key().clone(), pos, self.merges.chain(pos))
+                })
+                .collect();
+            let removed: Vec<Vec<u8>> = since
+                .iter()
+                .flat_map(|(_, _, _, keys)| keys.iter())
+                .map(|entry| entry.key().clone())
+                .filter(|key| {
+                    self.index
+                        .get(key)
+                        .is_none_or(|entry| entry.value().expired(now))
+                })
+                .collect();
+            let files: HashMap<u64, Arc<File>> = self
+                .readers
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect();
+            let watermark = Watermark {
+                gen: writer.gen,
+                offset: writer.offset,
+            };
+            (records, removed, files, writer.store_id, watermark)
+        };
+
+        fs::create_dir_all(layout::segments_dir(dir))?;
+        let path = dir.join(layout::segment_name(1));
+        let mut log = BufWriter::new(File::create(&path)?);
+        let mut crc = crc32fast::Hasher::new();
+        let mut len = 0;
+        let mut append = |buf: &[u8]| -> Result<()> {
+            log.write_all(buf)?;
+            crc.update(buf);
+            len += buf.len() as u64;
+            Ok(())
+        };
+        append(&record::file_header(store_id, self.codec.format()))?;
+        let read = |pos: RecordPos| -> Result<Vec<u8>> {
+            let mut buf = vec![0; pos.len as usize];
+            read_exact_at(&files[&pos.gen], &mut buf, pos.offset)?;
+            Ok(buf)
+        };
+        for (key, pos, chain) in records {
+            match chain {
+                // the backup holds the value the merges result in
+                Some(chain) => {
+                    let records = chain
+                        .into_iter()
+                        .map(|pos| decode(&self.codec, &read(pos)?, pos))
+                        .collect::<Result<Vec<_>>>()?;
+                    let version = records[0].version;
+                    let value = self.merges.fold(records)?.unwrap_or_default();
+                    let mut command = Command::set(key, value);
+                    command.expires_at = pos.expires_at;
+                    command.version = version;
+                    append(&self.codec.encode(&command)?)?;
+                }
+                None => append(&read(pos)?)?,
+            }
+        }
+        for key in removed {
+            append(&self.codec.encode(&Command::remove(key))?)?;
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+
+        let base = since.map(|(previous, _, _, _)| previous);
+        for name in self.namespaces()? {
+            // a namespace created since the previous backup starts with a full backup
+            let previous = base
+                .as_ref()
+                .map(|base| base.join(NAMESPACES_DIR).join(&name))
+                .filter(|previous| previous.join(backup::MANIFEST_NAME).exists());
+            self.namespace(&name)?
+                .write_backup(&dir.join(NAMESPACES_DIR).join(name), previous.as_deref())?;
+        }
+        Manifest {
+            store_id: store_id.to_string(),
+            created_at: now_millis(),
+            watermark: Some(watermark),
+            base,
+            files: vec![BackupFile {
+                name: layout::segment_name(1),
+                len,
+                crc32: crc.finalize(),
+            }],
+        }
+        .write(dir)
+    }
+
+    /// Restores the backup made by [`KvStore::backup`] in `backup_dir` into `target_dir`, which
+    /// must be empty or not exist yet.
+    ///
+    /// Every file of the backup, of the backups an incremental backup builds on and of their
+    /// namespaces is checked against the manifests before anything is written, so a damaged or
+    /// incomplete backup leaves `target_dir` alone. The restored store gets a new UUID, so
+    /// incremental backups of it don't build on backups of the original.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let backup_dir = TempDir::new().unwrap();
+    /// store.backup(backup_dir.path()).unwrap();
+    ///
+    /// let target_dir = TempDir::new().unwrap();
+    /// KvStore::restore(backup_dir.path(), target_dir.path()).unwrap();
+    /// let restored = KvStore::open(target_dir.path()).unwrap();
+    /// ```
+    pub fn restore(backup_dir: impl AsRef<Path>, target_dir: impl AsRef<Path>) -> Result<()> {
+        let target_dir = target_dir.as_ref();
+        if target_dir.exists() && fs::read_dir(target_dir)?.next().is_some() {
+            return Err(KvsError::Message(format!(
+                "{} is not empty",
+                target_dir.display()
+            )));
+        }
+        backup::verify(backup_dir.as_ref())?;
+        backup::copy(backup_dir.as_ref(), target_dir)
+    }
+
+    /// Reports how long the gets, sets, removals and compactions of the store took since it was
+    /// opened, as histograms
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let latencies = store.latencies();
+    /// assert_eq!(latencies.set.count, 1);
+    /// println!("p99 of sets: {:?}", latencies.set.percentile(99.0));
+    /// ```
+    #[cfg(feature = "metrics")]
+    pub fn latencies(&self) -> LatencyStats {
+        self.latencies.stats()
+    }
+
+    /// How big the log is and how much of it is taken by live records.
+    ///
+    /// Sizes are read from the index and the log files without reading any record, but counting
+    /// the live records walks the whole index.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.set(String::from("key1"), String::from("value2")).unwrap();
+    /// let stats = store.stats().unwrap();
+    /// assert_eq!(stats.keys, 1);
+    /// assert!(stats.stale_bytes > 0);
+    /// ```
+    pub fn stats(&self) -> Result<StoreStats> {
+        let (log_bytes, segments, last_compaction) = {
+            let mut writer = self.writer();
+            writer.log.flush()?;
+            let mut log_bytes = 0;
+            for entry in self.readers.iter() {
+                log_bytes += entry.value().metadata()?.len();
+            }
+            (log_bytes, self.readers.len(), writer.last_compaction)
+        };
+        let now = now_millis();
+        let (keys, live_bytes) = self
+            .index
+            .iter()
+            .filter(|entry| !entry.value().expired(now))
+            .fold((0, 0), |(keys, bytes), entry| {
+                (keys + 1, bytes + self.merges.chain_len(*entry.value()))
+            });
+        let headers = segments as u64 * record::FILE_HEADER_LEN;
+        let (cache_hits, cache_misses) = self.cache.counters();
+        Ok(StoreStats {
+            keys,
+            live_bytes,
+            stale_bytes: log_bytes.saturating_sub(headers + live_bytes),
+            log_bytes,
+            segments,
+            last_compaction,
+            cache_hits,
+            cache_misses,
+        })
+    }
+
+    /// Every record in the log, oldest first, and whether the index still points at it.
+    ///
+    /// This is meant for debugging: the log files are read from disk in full. Records appended
+    /// while it runs may or may not be reported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.set(String::from("key1"), String::from("value2")).unwrap();
+    /// let records = store.log_records().unwrap();
+    /// assert!(!records[0].live && records[1].live);
+    /// ```
+    pub fn log_records(&self) -> Result<Vec<LogRecord>> {
+        self.writer().log.flush()?;
+        let now = now_millis();
+        // the records the current values are made of, merges included
+        let live: HashSet<(u64, u64)> = self
+            .index
+            .iter()
+            .map(|entry| *entry.value())
+            .filter(|pos| !pos.expired(now))
+            .flat_map(|pos| self.merges.chain(pos).unwrap_or_else(|| vec![pos]))
+            .map(|pos| (pos.gen, pos.offset))
+            .collect();
+        let mut records = Vec::new();
+        for entry in self.readers.iter() {
+            let gen = *entry.key();
+            let file = &**entry.value();
+            let mut offset = record::FILE_HEADER_LEN;
+            let end = file.metadata()?.len();
+            let mut reader = BufReader::new(FileSlice { file, offset, end });
+            while let Some(frame) = record::read_frame(&mut reader)? {
+                let len = frame.len() as u64;
+                let (kind, key, expires_at) = match self.codec.decode(&frame) {
+                    Ok(command) => {
+                        let kind = match command.command_type {
+                            CommandType::SET => RecordKind::Set,
+                            CommandType::RM => RecordKind::Remove,
+                            CommandType::BEGIN => RecordKind::Begin,
+                            CommandType::COMMIT => RecordKind::Commit,
+                            CommandType::CLEAR => RecordKind::Clear,
+                            CommandType::MERGE => RecordKind::Merge,
+                            CommandType::APPEND => RecordKind::Append,
+                            CommandType::RMRANGE => RecordKind::RemoveRange,
+                            // never written to the log
+                            CommandType::GET => RecordKind::Corrupt,
+                        };
+                        (kind, command.key, command.expires_at)
+                    }
+                    Err(DecodeError::Corrupt) => (RecordKind::Corrupt, Vec::new(), None),
+                    Err(DecodeError::NoKey) => (RecordKind::Unreadable, Vec::new(), None),
+                };
+                let live = matches!(
+                    kind,
+                    RecordKind::Set | RecordKind::Merge | RecordKind::Append
+                ) && live.contains(&(gen, offset));
+                records.push(LogRecord {
+                    gen,
+                    offset,
+                    len,
+                    kind,
+                    key,
+                    expires_at,
+                    live,
+                });
+                offset += len;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Checks the framing and the checksum of every record in the log of the store in `path`,
+    /// without changing anything, and counts the live, stale and corrupt records.
+    ///
+    /// The store is opened read-only, so this fails while it is open for writing. `options` only
+    /// need the encryption keys of the store, if it has any.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// drop(store);
+    /// let report = KvStore::fsck(temp_dir.path(), KvStoreOptions::new()).unwrap();
+    /// assert!(report.is_clean());
+    /// assert_eq!(report.live, 1);
+    /// ```
+    pub fn fsck(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<FsckReport> {
+        let options = options.skip_corrupt(true).strict(false).read_only(true);
+        let store = KvStore::open_with(path, options)?;
+        let records = store.log_records()?;
+        let mut report = FsckReport::default();
+        for record in &records {
+            match record.kind {
+                RecordKind::Corrupt | RecordKind::Unreadable => report.corrupt += 1,
+                _ if record.live => report.live += 1,
+                _ => report.stale += 1,
+            }
+        }
+        for entry in store.readers.iter() {
+            let end = records
+                .iter()
+                .rfind(|record| record.gen == *entry.key())
+                .map_or(record::FILE_HEADER_LEN, |record| record.offset + record.len);
+            report.torn_bytes += entry.value().metadata()?.len().saturating_sub(end);
+        }
+        Ok(report)
+    }
+
+    /// Checks the store in `path` like [`KvStore::fsck`] and, if the log isn't clean, rebuilds it
+    /// from the records that can be recovered.
+    ///
+    /// Torn records are truncated away and the live records copied into a new log, dropping the
+    /// corrupt ones: a key whose current record is corrupt keeps its previous value, if any.
+    /// Returns what the check found before the repair.
+    pub fn repair(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<FsckReport> {
+        let path = path.into();
+        let report = KvStore::fsck(path.clone(), options.clone())?;
+        if !report.is_clean() {
+            let options = options.skip_corrupt(true).strict(false).read_only(false);
+            let store = KvStore::open_with(path, options)?;
+            store.compact()?;
+        }
+        Ok(report)
+    }
+
+    /// Rewrites the log of the store in `path`, and of its namespaces, with records in the format
+    /// `to` instead of `from`, e.g. to move a store off JSON records.
+    ///
+    /// The store must not be open. Every record, stale ones included, is rewritten into a new file
+    /// next to the one it replaces, which is read back to check it holds the same records. Only
+    /// then do the new files replace the old ones, in a step that opening the store finishes if a
+    /// crash interrupts it. A store that already holds records in the format `to` is left as it
+    /// is, so an interrupted migration can be run again. The records are compressed and encrypted
+    /// as `options` says.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, KvsEngine, RecordFormat};
+    /// # use tempfile::TempDir;
+    ///
+    /// let dir = TempDir::new().unwrap();
+    /// let options = KvStoreOptions::new().record_format(RecordFormat::Json);
+    /// let store = KvStore::open_with(dir.path(), options).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// drop(store);
+    ///
+    /// let (from, to) = (RecordFormat::Json, RecordFormat::Bincode);
+    /// KvStore::migrate(dir.path(), from, to, KvStoreOptions::new()).unwrap();
+    /// let store = KvStore::open(dir.path()).unwrap();
+    /// assert_eq!(store.get(String::from("key1")).unwrap(), Some(String::from("value1")));
+    /// ```
+    pub fn migrate(
+        path: impl Into<PathBuf>,
+        from: RecordFormat,
+        to: RecordFormat,
+        options: KvStoreOptions,
+    ) -> Result<()> {
+        let path = path.into();
+        // opening the store recovers it and checks that its files agree on their format
+        let store = KvStore::open_with(path.clone(), options.clone().record_format(from))?;
+        let format = store.codec.format();
+        let namespaces = store.namespaces()?;
+        drop(store);
+        if format != to {
+            if format != from {
+                return Err(KvsError::Message(format!(
+                    "{} holds {:?} records, not {:?} records",
+                    path.display(),
+                    format,
+                    from
+                )));
+            }
+            let _lock = lock_dir(&path, false)?;
+            migrate::rewrite(
+                &path,
+                &Codec::new(&options.clone().record_format(from)),
+                &Codec::new(&options.clone().record_format(to)),
+                options.replace_strategy,
+            )?;
+        }
+        for name in namespaces {
+            KvStore::migrate(
+                path.join(NAMESPACES_DIR).join(name),
+                from,
+                to,
+                options.clone(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites every record in the log with the current encryption key and compression, waiting
+    /// until it is done.
+    ///
+    /// Once this returns, the previous encryption keys are no longer needed to open the store.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new()
+    ///     .encryption_key([2; 32])
+    ///     .previous_encryption_key([1; 32]);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// store.reencrypt().unwrap();
+    /// ```
+    pub fn reencrypt(&self) -> Result<()> {
+        self.run_compaction(true)?;
+        Ok(())
+    }
+
+    /// Compacts the log now instead of waiting for enough records to be stale, and returns how
+    /// many bytes it reclaimed.
+    ///
+    /// Every live record is copied into a new generation and the older ones are deleted, like the
+    /// compaction started in the background, but this waits until it is done. A compaction that
+    /// is already running is waited for first.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// store.set(String::from("key1"), String::from("value2")).unwrap();
+    /// assert!(store.compact().unwrap() > 0);
+    /// ```
+    pub fn compact(&self) -> Result<u64> {
+        self.run_compaction(false)
+    }
+
+    /// Compacts every generation up to the active one, re-encoding the records if `reencode` is
+    /// set, and waits until it is done
+    #[instrument(level = "debug", skip(self))]
+    fn run_compaction(&self, reencode: bool) -> Result<u64> {
+        loop {
+            // a running compaction copies records as they are, so wait for it and start ours
+            let running = {
+                let mut writer = self.write_lock()?;
+                let mut thread = self.compaction.thread.lock().unwrap();
+                match thread.take() {
+                    Some(running) if !running.is_finished() => Some(running),
+                    _ => {
+                        *thread = Some(self.spawn_compaction(
+                            &mut writer,
+                            reencode,
+                            CompactionStrategy::Full,
+                        )?);
+                        None
+                    }
+                }
+            };
+            match running {
+                Some(running) => join_compaction(running),
+                None => break,
+            }
+        }
+        let ours = {
+            let _writer = self.writer();
+            self.compaction.thread.lock().unwrap().take()
+        };
+        match ours {
+            Some(ours) => ours.join().unwrap_or_else(|_| {
+                Err(KvsError::Message("Compaction thread panicked".to_owned()))
+            }),
+            None => Ok(0),
+        }
+    }
+
+    /// Reads the value of `key` from the log, as bytes.
+    ///
+    /// Callers holding the writer pass it in, so a record still buffered in it can be flushed.
+    fn read(&self, key: &[u8], mut writer: Option<&mut KvStoreWriter>) -> Result<Option<Vec<u8>>> {
+        loop {
+            let pos = match self.lookup(key, writer.is_some()) {
+                Some(pos) => pos,
+                None => return Ok(None),
+            };
+            if let Some(value) = self.cache.get(key, (pos.gen, pos.offset)) {
+                return Ok(Some(value));
+            }
+            let chain = self.merges.chain(pos).unwrap_or_else(|| vec![pos]);
+            let mut records = Vec::with_capacity(chain.len());
+            for &pos in &chain {
+                match self.read_record(pos, writer.as_deref_mut())? {
+                    Some(command) => records.push(command),
+                    None => break,
+                }
+            }
+            // the generation may have just been compacted away, in which case the index already
+            // points to the record's new position
+            if records.len() < chain.len() {
+                continue;
+            }
+            // the chain of a merge is unlinked once the key is written again, so it may have been
+            // cut short if the index moved on meanwhile
+            if records[0].command_type.is_operand()
+                && self
+                    .index
+                    .get(key)
+                    .is_none_or(|entry| *entry.value() != pos)
+            {
+                continue;
+            }
+            let value = self.merges.fold(records)?;
+            if let Some(value) = &value {
+                self.cache.insert(key, (pos.gen, pos.offset), value);
+            }
+            return Ok(value);
+        }
+    }
+
+    /// Reads the record at `pos`, unless its generation is no longer in the log.
+    ///
+    /// Callers holding the writer pass it in, so a record still buffered in it can be flushed.
+    fn read_record(
+        &self,
+        pos: RecordPos,
+        writer: Option<&mut KvStoreWriter>,
+    ) -> Result<Option<Command>> {
+        let file = match self.readers.get(&pos.gen) {
+            Some(entry) => entry.value().clone(),
+            None => return Ok(None),
+        };
+        let mut buf = vec![0; pos.len as usize];
+        if let Err(e) = read_exact_at(&file, &mut buf, pos.offset) {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                return Err(e.into());
+            }
+            // the record is still sitting in the writer's buffer
+            match writer {
+                Some(writer) => writer.log.flush()?,
+                None => self.writer().log.flush()?,
+            }
+            read_exact_at(&file, &mut buf, pos.offset)?;
+        }
+        decode(&self.codec, &buf, pos).map(Some)
+    }
+
+    /// Appends the set `command` to the log and points the index at it
+    fn append_set(&self, writer: &mut KvStoreWriter, mut command: Command) -> Result<()> {
+        command.version = Some(writer.next_version());
+        let frame = self.codec.encode(&command)?;
+        let Command {
+            key,
+            value,
+            expires_at,
+            ..
+        } = command;
+        let old_value = if writer.watchers.watching(&key) {
+            self.read(&key, Some(writer))?
+        } else {
+            None
+        };
+        writer.log.write_all(&frame)?;
+        let pos = RecordPos {
+            gen: writer.gen,
+            offset: writer.offset,
+            len: frame.len() as u64,
+            expires_at,
+        };
+        writer.offset += pos.len;
+        writer.written(pos.len)?;
+        if let Some(value) = &value {
+            self.indexes.set(&key, value);
+        }
+        if writer.watchers.watching(&key) {
+            writer.watchers.send(WatchEvent {
+                op: WatchOp::Set,
+                key: key.clone(),
+                old_value,
+                new_value: value,
+            });
+        }
+        let old = self.index.get(&key).map(|entry| *entry.value());
+        self.index.insert(key, pos);
+        // count the overwritten records as stale
+        if let Some(old) = old {
+            writer.stale_bytes += old.len + self.merges.unlink(old);
+        }
+        self.appended(writer)
+    }
+
+    /// Appends the removal of `key`, which must exist, to the log and drops it from the index
+    fn append_remove(&self, writer: &mut KvStoreWriter, key: Vec<u8>) -> Result<()> {
+        let mut command = Command::remove(key.clone());
+        command.version = Some(writer.next_version());
+        let frame = self.codec.encode(&command)?;
+        let old_value = if writer.watchers.watching(&key) {
+            self.read(&key, Some(writer))?
+        } else {
+            None
+        };
+        writer.log.write_all(&frame)?;
+        writer.offset += frame.len() as u64;
+        writer.written(frame.len() as u64)?;
+        if let Some(old) = self.index.remove(&key) {
+            writer.stale_bytes += old.value().len + self.merges.unlink(*old.value());
+        }
+        self.indexes.remove(&key);
+        if writer.watchers.watching(&key) {
+            writer.watchers.send(WatchEvent {
+                op: WatchOp::Remove,
+                key,
+                old_value,
+                new_value: None,
+            });
+        }
+        // both the removed value and the removal itself are garbage from now on
+        writer.stale_bytes += frame.len() as u64;
+        self.appended(writer)
+    }
+
+    /// Sets `to` to the value of `from`, expiring at the same time, and removes `from` too if
+    /// `remove`, in a single batch
+    fn copy_key(&self, from: Vec<u8>, to: Vec<u8>, remove: bool) -> Result<()> {
+        let mut writer = self.write_lock()?;
+        let not_found = || KvsError::KeyNotFound;
+        let pos = self.lookup(&from, true).ok_or_else(not_found)?;
+        let value = self.read(&from, Some(&mut writer))?.ok_or_else(not_found)?;
+        if from == to {
+            return Ok(());
+        }
+        let mut set = Command::set(to, value);
+        set.expires_at = pos.expires_at;
+        let mut commands = vec![set];
+        if remove {
+            commands.push(Command::remove(from));
+        }
+        self.append_batch(&mut writer, commands)
+    }
+
+    /// Position of the record holding the value of `key`, unless it doesn't exist or has expired.
+    ///
+    /// Overwriting a key unlinks its entry before linking the new one, so it can look missing for
+    /// a moment. Writers hold the writer while doing it, so unless the caller `holds_writer`
+    /// already, a missing key is looked up again under it.
+    fn lookup(&self, key: &[u8], holds_writer: bool) -> Option<RecordPos> {
+        let pos = match self.index.get(key) {
+            Some(entry) => *entry.value(),
+            None if !holds_writer => {
+                let _writer = self.writer();
+                *self.index.get(key)?.value()
+            }
+            None => return None,
+        };
+        (!pos.expired(now_millis())).then_some(pos)
+    }
+
+    fn downgrade(&self) -> WeakKvStore {
+        WeakKvStore {
+            index: Arc::downgrade(&self.index),
+            readers: Arc::downgrade(&self.readers),
+            writer: Arc::downgrade(&self.writer),
+            compaction: Arc::downgrade(&self.compaction),
+            codec: Arc::downgrade(&self.codec),
+            namespaces: Arc::downgrade(&self.namespaces),
+            cache: Arc::downgrade(&self.cache),
+            indexes: Arc::downgrade(&self.indexes),
+            merges: Arc::downgrade(&self.merges),
+            latencies: Arc::downgrade(&self.latencies),
+            filters: Arc::downgrade(&self.filters),
+            #[cfg(feature = "tokio")]
+            path: self.path.clone(),
+        }
+    }
+
+    /// Reads the values of the index `entries` whose keys are valid UTF-8, as strings
+    fn str_entries<'a>(
+        &'a self,
+        entries: impl Iterator<Item = Entry<'a, Vec<u8>, RecordPos>> + 'a,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a {
+        entries
+            .filter(|entry| std::str::from_utf8(entry.key()).is_ok())
+            .filter_map(move |entry| self.read_entry(entry))
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((String::from_utf8(key)?, utf8_string(value)?))
+            })
+    }
+
+    /// Reads the value of an index entry, unless it expired or was removed
+    fn read_entry(
+        &self,
+        entry: Entry<'_, Vec<u8>, RecordPos>,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        if entry.value().expired(now_millis()) {
+            return None;
+        }
+        match self.read(entry.key(), None) {
+            Ok(Some(value)) => Some(Ok((entry.key().clone(), value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        self.writer.lock().unwrap()
+    }
+
+    /// Takes the writer to change the store, failing if the store was opened read-only, and
+    /// holding the write back first if the running compaction lags behind
+    fn write_lock(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        let writer = self.writer();
+        if writer.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        match writer.write_stall {
+            Some(stall) => Ok(self.stall(writer, stall)),
+            None => Ok(writer),
+        }
+    }
+
+    /// Delays a write, or makes it wait until the running compaction is done, depending on the
+    /// stale bytes written since it started. The writer is released meanwhile, so the compaction
+    /// and the reads needing it can go on.
+    fn stall<'a>(
+        &'a self,
+        mut writer: MutexGuard<'a, KvStoreWriter>,
+        stall: WriteStall,
+    ) -> MutexGuard<'a, KvStoreWriter> {
+        if writer.stale_bytes <= stall.slowdown_bytes.min(stall.stop_bytes) || !self.compacting() {
+            return writer;
+        }
+        let start = Instant::now();
+        if writer.stale_bytes > stall.slowdown_bytes {
+            drop(writer);
+            thread::sleep(stall.delay);
+            writer = self.writer();
+        }
+        if writer.stale_bytes > stall.stop_bytes && self.compacting() {
+            warn!(
+                "Writes stopped until compaction is done: {} stale bytes were written meanwhile",
+                writer.stale_bytes
+            );
+            while writer.stale_bytes > stall.stop_bytes && self.compacting() {
+                drop(writer);
+                thread::sleep(STALL_POLL_INTERVAL);
+                writer = self.writer();
+            }
+        }
+        writer.metrics.write_stalls += 1;
+        writer.metrics.write_stall_time += start.elapsed();
+        writer
+    }
+
+    /// Whether a compaction is running
+    fn compacting(&self) -> bool {
+        let thread = self.compaction.thread.lock().unwrap();
+        thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Moves on to a new generation if the active one is full, and starts a compaction if enough
+    /// of the log is stale, after records were appended
+    fn appended(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        if writer
+            .segment_size
+            .is_some_and(|size| writer.offset >= record::FILE_HEADER_LEN + size)
+        {
+            self.switch_log(writer, writer.gen + 1)?;
+        }
+        if writer.wants_compaction() {
+            self.start_compaction(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the writer on to a new generation `gen`, leaving the active one complete on disk as
+    /// far as the sync policy asks
+    fn switch_log(&self, writer: &mut KvStoreWriter, gen: u64) -> Result<()> {
+        // writes the policy hasn't synced yet would otherwise be left behind in the old generation
+        if writer.sync_policy == SyncPolicy::Never {
+            writer.log.flush()?;
+        } else {
+            writer.sync()?;
+        }
+        writer.gen = gen;
+        writer.log = LogWriter::new(new_log(
+            &writer.path,
+            writer.gen,
+            writer.store_id,
+            self.codec.format(),
+            &self.readers,
+        )?);
+        writer.offset = record::FILE_HEADER_LEN;
+        Ok(())
+    }
+
+    /// Starts compacting every generation up to the active one on a background thread, unless a
+    /// compaction is already running.
+    fn start_compaction(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        let mut thread = self.compaction.thread.lock().unwrap();
+        if thread.as_ref().is_some_and(|thread| !thread.is_finished()) {
+            return Ok(());
+        }
+        *thread = Some(self.spawn_compaction(writer, false, self.compaction.strategy)?);
+        Ok(())
+    }
+
+    /// Spawns a thread compacting the generations up to the active one that `strategy` picks,
+    /// re-encoding the records instead of copying them if `reencode` is set.
+    ///
+    /// The writer moves on to a fresh generation first, so the generations being compacted are no
+    /// longer written to.
+    fn spawn_compaction(
+        &self,
+        writer: &mut KvStoreWriter,
+        reencode: bool,
+        strategy: CompactionStrategy,
+    ) -> Result<JoinHandle<Result<u64>>> {
+        let compaction_gen = writer.gen + 1;
+        let start_event = CompactionStart {
+            gen: compaction_gen,
+            log_bytes: writer.log_bytes,
+            stale_bytes: writer.stale_bytes,
+        };
+        writer.compaction_gen = compaction_gen;
+        self.switch_log(writer, compaction_gen + 1)?;
+        // written under another name, so a crash before it is complete leaves nothing to replay
+        let compacted = create_log(
+            &compacting_path(&writer.path, compaction_gen),
+            compaction_gen,
+            writer.store_id,
+            self.codec.format(),
+            &self.readers,
+        )?;
+
+        // the versions from the cutoff on, and the one each key had at it, are kept
+        let cutoff = match writer.version_retention {
+            VersionRetention::Latest => None,
+            VersionRetention::Age(age) => Some(now_micros().saturating_sub(age.as_micros() as u64)),
+            VersionRetention::All => Some(0),
+        };
+        // the versions to keep are only found by going through every generation
+        let max_bytes = match strategy {
+            CompactionStrategy::Partial { max_bytes } if cutoff.is_none() => Some(max_bytes),
+            _ => None,
+        };
+        if max_bytes.is_none() {
+            // the compacted generation will only hold the records that aren't stale
+            writer.log_bytes = writer.log_bytes.saturating_sub(writer.stale_bytes);
+            writer.stale_bytes = 0;
+        }
+        let index = self.index.clone();
+        let merges = self.merges.clone();
+        let readers = self.readers.clone();
+        let store_writer = self.writer.clone();
+        let codec = self.codec.clone();
+        let path = writer.path.clone();
+        let latencies = self.latencies.clone();
+        let listener = self.compaction.listener.clone();
+        let rate_limit = self.compaction.rate_limit;
+        Ok(thread::spawn(move || {
+            if let Some(listener) = &listener {
+                listener.on_compaction_start(&start_event);
+            }
+            let start = Instant::now();
+            let result = compact(
+                &index,
+                &merges,
+                &readers,
+                &store_writer,
+                compacted,
+                compaction_gen,
+                &path,
+                &codec,
+                reencode,
+                cutoff,
+                rate_limit,
+                max_bytes,
+            );
+            let duration = start.elapsed();
+            latencies.record(
+                StoreOp::Compaction,
+                format_args!("generation {}", compaction_gen),
+                duration,
+            );
+            if let Some(listener) = &listener {
+                listener.on_compaction_end(&CompactionEnd {
+                    gen: compaction_gen,
+                    duration,
+                    result: result.as_ref().copied().map_err(ToString::to_string),
+                });
+            }
+            if let Err(e) = &result {
+                error!(
+                    "Compaction into generation {} failed: {}",
+                    compaction_gen, e
+                );
+            }
+            result
+        }))
+    }
+}
+
+/// The committed records of the log of a [`KvStore`] from a position on, see [`KvStore::tail`]
+pub struct Tail {
+    store: KvStore,
+    /// Where the records that weren't read yet start
+    position: LogPosition,
+    /// The log file of the generation being read, kept open so it can be read to the end even if
+    /// compaction deletes it
+    file: Arc<File>,
+    /// Records read but not yielded yet
+    records: VecDeque<ChangeRecord>,
+}
+
+impl Tail {
+    /// Where the records the tail didn't yield yet start
+    pub fn position(&self) -> LogPosition {
+        self.records
+            .front()
+            .map_or(self.position, |record| record.position)
+    }
+
+    /// Reads the records written since the last call, up to [`TAIL_READ_AHEAD`] of them
+    fn read_ahead(&mut self) -> Result<()> {
+        let (end, compacting) = {
+            let mut writer = self.store.writer();
+            writer.log.flush()?;
+            let compacting = self
+                .store
+                .compaction
+                .thread
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished());
+            let end = LogPosition {
+                gen: writer.gen,
+                offset: writer.offset,
+            };
+            // the generation a running compaction is writing only holds copies of older records
+            (end, compacting.then_some(writer.compaction_gen))
+        };
+        while self.position.gen <= end.gen {
+            let sealed = self.position.gen < end.gen;
+            let stop = if sealed {
+                self.file.metadata()?.len()
+            } else {
+                end.offset
+            };
+            if !self.read_records(stop)? || !sealed {
+                return Ok(());
+            }
+            let next = self
+                .store
+                .readers
+                .range(self.position.gen + 1..)
+                .find(|entry| Some(*entry.key()) != compacting);
+            match next {
+                Some(entry) => {
+                    self.position = LogPosition {
+                        gen: *entry.key(),
+                        offset: record::FILE_HEADER_LEN,
+                    };
+                    self.file = entry.value().clone();
+                }
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the records of the current generation up to `stop`, and returns whether it got
+    /// there. Batches are left for later unless their commit marker is before `stop`.
+    fn read_records(&mut self, stop: u64) -> Result<bool> {
+        let gen = self.position.gen;
+        let mut offset = self.position.offset;
+        let mut reader = BufReader::new(FileSlice {
+            file: &self.file,
+            offset,
+            end: stop,
+        });
+        let mut batch: Option<Vec<ChangeRecord>> = None;
+        while self.records.len() < TAIL_READ_AHEAD || batch.is_some() {
+            let frame = match record::read_frame(&mut reader)? {
+                Some(frame) => frame,
+                None => return Ok(true),
+            };
+            let pos = RecordPos {
+                gen,
+                offset,
+                len: frame.len() as u64,
+                expires_at: None,
+            };
+            let command = decode(&self.store.codec, &frame, pos)?;
+            offset += pos.len;
+            let op = match command.command_type {
+                CommandType::BEGIN => {
+                    batch = Some(Vec::new());
+                    continue;
+                }
+                CommandType::COMMIT => {
+                    self.records.extend(batch.take().unwrap_or_default());
+                    self.position.offset = offset;
+                    continue;
+                }
+                CommandType::SET => WatchOp::Set,
+                CommandType::RM => WatchOp::Remove,
+                CommandType::CLEAR => WatchOp::Clear,
+                CommandType::MERGE => WatchOp::Merge,
+                CommandType::APPEND => WatchOp::Append,
+                CommandType::RMRANGE => WatchOp::RemoveRange,
+                // never written to the log
+                CommandType::GET => continue,
+            };
+            let record = ChangeRecord {
+                position: LogPosition {
+                    gen,
+                    offset: pos.offset,
+                },
+                op,
+                key: command.key,
+                value: command.value,
+                expires_at: command.expires_at,
+            };
+            match &mut batch {
+                Some(batch) => batch.push(record),
+                None => {
+                    self.records.push_back(record);
+                    self.position.offset = offset;
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<ChangeRecord>;
+
+    fn next(&mut self) -> Option<Result<ChangeRecord>> {
+        if self.records.is_empty() {
+            if let Err(e) = self.read_ahead() {
+                return Some(Err(e));
+            }
+        }
+        self.records.pop_front().map(Ok)
+    }
+}
+
+/// A consistent view of a [`KvStore`] as it was at a position of its log, see
+/// [`KvStore::snapshot`]
+pub struct Snapshot {
+    store: KvStore,
+    /// Where the log ended when the snapshot was taken
+    position: LogPosition,
+    /// Version of the last write the snapshot sees
+    version: u64,
+    /// When the snapshot was taken, in milliseconds since the Unix epoch, which is when the keys
+    /// are checked for expiry
+    taken_at: u64,
+    /// The log files with where each ended, kept open so they can be read even if compaction
+    /// deletes them
+    files: Vec<(u64, Arc<File>, u64)>,
+    /// The records of the keys, with the operands of the merges after their base record, replayed
+    /// from `files` the first time they're needed
+    entries: OnceLock<BTreeMap<Vec<u8>, Vec<RecordPos>>>,
+}
+
+impl Snapshot {
+    /// Where the log ended when the snapshot was taken: it sees the writes before it and none
+    /// after it
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    /// Gets the value the key had when the snapshot was taken
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        utf8(self.get_raw(key.as_bytes())?)
+    }
+
+    /// Gets the value, as bytes, the key made of arbitrary bytes had when the snapshot was taken
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(entries) = self.entries.get() {
+            return match entries.get(key) {
+                Some(chain) => self.read(chain),
+                None => Ok(None),
+            };
+        }
+        if let Some(value) = self.read_unchanged(key)? {
+            return Ok(value);
+        }
+        // the key was written since, so it's looked up in its history
+        let versions = self.store.key_versions(key, &self.files)?;
+        Ok(versions
+            .into_iter()
+            .next_back()
+            .filter(|last| {
+                last.expires_at
+                    .is_none_or(|expires_at| expires_at > self.taken_at)
+            })
+            .and_then(|last| last.value))
+    }
+
+    /// Whether the key existed when the snapshot was taken
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get_raw(key.as_bytes())?.is_some())
+    }
+
+    /// Number of keys when the snapshot was taken
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.entries()?.len())
+    }
+
+    /// Whether the store was empty when the snapshot was taken
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.entries()?.is_empty())
+    }
+
+    /// Iterates over the keys and values when the snapshot was taken, in lexical order of the
+    /// keys, like [`KvStore::iter`]
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        self.str_entries(self.entries().map(|entries| entries.iter()))
+    }
+
+    /// Iterates over the keys in `range` and their values when the snapshot was taken, like
+    /// [`KvStore::range`]
+    pub fn range<K, R>(&self, range: R) -> impl Iterator<Item = Result<(String, String)>> + '_
+    where
+        K: AsRef<str>,
+        R: RangeBounds<K>,
+    {
+        let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().as_bytes().to_vec());
+        let range = (bound(range.start_bound()), bound(range.end_bound()));
+        self.str_entries(self.entries().map(|entries| entries.range(range)))
+    }
+
+    /// Iterates over the keys starting with `prefix` and their values when the snapshot was
+    /// taken, like [`KvStore::scan_prefix`]
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let prefix = prefix.as_bytes().to_vec();
+        self.str_entries(self.entries().map(|entries| {
+            entries
+                .range(prefix.clone()..)
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+        }))
+    }
+
+    /// Reads the value of `key` from the store if the key wasn't written since the snapshot was
+    /// taken, as the index points at a record older than the snapshot then
+    fn read_unchanged(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        let store = &self.store;
+        let pos = match store.index.get(key) {
+            Some(entry) => *entry.value(),
+            None => return Ok(None),
+        };
+        let chain = store.merges.chain(pos).unwrap_or_else(|| vec![pos]);
+        let mut records = Vec::with_capacity(chain.len());
+        for &pos in &chain {
+            match store.read_record(pos, None)? {
+                Some(command) => records.push(command),
+                // compacted away meanwhile
+                None => return Ok(None),
+            }
+        }
+        // the chain of a merge is unlinked once the key is written again
+        if records[0].command_type.is_operand()
+            && store
+                .index
+                .get(key)
+                .is_none_or(|entry| *entry.value() != pos)
+        {
+            return Ok(None);
+        }
+        // compaction keeps the versions of the records it copies
+        if records[records.len() - 1].version.unwrap_or(0) > self.version {
+            return Ok(None);
+        }
+        if pos.expired(self.taken_at) {
+            return Ok(Some(None));
+        }
+        store.merges.fold(records).map(Some)
+    }
+
+    /// The records of the keys when the snapshot was taken, replayed from its files the first
+    /// time
+    fn entries(&self) -> Result<&BTreeMap<Vec<u8>, Vec<RecordPos>>> {
+        if let Some(entries) = self.entries.get() {
+            return Ok(entries);
+        }
+        let index = SkipMap::new();
+        // only the chains are needed, the store's merge operator folds them
+        let merges = Merges::new(None);
+        for (gen, file, end) in &self.files {
+            for_each_committed(*gen, file, *end, &self.store.codec, |command, pos| {
+                if command.command_type == CommandType::CLEAR {
+                    index.clear();
+                    merges.clear();
+                } else {
+                    apply(&index, &merges, command, pos, self.taken_at);
+                }
+                Ok(())
+            })?;
+        }
+        let entries = index
+            .iter()
+            .map(|entry| {
+                let pos = *entry.value();
+                let chain = merges.chain(pos).unwrap_or_else(|| vec![pos]);
+                (entry.key().clone(), chain)
+            })
+            .collect();
+        Ok(self.entries.get_or_init(|| entries))
+    }
+
+    /// Reads the values of `entries` whose keys are valid UTF-8, as strings
+    fn str_entries<'a, I>(
+        &'a self,
+        entries: Result<I>,
+    ) -> impl Iterator<Item = Result<(String, String)>> + 'a
+    where
+        I: Iterator<Item = (&'a Vec<u8>, &'a Vec<RecordPos>)> + 'a,
+    {
+        let (entries, error) = match entries {
+            Ok(entries) => (Some(entries), None),
+            Err(e) => (None, Some(Err(e))),
+        };
+        error.into_iter().chain(
+            entries
+                .into_iter()
+                .flatten()
+                .filter(|(key, _)| std::str::from_utf8(key).is_ok())
+                .filter_map(move |(key, chain)| match self.read(chain) {
+                    Ok(Some(value)) => Some(Ok((key.clone(), value))),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((String::from_utf8(key)?, utf8_string(value)?))
+                }),
+        )
+    }
+
+    /// Reads the records of `chain` from the files the snapshot kept open and folds them into
+    /// the value
+    fn read(&self, chain: &[RecordPos]) -> Result<Option<Vec<u8>>> {
+        let mut records = Vec::with_capacity(chain.len());
+        for &pos in chain {
+            let (_, file, _) = self
+                .files
+                .iter()
+                .find(|(gen, _, _)| *gen == pos.gen)
+                .ok_or_else(|| {
+                    KvsError::Message(format!(
+                        "Generation {} is missing from the snapshot",
+                        pos.gen
+                    ))
+                })?;
+            let mut buf = vec![0; pos.len as usize];
+            read_exact_at(file, &mut buf, pos.offset)?;
+            records.push(decode(&self.store.codec, &buf, pos)?);
+        }
+        self.store.merges.fold(records)
+    }
+}
+
+/// A range of a log file, read with positional reads so the cursor shared by the handles of the
+/// file is left alone
+struct FileSlice<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for FileSlice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.end.saturating_sub(self.offset) as usize);
+        read_exact_at(self.file, &mut buf[..len], self.offset)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+/// Waits for a compaction thread, whose errors were already logged
+fn join_compaction(thread: JoinHandle<Result<u64>>) {
+    if thread.join().is_err() {
+        error!("Compaction thread panicked");
+    }
+}
+
+/// Records are read from the log files with `tokio::fs`. The records a write appends are only
+/// buffered while it holds the writer, and written out with `tokio::fs` once it let go of it,
+/// along with the sync the sync policy asks for.
+#[cfg(feature = "tokio")]
+impl AsyncEngine for KvStore {
+    async fn get_async(&self, key: String) -> Result<Option<String>> {
+        utf8(self.read_async(key.as_bytes()).await?)
+    }
+
+    async fn multi_get_async(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(utf8(self.read_async(key.as_bytes()).await?)?);
+        }
+        Ok(values)
+    }
+
+    async fn keys_with_prefix_async(&self, prefix: String) -> Result<Vec<String>> {
+        // the keys are in the index, so the log isn't read
+        self.keys_with_prefix(&prefix)
+    }
+
+    async fn write_async<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(Self) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        // so `op` finds nothing in flight to wait for, blocking the executor
+        self.wait_written().await?;
+        let result = log_writer::deferred(|| op(self.clone()));
+        // the records are written out once they fill the buffer, as they are without an async
+        // handle
+        self.write_out(false).await?;
+        result
+    }
+
+    async fn flush_async(&self) -> Result<()> {
+        self.write_out(true).await
+    }
+}
+
+/// The async IO of a [`KvStore`], see [`AsyncEngine`]
+#[cfg(feature = "tokio")]
+impl KvStore {
+    /// Reads the value of `key` like [`KvStore::read`], with `tokio::fs`
+    async fn read_async(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let pos = match self.index.get(key) {
+                Some(entry) => *entry.value(),
+                None => {
+                    // an overwritten key looks missing for a moment, see `KvStore::lookup`
+                    let pos = self
+                        .with_writer(|_| self.index.get(key).map(|entry| *entry.value()))
+                        .await;
+                    match pos {
+                        Some(pos) => pos,
+                        None => return Ok(None),
+                    }
+                }
+            };
+            if pos.expired(now_millis()) {
+                return Ok(None);
+            }
+            if let Some(value) = self.cache.get(key, (pos.gen, pos.offset)) {
+                return Ok(Some(value));
+            }
+            let chain = self.merges.chain(pos).unwrap_or_else(|| vec![pos]);
+            let mut records = Vec::with_capacity(chain.len());
+            for &pos in &chain {
+                match self.read_record_async(pos).await? {
+                    Some(command) => records.push(command),
+                    None => break,
+                }
+            }
+            // the generation may have just been compacted away, or sealed into `segments/`
+            if records.len() < chain.len() {
+                continue;
+            }
+            if records[0].command_type.is_operand()
+                && self
+                    .index
+                    .get(key)
+                    .is_none_or(|entry| *entry.value() != pos)
+            {
+                continue;
+            }
+            let value = self.merges.fold(records)?;
+            if let Some(value) = &value {
+                self.cache.insert(key, (pos.gen, pos.offset), value);
+            }
+            return Ok(value);
+        }
+    }
+
+    /// Reads the record at `pos` like [`KvStore::read_record`], with `tokio::fs`
+    async fn read_record_async(&self, pos: RecordPos) -> Result<Option<Command>> {
+        if !self.readers.contains_key(&pos.gen) {
+            return Ok(None);
+        }
+        let mut file = match tokio::fs::File::open(log_path(&self.path, pos.gen)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = vec![0; pos.len as usize];
+        file.seek(SeekFrom::Start(pos.offset)).await?;
+        if let Err(e) = file.read_exact(&mut buf).await {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                return Err(e.into());
+            }
+            // the record is still sitting in the writer's buffer
+            self.write_out(true).await?;
+            file.seek(SeekFrom::Start(pos.offset)).await?;
+            file.read_exact(&mut buf).await?;
+        }
+        decode(&self.codec, &buf, pos).map(Some)
+    }
+
+    /// Writes the records buffered in the writer out with `tokio::fs`, unless they don't fill the
+    /// buffer yet and no sync is due, or `all` of them
+    async fn write_out(&self, all: bool) -> Result<()> {
+        loop {
+            let (pending, written) = self
+                .with_writer(|writer| (writer.log.take_pending(all), writer.log.wait_written()))
+                .await;
+            match pending {
+                // on a task of its own, so it's written whatever happens to the caller
+                Ok(Some(pending)) => return Ok(task::spawn(pending.write()).await??),
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => written.await,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Waits for the records being written out with `tokio::fs` to be
+    async fn wait_written(&self) -> Result<()> {
+        loop {
+            let (in_flight, written) = self
+                .with_writer(|writer| (writer.log.in_flight(), writer.log.wait_written()))
+                .await;
+            if !in_flight? {
+                return Ok(());
+            }
+            written.await;
+        }
+    }
+
+    /// Runs `f` with the writer, without blocking the executor while another handle holds it
+    async fn with_writer<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> T) -> T {
+        loop {
+            if let Some(mut writer) = self.try_writer() {
+                return f(&mut writer);
+            }
+            task::yield_now().await;
+        }
+    }
+
+    /// The writer, unless another handle holds it
+    fn try_writer(&self) -> Option<MutexGuard<'_, KvStoreWriter>> {
+        match self.writer.try_lock() {
+            Ok(writer) => Some(writer),
+            Err(std::sync::TryLockError::WouldBlock) => None,
+            Err(std::sync::TryLockError::Poisoned(e)) => panic!("{}", e),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Sets a value corresponding to a key in the [`KvStore`]
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1"));
+    /// ```
+    #[instrument(level = "debug", skip(self, value))]
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let start = Instant::now();
+        let result = self.set_bytes(key.clone(), value.into_bytes());
+        self.latencies.record(StoreOp::Set, key, start.elapsed());
+        result
+    }
+
+    /// Gets a value for a key from the [`KvStore`]
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1"));
+    /// store.get(String::from("key1"));
+    /// ```
+    #[instrument(level = "debug", skip(self))]
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let value = self.read(key.as_bytes(), None);
+        self.latencies.record(StoreOp::Get, key, start.elapsed());
+        let value = utf8(value?)?;
+        debug!(found = value.is_some());
+        Ok(value)
+    }
+
+    /// Gets the values of several keys from the [`KvStore`], reading the records in the order they
+    /// are laid out in the log
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let values = store.multi_get(&[String::from("key1"), String::from("key2")]).unwrap();
+    /// assert_eq!(values, vec![Some(String::from("value1")), None]);
+    /// ```
+    fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut order: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let pos = self.index.get(key.as_bytes()).map(|entry| *entry.value());
+                (pos.map(|pos| (pos.gen, pos.offset)), i)
+            })
+            .collect();
+        order.sort_unstable();
+        let mut values = vec![None; keys.len()];
+        for (_, i) in order {
+            values[i] = utf8(self.read(keys[i].as_bytes(), None)?)?;
+        }
+        Ok(values)
+    }
+
+    /// Removes a key from the [`KvStore`]
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1"));
+    /// store.remove(String::from("key1"));
+    /// ```
+    #[instrument(level = "debug", skip(self))]
+    fn remove(&self, key: String) -> Result<()> {
+        let start = Instant::now();
+        let result = self.remove_raw(key.as_bytes());
+        self.latencies.record(StoreOp::Remove, key, start.elapsed());
+        result
+    }
+
+    /// Replaces the value of `key` with `new` if it is `expected`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let swapped = store.compare_and_swap(String::from("lock"), None, Some(String::from("me")));
+    /// assert_eq!(swapped.unwrap(), Ok(()));
+    /// let swapped = store.compare_and_swap(String::from("lock"), None, Some(String::from("you")));
+    /// assert_eq!(swapped.unwrap(), Err(Some(String::from("me"))));
+    /// ```
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let key = key.into_bytes();
+        let mut writer = self.write_lock()?;
+        let current = self.read(&key, Some(&mut writer))?;
+        if current.as_deref() != expected.as_ref().map(String::as_bytes) {
+            return Ok(Err(utf8(current)?));
+        }
+        match new {
+            Some(value) => {
+                let command = Command::set(key, value.into_bytes());
+                self.append_set(&mut writer, command)?;
+            }
+            None if current.is_some() => self.append_remove(&mut writer, key)?,
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    /// Sets the value of `key` and returns the value it replaced
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("token"), String::from("old")).unwrap();
+    /// let previous = store.get_and_set(String::from("token"), String::from("new")).unwrap();
+    /// assert_eq!(previous, Some(String::from("old")));
+    /// ```
+    fn get_and_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let key = key.into_bytes();
+        let mut writer = self.write_lock()?;
+        let old = utf8(self.read(&key, Some(&mut writer))?)?;
+        self.append_set(&mut writer, Command::set(key, value.into_bytes()))?;
+        Ok(old)
+    }
+
+    /// Moves the value of `from` to `to`, with a batch of a set and a removal, so that after a
+    /// crash either both or none are found. A key that expires keeps its expiry time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("draft"), String::from("text")).unwrap();
+    /// store.rename(String::from("draft"), String::from("published")).unwrap();
+    /// assert_eq!(store.get(String::from("draft")).unwrap(), None);
+    /// ```
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.copy_key(from.into_bytes(), to.into_bytes(), true)
+    }
+
+    /// Copies the value of `from` to `to`. A key that expires keeps its expiry time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("config"), String::from("v1")).unwrap();
+    /// store.copy(String::from("config"), String::from("config.bak")).unwrap();
+    /// assert_eq!(store.get(String::from("config.bak")).unwrap(), Some(String::from("v1")));
+    /// ```
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        self.copy_key(from.into_bytes(), to.into_bytes(), false)
+    }
+
+    /// Lists the keys starting with `prefix` in lexical order
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("user:1"), String::from("value1"));
+    /// store.keys_with_prefix("user:");
+    /// ```
+    fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let now = now_millis();
+        Ok(self
+            .index
+            .range(prefix.as_bytes().to_vec()..)
+            .take_while(|entry| entry.key().starts_with(prefix.as_bytes()))
+            .filter(|entry| !entry.value().expired(now))
+            .filter_map(|entry| String::from_utf8(entry.key().clone()).ok())
+            .collect())
+    }
+
+    fn keys_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let now = now_millis();
+        let start = match after {
+            Some(after) => Bound::Excluded(after.as_bytes().to_vec()),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .index
+            .range((start, Bound::Unbounded))
+            .filter(|entry| !entry.value().expired(now))
+            .filter_map(|entry| String::from_utf8(entry.key().clone()).ok())
+            .take(limit)
+            .collect())
+    }
+
+    /// Hands the records buffered in memory to the operating system.
+    ///
+    /// Writes are buffered, so records that were not flushed are lost if the process dies. Use
+    /// [`KvStore::sync_all`] to also make sure they reach the disk.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1"));
+    /// store.flush().unwrap();
+    /// ```
+    fn flush(&self) -> Result<()> {
+        self.writer().log.flush()?;
+        Ok(())
+    }
+
+    /// Applies every write in `batch` atomically, see [`KvStore::write_batch`]
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        KvStore::write_batch(self, batch)
+    }
+
+    /// Counts the compactions and the bytes written and reclaimed since the [`KvStore`] was opened
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// assert!(store.metrics().bytes_written > 0);
+    /// ```
+    fn metrics(&self) -> EngineMetrics {
+        self.writer().metrics.clone()
+    }
+
+    /// Reads the changes from the [`KvStore::tail`] of the log, starting over from the beginning
+    /// if `from` was compacted away or `store_id` isn't the UUID of this store
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, LogPosition};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// store.set(String::from("key1"), String::from("value1")).unwrap();
+    /// let batch = store.changes(None, LogPosition::START, 100).unwrap();
+    /// assert!(batch.reset);
+    /// assert_eq!(batch.records.len(), 1);
+    /// ```
+    fn changes(
+        &self,
+        store_id: Option<&str>,
+        from: LogPosition,
+        limit: usize,
+    ) -> Result<ChangeBatch> {
+        let own_id = self.writer().store_id.to_string();
+        let tail = match store_id {
+            Some(store_id) if store_id == own_id => self.tail(from).ok(),
+            _ => None,
+        };
+        let reset = tail.is_none();
+        let mut tail = match tail {
+            Some(tail) => tail,
+            None => self.tail(LogPosition::START)?,
+        };
+        let records = tail.by_ref().take(limit).collect::<Result<Vec<_>>>()?;
+        Ok(ChangeBatch {
+            store_id: own_id,
+            reset,
+            records,
+            next: tail.position(),
+        })
+    }
+}
+
+/// Syncs the log every `interval` while it has unsynced writes, until the store is dropped
+fn spawn_syncer(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if writer.unsynced > 0 {
+            if let Err(e) = writer.sync() {
+                error!("Failed to sync the log: {}", e);
+            }
+        }
+    });
+}
+
+/// Checkpoints the index every `interval`, until the store is dropped
+fn spawn_checkpointer(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if let Err(e) = writer.checkpoint() {
+            error!("Failed to checkpoint the index: {}", e);
+        }
+    });
+}
+
+/// Removes expired keys every `interval`, until the store is dropped
+fn spawn_sweeper(store: WeakKvStore, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => return,
+        };
+        if let Err(e) = store.sweep_expired() {
+            error!("Failed to remove expired keys: {}", e);
+        }
+    });
+}
+
+/// Copies the records the index points to in generations older than `compaction_gen` into
+/// `compacted`, then deletes those generations and returns by how many bytes they outweighed the
+/// copies.
+///
+/// Given `max_bytes`, only the generations [`pick_generations`] picks are compacted. The removals
+/// in them are copied too while older generations are left, as their records would come back
+/// otherwise, and so are the live records the removals would shadow once they come after them.
+///
+/// Records are copied byte for byte, unless `reencode` is given, in which case they are decoded and
+/// encoded again with it.
+///
+/// Index entries are only moved to the copies while holding the writer, and only if they weren't
+/// overwritten or removed in the meantime. Readers holding a position in a deleted generation find
+/// it missing and look the key up again.
+#[instrument(level = "debug", skip_all, fields(gen = compaction_gen))]
+#[allow(clippy::too_many_arguments)]
+fn compact(
+    index: &SkipMap<Vec<u8>, RecordPos>,
+    merges: &Merges,
+    readers: &SkipMap<u64, Arc<File>>,
+    writer: &Mutex<KvStoreWriter>,
+    compacted: File,
+    compaction_gen: u64,
+    path: &Path,
+    codec: &Codec,
+    reencode: bool,
+    cutoff: Option<u64>,
+    rate_limit: Option<u64>,
+    max_bytes: Option<u64>,
+) -> Result<u64> {
+    let gens: Vec<u64> = readers
+        .iter()
+        .map(|entry| *entry.key())
+        .take_while(|&gen| gen < compaction_gen)
+        .collect();
+    let stale_gens = match max_bytes {
+        Some(max_bytes) => pick_generations(index, merges, readers, &gens, max_bytes)?,
+        None => gens.clone(),
+    };
+    let compacting = |gen: u64| stale_gens.binary_search(&gen).is_ok();
+    let oldest_kept = gens.iter().copied().find(|&gen| !compacting(gen));
+    let limiter = rate_limit.map(RateLimiter::new);
+    let throttle = |bytes: usize| {
+        if let Some(limiter) = &limiter {
+            limiter.consume(bytes as u64);
+        }
+    };
+    let mut compacted = BufWriter::new(compacted);
+    let mut copied = Vec::new();
+    let mut expired = Vec::new();
+    let mut new_byte_offset = record::FILE_HEADER_LEN;
+    let now = now_millis();
+    // bytes of the live records copied out of the compacted generations
+    let mut moved_bytes = 0;
+    let read = |pos: RecordPos| -> Result<Vec<u8>> {
+        let file = readers.get(&pos.gen).unwrap().value().clone();
+        let mut buf = vec![0; pos.len as usize];
+        read_exact_at(&file, &mut buf, pos.offset)?;
+        throttle(buf.len());
+        Ok(buf)
+    };
+    let copy = |pos: RecordPos| -> Result<Vec<u8>> {
+        if reencode {
+            codec.encode(&decode(codec, &read(pos)?, pos)?)
+        } else {
+            read(pos)
+        }
+    };
+    // the merges of `chain`, newest first, as a set of the value they result in
+    let resolve = |key: &[u8], chain: &[RecordPos]| -> Result<Vec<u8>> {
+        let records = chain
+            .iter()
+            .map(|&pos| decode(codec, &read(pos)?, pos))
+            .collect::<Result<Vec<_>>>()?;
+        let version = records[0].version;
+        let value = merges.fold(records)?.unwrap_or_default();
+        let mut command = Command::set(key.to_vec(), value);
+        command.expires_at = chain[0].expires_at;
+        command.version = version;
+        codec.encode(&command)
+    };
+
+    // removals in front of the records they would shadow, as the ones they shadow were written
+    // before; they stay stale
+    let mut kept_stale_bytes = 0;
+    let mut removed_keys = BTreeSet::new();
+    let mut removed_ranges = Vec::new();
+    let mut cleared = false;
+    if let Some(oldest_kept) = oldest_kept {
+        for &gen in stale_gens.iter().filter(|&&gen| gen > oldest_kept) {
+            let file = readers.get(&gen).unwrap().value().clone();
+            let end = file.metadata()?.len();
+            throttle(end as usize);
+            for_each_committed(gen, &file, end, codec, |command, _| {
+                match command.command_type {
+                    CommandType::RM => {
+                        removed_keys.insert(command.key.clone());
+                    }
+                    CommandType::RMRANGE => removed_ranges.push(removed_range(&command)),
+                    CommandType::CLEAR => cleared = true,
+                    _ => return Ok(()),
+                }
+                let buf = codec.encode(&command)?;
+                compacted.write_all(&buf)?;
+                throttle(buf.len());
+                new_byte_offset += buf.len() as u64;
+                kept_stale_bytes += buf.len() as u64;
+                Ok(())
+            })?;
+        }
+    }
+    let shadowed = |key: &[u8]| {
+        cleared
+            || removed_keys.contains(key)
+            || removed_ranges
+                .iter()
+                .any(|range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)| range.contains(&key.to_vec()))
+    };
+
+    // the older versions the retention policy keeps go first, so they replay before the current
+    // values
+    let mut retained_bytes = 0;
+    if let Some(cutoff) = cutoff {
+        for retained in retained_versions(index, merges, readers, codec, compaction_gen, cutoff)? {
+            let buf = match retained {
+                Retained::Copy(pos) => copy(pos)?,
+                Retained::Resolve(key, chain) => resolve(&key, &chain)?,
+                Retained::Remove(key, version) => {
+                    let mut command = Command::remove(key);
+                    command.version = Some(version);
+                    codec.encode(&command)?
+                }
+            };
+            compacted.write_all(&buf)?;
+            throttle(buf.len());
+            new_byte_offset += buf.len() as u64;
+            retained_bytes += buf.len() as u64;
+        }
+    }
+
+    for entry in index.iter() {
+        let head = *entry.value();
+        // a merge written since may build on records being compacted, which are copied from the
+        // first of them on
+        let chain = merges.chain(head).unwrap_or_else(|| vec![head]);
+        let start = match chain.iter().position(|pos| pos.gen < compaction_gen) {
+            Some(start) => start,
+            None => continue,
+        };
+        let pos = chain[start];
+        if !chain[start..].iter().any(|pos| compacting(pos.gen)) && !shadowed(entry.key()) {
+            continue;
+        }
+        for pos in &chain[start..] {
+            if compacting(pos.gen) {
+                moved_bytes += pos.len;
+            } else {
+                // only the copy is live from now on
+                kept_stale_bytes += pos.len;
+            }
+        }
+        if head.expired(now) {
+            if oldest_kept.is_some() {
+                // the key has to stay removed when its older records are replayed
+                let buf = codec.encode(&Command::remove(entry.key().clone()))?;
+                compacted.write_all(&buf)?;
+                throttle(buf.len());
+                new_byte_offset += buf.len() as u64;
+                kept_stale_bytes += buf.len() as u64;
+            }
+            expired.push((entry.key().clone(), head));
+            continue;
+        }
+        let buf = if merges.is_merge(pos) {
+            resolve(entry.key(), &chain[start..])?
+        } else {
+            copy(pos)?
+        };
+        compacted.write_all(&buf)?;
+        throttle(buf.len());
+        let new_pos = RecordPos {
+            gen: compaction_gen,
+            offset: new_byte_offset,
+            len: buf.len() as u64,
+            expires_at: pos.expires_at,
+        };
+        copied.push((entry.key().clone(), pos, new_pos));
+        new_byte_offset += new_pos.len;
+    }
+
+    // the compacted generation has to be on disk before it replaces the old ones
+    compacted.flush()?;
+    compacted.get_ref().sync_all()?;
+    fs::rename(
+        compacting_path(path, compaction_gen),
+        layout::segment_path(path, compaction_gen),
+    )?;
+    sync_dir(&layout::segments_dir(path))?;
+
+    // the chains that nothing points at once the old generations are deleted
+    let mut unlinked = Vec::new();
+    {
+        let _writer = writer.lock().unwrap();
+        for (key, pos, new_pos) in copied {
+            // point the index, or the merge building on the record, at the record's new position
+            let head = index.get(&key).map(|entry| *entry.value());
+            if head == Some(pos) {
+                index.insert(key, new_pos);
+            } else if let Some(child) = head.and_then(|head| merges.child(head, pos)) {
+                merges.link(child, Some(new_pos));
+            }
+            unlinked.push(pos);
+        }
+        for (key, pos) in expired {
+            // expired records aren't copied, so nothing may point at them once they're deleted
+            if index.get(&key).is_some_and(|entry| *entry.value() == pos) {
+                index.remove(&key);
+            }
+            unlinked.push(pos);
+        }
+    }
+
+    // a crash while they are deleted would leave the rest to be replayed along with the
+    // compacted generation, so the manifest says which ones to finish deleting
+    let (strategy, archive) = {
+        let writer = writer.lock().unwrap();
+        (writer.replace_strategy, writer.archive.clone())
+    };
+    write_manifest(path, &stale_gens, strategy)?;
+    let mut removed_bytes = 0;
+    for &gen in &stale_gens {
+        readers.remove(&gen);
+        removed_bytes += fs::metadata(log_path(path, gen))?.len();
+    }
+    archive::retire(path, &stale_gens, archive.as_ref())?;
+    sync_dir(&layout::segments_dir(path))?;
+    fs::remove_file(path.join(COMPACTION_MANIFEST))?;
+    // only now, so that a reader walking one of the chains either sees all of it or fails to read
+    // its records and looks the key up again
+    for pos in unlinked {
+        merges.unlink(pos);
+    }
+    let reclaimed = removed_bytes.saturating_sub(new_byte_offset);
+    debug!(reclaimed, "Compaction finished");
+    let mut writer = writer.lock().unwrap();
+    writer.last_compaction = Some(SystemTime::now());
+    writer.metrics.compactions += 1;
+    writer.metrics.bytes_reclaimed += reclaimed;
+    if max_bytes.is_some() {
+        // the stale bytes of the generations left are still there
+        let removed =
+            removed_bytes.saturating_sub(stale_gens.len() as u64 * record::FILE_HEADER_LEN);
+        writer.log_bytes =
+            (writer.log_bytes + new_byte_offset - record::FILE_HEADER_LEN).saturating_sub(removed);
+        writer.stale_bytes = writer
+            .stale_bytes
+            .saturating_sub(removed.saturating_sub(moved_bytes))
+            + kept_stale_bytes;
+    } else {
+        // kept on purpose, so they don't count as stale
+        writer.log_bytes += retained_bytes;
+    }
+    Ok(reclaimed)
+}
+
+/// The generations among `gens` a partial compaction rewrites, oldest first: the ones with the
+/// highest share of stale bytes, as many as fit in `max_bytes`, or the one with the most if none
+/// does. Generations without stale bytes are left alone.
+fn pick_generations(
+    index: &SkipMap<Vec<u8>, RecordPos>,
+    merges: &Merges,
+    readers: &SkipMap<u64, Arc<File>>,
+    gens: &[u64],
+    max_bytes: u64,
+) -> Result<Vec<u64>> {
+    let mut live = HashMap::<u64, u64>::new();
+    for entry in index.iter() {
+        let head = *entry.value();
+        for pos in merges.chain(head).unwrap_or_else(|| vec![head]) {
+            *live.entry(pos.gen).or_default() += pos.len;
+        }
+    }
+    let mut candidates = Vec::new();
+    for &gen in gens {
+        let len = readers.get(&gen).unwrap().value().metadata()?.len();
+        let bytes = len.saturating_sub(record::FILE_HEADER_LEN);
+        let stale = bytes.saturating_sub(live.get(&gen).copied().unwrap_or(0));
+        candidates.push((gen, bytes, stale as f64 / bytes.max(1) as f64));
+    }
+    // the older of two generations as stale goes first
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+
+    let mut picked = Vec::new();
+    let mut picked_bytes = 0;
+    for (gen, bytes, stale_ratio) in candidates {
+        if !picked.is_empty() && (stale_ratio == 0.0 || picked_bytes + bytes > max_bytes) {
+            continue;
+        }
+        picked.push(gen);
+        picked_bytes += bytes;
+    }
+    picked.sort_unstable();
+    Ok(picked)
+}
+
+/// A record of an older version that compaction keeps
+enum Retained {
+    /// Copied as it is
+    Copy(RecordPos),
+    /// A merge, written as a set of the value it resulted in from the records of its key, newest
+    /// first
+    Resolve(Vec<u8>, Vec<RecordPos>),
+    /// The removal of a key by a range removal, written as a removal of the key alone with the
+    /// version of the range removal
+    Remove(Vec<u8>, u64),
+}
+
+/// The records of the generations before `compaction_gen` holding the older versions the retention
+/// policy keeps: the versions from `cutoff` on and, for each key, the last one before it. The
+/// current values are left to the compaction, and the records of each key are in the order they
+/// were written.
+fn retained_versions(
+    index: &SkipMap<Vec<u8>, RecordPos>,
+    merges: &Merges,
+    readers: &SkipMap<u64, Arc<File>>,
+    codec: &Codec,
+    compaction_gen: u64,
+    cutoff: u64,
+) -> Result<Vec<Retained>> {
+    // the committed records of every key, oldest first
+    let mut keys: HashMap<Vec<u8>, Vec<(RecordPos, CommandType, u64)>> = HashMap::new();
+    for entry in readers.range(..compaction_gen) {
+        let file = entry.value();
+        for_each_committed(
+            *entry.key(),
+            file,
+            file.metadata()?.len(),
+            codec,
+            |command, pos| {
+                match command.command_type {
+                    CommandType::CLEAR => keys.clear(),
+                    // a removal of each key in the range
+                    CommandType::RMRANGE => {
+                        let range = removed_range(&command);
+                        let version = command.version.unwrap_or(0);
+                        for (_, records) in keys.iter_mut().filter(|(key, _)| range.contains(*key))
+                        {
+                            records.push((pos, CommandType::RMRANGE, version));
+                        }
+                    }
+                    command_type => keys.entry(command.key).or_default().push((
+                        pos,
+                        command_type,
+                        command.version.unwrap_or(0),
+                    )),
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    let now = now_millis();
+    let mut retained = Vec::new();
+    for (key, records) in keys {
+        let head = index.get(&key).map(|entry| *entry.value());
+        // the first record of the current value that compaction copies
+        let current = head.filter(|head| !head.expired(now)).and_then(|head| {
+            merges
+                .chain(head)
+                .unwrap_or_else(|| vec![head])
+                .into_iter()
+                .find(|pos| pos.gen < compaction_gen)
+        });
+        // replaying the older versions of a key whose value expired would bring them back
+        let (_, last, _) = records[records.len() - 1];
+        if current.is_none()
+            && !matches!(last, CommandType::RM | CommandType::RMRANGE)
+            && head.is_none_or(|head| head.gen < compaction_gen)
+        {
+            continue;
+        }
+        let start = records
+            .iter()
+            .rposition(|&(_, _, version)| version < cutoff)
+            .unwrap_or(0);
+        for (i, &(pos, command_type, version)) in records.iter().enumerate().skip(start) {
+            if Some(pos) == current {
+                continue;
+            }
+            match command_type {
+                // nothing to keep of a key that didn't exist at the cutoff
+                CommandType::RM | CommandType::RMRANGE if i == start => {}
+                // the merges before the cutoff go, so the value of the last one is kept instead
+                command_type if command_type.is_operand() && i == start => {
+                    let from = match records[..i]
+                        .iter()
+                        .rposition(|&(_, command_type, _)| !command_type.is_operand())
+                    {
+                        Some(base) if records[base].1 == CommandType::SET => base,
+                        Some(removal) => removal + 1,
+                        None => 0,
+                    };
+                    let chain = records[from..=i].iter().rev().map(|&(pos, _, _)| pos);
+                    retained.push(Retained::Resolve(key.clone(), chain.collect()));
+                }
+                CommandType::RMRANGE => retained.push(Retained::Remove(key.clone(), version)),
+                _ => retained.push(Retained::Copy(pos)),
+            }
+        }
+    }
+    Ok(retained)
+}
+
+/// Converts a value read from the log into a `String`
+pub(super) fn utf8(value: Option<Vec<u8>>) -> Result<Option<String>> {
+    value.map(utf8_string).transpose()
+}
+
+/// Converts a value that exists into a `String`
+pub(super) fn utf8_string(value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value)
+        .map_err(|_| KvsError::Message("Value is not valid UTF-8, read it as bytes".to_owned()))
+}
+
+/// Milliseconds since the Unix epoch, the unit expiry times are kept in
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Microseconds since the Unix epoch, the unit versions are kept in
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_micros() as u64)
+}
+
+/// Locks the store in `dir`, exclusively unless `read_only` is set.
+///
+/// A read-only open doesn't create the lock file, so it skips locking a store that was never
+/// opened for writing since stores started to be locked.
+pub(super) fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<File>> {
+    let path = dir.join(LOCK_FILE);
+    let (file, locked) = if read_only {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(&path)?;
+        let locked = file.try_lock_shared();
+        (file, locked)
+    } else {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        let locked = file.try_lock();
+        (file, locked)
+    };
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) if read_only => Err(KvsError::Message(format!(
+            "{} is open for writing by another handle or process",
+            dir.display()
+        ))),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Message(format!(
+            "{} is already open by another handle or process",
+            dir.display()
+        ))),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Creates the log file of generation `gen` in `wal/`, registers a reader for it, and moves the
+/// generations before it to `segments/`
+fn new_log(
+    dir: &Path,
+    gen: u64,
+    store_id: Uuid,
+    format: RecordFormat,
+    readers: &SkipMap<u64, Arc<File>>,
+) -> Result<File> {
+    let log = create_log(&layout::wal_path(dir, gen), gen, store_id, format, readers)?;
+    layout::seal(dir, gen)?;
+    Ok(log)
+}
+
+/// Creates the file in `path` holding the log of generation `gen` and registers a reader for it
+fn create_log(
+    path: &Path,
+    gen: u64,
+    store_id: Uuid,
+    format: RecordFormat,
+    readers: &SkipMap<u64, Arc<File>>,
+) -> Result<File> {
+    let mut log = OpenOptions::new().append(true).create(true).open(path)?;
+    log.write_all(&record::file_header(store_id, format))?;
+    readers.insert(gen, Arc::new(File::open(path)?));
+    Ok(log)
+}
+
+/// Path of the file compaction writes generation `gen` into
+fn compacting_path(dir: &Path, gen: u64) -> PathBuf {
+    layout::tmp_path(dir, &format!("{}.{}", gen, COMPACTING_EXTENSION))
+}
+
+/// Records in the manifest of `dir` that the generations `replaced` were compacted and are to be
+/// deleted, replacing the file in one step
+fn write_manifest(dir: &Path, replaced: &[u64], strategy: ReplaceStrategy) -> Result<()> {
+    let tmp = layout::tmp_path(dir, &format!("{}.tmp", COMPACTION_MANIFEST));
+    let mut file = File::create(&tmp)?;
+    for gen in replaced {
+        writeln!(file, "{}", gen)?;
+    }
+    file.sync_all()?;
+    files::replace_file(&tmp, &dir.join(COMPACTION_MANIFEST), strategy)?;
+    sync_dir(dir)
+}
+
+/// Cleans up after a compaction of the store in `dir` that a crash interrupted, and returns the
+/// generations it replaced, which must not be replayed.
+///
+/// A generation compaction didn't finish writing is deleted, while the store still has the ones
+/// it was compacting. Once it is complete, the generations it replaced are deleted, or moved to
+/// `archive`, as its manifest says. Nothing is deleted if `read_only` is set.
+fn recover_compaction(dir: &Path, read_only: bool, archive: Option<&Archive>) -> Result<Vec<u64>> {
+    let manifest = dir.join(COMPACTION_MANIFEST);
+    let replaced = match fs::read_to_string(&manifest) {
+        Ok(manifest) => manifest
+            .lines()
+            .map(|gen| {
+                gen.parse::<u64>().map_err(|_| {
+                    KvsError::Message(format!("Invalid compaction manifest in {}", dir.display()))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if read_only {
+        return Ok(replaced);
+    }
+
+    for entry in fs::read_dir(layout::tmp_dir(dir))? {
+        let path = entry?.path();
+        let leftover = path.extension() == Some(OsStr::new(COMPACTING_EXTENSION))
+            || path.file_name() == Some(OsStr::new(&format!("{}.tmp", COMPACTION_MANIFEST)));
+        if leftover && path.is_file() {
+            warn!(
+                "Deleting {}, left by an interrupted compaction",
+                path.display()
+            );
+            fs::remove_file(&path)?;
+        }
+    }
+    archive::retire(dir, &replaced, archive)?;
+    match fs::remove_file(manifest) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    sync_dir(&layout::segments_dir(dir))?;
+    sync_dir(dir)?;
+    Ok(replaced)
+}
+
+/// Reads exactly `buf.len()` bytes at `offset` without moving a shared file cursor
+#[cfg(unix)]
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Reads exactly `buf.len()` bytes at `offset` without moving a shared file cursor
+#[cfg(windows)]
+pub(super) fn read_exact_at(
+    file: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the frame read from `pos`
+pub(super) fn decode(codec: &Codec, frame: &[u8], pos: RecordPos) -> Result<Command> {
+    codec.decode(frame).map_err(|e| match e {
+        DecodeError::Corrupt => CorruptRecord {
+            gen: pos.gen,
+            offset: pos.offset,
+        }
+        .into(),
+        DecodeError::NoKey => KvsError::Message(format!(
+            "Record in generation {} at offset {} is encrypted with a key the store wasn't opened with",
+            pos.gen, pos.offset
+        )),
+    })
+}
+
+/// Replay a generation of the log into the index, from the record at offset `from` on. This only keeps the valid keys in the index.
+/// The index stores the key and the position of the data stored in the log. If the log has a set entry for a key and then a remove entry then the key will effectively be removed from the index.
+/// The records of a batch are dropped unless its commit marker follows them.
+/// Returns the offset where the last whole record ends, which is short of the end of the file if
+/// the process died in the middle of writing a record, and the newest version replayed.
+fn replay(
+    gen: u64,
+    file: &File,
+    from: u64,
+    index: &SkipMap<Vec<u8>, RecordPos>,
+    merges: &Merges,
+    codec: &Codec,
+    options: &KvStoreOptions,
+) -> Result<(u64, u64)> {
+    let mut reader = BufReader::new(file);
+    let mut version = 0;
+    // the header was validated when the store was opened
+    reader.seek(SeekFrom::Start(from))?;
+    let mut byte_offset = from;
+    // the records of the batch being replayed, applied once its commit marker is reached
+    let mut batch: Option<Vec<(Command, RecordPos)>> = None;
+    while let Some(frame) = record::read_frame(&mut reader)? {
+        let pos = RecordPos {
+            gen,
+            offset: byte_offset,
+            len: frame.len() as u64,
+            expires_at: None,
+        };
+        let command = decode(codec, &frame, pos);
+        if let Ok(c) = &command {
+            version = version.max(c.version.unwrap_or(0));
+        }
+        match command {
+            // a batch left open can only be followed by another if its writer failed midway
+            Ok(c) if c.command_type == CommandType::BEGIN => batch = Some(Vec::new()),
+            Ok(c) if c.command_type == CommandType::CLEAR => {
+                batch = None;
+                index.clear();
+                merges.clear();
+            }
+            Ok(c) if c.command_type == CommandType::COMMIT => {
+                for (c, pos) in batch.take().unwrap_or_default() {
+                    apply(index, merges, c, pos, now_millis());
+                }
+            }
+            Ok(c) => {
+                let pos = RecordPos {
+                    expires_at: c.expires_at,
+                    ..pos
+                };
+                match &mut batch {
+                    Some(batch) => batch.push((c, pos)),
+                    None => apply(index, merges, c, pos, now_millis()),
+                }
+            }
+            Err(KvsError::Corruption(corrupt)) if options.skip_corrupt => {
+                warn!("Skipping {}", corrupt)
+            }
+            Err(e) => return Err(e),
+        }
+        byte_offset += pos.len;
+    }
+    Ok((byte_offset, version))
+}
+
+/// Calls `f` with the committed records of the log file of generation `gen` up to `end`, in the
+/// order they were written, leaving the batch markers out
+fn for_each_committed(
+    gen: u64,
+    file: &File,
+    end: u64,
+    codec: &Codec,
+    mut f: impl FnMut(Command, RecordPos) -> Result<()>,
+) -> Result<()> {
+    let mut offset = record::FILE_HEADER_LEN;
+    let mut reader = BufReader::new(FileSlice { file, offset, end });
+    let mut batch: Option<Vec<(Command, RecordPos)>> = None;
+    while let Some(frame) = record::read_frame(&mut reader)? {
+        let pos = RecordPos {
+            gen,
+            offset,
+            len: frame.len() as u64,
+            expires_at: None,
+        };
+        offset += pos.len;
+        let command = decode(codec, &frame, pos)?;
+        let pos = RecordPos {
+            expires_at: command.expires_at,
+            ..pos
+        };
+        match command.command_type {
+            CommandType::BEGIN => batch = Some(Vec::new()),
+            CommandType::COMMIT => {
+                for (command, pos) in batch.take().unwrap_or_default() {
+                    f(command, pos)?;
+                }
+            }
+            CommandType::CLEAR => {
+                batch = None;
+                f(command, pos)?;
+            }
+            _ => match &mut batch {
+                Some(batch) => batch.push((command, pos)),
+                None => f(command, pos)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// The keys the range removal record `command` removes
+fn removed_range(command: &Command) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = command.value.clone();
+    (
+        Bound::Included(command.key.clone()),
+        end.map_or(Bound::Unbounded, Bound::Excluded),
+    )
+}
+
+/// Whether the value of a [`KeyVersion`] had expired by `version`
+fn expired_at(entry: &KeyVersion, version: u64) -> bool {
+    entry
+        .expires_at
+        .is_some_and(|expires_at| expires_at.saturating_mul(1000) <= version)
+}
+
+/// Applies a replayed record to the index, expiring the keys that expired by `now`
+fn apply(
+    index: &SkipMap<Vec<u8>, RecordPos>,
+    merges: &Merges,
+    command: Command,
+    pos: RecordPos,
+    now: u64,
+) {
+    if command.command_type == CommandType::RMRANGE {
+        for entry in index.range(removed_range(&command)) {
+            merges.unlink(*entry.value());
+            entry.remove();
+        }
+        return;
+    }
+    let old = index.get(&command.key).map(|entry| *entry.value());
+    if command.command_type == CommandType::RM || pos.expired(now) {
+        index.remove(&command.key);
+    } else if command.command_type.is_operand() {
+        // a merge builds on the value of the key, unless it expired
+        let prev = old.filter(|old| !old.expired(now));
+        merges.link(pos, prev);
+        index.insert(command.key, pos);
+        if prev.is_some() {
+            return;
+        }
+    } else {
+        index.insert(command.key, pos);
+    }
+    if let Some(old) = old {
+        merges.unlink(old);
+    }
+}
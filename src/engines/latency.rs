@@ -0,0 +1,180 @@
+//! Timing the operations of a [`KvStore`](super::KvStore): operations slower than
+//! [`KvStoreOptions::slow_op_threshold`](super::KvStoreOptions::slow_op_threshold) are logged,
+//! and with the `metrics` feature every operation is counted in a latency histogram, which
+//! [`KvStore::latencies`](super::KvStore::latencies) reports.
+
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fmt, time::Duration};
+
+use tracing::warn;
+
+/// Upper bounds of the latency histogram buckets, in microseconds. Slower operations fall into a
+/// last bucket without a bound.
+#[cfg(feature = "metrics")]
+const BUCKETS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// The operations of a store that are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum StoreOp {
+    Get,
+    Set,
+    Remove,
+    Compaction,
+}
+
+impl fmt::Display for StoreOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StoreOp::Get => "get",
+            StoreOp::Set => "set",
+            StoreOp::Remove => "remove",
+            StoreOp::Compaction => "compaction",
+        })
+    }
+}
+
+/// How long the operations of a store took. Shared by every handle to the store and updated
+/// concurrently.
+#[derive(Debug)]
+pub(super) struct Latencies {
+    /// Operations taking longer are logged
+    slow_threshold: Option<Duration>,
+    #[cfg(feature = "metrics")]
+    histograms: [AtomicHistogram; 4],
+}
+
+impl Latencies {
+    pub(super) fn new(slow_threshold: Option<Duration>) -> Latencies {
+        Latencies {
+            slow_threshold,
+            #[cfg(feature = "metrics")]
+            histograms: Default::default(),
+        }
+    }
+
+    /// Counts an `op` on `subject`, a key or a generation, that took `elapsed`, and logs it if
+    /// it was slow
+    pub(super) fn record(&self, op: StoreOp, subject: impl fmt::Display, elapsed: Duration) {
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            warn!(
+                %op,
+                %subject,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow {} of {} took {:?}",
+                op,
+                subject,
+                elapsed
+            );
+        }
+        #[cfg(feature = "metrics")]
+        self.histograms[op as usize].record(elapsed);
+    }
+
+    /// A snapshot of the histograms
+    #[cfg(feature = "metrics")]
+    pub(super) fn stats(&self) -> LatencyStats {
+        LatencyStats {
+            get: self.histograms[StoreOp::Get as usize].snapshot(),
+            set: self.histograms[StoreOp::Set as usize].snapshot(),
+            remove: self.histograms[StoreOp::Remove as usize].snapshot(),
+            compaction: self.histograms[StoreOp::Compaction as usize].snapshot(),
+        }
+    }
+}
+
+/// A latency histogram updated concurrently
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+struct AtomicHistogram {
+    /// Operations that took at most each of [`BUCKETS`] but more than the one before, and the
+    /// ones slower than all of them
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    /// Total time taken, in microseconds
+    micros: AtomicU64,
+    /// Longest time taken, in microseconds
+    max_micros: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl AtomicHistogram {
+    fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = BUCKETS.partition_point(|&bound| bound < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        LatencyHistogram {
+            count: counts.iter().sum(),
+            total: Duration::from_micros(self.micros.load(Ordering::Relaxed)),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+            buckets: BUCKETS
+                .iter()
+                .map(|&bound| Some(Duration::from_micros(bound)))
+                .chain([None])
+                .zip(counts)
+                .collect(),
+        }
+    }
+}
+
+/// How long the operations of a [`KvStore`](super::KvStore) took since it was opened, see
+/// [`KvStore::latencies`](super::KvStore::latencies)
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// Gets through [`KvsEngine::get`](super::KvsEngine::get)
+    pub get: LatencyHistogram,
+    /// Sets through [`KvsEngine::set`](super::KvsEngine::set)
+    pub set: LatencyHistogram,
+    /// Removals through [`KvsEngine::remove`](super::KvsEngine::remove)
+    pub remove: LatencyHistogram,
+    /// Compactions, in the background or on demand
+    pub compaction: LatencyHistogram,
+}
+
+/// A histogram of how long one kind of operation took
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Number of operations
+    pub count: u64,
+    /// Time taken by all of them
+    pub total: Duration,
+    /// Time taken by the slowest
+    pub max: Duration,
+    /// The upper bound of each bucket, or none for the last one, and how many operations took
+    /// at most that long but longer than the bound of the bucket before
+    pub buckets: Vec<(Option<Duration>, u64)>,
+}
+
+#[cfg(feature = "metrics")]
+impl LatencyHistogram {
+    /// An upper bound for the time taken by `percent`% of the operations: the bound of the bucket
+    /// the percentile falls into, or [`LatencyHistogram::max`] past the last bound. Zero if
+    /// nothing was counted.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        let rank = (self.count as f64 * percent / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for &(bound, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return bound.map_or(self.max, |bound| bound.min(self.max));
+            }
+        }
+        Duration::ZERO
+    }
+}
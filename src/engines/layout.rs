@@ -0,0 +1,266 @@
+//! Where the files of a [`KvStore`](super::KvStore) live in its data directory.
+//!
+//! - `wal/<gen>.log` is the generation of the log being appended to
+//! - `segments/<gen>.log` are the generations before it, which are never appended to again
+//! - `hints/` holds what speeds up opening the store, the checkpoint of the index, and looking
+//!   keys up through `segments/`, the bloom filters of the generations there
+//! - `tmp/` holds the files being written, like a generation being compacted, until they
+//!   replace the file they are for
+//! - `backups/` is where backups go unless they are written elsewhere, see
+//!   [`KvStore::backups_dir`](super::KvStore::backups_dir)
+//! - `archive/` is where the generations no longer needed go if they are archived rather than
+//!   deleted, and no other directory is set, see
+//!   [`KvStoreOptions::wal_archive`](super::KvStoreOptions::wal_archive)
+//! - `namespaces/<name>/` holds the namespaces, each laid out the same way
+//!
+//! The lock file, the engine marker and the manifests of compactions and migrations stay at the
+//! top, along with the JSON `LAYOUT` manifest naming the version of the layout and its
+//! directories. Stores created before had every file at the top of the directory: opening them
+//! for writing moves the files where they belong and writes the manifest last, so a crash in
+//! between is finished by opening the store again.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::files::{self, sync_dir, ReplaceStrategy};
+use crate::{KvsError, Result};
+
+/// Name of the manifest of the layout
+const LAYOUT_FILE: &str = "LAYOUT";
+
+/// Version of the layout this build writes, and the newest it can read
+const LAYOUT_VERSION: u32 = 1;
+
+/// Directory of the generation being appended to
+const WAL_DIR: &str = "wal";
+
+/// Directory of the generations that are no longer appended to
+const SEGMENTS_DIR: &str = "segments";
+
+/// Directory of the checkpoint
+const HINTS_DIR: &str = "hints";
+
+/// Directory of the files being written
+const TMP_DIR: &str = "tmp";
+
+/// Directory meant for backups
+const BACKUPS_DIR: &str = "backups";
+
+/// Directory the generations are archived in by default
+const ARCHIVE_DIR: &str = "archive";
+
+/// Extensions of the files that belong in `tmp/` in stores from before the layout
+const TMP_EXTENSIONS: [&str; 3] = ["compacting", "migrating", "tmp"];
+
+/// The contents of the `LAYOUT` manifest
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    wal: String,
+    segments: String,
+    hints: String,
+    tmp: String,
+    backups: String,
+}
+
+/// Moves the files of a store in `dir` from before the layout where they belong, and creates the
+/// directories of the layout, unless the store is opened `read_only`, in which case it fails if
+/// there is anything to move
+pub(super) fn upgrade(dir: &Path, read_only: bool, strategy: ReplaceStrategy) -> Result<()> {
+    match File::open(dir.join(LAYOUT_FILE)) {
+        Ok(file) => {
+            let manifest: Manifest = serde_json::from_reader(BufReader::new(file))?;
+            if manifest.version > LAYOUT_VERSION {
+                return Err(KvsError::Message(format!(
+                    "{} has layout version {}, but only versions up to {} are supported",
+                    dir.display(),
+                    manifest.version,
+                    LAYOUT_VERSION
+                )));
+            }
+            return Ok(());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut moves = Vec::new();
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let name = path.file_name().unwrap();
+            let extension = path.extension().and_then(OsStr::to_str);
+            let to = if extension == Some("log") {
+                dir.join(SEGMENTS_DIR)
+            } else if name == "checkpoint" {
+                dir.join(HINTS_DIR)
+            } else if extension.is_some_and(|extension| TMP_EXTENSIONS.contains(&extension)) {
+                dir.join(TMP_DIR)
+            } else {
+                continue;
+            };
+            moves.push((path.clone(), to.join(name)));
+        }
+    }
+    if read_only {
+        if !moves.is_empty() {
+            return Err(KvsError::Message(format!(
+                "{} has to be upgraded by opening it for writing first",
+                dir.display()
+            )));
+        }
+        return Ok(());
+    }
+
+    for sub_dir in [WAL_DIR, SEGMENTS_DIR, HINTS_DIR, TMP_DIR, BACKUPS_DIR] {
+        fs::create_dir_all(dir.join(sub_dir))?;
+    }
+    for (from, to) in moves {
+        fs::rename(from, to)?;
+    }
+    for sub_dir in [SEGMENTS_DIR, HINTS_DIR, TMP_DIR] {
+        sync_dir(&dir.join(sub_dir))?;
+    }
+    let manifest = Manifest {
+        version: LAYOUT_VERSION,
+        wal: WAL_DIR.to_owned(),
+        segments: SEGMENTS_DIR.to_owned(),
+        hints: HINTS_DIR.to_owned(),
+        tmp: TMP_DIR.to_owned(),
+        backups: BACKUPS_DIR.to_owned(),
+    };
+    let tmp = tmp_path(dir, &format!("{}.tmp", LAYOUT_FILE));
+    let file = File::create(&tmp)?;
+    serde_json::to_writer_pretty(&file, &manifest)?;
+    file.sync_all()?;
+    files::replace_file(&tmp, &dir.join(LAYOUT_FILE), strategy)?;
+    sync_dir(dir)
+}
+
+/// The directories of the store in `dir`, besides `dir` itself, that files are replaced in
+pub(super) fn replaced_dirs(dir: &Path) -> [PathBuf; 3] {
+    [
+        dir.join(WAL_DIR),
+        dir.join(SEGMENTS_DIR),
+        dir.join(HINTS_DIR),
+    ]
+}
+
+/// Generations of the log of the store in `dir`, oldest first
+pub(super) fn gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for sub_dir in [WAL_DIR, SEGMENTS_DIR] {
+        let sub_dir = dir.join(sub_dir);
+        if !sub_dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(sub_dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+                if let Some(gen) = path
+                    .file_stem()
+                    .and_then(OsStr::to_str)
+                    .and_then(|stem| stem.parse::<u64>().ok())
+                {
+                    gens.push(gen);
+                }
+            }
+        }
+    }
+    gens.sort_unstable();
+    gens.dedup();
+    Ok(gens)
+}
+
+/// Moves the generations in `wal/` other than `active` to `segments/`, as they are no longer
+/// appended to
+pub(super) fn seal(dir: &Path, active: u64) -> Result<()> {
+    let mut sealed = false;
+    for entry in fs::read_dir(dir.join(WAL_DIR))? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("log"))
+            && path.file_stem() != Some(OsStr::new(&active.to_string()))
+        {
+            fs::rename(
+                &path,
+                dir.join(SEGMENTS_DIR).join(path.file_name().unwrap()),
+            )?;
+            sealed = true;
+        }
+    }
+    if sealed {
+        sync_dir(&dir.join(WAL_DIR))?;
+        sync_dir(&dir.join(SEGMENTS_DIR))?;
+    }
+    Ok(())
+}
+
+/// Path of generation `gen` of the store in `dir`, which is in `segments/` unless it is still
+/// in `wal/`
+pub(super) fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    let wal = wal_path(dir, gen);
+    if wal.exists() {
+        wal
+    } else {
+        segment_path(dir, gen)
+    }
+}
+
+/// Path of generation `gen` of the store in `dir` while it is appended to
+pub(super) fn wal_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(WAL_DIR).join(format!("{}.log", gen))
+}
+
+/// Path of generation `gen` of the store in `dir` once it is no longer appended to
+pub(super) fn segment_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(segment_name(gen))
+}
+
+/// Path of generation `gen` once it is no longer appended to, relative to the store directory
+pub(super) fn segment_name(gen: u64) -> String {
+    format!("{}/{}.log", SEGMENTS_DIR, gen)
+}
+
+/// Directory of the generations of the store in `dir` that are no longer appended to
+pub(super) fn segments_dir(dir: &Path) -> PathBuf {
+    dir.join(SEGMENTS_DIR)
+}
+
+/// Directory of the hints of the store in `dir`
+pub(super) fn hints_dir(dir: &Path) -> PathBuf {
+    dir.join(HINTS_DIR)
+}
+
+/// Path of the file named `name` in the hints of the store in `dir`
+pub(super) fn hint_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(HINTS_DIR).join(name)
+}
+
+/// Path of the file named `name` being written for the store in `dir`
+pub(super) fn tmp_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(TMP_DIR).join(name)
+}
+
+/// Directory of the store in `dir` meant for backups
+pub(super) fn backups_dir(dir: &Path) -> PathBuf {
+    dir.join(BACKUPS_DIR)
+}
+
+/// Directory of the store in `dir` its generations are archived in by default
+pub(super) fn archive_dir(dir: &Path) -> PathBuf {
+    dir.join(ARCHIVE_DIR)
+}
+
+/// The directory of the store in `dir` that the files being written are in
+pub(super) fn tmp_dir(dir: &Path) -> PathBuf {
+    dir.join(TMP_DIR)
+}
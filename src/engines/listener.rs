@@ -0,0 +1,49 @@
+//! Notifications of the compactions of a [`KvStore`](super::KvStore), see
+//! [`KvStoreOptions::compaction_listener`](super::KvStoreOptions::compaction_listener).
+
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Notified when a [`KvStore`](super::KvStore) compacts its log, in the background or on demand,
+/// to log, alert on or measure compactions.
+///
+/// The methods are called from the thread running the compaction, which waits for them, so they
+/// should return quickly. Both do nothing by default.
+pub trait CompactionListener: Send + Sync + 'static {
+    /// Called when a compaction starts copying the live records
+    fn on_compaction_start(&self, _event: &CompactionStart) {}
+
+    /// Called when a compaction is done, whether it succeeded or not
+    fn on_compaction_end(&self, _event: &CompactionEnd) {}
+}
+
+/// A compaction that started, see [`CompactionListener::on_compaction_start`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionStart {
+    /// Generation the live records are copied into
+    pub gen: u64,
+    /// Bytes of the records in the generations being compacted
+    pub log_bytes: u64,
+    /// Bytes of those records known to be stale, which the compaction should reclaim
+    pub stale_bytes: u64,
+}
+
+/// A compaction that is done, see [`CompactionListener::on_compaction_end`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionEnd {
+    /// Generation the live records were copied into
+    pub gen: u64,
+    /// Time the compaction took
+    pub duration: Duration,
+    /// Bytes the compaction freed on disk, or the error it failed with
+    pub result: Result<u64, String>,
+}
+
+/// The listener a store was opened with
+#[derive(Clone)]
+pub(crate) struct Listener(pub(crate) Arc<dyn CompactionListener>);
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionListener(..)")
+    }
+}
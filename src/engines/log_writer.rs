@@ -0,0 +1,255 @@
+//! The buffered writer of the active generation of the log.
+//!
+//! Records are buffered in memory and written out in order. Besides writing the buffer out itself
+//! when it fills up or is flushed, the writer can hand it over to an async handle, which writes it
+//! with `tokio::fs` once it let go of the writer of the store: later writes wait for it.
+
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+#[cfg(feature = "tokio")]
+use tokio::{io::AsyncWriteExt, sync::Notify};
+
+/// Bytes buffered before they are written out, like [`std::io::BufWriter`]
+const CAPACITY: usize = 8 * 1024;
+
+/// How often writing the buffer out checks whether the bytes an async handle took are written
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Nothing is being written asynchronously
+const IDLE: u8 = 0;
+/// Bytes taken from the buffer are being written asynchronously
+const WRITING: u8 = 1;
+/// Bytes taken from the buffer couldn't be written, so the log misses records
+#[cfg(feature = "tokio")]
+const FAILED: u8 = 2;
+
+thread_local! {
+    /// Whether the writes on this thread are for an async handle, see [`deferred`]
+    static DEFERRED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the writes on this thread are for an async handle, which syncs the log itself if asked
+/// to
+pub(super) fn is_deferred() -> bool {
+    DEFERRED.with(Cell::get)
+}
+
+/// Runs `op`, whose writes to the log are only buffered: the buffer isn't written out when it
+/// fills up, nor synced for the sync policy, until the async handle running it takes it with
+/// [`LogWriter::take_pending`]
+#[cfg(feature = "tokio")]
+pub(super) fn deferred<T>(op: impl FnOnce() -> T) -> T {
+    /// Clears the flag however `op` returns, a panic included
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            DEFERRED.with(|deferred| deferred.set(false));
+        }
+    }
+
+    DEFERRED.with(|deferred| deferred.set(true));
+    let _reset = Reset;
+    op()
+}
+
+/// The state of the async writes of a log, shared with them
+struct Shared {
+    state: AtomicU8,
+    /// Notified once the bytes being written asynchronously are
+    #[cfg(feature = "tokio")]
+    written: Notify,
+}
+
+/// The buffered writer of the log file of the active generation, opened for appending
+pub(super) struct LogWriter {
+    file: File,
+    buf: Vec<u8>,
+    /// Whether a deferred write asked for the log to be synced
+    sync: bool,
+    shared: Arc<Shared>,
+}
+
+impl LogWriter {
+    pub(super) fn new(file: File) -> LogWriter {
+        LogWriter {
+            file,
+            buf: Vec::with_capacity(CAPACITY),
+            sync: false,
+            shared: Arc::new(Shared {
+                state: AtomicU8::new(IDLE),
+                #[cfg(feature = "tokio")]
+                written: Notify::new(),
+            }),
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Asks the async handle taking the buffer to sync the log once it is written
+    pub(super) fn sync_later(&mut self) {
+        self.sync = true;
+    }
+
+    /// Waits until the bytes an async handle took are written, failing if they couldn't be
+    fn wait_in_flight(&self) -> io::Result<()> {
+        #[cfg(feature = "tokio")]
+        if self.shared.state.load(Ordering::Acquire) == WRITING
+            && tokio::runtime::Handle::try_current().is_ok_and(|runtime| {
+                runtime.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread
+            })
+        {
+            // the task writing them may be queued behind the caller on this worker
+            return tokio::task::block_in_place(|| self.poll_in_flight());
+        }
+        self.poll_in_flight()
+    }
+
+    fn poll_in_flight(&self) -> io::Result<()> {
+        loop {
+            match self.shared.state.load(Ordering::Acquire) {
+                IDLE => return Ok(()),
+                WRITING => thread::sleep(IN_FLIGHT_POLL_INTERVAL),
+                _ => return Err(failed()),
+            }
+        }
+    }
+
+    /// Takes the buffer to write it out asynchronously if it filled up or a sync is due, or
+    /// whatever it holds if `all`.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] while the bytes taken before are still being
+    /// written: [`LogWriter::wait_written`] waits for them.
+    #[cfg(feature = "tokio")]
+    pub(super) fn take_pending(&mut self, all: bool) -> io::Result<Option<PendingWrite>> {
+        if self.in_flight()? {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let full = self.buf.len() >= CAPACITY || (all && !self.buf.is_empty());
+        if !full && !self.sync {
+            return Ok(None);
+        }
+        let file = self.file.try_clone()?;
+        self.shared.state.store(WRITING, Ordering::Release);
+        Ok(Some(PendingWrite {
+            file,
+            bytes: std::mem::replace(&mut self.buf, Vec::with_capacity(CAPACITY)),
+            sync: std::mem::take(&mut self.sync),
+            shared: self.shared.clone(),
+            done: false,
+        }))
+    }
+
+    /// Whether the bytes an async handle took are still being written, failing if they couldn't be
+    #[cfg(feature = "tokio")]
+    pub(super) fn in_flight(&self) -> io::Result<bool> {
+        match self.shared.state.load(Ordering::Acquire) {
+            IDLE => Ok(false),
+            WRITING => Ok(true),
+            _ => Err(failed()),
+        }
+    }
+
+    /// Waits for the bytes an async handle took to be written, without holding the writer
+    #[cfg(feature = "tokio")]
+    pub(super) fn wait_written(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let shared = self.shared.clone();
+        async move {
+            let written = shared.written.notified();
+            tokio::pin!(written);
+            written.as_mut().enable();
+            if shared.state.load(Ordering::Acquire) == WRITING {
+                written.await;
+            }
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > CAPACITY && !is_deferred() {
+            self.flush()?;
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // the bytes taken before go first
+        self.wait_in_flight()?;
+        if !self.buf.is_empty() {
+            self.file.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        // like a `BufWriter`, whose errors on drop are ignored too
+        let _ = self.flush();
+    }
+}
+
+/// Bytes taken from the buffer of a [`LogWriter`], which later writes wait for until
+/// [`PendingWrite::write`] wrote them
+#[cfg(feature = "tokio")]
+pub(super) struct PendingWrite {
+    /// A handle to the log file, which is opened for appending, so it writes at its end too
+    file: File,
+    bytes: Vec<u8>,
+    /// Whether to sync the log once the bytes are written
+    sync: bool,
+    shared: Arc<Shared>,
+    done: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl PendingWrite {
+    /// Writes the bytes out with `tokio::fs`, and syncs them if asked to
+    pub(super) async fn write(mut self) -> io::Result<()> {
+        let mut file = tokio::fs::File::from_std(self.file.try_clone()?);
+        let result = async {
+            file.write_all(&self.bytes).await?;
+            file.flush().await?;
+            if self.sync {
+                file.sync_all().await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.done = true;
+        let state = if result.is_ok() { IDLE } else { FAILED };
+        self.shared.state.store(state, Ordering::Release);
+        self.shared.written.notify_waiters();
+        result
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        // dropped before it was written, e.g. as the runtime shut down: some bytes may be missing
+        if !self.done {
+            self.shared.state.store(FAILED, Ordering::Release);
+            self.shared.written.notify_waiters();
+        }
+    }
+}
+
+fn failed() -> io::Error {
+    io::Error::other("An earlier write of the log failed, so it misses records")
+}
@@ -0,0 +1,797 @@
+//! [`LsmKvStore`], an engine built as a log-structured merge-tree.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::{
+    kvs::lock_dir,
+    marker,
+    sstable::{self, Entry, Table, TableBuilder, TableMeta},
+    KvsEngine,
+};
+use crate::{KvsError, Result};
+
+/// Name of the file listing the tables of each level
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Number of levels; the last one has no size limit
+const LEVELS: usize = 7;
+
+/// How much larger each level from level 1 on may grow than the one above it
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Bytes a memtable entry takes besides its key and value, roughly
+const ENTRY_OVERHEAD: usize = 16;
+
+/// Settings of an [`LsmKvStore`], gathered with a builder and passed to
+/// [`LsmKvStore::open_with`].
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{LsmKvStore, LsmOptions};
+/// # use tempfile::TempDir;
+///
+/// let options = LsmOptions::new().memtable_size(64 * 1024 * 1024);
+/// let store = LsmKvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    memtable_size: usize,
+    table_size: u64,
+    level0_tables: usize,
+    level1_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// The default settings: 4 MiB memtables, 2 MiB tables, level 0 compacted once it holds 4
+    /// tables and level 1 once it holds 10 MiB
+    pub fn new() -> Self {
+        LsmOptions::default()
+    }
+
+    /// Makes the memtable immutable and flushes it to a table once its keys and values take
+    /// `bytes`
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Splits the output of compactions into tables of about `bytes`
+    pub fn table_size(mut self, bytes: u64) -> Self {
+        self.table_size = bytes;
+        self
+    }
+
+    /// Compacts level 0, where flushed memtables land, into level 1 once it holds `tables`
+    pub fn level0_tables(mut self, tables: usize) -> Self {
+        self.level0_tables = tables;
+        self
+    }
+
+    /// Compacts a table of level 1 into level 2 once level 1 holds more than `bytes`. Each
+    /// following level may hold ten times more than the one above it.
+    pub fn level1_size(mut self, bytes: u64) -> Self {
+        self.level1_size = bytes;
+        self
+    }
+}
+
+/// A [`KvsEngine`] built as a log-structured merge-tree, for data sets larger than memory.
+///
+/// Writes are appended to a write-ahead log and applied to the memtable, a sorted map in memory.
+/// Once the memtable is full it becomes immutable, writes move on to a new memtable and write-ahead
+/// log, and a background thread flushes it to a sorted string table (SSTable), a `<id>.sst` file,
+/// in level 0. When level 0 holds enough tables, they are merged into level 1, and whenever a level
+/// from 1 on outgrows its size limit, one of its tables is merged into the next level. The tables
+/// of a level from 1 on don't overlap, so a read looks at most at one table per level, and a bloom
+/// filter per table skips most tables that don't hold the key. A `MANIFEST` file lists the tables
+/// of each level.
+///
+/// Only the memtables, and the index and bloom filter of every table, are kept in memory.
+/// Cloning an [`LsmKvStore`] gives another handle to the same store.
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{KvsEngine, LsmKvStore};
+/// # use tempfile::TempDir;
+///
+/// let store = LsmKvStore::open(TempDir::new().unwrap().path()).unwrap();
+/// store.set(String::from("key1"), String::from("value1")).unwrap();
+/// assert_eq!(store.get(String::from("key1")).unwrap(), Some(String::from("value1")));
+/// ```
+#[derive(Clone)]
+pub struct LsmKvStore {
+    shared: Arc<Shared>,
+    _worker: Arc<Worker>,
+}
+
+/// The state of an [`LsmKvStore`] shared by its handles and its background thread
+struct Shared {
+    dir: PathBuf,
+    options: LsmOptions,
+    /// The write-ahead log of the memtable; writes are serialized on it
+    wal: Mutex<BufWriter<File>>,
+    state: RwLock<State>,
+    /// Held while flushing memtables or compacting, the only changes to the tables
+    compaction: Mutex<Compaction>,
+    /// Wakes the background thread up to flush a memtable
+    work: Sender<()>,
+    _lock: Option<File>,
+}
+
+/// The background thread flushing and compacting, which [`LsmKvStore`] handles wait for when the
+/// last one is dropped
+struct Worker {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // the thread returns once the shared state, and the sender in it, is gone
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What reads look at: the memtables and the tables of each level
+struct State {
+    memtable: Memtable,
+    /// Memtables waiting to be flushed, oldest first
+    immutable: Vec<Arc<Memtable>>,
+    /// Tables of each level. Level 0 is ordered oldest first, the other levels by key. Replaced
+    /// as a whole when tables change, so reads can hold on to it without locking.
+    levels: Arc<Vec<Vec<Arc<Table>>>>,
+    /// Id of the next table or write-ahead log
+    next_id: u64,
+    /// Id of the oldest write-ahead log that wasn't flushed to a table
+    wal_floor: u64,
+}
+
+/// Sorted keys with their values, `None` for removed keys
+struct Memtable {
+    entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Bytes taken by the entries, roughly
+    size: usize,
+    /// Id of the newest write-ahead log holding the entries
+    wal: u64,
+}
+
+/// State of the compactions
+#[derive(Default)]
+struct Compaction {
+    /// Last key of the table of each level compacted last, so tables take turns
+    pointers: Vec<Vec<u8>>,
+}
+
+/// The contents of the `MANIFEST` file
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    wal_floor: u64,
+    levels: Vec<Vec<TableMeta>>,
+}
+
+impl LsmKvStore {
+    /// Opens the [`LsmKvStore`] at `path` with the default settings, creating it if needed
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvStore> {
+        LsmKvStore::open_with(path, LsmOptions::default())
+    }
+
+    /// Opens the [`LsmKvStore`] at `path` with the given settings, creating it if needed.
+    ///
+    /// The write-ahead logs of the memtables that weren't flushed yet are replayed; a record cut
+    /// short by a crash at the end of one is dropped with a warning.
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmKvStore> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        marker::claim(&dir, "lsm", false)?;
+        let lock = lock_dir(&dir, false)?;
+        let manifest = match File::open(dir.join(MANIFEST_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = vec![Vec::new(); LEVELS];
+        let mut next_id = manifest.next_id;
+        for (level, tables) in manifest.levels.into_iter().enumerate() {
+            for meta in tables {
+                next_id = next_id.max(meta.id + 1);
+                levels[level].push(Arc::new(Table::open(&dir, meta)?));
+            }
+        }
+        // tables written by a compaction that didn't complete aren't in the manifest
+        for id in file_ids(&dir, "sst")? {
+            if !levels.iter().flatten().any(|table| table.meta.id == id) {
+                fs::remove_file(sstable::table_path(&dir, id))?;
+            }
+        }
+
+        let mut memtable = Memtable::new(0);
+        for id in file_ids(&dir, "wal")? {
+            if id < manifest.wal_floor {
+                fs::remove_file(wal_path(&dir, id))?;
+            } else {
+                replay(&wal_path(&dir, id), &mut memtable)?;
+                next_id = next_id.max(id + 1);
+            }
+        }
+        let wal = BufWriter::new(create_wal(&dir, next_id)?);
+        memtable.wal = next_id;
+
+        let (work, work_receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            dir,
+            options,
+            wal: Mutex::new(wal),
+            state: RwLock::new(State {
+                memtable,
+                immutable: Vec::new(),
+                levels: Arc::new(levels),
+                next_id: next_id + 1,
+                wal_floor: manifest.wal_floor,
+            }),
+            compaction: Mutex::new(Compaction::default()),
+            work,
+            _lock: lock,
+        });
+        let thread = spawn_worker(Arc::downgrade(&shared), work_receiver);
+        Ok(LsmKvStore {
+            shared,
+            _worker: Arc::new(Worker {
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// Flushes the memtable to a table and compacts until every level is within its size limit,
+    /// without waiting for the background thread
+    pub fn compact(&self) -> Result<()> {
+        {
+            let mut wal = self.shared.wal.lock().unwrap();
+            let mut state = self.shared.state.write().unwrap();
+            if !state.memtable.entries.is_empty() {
+                self.shared.rotate(&mut wal, &mut state)?;
+            }
+        }
+        self.shared.flush_and_compact()
+    }
+
+    /// Number of tables in each level, from level 0 on
+    pub fn tables_per_level(&self) -> Vec<usize> {
+        let levels = self.shared.state.read().unwrap().levels.clone();
+        levels.iter().map(Vec::len).collect()
+    }
+}
+
+impl Shared {
+    /// The value of `key`, looking at newer data first: the memtables, level 0 from its newest
+    /// table on, then each following level
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let levels = {
+            let state = self.state.read().unwrap();
+            for memtable in state.memtables() {
+                if let Some(value) = memtable.entries.get(key) {
+                    return Ok(value.clone());
+                }
+            }
+            state.levels.clone()
+        };
+        for table in levels[0].iter().rev() {
+            if table.meta.overlaps(key, key) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        for tables in &levels[1..] {
+            let i = tables.partition_point(|table| &table.meta.largest[..] < key);
+            if let Some(table) = tables.get(i).filter(|table| table.meta.overlaps(key, key)) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Logs the change to `key` and applies it to the memtable, which is flushed once full
+    fn write(&self, wal: &mut BufWriter<File>, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        let mut entry = Vec::new();
+        sstable::encode_entry(&mut entry, &key, value.as_deref());
+        wal.write_all(&crc32fast::hash(&entry).to_le_bytes())?;
+        wal.write_all(&entry)?;
+        let mut state = self.state.write().unwrap();
+        state.memtable.insert(key, value);
+        if state.memtable.size >= self.options.memtable_size {
+            self.rotate(wal, &mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `to` to the value of `from`, and removes `from` too if `remove`.
+    ///
+    /// The destination is logged first, so a crash in between may leave both keys, but never
+    /// neither.
+    fn copy(&self, from: String, to: String, remove: bool) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let value = self
+            .get(from.as_bytes())?
+            .ok_or_else(|| KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.write(&mut wal, to.into_bytes(), Some(value))?;
+        if remove {
+            self.write(&mut wal, from.into_bytes(), None)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the memtable immutable and moves writes on to a new one and a new write-ahead log
+    fn rotate(&self, wal: &mut BufWriter<File>, state: &mut State) -> Result<()> {
+        let id = state.next_id;
+        state.next_id += 1;
+        wal.flush()?;
+        *wal = BufWriter::new(create_wal(&self.dir, id)?);
+        let memtable = mem::replace(&mut state.memtable, Memtable::new(id));
+        state.immutable.push(Arc::new(memtable));
+        // the thread only stops once the store is dropped
+        let _ = self.work.send(());
+        Ok(())
+    }
+
+    /// Flushes the immutable memtables, then compacts until every level is within its limit
+    fn flush_and_compact(&self) -> Result<()> {
+        let mut compaction = self.compaction.lock().unwrap();
+        loop {
+            let memtable = match self.state.read().unwrap().immutable.first() {
+                Some(memtable) => memtable.clone(),
+                None => break,
+            };
+            self.flush_memtable(&memtable)?;
+        }
+        while self.compact_level(&mut compaction)? {}
+        Ok(())
+    }
+
+    /// Writes the oldest immutable memtable to a table in level 0
+    fn flush_memtable(&self, memtable: &Memtable) -> Result<()> {
+        let entries = memtable
+            .entries
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let tables = self.write_tables(entries, false, u64::MAX)?;
+        let mut state = self.state.write().unwrap();
+        state.immutable.remove(0);
+        let mut levels = (*state.levels).clone();
+        levels[0].extend(tables);
+        state.levels = Arc::new(levels);
+        state.wal_floor = memtable.wal + 1;
+        let manifest = state.manifest();
+        drop(state);
+        self.save_manifest(&manifest)?;
+        for id in file_ids(&self.dir, "wal")? {
+            if id < manifest.wal_floor {
+                fs::remove_file(wal_path(&self.dir, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges tables of the first level over its limit into the next level, and returns whether
+    /// there was one
+    fn compact_level(&self, compaction: &mut Compaction) -> Result<bool> {
+        let levels = self.state.read().unwrap().levels.clone();
+        compaction.pointers.resize(LEVELS, Vec::new());
+        let (level, inputs) = if levels[0].len() >= self.options.level0_tables.max(1) {
+            // tables of level 0 may overlap, so they are all merged at once
+            (0, levels[0].iter().rev().cloned().collect::<Vec<_>>())
+        } else {
+            let mut limit = self.options.level1_size;
+            let over = (1..LEVELS - 1).find(|&level| {
+                let size: u64 = levels[level].iter().map(|table| table.meta.size).sum();
+                let over = size > limit;
+                limit = limit.saturating_mul(LEVEL_SIZE_MULTIPLIER);
+                over
+            });
+            let level = match over {
+                Some(level) => level,
+                None => return Ok(false),
+            };
+            let pointer = &compaction.pointers[level];
+            let table = levels[level]
+                .iter()
+                .find(|table| table.meta.smallest > *pointer)
+                .unwrap_or(&levels[level][0]);
+            (level, vec![table.clone()])
+        };
+        let smallest = inputs
+            .iter()
+            .map(|t| &t.meta.smallest)
+            .min()
+            .unwrap()
+            .clone();
+        let largest = inputs
+            .iter()
+            .map(|t| &t.meta.largest)
+            .max()
+            .unwrap()
+            .clone();
+        compaction.pointers[level] = largest.clone();
+        let overlapping: Vec<_> = levels[level + 1]
+            .iter()
+            .filter(|table| table.meta.overlaps(&smallest, &largest))
+            .cloned()
+            .collect();
+
+        // removals only need to be kept while an older value may be in a deeper level
+        let bottom = levels[level + 2..].iter().all(Vec::is_empty);
+        let sources = inputs
+            .iter()
+            .chain(&overlapping)
+            .map(|table| Box::new(table.iter_from(&[])) as Box<dyn Iterator<Item = _>>)
+            .collect();
+        let tables = self.write_tables(Merge::new(sources)?, bottom, self.options.table_size)?;
+
+        let mut state = self.state.write().unwrap();
+        let mut new_levels = (*state.levels).clone();
+        let obsolete: Vec<u64> = inputs
+            .iter()
+            .chain(&overlapping)
+            .map(|table| table.meta.id)
+            .collect();
+        for tables in &mut new_levels[level..=level + 1] {
+            tables.retain(|table| !obsolete.contains(&table.meta.id));
+        }
+        new_levels[level + 1].extend(tables);
+        new_levels[level + 1].sort_by(|a, b| a.meta.smallest.cmp(&b.meta.smallest));
+        state.levels = Arc::new(new_levels);
+        let manifest = state.manifest();
+        drop(state);
+        self.save_manifest(&manifest)?;
+        for table in inputs.iter().chain(&overlapping) {
+            table.remove_file(&self.dir)?;
+        }
+        Ok(true)
+    }
+
+    /// Writes `entries`, sorted by key, to new tables of about `table_size` bytes each, leaving
+    /// out removed keys if `drop_removed` is set
+    fn write_tables(
+        &self,
+        entries: impl Iterator<Item = Result<Entry>>,
+        drop_removed: bool,
+        table_size: u64,
+    ) -> Result<Vec<Arc<Table>>> {
+        let mut tables = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            if drop_removed && value.is_none() {
+                continue;
+            }
+            let table = match &mut builder {
+                Some(builder) => builder,
+                None => builder.insert(TableBuilder::create(&self.dir, self.next_id())?),
+            };
+            table.add(&key, value.as_deref())?;
+            if table.size() >= table_size {
+                let meta = builder.take().unwrap().finish()?;
+                tables.push(Arc::new(Table::open(&self.dir, meta)?));
+            }
+        }
+        if let Some(builder) = builder.filter(|builder| !builder.is_empty()) {
+            let meta = builder.finish()?;
+            tables.push(Arc::new(Table::open(&self.dir, meta)?));
+        }
+        Ok(tables)
+    }
+
+    /// Takes the next id of a table or write-ahead log
+    fn next_id(&self) -> u64 {
+        let mut state = self.state.write().unwrap();
+        state.next_id += 1;
+        state.next_id - 1
+    }
+
+    /// Replaces the manifest, so that a crash leaves either the old or the new one
+    fn save_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, manifest)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    /// The keys starting with `prefix` and their values, merged from every memtable and table
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>>>> = Vec::new();
+        let levels = {
+            let state = self.state.read().unwrap();
+            for memtable in state.memtables() {
+                let entries: Vec<_> = memtable
+                    .entries
+                    .range(prefix.to_vec()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| Ok((key.clone(), value.clone())))
+                    .collect();
+                sources.push(Box::new(entries.into_iter()));
+            }
+            state.levels.clone()
+        };
+        for table in levels[0].iter().rev() {
+            sources.push(Box::new(table.iter_from(prefix)));
+        }
+        for tables in &levels[1..] {
+            let level = tables
+                .iter()
+                .filter(|table| &table.meta.largest[..] >= prefix)
+                .map(|table| table.iter_from(prefix))
+                .collect::<Vec<_>>();
+            sources.push(Box::new(level.into_iter().flatten()));
+        }
+        let mut found = Vec::new();
+        for entry in Merge::new(sources)? {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            if let Some(value) = value {
+                found.push((key, value));
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl State {
+    /// The memtable and the immutable memtables, newest first
+    fn memtables(&self) -> impl Iterator<Item = &Memtable> {
+        std::iter::once(&self.memtable).chain(self.immutable.iter().rev().map(|m| &**m))
+    }
+
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            next_id: self.next_id,
+            wal_floor: self.wal_floor,
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.meta.clone()).collect())
+                .collect(),
+        }
+    }
+}
+
+impl Memtable {
+    fn new(wal: u64) -> Self {
+        Memtable {
+            entries: BTreeMap::new(),
+            size: 0,
+            wal,
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let size = |key: &[u8], value: &Option<Vec<u8>>| {
+            key.len() + value.as_ref().map_or(0, Vec::len) + ENTRY_OVERHEAD
+        };
+        self.size += size(&key, &value);
+        if let Some(old) = self.entries.insert(key.clone(), value) {
+            self.size -= size(&key, &old);
+        }
+    }
+}
+
+/// Merges entries from sources sorted by key into one sequence sorted by key. When several
+/// sources hold a key, the entry of the first one wins, so sources are given newest first.
+struct Merge<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>,
+    /// The next entry of each source
+    heads: Vec<Option<Entry>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(mut sources: Vec<Box<dyn Iterator<Item = Result<Entry>> + 'a>>) -> Result<Self> {
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<_>>()?;
+        Ok(Merge { sources, heads })
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        let key = self
+            .heads
+            .iter()
+            .flatten()
+            .map(|(key, _)| key)
+            .min()?
+            .clone();
+        let mut winner = None;
+        for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+            if head.as_ref().is_some_and(|(k, _)| *k == key) {
+                let entry = head.take();
+                winner = winner.or(entry);
+                match source.next().transpose() {
+                    Ok(next) => *head = next,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+        winner.map(Ok)
+    }
+}
+
+/// Flushes immutable memtables and compacts whenever woken up, until the store is dropped
+fn spawn_worker(shared: Weak<Shared>, work: Receiver<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while work.recv().is_ok() {
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            if let Err(e) = shared.flush_and_compact() {
+                error!("Failed to flush the memtable: {}", e);
+            }
+        }
+    })
+}
+
+/// Replays the write-ahead log at `path` into `memtable`
+fn replay(path: &Path, memtable: &mut Memtable) -> Result<()> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    let mut pos = 0;
+    while pos < buf.len() {
+        // a record is the CRC32 of its entry, then the entry
+        let mut end = pos + 4;
+        let entry = sstable::decode_entry(&buf, &mut end).filter(|_| {
+            let crc = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+            crc == crc32fast::hash(&buf[pos + 4..end])
+        });
+        match entry {
+            Some((key, value)) => memtable.insert(key, value),
+            None => {
+                warn!(
+                    "Dropping the torn record at offset {} of {}",
+                    pos,
+                    path.display()
+                );
+                break;
+            }
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+/// Path of the write-ahead log `id`
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// Creates the write-ahead log `id`
+fn create_wal(dir: &Path, id: u64) -> Result<File> {
+    Ok(OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(wal_path(dir, id))?)
+}
+
+/// Ids of the files in `dir` with the given extension, in increasing order
+fn file_ids(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new(extension)) {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+impl KvsEngine for LsmKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut wal = self.shared.wal.lock().unwrap();
+        self.shared
+            .write(&mut wal, key.into_bytes(), Some(value.into_bytes()))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .shared
+            .get(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut wal = self.shared.wal.lock().unwrap();
+        if self.shared.get(key.as_bytes())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.shared.write(&mut wal, key.into_bytes(), None)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let mut wal = self.shared.wal.lock().unwrap();
+        let current = self
+            .shared
+            .get(key.as_bytes())?
+            .map(String::from_utf8)
+            .transpose()?;
+        if current != expected {
+            return Ok(Err(current));
+        }
+        self.shared
+            .write(&mut wal, key.into_bytes(), new.map(String::into_bytes))?;
+        Ok(Ok(()))
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.shared.copy(from, to, true)
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        self.shared.copy(from, to, false)
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        self.shared
+            .scan_prefix(prefix.as_bytes())?
+            .into_iter()
+            .map(|(key, _)| Ok(String::from_utf8(key)?))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.wal.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! The `ENGINE` file in a data directory, naming the engine that created the store in it so that
+//! another engine fails to open it instead of taking its files for its own or writing over them.
+
+use std::{fs, io, path::Path};
+
+use crate::{KvsError, Result};
+
+/// Name of the file naming the engine that created the store in a data directory
+const ENGINE_FILE: &str = "ENGINE";
+
+/// Name of the file `kvs-server` recorded the engine in before the engines did
+const LEGACY_ENGINE_FILE: &str = "engine";
+
+/// The engine that created the store in `dir`, `kvs`, `lsm` or `sled`, or `None` if `dir` holds no
+/// store.
+///
+/// Stores created before the engine was recorded are recognised by their files.
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{engine_of, KvStore};
+/// # use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// assert_eq!(engine_of(temp_dir.path()).unwrap(), None);
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// assert_eq!(engine_of(temp_dir.path()).unwrap().as_deref(), Some("kvs"));
+/// ```
+pub fn engine_of(dir: impl AsRef<Path>) -> Result<Option<String>> {
+    let dir = dir.as_ref();
+    for name in [ENGINE_FILE, LEGACY_ENGINE_FILE] {
+        match fs::read_to_string(dir.join(name)) {
+            Ok(engine) => return Ok(Some(engine.trim().to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        files.push(entry?.path());
+    }
+    let has = |name: &str| {
+        files
+            .iter()
+            .any(|path| path.file_name() == Some(name.as_ref()))
+    };
+    let has_extension = |extension: &str| {
+        files
+            .iter()
+            .any(|path| path.extension() == Some(extension.as_ref()))
+    };
+    let engine = if has("kvs.store") || has_extension("log") {
+        Some("kvs")
+    } else if has_extension("sst") || has_extension("wal") {
+        Some("lsm")
+    } else if has("conf") && has("db") {
+        Some("sled")
+    } else {
+        None
+    };
+    Ok(engine.map(str::to_owned))
+}
+
+/// Checks that the store in `dir` was created by `engine`, failing with
+/// [`KvsError::WrongEngine`] otherwise, and records it as the store's engine unless it is being
+/// opened `read_only`
+pub(super) fn claim(dir: &Path, engine: &str, read_only: bool) -> Result<()> {
+    if let Some(found) = engine_of(dir)? {
+        if found != engine {
+            return Err(KvsError::WrongEngine {
+                found,
+                requested: engine.to_owned(),
+            });
+        }
+    }
+    if !read_only && !dir.join(ENGINE_FILE).exists() {
+        fs::write(dir.join(ENGINE_FILE), engine)?;
+    }
+    Ok(())
+}
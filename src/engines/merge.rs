@@ -0,0 +1,156 @@
+//! Merge operands of a [`KvStore`](super::KvStore), see
+//! [`KvStore::merge`](super::KvStore::merge) and [`KvStore::append`](super::KvStore::append).
+//!
+//! A merge record only holds its operand, so the value of a key written by merges is made of a
+//! chain of records: the merges, newest first, and the set they build on, unless the key had no
+//! value before the first of them. Append records are merges whose operand is concatenated to the
+//! value, whatever the merge operator. The chains are kept in memory as links from each merge record
+//! to the record before it, and are rebuilt when the log is replayed.
+
+use crossbeam_skiplist::SkipMap;
+
+use super::{
+    kvs::RecordPos,
+    options::MergeOperator,
+    record::{Command, CommandType},
+};
+use crate::{KvsError, Result};
+
+/// A merge record, by generation and offset, and the record it builds on
+pub(super) type Link = ((u64, u64), Option<RecordPos>);
+
+/// The merge operator of a store and the chains of its merge records.
+///
+/// Links are added before the index points at their merge record, and only dropped once it no
+/// longer does, so readers can walk a chain without locking.
+pub(super) struct Merges {
+    operator: Option<MergeOperator>,
+    links: SkipMap<(u64, u64), Option<RecordPos>>,
+}
+
+impl Merges {
+    pub(super) fn new(operator: Option<MergeOperator>) -> Merges {
+        Merges {
+            operator,
+            links: SkipMap::new(),
+        }
+    }
+
+    /// Fails unless the store was opened with a merge operator
+    pub(super) fn check_operator(&self) -> Result<()> {
+        self.operator()?;
+        Ok(())
+    }
+
+    fn operator(&self) -> Result<&MergeOperator> {
+        self.operator.as_ref().ok_or_else(|| {
+            KvsError::Message(
+                "The store was opened without a merge operator, see KvStoreOptions::merge_operator"
+                    .to_owned(),
+            )
+        })
+    }
+
+    /// Folds the operand of the merge or append record `command` into `existing`, the value of its
+    /// key
+    pub(super) fn apply(&self, command: &Command, existing: Option<&[u8]>) -> Result<Vec<u8>> {
+        let operand = command.value.as_deref().unwrap_or_default();
+        match command.command_type {
+            CommandType::APPEND => Ok([existing.unwrap_or_default(), operand].concat()),
+            _ => Ok((self.operator()?.0)(&command.key, existing, operand)),
+        }
+    }
+
+    /// Folds the `records` of a chain, newest first, into the value of their key
+    pub(super) fn fold(&self, records: Vec<Command>) -> Result<Option<Vec<u8>>> {
+        let mut value = None;
+        for command in records.into_iter().rev() {
+            value = if command.command_type.is_operand() {
+                Some(self.apply(&command, value.as_deref())?)
+            } else {
+                command.value
+            };
+        }
+        Ok(value)
+    }
+
+    /// Links the merge record at `pos` to the record of its key it builds on
+    pub(super) fn link(&self, pos: RecordPos, prev: Option<RecordPos>) {
+        self.links.insert((pos.gen, pos.offset), prev);
+    }
+
+    /// Whether the record at `pos` is a linked merge or append record
+    pub(super) fn is_merge(&self, pos: RecordPos) -> bool {
+        self.links.contains_key(&(pos.gen, pos.offset))
+    }
+
+    /// The records the value at `pos` is made of, newest first, unless it isn't a merge
+    pub(super) fn chain(&self, pos: RecordPos) -> Option<Vec<RecordPos>> {
+        let mut prev = *self.links.get(&(pos.gen, pos.offset))?.value();
+        let mut chain = vec![pos];
+        while let Some(pos) = prev {
+            chain.push(pos);
+            prev = self
+                .links
+                .get(&(pos.gen, pos.offset))
+                .and_then(|entry| *entry.value());
+        }
+        Some(chain)
+    }
+
+    /// Bytes of the records the value at `pos` is made of
+    pub(super) fn chain_len(&self, pos: RecordPos) -> u64 {
+        self.chain(pos)
+            .map_or(pos.len, |chain| chain.iter().map(|pos| pos.len).sum())
+    }
+
+    /// The merge record in the chain of `head` that builds on the record at `pos`, if any
+    pub(super) fn child(&self, head: RecordPos, pos: RecordPos) -> Option<RecordPos> {
+        let chain = self.chain(head)?;
+        chain
+            .windows(2)
+            .find(|pair| pair[1] == pos)
+            .map(|pair| pair[0])
+    }
+
+    /// Drops the links of the chain of `pos`, which is no longer the value of its key, and returns
+    /// the bytes of the records it built on
+    pub(super) fn unlink(&self, pos: RecordPos) -> u64 {
+        let mut bytes = 0;
+        let mut next = self.links.remove(&(pos.gen, pos.offset));
+        while let Some(entry) = next {
+            let prev = match *entry.value() {
+                Some(prev) => prev,
+                None => break,
+            };
+            bytes += prev.len;
+            next = self.links.remove(&(prev.gen, prev.offset));
+        }
+        bytes
+    }
+
+    /// Drops every link
+    pub(super) fn clear(&self) {
+        self.links.clear();
+    }
+
+    /// The links of the chains of `heads`, to checkpoint them
+    pub(super) fn links(&self, heads: impl Iterator<Item = RecordPos>) -> Vec<Link> {
+        let mut links = Vec::new();
+        for head in heads {
+            for pos in self.chain(head).unwrap_or_default() {
+                if let Some(entry) = self.links.get(&(pos.gen, pos.offset)) {
+                    links.push((*entry.key(), *entry.value()));
+                }
+            }
+        }
+        links
+    }
+
+    /// Adds `links` read from a checkpoint
+    pub(super) fn load(&self, links: Vec<Link>) {
+        for (key, prev) in links {
+            self.links.insert(key, prev);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Migrating the log of a [`KvStore`](super::KvStore) to another record format, see
+//! [`KvStore::migrate`](super::KvStore::migrate).
+//!
+//! Every log file `<gen>.log` is rewritten into `tmp/<gen>.migrating`, and read back to check that it
+//! holds the same records. Once every file is, the `migration` manifest listing the generations is
+//! written and the new files replace the old ones. A crash before the manifest is written leaves
+//! the store as it was, the new files being deleted when it is opened; after, opening the store
+//! finishes replacing them.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Seek, SeekFrom, Write},
+    iter,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    checkpoint::Checkpoint,
+    files::{self, sync_dir, ReplaceStrategy},
+    kvs::{decode, RecordPos},
+    layout::{self, log_path},
+    record::{self, Codec, Command},
+};
+use crate::{KvsError, Result};
+
+/// Name of the manifest listing the generations whose migrated files replace them
+const MIGRATION_MANIFEST: &str = "migration";
+
+/// Extension of the file a generation is migrated into
+const MIGRATING_EXTENSION: &str = "migrating";
+
+/// Rewrites every log file of the locked store in `dir` with the records `from` decodes encoded
+/// by `to`, and replaces the files once they all read back as the records they replace
+pub(super) fn rewrite(
+    dir: &Path,
+    from: &Codec,
+    to: &Codec,
+    strategy: ReplaceStrategy,
+) -> Result<()> {
+    let gens = layout::gens(dir)?;
+    for &gen in &gens {
+        let store_id = record::read_file_header(&log_path(dir, gen))?.store_id;
+        let mut migrated = BufWriter::new(File::create(migrating_path(dir, gen))?);
+        migrated.write_all(&record::file_header(store_id, to.format()))?;
+        for record in records(dir, gen, from)? {
+            migrated.write_all(&to.encode(&record?)?)?;
+        }
+        migrated.flush()?;
+        migrated.get_ref().sync_all()?;
+    }
+
+    // nothing is replaced until every file is known to hold the same records
+    for &gen in &gens {
+        let mut migrated = frames(&migrating_path(dir, gen))?;
+        for record in records(dir, gen, from)? {
+            let record = record?;
+            let matches = match migrated.next() {
+                Some(frame) => to.decode(&frame?).is_ok_and(|command| command == record),
+                None => false,
+            };
+            if !matches {
+                return Err(mismatch(dir, gen));
+            }
+        }
+        if migrated.next().is_some() {
+            return Err(mismatch(dir, gen));
+        }
+    }
+
+    // the records move within their files, so the checkpoint of their positions is of no use
+    Checkpoint::remove(dir)?;
+    let tmp = layout::tmp_path(dir, &format!("{}.tmp", MIGRATION_MANIFEST));
+    let mut manifest = File::create(&tmp)?;
+    for gen in &gens {
+        writeln!(manifest, "{}", gen)?;
+    }
+    manifest.sync_all()?;
+    files::replace_file(&tmp, &dir.join(MIGRATION_MANIFEST), strategy)?;
+    sync_dir(dir)?;
+    finish(dir, &gens, strategy)
+}
+
+/// Cleans up after a migration of the store in `dir` that a crash interrupted: the files it
+/// migrated replace the old ones if its manifest was written, and are deleted otherwise.
+///
+/// A store opened read-only can't be recovered, so it fails if a migration has to be finished.
+pub(super) fn recover(dir: &Path, read_only: bool, strategy: ReplaceStrategy) -> Result<()> {
+    let gens = match fs::read_to_string(dir.join(MIGRATION_MANIFEST)) {
+        Ok(manifest) => manifest
+            .lines()
+            .map(|gen| {
+                gen.parse::<u64>().map_err(|_| {
+                    KvsError::Message(format!("Invalid migration manifest in {}", dir.display()))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if !read_only {
+                remove_leftovers(dir)?;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if read_only {
+        return Err(KvsError::Message(format!(
+            "{} has to finish migrating by opening it for writing first",
+            dir.display()
+        )));
+    }
+    finish(dir, &gens, strategy)
+}
+
+/// Replaces the log files of the generations `gens` with their migrated files that are left, and
+/// deletes the manifest
+fn finish(dir: &Path, gens: &[u64], strategy: ReplaceStrategy) -> Result<()> {
+    for &gen in gens {
+        let migrated = migrating_path(dir, gen);
+        if migrated.exists() {
+            files::replace_file(&migrated, &log_path(dir, gen), strategy)?;
+        }
+    }
+    sync_dir(dir)?;
+    fs::remove_file(dir.join(MIGRATION_MANIFEST))?;
+    sync_dir(dir)
+}
+
+/// Deletes the files left by a migration that didn't get as far as writing its manifest
+fn remove_leftovers(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(layout::tmp_dir(dir))? {
+        let path = entry?.path();
+        let leftover = path.extension() == Some(OsStr::new(MIGRATING_EXTENSION))
+            || path.file_name() == Some(OsStr::new(&format!("{}.tmp", MIGRATION_MANIFEST)));
+        if leftover && path.is_file() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The records of generation `gen`, decoded by `codec`
+fn records<'a>(
+    dir: &Path,
+    gen: u64,
+    codec: &'a Codec,
+) -> Result<impl Iterator<Item = Result<Command>> + 'a> {
+    let mut offset = record::FILE_HEADER_LEN;
+    Ok(frames(&log_path(dir, gen))?.map(move |frame| {
+        let frame = frame?;
+        let pos = RecordPos {
+            gen,
+            offset,
+            len: frame.len() as u64,
+            expires_at: None,
+        };
+        offset += pos.len;
+        decode(codec, &frame, pos)
+    }))
+}
+
+/// The frames of the log file at `path`, up to the first one cut short
+fn frames(path: &Path) -> Result<impl Iterator<Item = io::Result<Vec<u8>>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(record::FILE_HEADER_LEN))?;
+    Ok(iter::from_fn(move || {
+        record::read_frame(&mut reader).transpose()
+    }))
+}
+
+/// The error for a generation whose migrated file doesn't hold the records of its log file
+fn mismatch(dir: &Path, gen: u64) -> KvsError {
+    KvsError::Message(format!(
+        "{} doesn't read back as the records of {}",
+        migrating_path(dir, gen).display(),
+        log_path(dir, gen).display()
+    ))
+}
+
+/// Path generation `gen` of the store in `dir` is migrated into
+fn migrating_path(dir: &Path, gen: u64) -> PathBuf {
+    layout::tmp_path(dir, &format!("{}.{}", gen, MIGRATING_EXTENSION))
+}
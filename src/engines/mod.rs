@@ -0,0 +1,190 @@
+//! Storage engines that can back a [`KvsServer`](crate::KvsServer).
+
+use crate::{KvsError, Result};
+
+#[cfg(feature = "tokio")]
+pub use self::async_kvs::{AsyncEngine, AsyncKvStore};
+pub use self::batch::WriteBatch;
+pub use self::dump::{ExportFormat, OnConflict};
+pub use self::files::ReplaceStrategy;
+pub use self::inspect::{EngineMetrics, FsckReport, LogRecord, RecordKind, StoreStats};
+pub use self::key_codec::{KeyCodec, KeyedKvStore};
+pub use self::kvs::{CorruptRecord, KvStore, Snapshot, Tail};
+#[cfg(feature = "metrics")]
+pub use self::latency::{LatencyHistogram, LatencyStats};
+pub use self::listener::{CompactionEnd, CompactionListener, CompactionStart};
+pub use self::lsm::{LsmKvStore, LsmOptions};
+pub use self::marker::engine_of;
+pub use self::options::{
+    CompactionStrategy, CompactionTrigger, Compression, KvStoreOptions, RecordFormat, SyncPolicy,
+    VersionRetention, WalArchive, WriteStall,
+};
+pub use self::sled::SledKvsEngine;
+pub use self::tail::{ChangeBatch, ChangeRecord, LogPosition};
+pub use self::typed::TypedKvStore;
+pub use self::version::KeyVersion;
+pub use self::watch::{WatchEvent, WatchOp};
+
+mod archive;
+#[cfg(feature = "tokio")]
+mod async_kvs;
+mod backup;
+mod batch;
+mod bloom;
+mod cache;
+mod checkpoint;
+mod dump;
+mod files;
+pub(crate) mod glob;
+mod inspect;
+mod key_codec;
+mod kvs;
+mod latency;
+mod layout;
+mod listener;
+mod log_writer;
+mod lsm;
+mod marker;
+mod merge;
+mod migrate;
+mod options;
+mod rate_limit;
+mod record;
+mod record_codec;
+mod secondary;
+mod sled;
+mod sstable;
+mod tail;
+mod typed;
+mod version;
+mod watch;
+
+/// The operations every storage engine provides.
+///
+/// The server and embedders can be written against this trait so the backend can be swapped
+/// without code changes. Engines are cheap to clone, and every clone is a handle to the same store
+/// that can be moved to or shared with other threads, so a server can hand one to each worker.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a key, overwriting any previous value
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Gets the value of a key, or `None` if the key does not exist
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets the values of several keys at once, in the order of `keys`
+    fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Removes a key. Fails if the key does not exist.
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Atomically replaces the value of a key with `new` if its current value is `expected`.
+    ///
+    /// `None` stands for the key not existing, both as `expected` and as `new`, which removes the
+    /// key. If the current value is not `expected`, nothing is written and it is returned as the
+    /// error.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>>;
+
+    /// Moves the value of `from` to the key `to`, overwriting any value of `to`, atomically. Fails if
+    /// `from` does not exist.
+    fn rename(&self, from: String, to: String) -> Result<()>;
+
+    /// Copies the value of `from` to the key `to`, overwriting any value of `to`, atomically. Fails
+    /// if `from` does not exist.
+    fn copy(&self, from: String, to: String) -> Result<()>;
+
+    /// Sets the value of a key and returns the value it replaced, or `None` if the key did not
+    /// exist, atomically
+    fn get_and_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut current = self.get(key.clone())?;
+        loop {
+            match self.compare_and_swap(key.clone(), current.clone(), Some(value.clone()))? {
+                Ok(()) => return Ok(current),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Removes a key if its value is `expected`, and tells whether it did, atomically. Releases a
+    /// lock only if it is still held by the caller, for instance.
+    fn remove_if(&self, key: String, expected: String) -> Result<bool> {
+        Ok(self.compare_and_swap(key, Some(expected), None)?.is_ok())
+    }
+
+    /// Sets the value of a key unless it already exists, and tells whether it did, atomically
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        Ok(self.compare_and_swap(key, None, Some(value))?.is_ok())
+    }
+
+    /// Gets the value of a key, setting it to the value returned by `f` first if the key does not
+    /// exist.
+    ///
+    /// When several callers race to create the key, a single value is set and returned to all of
+    /// them, though `f` may run for each.
+    fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        match self.compare_and_swap(key, None, Some(value.clone()))? {
+            Ok(()) => Ok(value),
+            // the swap only fails if another value was set
+            Err(current) => Ok(current.unwrap_or_default()),
+        }
+    }
+
+    /// Lists the keys starting with `prefix` in lexical order
+    fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Lists up to `limit` keys in lexical order, starting after `after`, or from the first key if
+    /// it is `None`, so that the keys can be listed a page at a time
+    fn keys_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        Ok(self
+            .keys_with_prefix("")?
+            .into_iter()
+            .filter(|key| after.is_none_or(|after| key.as_str() > after))
+            .take(limit)
+            .collect())
+    }
+
+    /// Hands any writes the engine buffers in memory to the operating system, so they survive the
+    /// process exiting
+    fn flush(&self) -> Result<()>;
+
+    /// Applies every write in `batch` atomically, failing with [`KvsError::KeyNotFound`], without
+    /// writing anything, if it removes a key that doesn't exist at that point of the batch.
+    /// Engines that can't apply writes atomically fail.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _ = batch;
+        Err(KvsError::Message(
+            "The engine doesn't support atomic batches".to_owned(),
+        ))
+    }
+
+    /// Counters of the work the engine did since it was opened, for monitoring. Engines that don't
+    /// keep them report zeros.
+    fn metrics(&self) -> EngineMetrics {
+        EngineMetrics::default()
+    }
+
+    /// Reads up to `limit` of the changes made to the store from `from` on, for a follower that
+    /// replicated the store with UUID `store_id` up to there, or none yet. Engines that can't be
+    /// replicated fail.
+    fn changes(
+        &self,
+        store_id: Option<&str>,
+        from: LogPosition,
+        limit: usize,
+    ) -> Result<ChangeBatch> {
+        let _ = (store_id, from, limit);
+        Err(KvsError::Message(
+            "The engine doesn't support replication".to_owned(),
+        ))
+    }
+}
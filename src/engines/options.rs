@@ -0,0 +1,587 @@
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use super::{
+    listener::{CompactionListener, Listener},
+    ReplaceStrategy,
+};
+
+/// Options for opening a [`KvStore`](super::KvStore) with
+/// [`KvStore::open_with`](super::KvStore::open_with)
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions};
+/// # use tempfile::TempDir;
+///
+/// let options = KvStoreOptions::new().skip_corrupt(true);
+/// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    pub(crate) read_only: bool,
+    pub(crate) skip_corrupt: bool,
+    pub(crate) strict: bool,
+    pub(crate) record_format: RecordFormat,
+    pub(crate) compression: Compression,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) previous_encryption_keys: Vec<EncryptionKey>,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) expiry_sweep_interval: Option<Duration>,
+    pub(crate) checkpoint_interval: Option<Duration>,
+    pub(crate) compaction_trigger: CompactionTrigger,
+    pub(crate) compaction_strategy: CompactionStrategy,
+    pub(crate) write_stall: Option<WriteStall>,
+    pub(crate) compaction_rate_limit: Option<u64>,
+    pub(crate) segment_size: Option<u64>,
+    pub(crate) cache_capacity: u64,
+    pub(crate) merge_operator: Option<MergeOperator>,
+    pub(crate) version_retention: VersionRetention,
+    pub(crate) wal_archive: Option<WalArchive>,
+    pub(crate) replace_strategy: ReplaceStrategy,
+    pub(crate) slow_op_threshold: Option<Duration>,
+    pub(crate) compaction_listener: Option<Listener>,
+}
+
+/// A 256-bit key for encrypting log records, kept out of `Debug` output
+#[derive(Clone)]
+pub(crate) struct EncryptionKey(pub(crate) [u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// The function a [`MergeOperator`] calls
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// Folds an operand into the existing value of a key, see
+/// [`KvStoreOptions::merge_operator`]
+#[derive(Clone)]
+pub(crate) struct MergeOperator(pub(crate) Arc<MergeFn>);
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator(..)")
+    }
+}
+
+/// How the records of a [`KvStore`](super::KvStore) are serialized, see
+/// [`KvStoreOptions::record_format`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordFormat {
+    /// Compact binary encoding with bincode
+    #[default]
+    Bincode,
+    /// JSON objects, readable with any JSON tool once decompressed and decrypted
+    Json,
+    /// MessagePack arrays
+    MessagePack,
+    /// CBOR arrays
+    Cbor,
+}
+
+/// How records are compressed before being appended to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Records are stored as they are
+    #[default]
+    None,
+    /// Fast compression with a moderate ratio
+    Snappy,
+    /// Slower compression with a better ratio
+    Zstd,
+}
+
+/// When a [`KvStore`](super::KvStore) compacts its log on its own: once stale records take more
+/// than `stale_ratio` of it, unless it is smaller than `min_log_bytes`.
+///
+/// Bytes are counted rather than records, as a few large stale values are worth compacting sooner
+/// than many small ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionTrigger {
+    /// Share of the log taken by overwritten and removed records, and markers, from 0 to 1
+    pub stale_ratio: f64,
+    /// Size of the log below which it isn't compacted, however much of it is stale
+    pub min_log_bytes: u64,
+}
+
+impl Default for CompactionTrigger {
+    /// Compacts once 40% of a log of at least 1 MiB is stale
+    fn default() -> Self {
+        CompactionTrigger {
+            stale_ratio: 0.4,
+            min_log_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// How much of the log a compaction a [`KvStore`](super::KvStore) starts on its own rewrites, see
+/// [`KvStoreOptions::compaction_strategy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStrategy {
+    /// Every generation is rewritten into one, reclaiming all the garbage at once
+    #[default]
+    Full,
+    /// Only the generations with the highest share of stale bytes are rewritten, as many as fit
+    /// in `max_bytes`, or the one with the most if none does
+    Partial {
+        /// Bytes of generations a compaction rewrites at most
+        max_bytes: u64,
+    },
+}
+
+/// How a [`KvStore`](super::KvStore) holds back writes while a compaction can't keep up with
+/// the garbage they produce, see [`KvStoreOptions::write_stall`].
+///
+/// Only the stale bytes written since the running compaction started count, as the ones before
+/// are being reclaimed. Without a running compaction writes aren't held back, and start one as
+/// usual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStall {
+    /// Stale bytes past which every write is delayed by `delay`
+    pub slowdown_bytes: u64,
+    /// How long each write is delayed past `slowdown_bytes`
+    pub delay: Duration,
+    /// Stale bytes past which writes wait until the compaction is done
+    pub stop_bytes: u64,
+}
+
+impl Default for WriteStall {
+    /// Delays writes by a millisecond past 64 MiB of garbage, and stops them past 256 MiB
+    fn default() -> Self {
+        WriteStall {
+            slowdown_bytes: 64 * 1024 * 1024,
+            delay: Duration::from_millis(1),
+            stop_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Which of the versions that were overwritten or removed compaction keeps, so that
+/// [`KvStore::get_at`](super::KvStore::get_at) and [`KvStore::history`](super::KvStore::history)
+/// can still read them.
+///
+/// Versions compaction didn't get to yet are readable whatever the retention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VersionRetention {
+    /// Only the current values are kept
+    #[default]
+    Latest,
+    /// The versions written in the last `Duration` are kept, along with the value each key had
+    /// before then
+    Age(Duration),
+    /// Every version is kept
+    All,
+}
+
+/// Where the generations of the log go once they are no longer needed, and how many of them are
+/// kept there, see [`KvStoreOptions::wal_archive`].
+///
+/// The oldest archived generations are deleted once there are more than `max_segments` of them,
+/// they take more than `max_bytes`, or they were last written to longer than `max_age` ago.
+/// Without any of the three, every generation is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WalArchive {
+    /// Directory the generations are moved to, `archive/` in the store by default. It must not
+    /// be shared with another store, as each one numbers its generations from 1.
+    pub dir: Option<PathBuf>,
+    /// Most archived generations kept
+    pub max_segments: Option<usize>,
+    /// Most bytes the archived generations take
+    pub max_bytes: Option<u64>,
+    /// How long an archived generation is kept after it was last written to
+    pub max_age: Option<Duration>,
+}
+
+/// When the log is synced to disk after writes.
+///
+/// Syncing makes writes survive a power loss or an operating system crash, at the cost of waiting
+/// for the disk. Whatever the policy, [`KvStore::sync_all`](super::KvStore::sync_all) syncs on
+/// demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Every write is synced before it returns
+    Always,
+    /// The log is synced after every `n` writes
+    EveryNWrites(u64),
+    /// A background thread syncs the log this often, if it was written to
+    Interval(Duration),
+    /// The log is never synced: writes are only buffered, and flushed when the buffer fills up, on
+    /// [`KvsEngine::flush`](super::KvsEngine::flush) or when the store is dropped
+    #[default]
+    Never,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options, which are the ones [`KvStore::open`](super::KvStore::open)
+    /// uses
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Opens the store for reading only, see [`KvStore::open_read_only`](super::KvStore::open_read_only)
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Skips records failing their checksum when replaying the log, instead of refusing to open.
+    ///
+    /// Skipped records are lost: the key keeps its previous value, if any. Use this to recover a
+    /// store whose log was damaged on disk.
+    pub fn skip_corrupt(mut self, skip_corrupt: bool) -> Self {
+        self.skip_corrupt = skip_corrupt;
+        self
+    }
+
+    /// Refuses to open a store whose log ends with a record cut short by a crash in the middle of
+    /// a write, failing with [`KvsError::Corruption`](crate::KvsError::Corruption).
+    ///
+    /// By default the torn record, which was never acknowledged as written, is truncated away with
+    /// a warning.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Serializes the records of a new store in `format`, bincode by default.
+    ///
+    /// The format is recorded in the header of every log file, and a store keeps the one it was
+    /// created with: opening an existing store reads it from there, whatever this is set to.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, RecordFormat};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().record_format(RecordFormat::Cbor);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn record_format(mut self, format: RecordFormat) -> Self {
+        self.record_format = format;
+        self
+    }
+
+    /// Compresses records written from now on.
+    ///
+    /// Records are decompressed transparently, whatever compression they were written with, so
+    /// this can be changed between opens. Records that don't shrink are stored uncompressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts records written from now on with ChaCha20-Poly1305 under `key`.
+    ///
+    /// Records already in the log stay as they are. To rotate keys, open the store with the new
+    /// key plus the old one as a [`previous_encryption_key`](Self::previous_encryption_key), then
+    /// call [`KvStore::reencrypt`](super::KvStore::reencrypt).
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_key = Some(EncryptionKey(key));
+        self
+    }
+
+    /// Adds a key that only decrypts records, for records written before the last key rotation.
+    /// Can be given several times.
+    pub fn previous_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.previous_encryption_keys.push(EncryptionKey(key));
+        self
+    }
+
+    /// Sets when the log is synced to disk after writes
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+    /// # use std::time::Duration;
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_secs(1)));
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets when the log is compacted on its own.
+    ///
+    /// [`KvStore::compact`](super::KvStore::compact) compacts it on demand whatever the trigger.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{CompactionTrigger, KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let trigger = CompactionTrigger { stale_ratio: 0.6, min_log_bytes: 64 * 1024 * 1024 };
+    /// let options = KvStoreOptions::new().compaction_trigger(trigger);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// Sets how much of the log a compaction started in the background rewrites. Along with
+    /// [`segment_size`](Self::segment_size), [`CompactionStrategy::Partial`] only rewrites the
+    /// generations with the most garbage, so a compaction takes about as long however large the
+    /// store grows, and the next ones deal with the rest. Every generation is rewritten by default.
+    ///
+    /// [`KvStore::compact`](super::KvStore::compact) still rewrites the whole log, and so does
+    /// every compaction of a store keeping older versions with
+    /// [`version_retention`](Self::version_retention).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{CompactionStrategy, KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new()
+    ///     .segment_size(64 * 1024 * 1024)
+    ///     .compaction_strategy(CompactionStrategy::Partial {
+    ///         max_bytes: 256 * 1024 * 1024,
+    ///     });
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction_strategy = strategy;
+        self
+    }
+
+    /// Applies backpressure to writers once the garbage they write while a compaction runs
+    /// crosses the thresholds of `stall`, so the log can't outgrow compaction without bound.
+    /// Writes are never held back by default.
+    ///
+    /// [`KvsEngine::metrics`](super::KvsEngine::metrics) counts the stalled writes and the time
+    /// they waited.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, WriteStall};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().write_stall(WriteStall::default());
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn write_stall(mut self, stall: WriteStall) -> Self {
+        self.write_stall = Some(stall);
+        self
+    }
+
+    /// Limits the bytes a compaction reads and writes to `bytes_per_sec`, so that compacting in
+    /// the background doesn't take the disk away from reads and writes on a slow disk, at the
+    /// cost of compactions taking longer. Compactions run as fast as they can by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_sec` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().compaction_rate_limit(16 * 1024 * 1024);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "compaction rate limit must not be zero");
+        self.compaction_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Moves writes to a new generation once the active one holds `bytes`, so that no log file
+    /// grows without bound between compactions. Opening the store goes on appending to its newest
+    /// generation unless it is full. By default a generation grows until the next compaction.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().segment_size(64 * 1024 * 1024);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Keeps up to `bytes` of recently read keys and values in memory, so that reading a hot key
+    /// again doesn't read the log. The cache is disabled by default.
+    ///
+    /// [`KvStore::stats`](super::KvStore::stats) counts its hits and misses.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().cache_capacity(64 * 1024 * 1024);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Sets which overwritten and removed versions compaction keeps for
+    /// [`KvStore::get_at`](super::KvStore::get_at) and [`KvStore::history`](super::KvStore::history).
+    /// By default only the current values are kept.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, VersionRetention};
+    /// # use std::time::Duration;
+    /// # use tempfile::TempDir;
+    ///
+    /// let retention = VersionRetention::Age(Duration::from_secs(24 * 60 * 60));
+    /// let options = KvStoreOptions::new().version_retention(retention);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn version_retention(mut self, retention: VersionRetention) -> Self {
+        self.version_retention = retention;
+        self
+    }
+
+    /// Sets the function [`KvStore::merge`](super::KvStore::merge) folds operands into values
+    /// with. It is called with the key, its existing value, unless it has none, and the operand,
+    /// and returns the new value.
+    ///
+    /// Operands are only folded in when the key is read or compacted, so the function must give
+    /// the same result every time, and a store holding merges has to be opened with it again.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// // appends the operand to a comma-separated list
+    /// let options = KvStoreOptions::new().merge_operator(|_key, existing, operand| {
+    ///     match existing {
+    ///         Some(existing) => [existing, b",", operand].concat(),
+    ///         None => operand.to_vec(),
+    ///     }
+    /// });
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn merge_operator<F>(mut self, operator: F) -> Self
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.merge_operator = Some(MergeOperator(Arc::new(operator)));
+        self
+    }
+
+    /// Moves the generations that compaction replaced, or that
+    /// [`KvStore::clear`](super::KvStore::clear) emptied, to an archive directory instead of
+    /// deleting them, and deletes the oldest archived ones past the retention of `archive`.
+    ///
+    /// Archived generations are log files like the ones of the store, so they keep the history of
+    /// its writes for point-in-time recovery: a directory holding generations 1 to `n` of the
+    /// archive in its `segments/` opens as a store as it was when generation `n` was last written
+    /// to. Namespaces archive their generations in the `archive/` directory of their own, or in
+    /// the `namespaces/<name>/` subdirectory of the one set in `archive`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions, WalArchive};
+    /// # use std::time::Duration;
+    /// # use tempfile::TempDir;
+    ///
+    /// let archive = WalArchive {
+    ///     max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+    ///     ..WalArchive::default()
+    /// };
+    /// let options = KvStoreOptions::new().wal_archive(archive);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn wal_archive(mut self, archive: WalArchive) -> Self {
+        self.wal_archive = Some(archive);
+        self
+    }
+
+    /// Removes expired keys every `interval` on a background thread, instead of leaving their
+    /// records in the log until compaction.
+    ///
+    /// See [`KvStore::sweep_expired`](super::KvStore::sweep_expired).
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiry_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Checkpoints the index every `interval` on a background thread, so that opening the store
+    /// after a crash only replays the log written since the last checkpoint. The index is always
+    /// checkpointed when the store is closed.
+    ///
+    /// See [`KvStore::checkpoint`](super::KvStore::checkpoint).
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Sets how the files the store rewrites, such as the checkpoint of the index, replace their
+    /// previous version. The default suits the platform, so this is mostly useful to test the
+    /// Windows code path elsewhere.
+    pub fn replace_strategy(mut self, strategy: ReplaceStrategy) -> Self {
+        self.replace_strategy = strategy;
+        self
+    }
+
+    /// Logs a warning for every get, set, removal and compaction taking longer than `threshold`,
+    /// with the key or the generation compacted into and the time taken, to diagnose stalls in
+    /// production. Nothing is logged by default.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvStoreOptions};
+    /// # use std::time::Duration;
+    /// # use tempfile::TempDir;
+    ///
+    /// let options = KvStoreOptions::new().slow_op_threshold(Duration::from_millis(50));
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn slow_op_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Notifies `listener` when a compaction starts and ends, with the bytes it reclaimed and the
+    /// time it took. Namespaces of the store notify it too.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{CompactionEnd, CompactionListener, KvStore, KvStoreOptions};
+    /// # use tempfile::TempDir;
+    ///
+    /// struct LogCompactions;
+    ///
+    /// impl CompactionListener for LogCompactions {
+    ///     fn on_compaction_end(&self, event: &CompactionEnd) {
+    ///         println!("compaction took {:?}: {:?}", event.duration, event.result);
+    ///     }
+    /// }
+    ///
+    /// let options = KvStoreOptions::new().compaction_listener(LogCompactions);
+    /// let store = KvStore::open_with(TempDir::new().unwrap().path(), options).unwrap();
+    /// ```
+    pub fn compaction_listener(mut self, listener: impl CompactionListener) -> Self {
+        self.compaction_listener = Some(Listener(Arc::new(listener)));
+        self
+    }
+}
@@ -0,0 +1,41 @@
+//! Pacing the disk traffic of compaction, see
+//! [`KvStoreOptions::compaction_rate_limit`](super::KvStoreOptions::compaction_rate_limit).
+
+use std::{
+    cell::Cell,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Holds a compaction to a number of bytes read and written per second, by sleeping whenever it
+/// gets ahead of that rate.
+///
+/// The rate is averaged from the start of the compaction, which reads and writes without pause,
+/// so it never builds up a burst of unused bytes.
+pub(super) struct RateLimiter {
+    bytes_per_sec: u64,
+    start: Instant,
+    /// Bytes read and written so far
+    bytes: Cell<u64>,
+}
+
+impl RateLimiter {
+    /// Starts pacing at `bytes_per_sec`, which must not be zero
+    pub(super) fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: Cell::new(0),
+        }
+    }
+
+    /// Counts `bytes` that were read or written, sleeping until they fit within the rate
+    pub(super) fn consume(&self, bytes: u64) {
+        let bytes = self.bytes.get() + bytes;
+        self.bytes.set(bytes);
+        let due = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! The on-disk format of [`KvStore`](super::KvStore) log files.
+//!
+//! Every log file starts with a header: the magic bytes `KVSLOG\0\0`, the little-endian `u16`
+//! format version, the little-endian `u16` number of the [`RecordFormat`] of its records and the 16
+//! byte UUID of the store the file belongs to. Files from before record formats could be chosen
+//! have a `u32` format version instead, which reads as bincode records.
+//!
+//! The header is followed by the records. Every record is a frame made of a little-endian `u32`
+//! payload length, the little-endian CRC32 of the payload, and the payload itself: a [`Command`]
+//! serialized in the record format of the file, followed by the little-endian `u64` version of the
+//! write. Records written
+//! before versions were recorded end with the command, and builds from before then ignore the
+//! version. Records are appended one after another with no separators. The records of
+//! a batch are enclosed in `BEGIN` and `COMMIT` marker records, and are ignored on replay unless
+//! the `COMMIT` marker follows them. A `CLEAR` marker record drops every key written before it, and
+//! an `RMRANGE` record the keys from its key on, up to its value if it has one. A
+//! `MERGE` record holds an operand to fold into the value of its key, see
+//! [`KvStore::merge`](super::KvStore::merge), and an `APPEND` record bytes to append to it, see
+//! [`KvStore::append`](super::KvStore::append).
+//!
+//! The top two bits of the length word name the [`Compression`] of the payload, the next bit is set
+//! if the payload is encrypted, and the one after that if the command expires, so payloads are
+//! limited to 256 MiB. An encrypted payload is a random 12 byte nonce followed by the (compressed)
+//! command sealed with ChaCha20-Poly1305. The command of an expiring record is preceded by its
+//! expiry time, in little-endian `u64` milliseconds since the Unix epoch, before it is compressed.
+//!
+//! Keys are arbitrary bytes, encoded the same way as strings, which is what keys used to be.
+//!
+//! Version 1 of the format had no expiring records, version 2 had no merge records, version 3 had
+//! no append records, and version 4 had no range removals.
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use uuid::Uuid;
+
+use super::{
+    files::{self, ReplaceStrategy},
+    Compression, KvStoreOptions, RecordFormat,
+};
+use crate::{KvsError, Result};
+
+/// Magic bytes every log file starts with
+const MAGIC: &[u8; 8] = b"KVSLOG\0\0";
+
+/// Version of the format this build writes, and the newest it can read
+const FORMAT_VERSION: u16 = 5;
+
+/// Length of the header at the start of every log file
+pub(super) const FILE_HEADER_LEN: u64 = 28;
+
+/// Length of the frame header preceding every payload
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Bits of the length word holding the payload length; the others describe the payload
+const LEN_MASK: u32 = (1 << 28) - 1;
+
+/// Bit of the length word set when the payload is encrypted
+const ENCRYPTED: u32 = 1 << 29;
+
+/// Bit of the length word set when the command is preceded by its expiry time
+const EXPIRES: u32 = 1 << 28;
+
+/// Length of the nonce preceding an encrypted payload
+const NONCE_LEN: usize = 12;
+
+/// A container for storing commands
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Command {
+    pub(super) key: Vec<u8>,
+    pub(super) value: Option<Vec<u8>>,
+    pub(super) command_type: CommandType,
+    /// When a set expires, in milliseconds since the Unix epoch. Stored in front of the command.
+    #[serde(skip)]
+    pub(super) expires_at: Option<u64>,
+    /// Version of the write, see [`KvStore::version`](super::KvStore::version). Stored after the
+    /// command, unless the record was written before versions were recorded.
+    #[serde(skip)]
+    pub(super) version: Option<u64>,
+}
+
+impl Command {
+    /// A command setting `key` to `value`
+    pub(super) fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command {
+            key,
+            value: Some(value),
+            command_type: CommandType::SET,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A command removing `key`
+    pub(super) fn remove(key: Vec<u8>) -> Command {
+        Command {
+            key,
+            value: None,
+            command_type: CommandType::RM,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A command removing the keys from `start` on, up to `end` excluded if there is one
+    pub(super) fn remove_range(start: Vec<u8>, end: Option<Vec<u8>>) -> Command {
+        Command {
+            key: start,
+            value: end,
+            command_type: CommandType::RMRANGE,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A command merging `operand` into the value of `key`
+    pub(super) fn merge(key: Vec<u8>, operand: Vec<u8>) -> Command {
+        Command {
+            key,
+            value: Some(operand),
+            command_type: CommandType::MERGE,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A command appending `suffix` to the value of `key`
+    pub(super) fn append(key: Vec<u8>, suffix: Vec<u8>) -> Command {
+        Command {
+            key,
+            value: Some(suffix),
+            command_type: CommandType::APPEND,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A marker opening a batch: the records up to the next [`Command::commit`] only count if
+    /// that marker made it to the log
+    pub(super) fn begin() -> Command {
+        Command {
+            key: Vec::new(),
+            value: None,
+            command_type: CommandType::BEGIN,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A marker closing a batch
+    pub(super) fn commit() -> Command {
+        Command {
+            key: Vec::new(),
+            value: None,
+            command_type: CommandType::COMMIT,
+            expires_at: None,
+            version: None,
+        }
+    }
+
+    /// A marker removing every key written before it
+    pub(super) fn clear() -> Command {
+        Command {
+            key: Vec::new(),
+            value: None,
+            command_type: CommandType::CLEAR,
+            expires_at: None,
+            version: None,
+        }
+    }
+}
+
+/// A record of the JSON log format, in which values could only be strings
+#[derive(Deserialize)]
+struct LegacyCommand {
+    key: String,
+    value: Option<String>,
+    command_type: CommandType,
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(legacy: LegacyCommand) -> Command {
+        Command {
+            key: legacy.key.into_bytes(),
+            value: legacy.value.map(String::into_bytes),
+            command_type: legacy.command_type,
+            expires_at: None,
+            version: None,
+        }
+    }
+}
+
+/// Command type to identify the commands
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub(super) enum CommandType {
+    SET,
+    GET,
+    RM,
+    BEGIN,
+    COMMIT,
+    CLEAR,
+    MERGE,
+    APPEND,
+    RMRANGE,
+}
+
+impl CommandType {
+    pub(super) const ALL: [CommandType; 9] = [
+        CommandType::SET,
+        CommandType::GET,
+        CommandType::RM,
+        CommandType::BEGIN,
+        CommandType::COMMIT,
+        CommandType::CLEAR,
+        CommandType::MERGE,
+        CommandType::APPEND,
+        CommandType::RMRANGE,
+    ];
+
+    /// The name of the command type, as serde serializes it
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            CommandType::SET => "SET",
+            CommandType::GET => "GET",
+            CommandType::RM => "RM",
+            CommandType::BEGIN => "BEGIN",
+            CommandType::COMMIT => "COMMIT",
+            CommandType::CLEAR => "CLEAR",
+            CommandType::MERGE => "MERGE",
+            CommandType::APPEND => "APPEND",
+            CommandType::RMRANGE => "RMRANGE",
+        }
+    }
+
+    /// Whether the record holds an operand folded into the value of its key, rather than a value
+    pub(super) fn is_operand(self) -> bool {
+        matches!(self, CommandType::MERGE | CommandType::APPEND)
+    }
+}
+
+/// Why a frame couldn't be decoded
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum DecodeError {
+    /// The frame doesn't match its checksum or doesn't decode
+    Corrupt,
+    /// The payload is encrypted, but none of the keys opens it
+    NoKey,
+}
+
+/// Encodes and decodes frames with the record format of a store and the compression and
+/// encryption keys it was opened with
+pub(super) struct Codec {
+    format: RecordFormat,
+    compression: Compression,
+    cipher: Option<ChaCha20Poly1305>,
+    previous_ciphers: Vec<ChaCha20Poly1305>,
+}
+
+impl Codec {
+    pub(super) fn new(options: &KvStoreOptions) -> Codec {
+        let cipher = |key: &[u8; 32]| ChaCha20Poly1305::new(key.into());
+        Codec {
+            format: options.record_format,
+            compression: options.compression,
+            cipher: options.encryption_key.as_ref().map(|key| cipher(&key.0)),
+            previous_ciphers: options
+                .previous_encryption_keys
+                .iter()
+                .map(|key| cipher(&key.0))
+                .collect(),
+        }
+    }
+
+    /// The record format of the store
+    pub(super) fn format(&self) -> RecordFormat {
+        self.format
+    }
+
+    /// Encodes `command` into a frame ready to be appended to the log
+    pub(super) fn encode(&self, command: &Command) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        let mut flags = 0;
+        if let Some(expires_at) = command.expires_at {
+            payload.extend_from_slice(&expires_at.to_le_bytes());
+            flags |= EXPIRES;
+        }
+        self.format.codec().serialize(command, &mut payload)?;
+        if let Some(version) = command.version {
+            payload.extend_from_slice(&version.to_le_bytes());
+        }
+        let mut codec = Compression::None;
+        if self.compression != Compression::None {
+            let compressed = compress(&payload, self.compression)?;
+            if compressed.len() < payload.len() {
+                payload = compressed;
+                codec = self.compression;
+            }
+        }
+        flags |= codec_bits(codec) << 30;
+        if let Some(cipher) = &self.cipher {
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let sealed = cipher
+                .encrypt(&nonce, payload.as_slice())
+                .map_err(|_| KvsError::Message("Failed to encrypt record".to_owned()))?;
+            payload = nonce.to_vec();
+            payload.extend_from_slice(&sealed);
+            flags |= ENCRYPTED;
+        }
+        if payload.len() > LEN_MASK as usize {
+            return Err(KvsError::Message("Record too large".to_owned()));
+        }
+        let len = payload.len() as u32 | flags;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decodes a whole frame
+    pub(super) fn decode(&self, frame: &[u8]) -> std::result::Result<Command, DecodeError> {
+        if frame.len() < FRAME_HEADER_LEN {
+            return Err(DecodeError::Corrupt);
+        }
+        let (header, payload) = frame.split_at(FRAME_HEADER_LEN);
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if crc32fast::hash(payload) != crc {
+            return Err(DecodeError::Corrupt);
+        }
+        let opened;
+        let payload = if len & ENCRYPTED != 0 {
+            if payload.len() < NONCE_LEN {
+                return Err(DecodeError::Corrupt);
+            }
+            let (nonce, sealed) = payload.split_at(NONCE_LEN);
+            // the checksum matched, so a payload no key opens was sealed with another key
+            opened = self
+                .cipher
+                .iter()
+                .chain(&self.previous_ciphers)
+                .find_map(|cipher| cipher.decrypt(Nonce::from_slice(nonce), sealed).ok())
+                .ok_or(DecodeError::NoKey)?;
+            &opened[..]
+        } else {
+            payload
+        };
+        let payload = match codec(len >> 30).ok_or(DecodeError::Corrupt)? {
+            Compression::None => Cow::Borrowed(payload),
+            Compression::Snappy => Cow::Owned(
+                snap::raw::Decoder::new()
+                    .decompress_vec(payload)
+                    .map_err(|_| DecodeError::Corrupt)?,
+            ),
+            Compression::Zstd => {
+                Cow::Owned(zstd::stream::decode_all(payload).map_err(|_| DecodeError::Corrupt)?)
+            }
+        };
+        let (expires_at, payload) = if len & EXPIRES != 0 {
+            if payload.len() < 8 {
+                return Err(DecodeError::Corrupt);
+            }
+            let (expires_at, payload) = payload.split_at(8);
+            (
+                Some(u64::from_le_bytes(expires_at.try_into().unwrap())),
+                payload,
+            )
+        } else {
+            (None, &payload[..])
+        };
+        let mut rest = payload;
+        let mut command = self
+            .format
+            .codec()
+            .deserialize(&mut rest)
+            .map_err(|_| DecodeError::Corrupt)?;
+        command.expires_at = expires_at;
+        command.version = match rest.len() {
+            0 => None,
+            8 => Some(u64::from_le_bytes(rest.try_into().unwrap())),
+            _ => return Err(DecodeError::Corrupt),
+        };
+        Ok(command)
+    }
+}
+
+/// Compresses `payload` with `compression`
+fn compress(payload: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    Ok(match compression {
+        Compression::None => payload.to_vec(),
+        Compression::Snappy => snap::raw::Encoder::new().compress_vec(payload)?,
+        Compression::Zstd => zstd::stream::encode_all(payload, 0)?,
+    })
+}
+
+/// The value stored in the top bits of the length word for `compression`
+fn codec_bits(compression: Compression) -> u32 {
+    match compression {
+        Compression::None => 0,
+        Compression::Snappy => 1,
+        Compression::Zstd => 2,
+    }
+}
+
+/// The compression named by the top bits of the length word
+fn codec(bits: u32) -> Option<Compression> {
+    match bits {
+        0 => Some(Compression::None),
+        1 => Some(Compression::Snappy),
+        2 => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+/// Reads the next whole frame from `reader`.
+///
+/// Returns `None` at the end of the log, including when the last frame was cut short by a crash
+/// in the middle of a write.
+pub(super) fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut frame = vec![0; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut frame) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = (u32::from_le_bytes(frame[..4].try_into().unwrap()) & LEN_MASK) as u64;
+    // read through `take` so a damaged length can't make us allocate gigabytes up front
+    let read = reader.take(len).read_to_end(&mut frame)?;
+    if (read as u64) < len {
+        return Ok(None);
+    }
+    Ok(Some(frame))
+}
+
+/// What the header of a log file says
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct FileHeader {
+    /// The store the file belongs to
+    pub(super) store_id: Uuid,
+    /// How the commands of its records are serialized
+    pub(super) format: RecordFormat,
+}
+
+/// The header of a log file belonging to the store `store_id`, holding records in `format`
+pub(super) fn file_header(store_id: Uuid, format: RecordFormat) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&format.id().to_le_bytes());
+    header.extend_from_slice(store_id.as_bytes());
+    header
+}
+
+/// Reads and validates the header of the log file at `path`
+pub(super) fn read_file_header(path: &Path) -> Result<FileHeader> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+    File::open(path)?
+        .take(FILE_HEADER_LEN)
+        .read_to_end(&mut header)?;
+    if header.len() < FILE_HEADER_LEN as usize || header[..8] != MAGIC[..] {
+        return Err(KvsError::Message(format!(
+            "{} is not a kvs log file",
+            path.display()
+        )));
+    }
+    let version = u16::from_le_bytes(header[8..10].try_into().unwrap());
+    let format = u16::from_le_bytes(header[10..12].try_into().unwrap());
+    if version > FORMAT_VERSION {
+        return Err(KvsError::Message(format!(
+            "{} has format version {}, but only versions up to {} are supported",
+            path.display(),
+            version,
+            FORMAT_VERSION
+        )));
+    }
+    let format = RecordFormat::from_id(format).ok_or_else(|| {
+        KvsError::Message(format!(
+            "{} has unknown record format {}",
+            path.display(),
+            format
+        ))
+    })?;
+    Ok(FileHeader {
+        store_id: Uuid::from_bytes(header[12..].try_into().unwrap()),
+        format,
+    })
+}
+
+/// Whether the log file at `path` holds concatenated JSON records, the format used before frames
+pub(super) fn is_legacy(path: &Path) -> io::Result<bool> {
+    let mut start = [0; 6];
+    let read = File::open(path)?.read(&mut start)?;
+    Ok(start[..read] == br#"{"key""#[..])
+}
+
+/// Rewrites a log file of concatenated JSON records in the current format, in place
+pub(super) fn upgrade_legacy(
+    path: &Path,
+    store_id: Uuid,
+    codec: &Codec,
+    strategy: ReplaceStrategy,
+) -> Result<()> {
+    let upgraded = path.with_extension("upgrade");
+    let mut writer = BufWriter::new(File::create(&upgraded)?);
+    writer.write_all(&file_header(store_id, codec.format()))?;
+    let stream = Deserializer::from_reader(BufReader::new(File::open(path)?)) // new line
+        .into_iter::<LegacyCommand>();
+    for command in stream {
+        match command {
+            Ok(command) => writer.write_all(&codec.encode(&command.into())?)?,
+            // like replay used to, stop at the first record that doesn't parse
+            Err(_) => break,
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    files::replace_file(&upgraded, path, strategy)
+}
@@ -0,0 +1,298 @@
+//! The serialization of the commands of log records, one [`RecordCodec`] per [`RecordFormat`].
+//!
+//! MessagePack and CBOR commands are an array of the key and the value as byte strings, the value
+//! being nil when there is none, and the name of the command type as a text string.
+
+use serde_json::Deserializer;
+
+use super::{
+    record::{Command, CommandType},
+    RecordFormat,
+};
+use crate::{KvsError, Result};
+
+/// Serializes the command of a record, within the payload of its frame
+pub(super) trait RecordCodec: Sync {
+    /// Appends the serialized `command` to `out`
+    fn serialize(&self, command: &Command, out: &mut Vec<u8>) -> Result<()>;
+
+    /// Deserializes a command from the start of `payload`, moving `payload` past it
+    fn deserialize(&self, payload: &mut &[u8]) -> Result<Command>;
+}
+
+impl RecordFormat {
+    /// The codec of the format
+    pub(super) fn codec(self) -> &'static dyn RecordCodec {
+        match self {
+            RecordFormat::Bincode => &Bincode,
+            RecordFormat::Json => &Json,
+            RecordFormat::MessagePack => &MessagePack,
+            RecordFormat::Cbor => &Cbor,
+        }
+    }
+
+    /// The number naming the format in the header of log files
+    pub(super) fn id(self) -> u16 {
+        match self {
+            RecordFormat::Bincode => 0,
+            RecordFormat::Json => 1,
+            RecordFormat::MessagePack => 2,
+            RecordFormat::Cbor => 3,
+        }
+    }
+
+    /// The format named by `id` in the header of a log file
+    pub(super) fn from_id(id: u16) -> Option<RecordFormat> {
+        match id {
+            0 => Some(RecordFormat::Bincode),
+            1 => Some(RecordFormat::Json),
+            2 => Some(RecordFormat::MessagePack),
+            3 => Some(RecordFormat::Cbor),
+            _ => None,
+        }
+    }
+}
+
+struct Bincode;
+
+impl RecordCodec for Bincode {
+    fn serialize(&self, command: &Command, out: &mut Vec<u8>) -> Result<()> {
+        Ok(bincode::serialize_into(out, command)?)
+    }
+
+    fn deserialize(&self, payload: &mut &[u8]) -> Result<Command> {
+        Ok(bincode::deserialize_from(payload)?)
+    }
+}
+
+struct Json;
+
+impl RecordCodec for Json {
+    fn serialize(&self, command: &Command, out: &mut Vec<u8>) -> Result<()> {
+        Ok(serde_json::to_writer(out, command)?)
+    }
+
+    fn deserialize(&self, payload: &mut &[u8]) -> Result<Command> {
+        let mut commands = Deserializer::from_slice(payload).into_iter::<Command>();
+        let command = commands.next().ok_or_else(|| malformed("JSON"))??;
+        *payload = &payload[commands.byte_offset()..];
+        Ok(command)
+    }
+}
+
+struct MessagePack;
+
+impl RecordCodec for MessagePack {
+    fn serialize(&self, command: &Command, out: &mut Vec<u8>) -> Result<()> {
+        // a fixarray of 3 elements
+        out.push(0x93);
+        msgpack_bin(&command.key, out)?;
+        match &command.value {
+            Some(value) => msgpack_bin(value, out)?,
+            None => out.push(0xc0),
+        }
+        // a fixstr, as every name is shorter than 32 bytes
+        let name = command.command_type.name();
+        out.push(0xa0 | name.len() as u8);
+        out.extend_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    fn deserialize(&self, payload: &mut &[u8]) -> Result<Command> {
+        let malformed = || malformed("MessagePack");
+        if take(payload, 1, malformed)? != [0x93] {
+            return Err(malformed());
+        }
+        let key = msgpack_read_bin(payload)?.ok_or_else(malformed)?;
+        let value = msgpack_read_bin(payload)?;
+        let len = match take(payload, 1, malformed)?[0] {
+            tag @ 0xa0..=0xbf => (tag & 0x1f) as usize,
+            _ => return Err(malformed()),
+        };
+        let command_type = command_type(take(payload, len, malformed)?, malformed)?;
+        Ok(command(key, value, command_type))
+    }
+}
+
+/// Appends `bytes` as a MessagePack bin
+fn msgpack_bin(bytes: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let len = bytes.len();
+    if len <= u8::MAX as usize {
+        out.extend_from_slice(&[0xc4, len as u8]);
+    } else if len <= u16::MAX as usize {
+        out.push(0xc5);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        let len = u32::try_from(len).map_err(|_| too_large("MessagePack"))?;
+        out.push(0xc6);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Reads a MessagePack bin, or `None` for nil
+fn msgpack_read_bin(payload: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+    let malformed = || malformed("MessagePack");
+    let len_bytes = match take(payload, 1, malformed)?[0] {
+        0xc0 => return Ok(None),
+        0xc4 => 1,
+        0xc5 => 2,
+        0xc6 => 4,
+        _ => return Err(malformed()),
+    };
+    let len = be_uint(take(payload, len_bytes, malformed)?);
+    Ok(Some(take(payload, len, malformed)?.to_vec()))
+}
+
+struct Cbor;
+
+/// Major type of a CBOR byte string
+const CBOR_BYTES: u8 = 2;
+
+/// Major type of a CBOR text string
+const CBOR_TEXT: u8 = 3;
+
+/// Major type of a CBOR array
+const CBOR_ARRAY: u8 = 4;
+
+/// The CBOR null
+const CBOR_NULL: u8 = 0xf6;
+
+impl RecordCodec for Cbor {
+    fn serialize(&self, command: &Command, out: &mut Vec<u8>) -> Result<()> {
+        cbor_head(CBOR_ARRAY, 3, out);
+        cbor_head(CBOR_BYTES, command.key.len() as u64, out);
+        out.extend_from_slice(&command.key);
+        match &command.value {
+            Some(value) => {
+                cbor_head(CBOR_BYTES, value.len() as u64, out);
+                out.extend_from_slice(value);
+            }
+            None => out.push(CBOR_NULL),
+        }
+        let name = command.command_type.name();
+        cbor_head(CBOR_TEXT, name.len() as u64, out);
+        out.extend_from_slice(name.as_bytes());
+        Ok(())
+    }
+
+    fn deserialize(&self, payload: &mut &[u8]) -> Result<Command> {
+        let malformed = || malformed("CBOR");
+        if cbor_read_head(payload)? != (CBOR_ARRAY, 3) {
+            return Err(malformed());
+        }
+        let key = cbor_read_bytes(payload)?.ok_or_else(malformed)?;
+        let value = cbor_read_bytes(payload)?;
+        let command_type = match cbor_read_head(payload)? {
+            (CBOR_TEXT, len) => command_type(take(payload, len as usize, malformed)?, malformed)?,
+            _ => return Err(malformed()),
+        };
+        Ok(command(key, value, command_type))
+    }
+}
+
+/// Appends the head of a CBOR item of major type `major` with the argument `arg`
+fn cbor_head(major: u8, arg: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    } else if arg <= u8::MAX as u64 {
+        out.extend_from_slice(&[major | 24, arg as u8]);
+    } else if arg <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(arg as u16).to_be_bytes());
+    } else if arg <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(arg as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&arg.to_be_bytes());
+    }
+}
+
+/// Reads the head of a CBOR item: its major type and argument
+fn cbor_read_head(payload: &mut &[u8]) -> Result<(u8, u64)> {
+    let malformed = || malformed("CBOR");
+    let initial = take(payload, 1, malformed)?[0];
+    let arg = match initial & 0x1f {
+        arg @ 0..=23 => arg as u64,
+        24 => be_uint(take(payload, 1, malformed)?) as u64,
+        25 => be_uint(take(payload, 2, malformed)?) as u64,
+        26 => be_uint(take(payload, 4, malformed)?) as u64,
+        27 => u64::from_be_bytes(take(payload, 8, malformed)?.try_into().unwrap()),
+        // indefinite lengths are never written
+        _ => return Err(malformed()),
+    };
+    Ok((initial >> 5, arg))
+}
+
+/// Reads a CBOR byte string, or `None` for null
+fn cbor_read_bytes(payload: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+    let malformed = || malformed("CBOR");
+    if payload.first() == Some(&CBOR_NULL) {
+        *payload = &payload[1..];
+        return Ok(None);
+    }
+    match cbor_read_head(payload)? {
+        (CBOR_BYTES, len) => Ok(Some(
+            take(
+                payload,
+                usize::try_from(len).map_err(|_| malformed())?,
+                malformed,
+            )?
+            .to_vec(),
+        )),
+        _ => Err(malformed()),
+    }
+}
+
+/// A command read from one of the formats that don't go through serde
+fn command(key: Vec<u8>, value: Option<Vec<u8>>, command_type: CommandType) -> Command {
+    Command {
+        key,
+        value,
+        command_type,
+        expires_at: None,
+        version: None,
+    }
+}
+
+/// The command type named `name`
+fn command_type(name: &[u8], malformed: impl Fn() -> KvsError) -> Result<CommandType> {
+    CommandType::ALL
+        .into_iter()
+        .find(|command_type| command_type.name().as_bytes() == name)
+        .ok_or_else(malformed)
+}
+
+/// Takes the first `len` bytes of `payload`
+fn take<'a>(
+    payload: &mut &'a [u8],
+    len: usize,
+    malformed: impl Fn() -> KvsError,
+) -> Result<&'a [u8]> {
+    if payload.len() < len {
+        return Err(malformed());
+    }
+    let (head, rest) = payload.split_at(len);
+    *payload = rest;
+    Ok(head)
+}
+
+/// The big-endian unsigned integer in `bytes`, at most 4 of them
+fn be_uint(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+/// The error for a command that doesn't deserialize
+fn malformed(format: &str) -> KvsError {
+    KvsError::Serde(format!("Malformed {} record", format))
+}
+
+/// The error for a command too large to be serialized
+fn too_large(format: &str) -> KvsError {
+    KvsError::Serde(format!("Record too large for {}", format))
+}
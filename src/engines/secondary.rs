@@ -0,0 +1,108 @@
+//! Secondary indexes of a [`KvStore`](super::KvStore), see
+//! [`KvStore::add_index`](super::KvStore::add_index).
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::RwLock,
+};
+
+use crate::Result;
+
+/// Pulls the indexed value out of the value of a key, if it has one
+pub(super) type Extractor = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// The secondary indexes of a store by name.
+///
+/// They are kept in memory, and updated by writers while holding the writer. Compaction only moves
+/// records, so it leaves them alone.
+#[derive(Default)]
+pub(super) struct SecondaryIndexes(RwLock<HashMap<String, SecondaryIndex>>);
+
+struct SecondaryIndex {
+    extract: Extractor,
+    /// Indexed values with the keys having them
+    entries: BTreeSet<(Vec<u8>, Vec<u8>)>,
+    /// Indexed value of each key that has one
+    values: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl SecondaryIndex {
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.remove(key);
+        if let Some(indexed) = (self.extract)(value) {
+            self.entries.insert((indexed.clone(), key.to_vec()));
+            self.values.insert(key.to_vec(), indexed);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(indexed) = self.values.remove(key) {
+            self.entries.remove(&(indexed, key.to_vec()));
+        }
+    }
+}
+
+impl SecondaryIndexes {
+    /// Adds the index `name`, or replaces it, built from every key and value of the store
+    pub(super) fn add(
+        &self,
+        name: &str,
+        extract: Extractor,
+        pairs: impl IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    ) -> Result<()> {
+        let mut index = SecondaryIndex {
+            extract,
+            entries: BTreeSet::new(),
+            values: HashMap::new(),
+        };
+        for pair in pairs {
+            let (key, value) = pair?;
+            index.set(&key, &value);
+        }
+        self.0.write().unwrap().insert(name.to_string(), index);
+        Ok(())
+    }
+
+    /// Whether there are no indexes, so values don't need to be read to keep them up to date
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Indexes the new value of `key`
+    pub(super) fn set(&self, key: &[u8], value: &[u8]) {
+        for index in self.0.write().unwrap().values_mut() {
+            index.set(key, value);
+        }
+    }
+
+    /// Drops `key` from every index
+    pub(super) fn remove(&self, key: &[u8]) {
+        for index in self.0.write().unwrap().values_mut() {
+            index.remove(key);
+        }
+    }
+
+    /// Drops every key from every index
+    pub(super) fn clear(&self) {
+        for index in self.0.write().unwrap().values_mut() {
+            index.entries.clear();
+            index.values.clear();
+        }
+    }
+
+    /// The keys whose value has `indexed` as the value of the index `name`, in order, unless there
+    /// is no such index
+    pub(super) fn get(&self, name: &str, indexed: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let indexes = self.0.read().unwrap();
+        let index = indexes.get(name)?;
+        let start = (indexed.to_vec(), Vec::new());
+        Some(
+            index
+                .entries
+                .range(start..)
+                .take_while(|(value, _)| value == indexed)
+                .map(|(_, key)| key.clone())
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,181 @@
+use std::{fs, ops::Bound, path::Path};
+
+use sled::{
+    transaction::{self, TransactionError},
+    Db,
+};
+
+use super::{marker, KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+
+/// A [`KvsEngine`] backed by the [`sled`] embedded database.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Wraps an open [`sled::Db`]
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::SledKvsEngine;
+    /// # use tempfile::TempDir;
+    ///
+    /// let db = sled::open(TempDir::new().unwrap().path()).unwrap();
+    /// let store = SledKvsEngine::new(db);
+    /// ```
+    pub fn new(db: Db) -> SledKvsEngine {
+        SledKvsEngine { db }
+    }
+
+    /// Opens the sled database at `path`, creating it if needed. Fails with
+    /// [`KvsError::WrongEngine`] if `path` holds a store of another engine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::SledKvsEngine;
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = SledKvsEngine::open(TempDir::new().unwrap().path()).unwrap();
+    /// ```
+    pub fn open(path: impl AsRef<Path>) -> Result<SledKvsEngine> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        marker::claim(path, "sled", false)?;
+        Ok(SledKvsEngine::new(sled::open(path)?))
+    }
+
+    /// Sets `to` to the value of `from`, and removes `from` too if `remove`, in a transaction
+    fn copy_key(&self, from: String, to: String, remove: bool) -> Result<()> {
+        let copied = self.db.transaction(|tx| {
+            let value = match tx.get(&from)? {
+                Some(value) => value,
+                None => return Ok(false),
+            };
+            if from != to {
+                tx.insert(to.as_bytes(), value)?;
+                if remove {
+                    tx.remove(from.as_bytes())?;
+                }
+            }
+            Ok(true)
+        });
+        match copied {
+            Ok(true) => {
+                self.db.flush()?;
+                Ok(())
+            }
+            Ok(false) => Err(KvsError::KeyNotFound),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+            Err(TransactionError::Abort(())) => unreachable!(),
+        }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .get(key)?
+            .map(|value| String::from_utf8(value.to_vec()))
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or_else(|| KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let swapped = self.db.compare_and_swap(
+            key,
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match swapped {
+            Ok(()) => {
+                self.db.flush()?;
+                Ok(Ok(()))
+            }
+            Err(e) => Ok(Err(e
+                .current
+                .map(|value| String::from_utf8(value.to_vec()))
+                .transpose()?)),
+        }
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.copy_key(from, to, true)
+    }
+
+    fn copy(&self, from: String, to: String) -> Result<()> {
+        self.copy_key(from, to, false)
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        self.db
+            .scan_prefix(prefix)
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn keys_after(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let keys = match after {
+            Some(after) => self
+                .db
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+            None => self.db.iter(),
+        };
+        keys.keys()
+            .take(limit)
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let written = self.db.transaction(|tx| {
+            for command in &batch.commands {
+                match &command.value {
+                    Some(value) => {
+                        tx.insert(command.key.as_slice(), value.as_slice())?;
+                    }
+                    None => {
+                        if tx.remove(command.key.as_slice())?.is_none() {
+                            return transaction::abort(());
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        match written {
+            Ok(()) => {
+                self.db.flush()?;
+                Ok(())
+            }
+            Err(TransactionError::Abort(())) => Err(KvsError::KeyNotFound),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Sorted string tables: the immutable files an [`LsmKvStore`](super::LsmKvStore) flushes its
+//! memtables to, and compacts into levels.
+//!
+//! A table is named `<id>.sst` and holds entries sorted by key, in data blocks of about
+//! [`BLOCK_SIZE`] bytes, followed by an index of the blocks, a bloom filter of the keys and a
+//! footer of fixed length:
+//!
+//! ```text
+//! block* | index | bloom filter | index offset | index len | bloom offset | bloom len | magic
+//! ```
+//!
+//! The footer is made of little-endian `u64`s. An entry is the length of its key and of its value
+//! as little-endian `u32`s, [`TOMBSTONE`] standing for a removed key, then the key and the value.
+//! The index holds the last key of every block, its length as a `u32` and the offset, length and
+//! CRC32 of the block.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    vec,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{bloom, kvs::read_exact_at};
+use crate::{KvsError, Result};
+
+/// Size past which a data block is closed
+const BLOCK_SIZE: usize = 4096;
+
+/// Value length of the entry of a removed key
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Last 8 bytes of every table, "kvs_sst1"
+const MAGIC: u64 = u64::from_be_bytes(*b"kvs_sst1");
+
+/// Length of the footer
+const FOOTER_LEN: usize = 40;
+
+/// A key and its value, `None` for a removed key
+pub(super) type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// What the manifest of an [`LsmKvStore`](super::LsmKvStore) records about a table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct TableMeta {
+    pub(super) id: u64,
+    /// First and last key of the table
+    pub(super) smallest: Vec<u8>,
+    pub(super) largest: Vec<u8>,
+    /// Length of the file
+    pub(super) size: u64,
+}
+
+impl TableMeta {
+    /// Whether the table may hold keys between `smallest` and `largest`
+    pub(super) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        &self.smallest[..] <= largest && &self.largest[..] >= smallest
+    }
+}
+
+/// Path of the table `id`
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Appends the encoding of an entry to `buf`
+pub(super) fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(
+        &value
+            .map_or(TOMBSTONE, |value| value.len() as u32)
+            .to_le_bytes(),
+    );
+    buf.extend_from_slice(key);
+    if let Some(value) = value {
+        buf.extend_from_slice(value);
+    }
+}
+
+/// Decodes the entry at `*pos` in `buf` and moves `pos` past it, unless `buf` ends before it does
+pub(super) fn decode_entry(buf: &[u8], pos: &mut usize) -> Option<Entry> {
+    let header = buf.get(*pos..*pos + 8)?;
+    let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let key_start = *pos + 8;
+    let key = buf.get(key_start..key_start + key_len)?.to_vec();
+    let value_start = key_start + key_len;
+    let (value, end) = match value_len {
+        TOMBSTONE => (None, value_start),
+        len => {
+            let end = value_start + len as usize;
+            (Some(buf.get(value_start..end)?.to_vec()), end)
+        }
+    };
+    *pos = end;
+    Some((key, value))
+}
+
+/// Writes a table, entries being added in key order
+pub(super) struct TableBuilder {
+    file: BufWriter<File>,
+    id: u64,
+    /// The block being filled
+    block: Vec<u8>,
+    last_key: Vec<u8>,
+    smallest: Option<Vec<u8>>,
+    index: Vec<u8>,
+    /// Offset of the block being filled
+    offset: u64,
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    /// Creates the file of table `id` in `dir`
+    pub(super) fn create(dir: &Path, id: u64) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            file: BufWriter::new(File::create(table_path(dir, id))?),
+            id,
+            block: Vec::new(),
+            last_key: Vec::new(),
+            smallest: None,
+            index: Vec::new(),
+            offset: 0,
+            key_hashes: Vec::new(),
+        })
+    }
+
+    /// Adds an entry, whose key must be greater than the one of the previous entry
+    pub(super) fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        encode_entry(&mut self.block, key, value);
+        self.smallest.get_or_insert_with(|| key.to_vec());
+        self.last_key = key.to_vec();
+        self.key_hashes.push(bloom::hash(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Whether no entry was added
+    pub(super) fn is_empty(&self) -> bool {
+        self.smallest.is_none()
+    }
+
+    /// Writes the block being filled and indexes it
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.block)?;
+        self.index
+            .extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        self.index.extend_from_slice(&self.last_key);
+        self.index.extend_from_slice(&self.offset.to_le_bytes());
+        self.index
+            .extend_from_slice(&(self.block.len() as u32).to_le_bytes());
+        self.index
+            .extend_from_slice(&crc32fast::hash(&self.block).to_le_bytes());
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the index, the bloom filter and the footer, and syncs the table
+    pub(super) fn finish(mut self) -> Result<TableMeta> {
+        self.finish_block()?;
+        let bloom = bloom::build(&self.key_hashes);
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + self.index.len() as u64;
+        self.file.write_all(&self.index)?;
+        self.file.write_all(&bloom)?;
+        for field in [
+            index_offset,
+            self.index.len() as u64,
+            bloom_offset,
+            bloom.len() as u64,
+            MAGIC,
+        ] {
+            self.file.write_all(&field.to_le_bytes())?;
+        }
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(TableMeta {
+            id: self.id,
+            smallest: self.smallest.unwrap_or_default(),
+            largest: self.last_key,
+            size: bloom_offset + bloom.len() as u64 + FOOTER_LEN as u64,
+        })
+    }
+}
+
+/// Where a data block is in its table
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+/// An open table. Its index and bloom filter are kept in memory, and blocks are read on demand.
+pub(super) struct Table {
+    pub(super) meta: TableMeta,
+    file: File,
+    blocks: Vec<BlockHandle>,
+    bloom: Vec<u8>,
+}
+
+impl Table {
+    /// Opens the table described by `meta` in `dir`
+    pub(super) fn open(dir: &Path, meta: TableMeta) -> Result<Table> {
+        let path = table_path(dir, meta.id);
+        let file = File::open(&path)?;
+        let corrupt = || KvsError::Message(format!("Corrupt table {}", path.display()));
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN as u64 {
+            return Err(corrupt());
+        }
+        let mut footer = [0; FOOTER_LEN];
+        read_exact_at(&file, &mut footer, len - FOOTER_LEN as u64)?;
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        if field(4) != MAGIC || field(2) + field(3) + FOOTER_LEN as u64 != len {
+            return Err(corrupt());
+        }
+        let mut index = vec![0; field(1) as usize];
+        read_exact_at(&file, &mut index, field(0))?;
+        let mut bloom = vec![0; field(3) as usize];
+        read_exact_at(&file, &mut bloom, field(2))?;
+
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        while pos < index.len() {
+            let key_len = index
+                .get(pos..pos + 4)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                .ok_or_else(corrupt)?;
+            let handle = index
+                .get(pos + 4..pos + 4 + key_len + 16)
+                .ok_or_else(corrupt)?;
+            let (last_key, handle) = handle.split_at(key_len);
+            blocks.push(BlockHandle {
+                last_key: last_key.to_vec(),
+                offset: u64::from_le_bytes(handle[..8].try_into().unwrap()),
+                len: u32::from_le_bytes(handle[8..12].try_into().unwrap()),
+                crc: u32::from_le_bytes(handle[12..].try_into().unwrap()),
+            });
+            pos += 4 + key_len + 16;
+        }
+        Ok(Table {
+            meta,
+            file,
+            blocks,
+            bloom,
+        })
+    }
+
+    /// Looks `key` up: `None` if the table doesn't hold it, `Some(None)` if it holds its removal
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if !bloom::may_contain(&self.bloom, bloom::hash(key)) {
+            return Ok(None);
+        }
+        let block = self
+            .blocks
+            .partition_point(|block| &block.last_key[..] < key);
+        if block == self.blocks.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Iterates over the entries of the table from the first key not less than `from` on
+    pub(super) fn iter_from(self: &Arc<Self>, from: &[u8]) -> TableIter {
+        TableIter {
+            table: self.clone(),
+            block: self
+                .blocks
+                .partition_point(|block| &block.last_key[..] < from),
+            entries: Vec::new().into_iter(),
+            from: Some(from.to_vec()),
+        }
+    }
+
+    /// Reads and decodes the data block `i`
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>> {
+        let handle = &self.blocks[i];
+        let mut buf = vec![0; handle.len as usize];
+        read_exact_at(&self.file, &mut buf, handle.offset)?;
+        let corrupt = || {
+            KvsError::Message(format!(
+                "Corrupt block at offset {} of table {}",
+                handle.offset, self.meta.id
+            ))
+        };
+        if crc32fast::hash(&buf) != handle.crc {
+            return Err(corrupt());
+        }
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            entries.push(decode_entry(&buf, &mut pos).ok_or_else(corrupt)?);
+        }
+        Ok(entries)
+    }
+
+    /// Deletes the file of the table; readers that still hold the table can keep reading it
+    pub(super) fn remove_file(&self, dir: &Path) -> Result<()> {
+        fs::remove_file(table_path(dir, self.meta.id))?;
+        Ok(())
+    }
+}
+
+/// The entries of a table in key order, read one block at a time
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    /// Next block to read
+    block: usize,
+    entries: vec::IntoIter<Entry>,
+    /// Key the entries of the first block read are skipped until
+    from: Option<Vec<u8>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block == self.table.blocks.len() {
+                return None;
+            }
+            let mut entries = match self.table.read_block(self.block) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            };
+            if let Some(from) = self.from.take() {
+                entries.retain(|(key, _)| *key >= from);
+            }
+            self.entries = entries.into_iter();
+            self.block += 1;
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! What [`KvStore::tail`](super::KvStore::tail) yields: the records of the log, in the order they
+//! were written, with their positions.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::WatchOp;
+
+/// A position in the log of a [`KvStore`](super::KvStore): a generation and a byte offset in its
+/// log file. Records written later are at greater positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// Generation of the log file
+    pub gen: u64,
+    /// Byte offset in the log file
+    pub offset: u64,
+}
+
+impl LogPosition {
+    /// The start of the log, whatever its oldest generation is
+    pub const START: LogPosition = LogPosition { gen: 0, offset: 0 };
+}
+
+impl fmt::Display for LogPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.gen, self.offset)
+    }
+}
+
+/// A committed change read from the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    /// Where the record is in the log
+    pub position: LogPosition,
+    /// What the record does
+    pub op: WatchOp,
+    /// The key the record is about; empty for [`WatchOp::Clear`]
+    pub key: Vec<u8>,
+    /// The value set, the operand merged, the bytes appended or the end of the range removed,
+    /// unless the record is a removal or a clear
+    pub value: Option<Vec<u8>>,
+    /// When the value expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// The changes a follower fetches from its leader at once, see
+/// [`KvsEngine::changes`](super::KvsEngine::changes)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    /// UUID of the store the changes were read from
+    pub store_id: String,
+    /// Whether the changes start over from the beginning of the log, because the position asked
+    /// for was compacted away or is in the log of another store. The follower has to drop its keys
+    /// before applying them.
+    pub reset: bool,
+    /// The changes, oldest first
+    pub records: Vec<ChangeRecord>,
+    /// Where the next batch starts
+    pub next: LogPosition,
+}
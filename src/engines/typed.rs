@@ -0,0 +1,78 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{KvStore, KvsEngine};
+use crate::Result;
+
+/// A handle to a [`KvsEngine`] that stores values of type `V`, encoded as JSON.
+///
+/// Values written through it are ordinary JSON strings in the store, so they can still be read
+/// with `kvs get` or an untyped handle.
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{KvStore, TypedKvStore};
+/// # use serde::{Deserialize, Serialize};
+/// # use tempfile::TempDir;
+///
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+/// let users = TypedKvStore::<User>::new(store);
+/// let alice = User { name: String::from("alice"), age: 30 };
+/// users.set(String::from("user:1"), &alice).unwrap();
+/// assert_eq!(users.get(String::from("user:1")).unwrap(), Some(alice));
+/// ```
+pub struct TypedKvStore<V, E: KvsEngine = KvStore> {
+    engine: E,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V, E: KvsEngine> Clone for TypedKvStore<V, E> {
+    fn clone(&self) -> Self {
+        TypedKvStore {
+            engine: self.engine.clone(),
+            value: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned, E: KvsEngine> TypedKvStore<V, E> {
+    /// Wraps an open engine
+    pub fn new(engine: E) -> Self {
+        TypedKvStore {
+            engine,
+            value: PhantomData,
+        }
+    }
+
+    /// Serializes `value` and sets it as the value of a key
+    pub fn set(&self, key: String, value: &V) -> Result<()> {
+        self.engine.set(key, serde_json::to_string(value)?)
+    }
+
+    /// Gets the value of a key, or `None` if the key does not exist. Fails if the stored value
+    /// isn't a `V`.
+    pub fn get(&self, key: String) -> Result<Option<V>> {
+        match self.engine.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key. Fails if the key does not exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    /// The wrapped engine
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+}
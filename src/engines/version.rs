@@ -0,0 +1,13 @@
+//! What [`KvStore::history`](super::KvStore::history) returns.
+
+/// A version of the value of a key still in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// Version of the write, see [`KvStore::version`](super::KvStore::version). Writes from before
+    /// versions were recorded have version 0.
+    pub version: u64,
+    /// The value the write left the key with, unless it removed the key
+    pub value: Option<Vec<u8>>,
+    /// When the value expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
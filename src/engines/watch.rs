@@ -0,0 +1,69 @@
+//! The change events [`KvStore::watch`](super::KvStore::watch) sends to its subscribers.
+
+use std::sync::mpsc::Sender;
+
+use serde::{Deserialize, Serialize};
+
+/// A change made to a [`KvStore`](super::KvStore)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// What the change did
+    pub op: WatchOp,
+    /// The key that changed; empty for [`WatchOp::Clear`]
+    pub key: Vec<u8>,
+    /// The value before the change, unless the key didn't exist or had expired
+    pub old_value: Option<Vec<u8>>,
+    /// The value after the change, unless the key was removed
+    pub new_value: Option<Vec<u8>>,
+}
+
+/// What a [`WatchEvent`] reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchOp {
+    /// A key was set
+    Set,
+    /// A key was removed
+    Remove,
+    /// Every key was removed by [`KvStore::clear`](super::KvStore::clear)
+    Clear,
+    /// An operand was merged into a key with [`KvStore::merge`](super::KvStore::merge). Watchers
+    /// get the value it resulted in, while the [`KvStore::tail`](super::KvStore::tail) of the log
+    /// yields the operand.
+    Merge,
+    /// Bytes were appended to a key with [`KvStore::append`](super::KvStore::append). Watchers get
+    /// the whole value, while the [`KvStore::tail`](super::KvStore::tail) of the log yields the
+    /// appended bytes.
+    Append,
+    /// The keys in a range were removed by [`KvStore::delete_range`](super::KvStore::delete_range)
+    /// or [`KvStore::delete_prefix`](super::KvStore::delete_prefix). Only the
+    /// [`KvStore::tail`](super::KvStore::tail) of the log yields it, with the start of the range as
+    /// the key and its end, if it has one, as the value; watchers get a removal of each key.
+    RemoveRange,
+}
+
+/// The subscribers of a store, each with the prefix of the keys it watches
+#[derive(Default)]
+pub(super) struct Watchers(Vec<(Vec<u8>, Sender<WatchEvent>)>);
+
+impl Watchers {
+    /// Subscribes `sender` to the changes of the keys starting with `prefix`
+    pub(super) fn add(&mut self, prefix: Vec<u8>, sender: Sender<WatchEvent>) {
+        self.0.push((prefix, sender));
+    }
+
+    /// Whether a subscriber watches `key`
+    pub(super) fn watching(&self, key: &[u8]) -> bool {
+        self.0.iter().any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Sends `event` to the subscribers watching its key, or to all of them for a clear, and drops
+    /// the subscribers that hung up
+    pub(super) fn send(&mut self, event: WatchEvent) {
+        self.0.retain(|(prefix, sender)| {
+            if event.op != WatchOp::Clear && !event.key.starts_with(prefix) {
+                return true;
+            }
+            sender.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -0,0 +1,161 @@
+//! The error type of the stores, the server and the client.
+
+use std::{error::Error, fmt, io, string::FromUtf8Error, time::SystemTimeError};
+
+use crate::{protocol::ErrorKind, CorruptRecord};
+
+/// Why an operation of a store, the server or the client failed
+#[derive(Debug)]
+pub enum KvsError {
+    /// The key doesn't exist
+    KeyNotFound,
+    /// Reading or writing a file or a socket failed
+    Io(io::Error),
+    /// A record, frame or document couldn't be encoded or decoded
+    Serde(String),
+    /// A record in the log failed its checksum
+    Corruption(CorruptRecord),
+    /// The data directory holds a store created by another engine than the one asked for
+    WrongEngine {
+        /// The engine that created the store
+        found: String,
+        /// The engine asked for
+        requested: String,
+    },
+    /// The store is open read-only
+    ReadOnly,
+    /// The sled engine failed
+    Sled(sled::Error),
+    /// A message didn't follow the wire protocol
+    Protocol(String),
+    /// The server failed the request
+    Server {
+        /// Why the request failed
+        kind: ErrorKind,
+        /// What the server said about it
+        message: String,
+    },
+    /// Any other failure, described by its message
+    Message(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Io(e) => write!(f, "{}", e),
+            KvsError::Corruption(e) => write!(f, "{}", e),
+            KvsError::WrongEngine { found, requested } => write!(
+                f,
+                "Store was created by the {} engine, not {}",
+                found, requested
+            ),
+            KvsError::ReadOnly => write!(f, "The store is open read-only"),
+            KvsError::Sled(e) => write!(f, "{}", e),
+            KvsError::Serde(message)
+            | KvsError::Protocol(message)
+            | KvsError::Server { message, .. }
+            | KvsError::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Corruption(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> KvsError {
+        KvsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> KvsError {
+        KvsError::Serde(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(e: bincode::Error) -> KvsError {
+        KvsError::Serde(e.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(e: FromUtf8Error) -> KvsError {
+        KvsError::Serde(e.to_string())
+    }
+}
+
+impl From<CorruptRecord> for KvsError {
+    fn from(e: CorruptRecord) -> KvsError {
+        KvsError::Corruption(e)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> KvsError {
+        KvsError::Sled(e)
+    }
+}
+
+impl From<SystemTimeError> for KvsError {
+    fn from(e: SystemTimeError) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
+
+impl From<uuid::Error> for KvsError {
+    fn from(e: uuid::Error) -> KvsError {
+        KvsError::Serde(e.to_string())
+    }
+}
+
+impl From<snap::Error> for KvsError {
+    fn from(e: snap::Error) -> KvsError {
+        KvsError::Serde(e.to_string())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<tokio::task::JoinError> for KvsError {
+    fn from(e: tokio::task::JoinError) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for KvsError {
+    fn from(e: rustls::Error) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::pki_types::pem::Error> for KvsError {
+    fn from(e: rustls::pki_types::pem::Error) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::pki_types::InvalidDnsNameError> for KvsError {
+    fn from(e: rustls::pki_types::InvalidDnsNameError) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl From<tonic::transport::Error> for KvsError {
+    fn from(e: tonic::transport::Error) -> KvsError {
+        KvsError::Message(e.to_string())
+    }
+}
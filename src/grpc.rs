@@ -0,0 +1,242 @@
+//! A gRPC front-end for the store, generated from `proto/kvs.proto` with tonic.
+//!
+//! The generated client ([`proto::kvs_client::KvsClient`]) can be used to talk to a
+//! `kvs-server --protocol grpc` from Rust; other languages can generate their own stubs from the
+//! same `.proto` file.
+
+// `tonic::Status` is large, but it is the error type every gRPC handler has to return
+#![allow(clippy::result_large_err)]
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::task;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    auth::{Acl, Operation, User},
+    server::cursor::CursorCipher,
+    KvsEngine, KvsError, Result,
+};
+use proto::{
+    kvs_server::{Kvs, KvsServer},
+    GetRequest, GetResponse, KeyValue, RemoveRequest, RemoveResponse, ScanRequest, ScanResponse,
+    SetRequest, SetResponse,
+};
+
+/// Most keys a page of `Scan` goes through
+const MAX_SCAN_LIMIT: usize = 1000;
+
+/// Types and stubs generated from `proto/kvs.proto`
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// Implementation of the `kvs.Kvs` gRPC service backed by a [`KvsEngine`].
+///
+/// Calls are served concurrently, each with its own handle to the store, and run on tokio's
+/// blocking thread pool, so a slow one doesn't hold up the others.
+pub struct KvsGrpcService<E: KvsEngine> {
+    store: E,
+    acl: Option<Arc<Acl>>,
+    /// Seals the cursors of scans, so they don't give away the keys they hold
+    cursors: Arc<CursorCipher>,
+}
+
+impl<E: KvsEngine> KvsGrpcService<E> {
+    /// Creates a service serving the given store
+    pub fn new(store: E) -> Self {
+        KvsGrpcService {
+            store,
+            acl: None,
+            cursors: Arc::new(CursorCipher::new()),
+        }
+    }
+
+    /// Requires calls to carry an `authorization: Bearer <token>` metadata entry matching a user
+    /// in `acl`, and restricts each user to the keys their rules allow
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Authenticates the caller of `request`. Returns `None` if there is no ACL.
+    fn user<T>(&self, request: &Request<T>) -> std::result::Result<Option<Arc<User>>, Status> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(None),
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("Authentication required"))?;
+        acl.authenticate(None, token)
+            .map(Some)
+            .ok_or_else(|| Status::unauthenticated("Invalid credentials"))
+    }
+
+    /// Checks that the caller of `request` may perform `op` on `key`
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        op: Operation,
+        key: &str,
+    ) -> std::result::Result<(), Status> {
+        match self.user(request)? {
+            Some(user) if !user.allows(op, key) => Err(Status::permission_denied(format!(
+                "Access to key {} denied",
+                key
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `op` with a handle to the store on tokio's blocking thread pool
+    async fn blocking<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(E) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let store = self.store.clone();
+        task::spawn_blocking(move || op(store)).await?
+    }
+}
+
+#[tonic::async_trait]
+impl<E: KvsEngine> Kvs for KvsGrpcService<E> {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetResponse>, Status> {
+        self.authorize(&request, Operation::Read, &request.get_ref().key)?;
+        let key = request.into_inner().key;
+        let value = self
+            .blocking(move |store| store.get(key))
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> std::result::Result<Response<SetResponse>, Status> {
+        self.authorize(&request, Operation::Write, &request.get_ref().key)?;
+        let SetRequest { key, value } = request.into_inner();
+        self.blocking(move |store| {
+            store.set(key, value)?;
+            store.flush()
+        })
+        .await
+        .map_err(internal)?;
+        Ok(Response::new(SetResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveResponse>, Status> {
+        self.authorize(&request, Operation::Write, &request.get_ref().key)?;
+        let key = request.into_inner().key;
+        let removed = self
+            .blocking(move |store| {
+                store.remove(key)?;
+                store.flush()
+            })
+            .await;
+        match removed {
+            Ok(()) => Ok(Response::new(RemoveResponse {})),
+            Err(KvsError::KeyNotFound) => Err(Status::not_found("Key not found")),
+            Err(e) => Err(internal(e)),
+        }
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<ScanResponse>, Status> {
+        // only the keys the caller may read are listed
+        let user = self.user(&request)?;
+        let ScanRequest {
+            prefix,
+            limit,
+            cursor,
+        } = request.into_inner();
+        let after = match cursor {
+            Some(cursor) => Some(
+                self.cursors
+                    .open(&cursor)
+                    .ok_or_else(|| Status::invalid_argument("Invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let limit = match limit as usize {
+            0 => MAX_SCAN_LIMIT,
+            limit => limit.min(MAX_SCAN_LIMIT),
+        };
+        let cursors = self.cursors.clone();
+        let (entries, cursor) = self
+            .blocking(move |store| {
+                let mut scanned = Vec::with_capacity(limit);
+                let after = match after {
+                    Some(after) => after,
+                    None => {
+                        // the first page starts at the prefix, which may be a key itself
+                        if store.get(prefix.clone())?.is_some() {
+                            scanned.push(prefix.clone());
+                        }
+                        prefix.clone()
+                    }
+                };
+                if scanned.len() < limit {
+                    scanned.extend(store.keys_after(Some(&after), limit - scanned.len())?);
+                }
+                let full = scanned.len() == limit;
+                scanned.retain(|key| key.starts_with(&prefix));
+                // the cursor holds the last key gone through, which the caller may not be allowed
+                // to read, so it is sealed
+                let cursor = match scanned.last() {
+                    Some(last) if full && scanned.len() == limit => Some(cursors.seal(last)?),
+                    _ => None,
+                };
+                let mut entries = Vec::new();
+                for key in scanned {
+                    if user
+                        .as_ref()
+                        .is_some_and(|user| !user.allows(Operation::Read, &key))
+                    {
+                        continue;
+                    }
+                    if let Some(value) = store.get(key.clone())? {
+                        entries.push(KeyValue { key, value });
+                    }
+                }
+                Ok((entries, cursor))
+            })
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(ScanResponse { entries, cursor }))
+    }
+}
+
+/// Serves the gRPC service for `store` on `addr`, blocking until the server shuts down.
+///
+/// Calls are checked against `acl` if one is given.
+pub fn serve<E: KvsEngine>(store: E, acl: Option<Arc<Acl>>, addr: SocketAddr) -> Result<()> {
+    let mut service = KvsGrpcService::new(store);
+    if let Some(acl) = acl {
+        service = service.with_acl(acl);
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(
+        Server::builder()
+            .add_service(KvsServer::new(service))
+            .serve(addr),
+    )?;
+    Ok(())
+}
+
+fn internal(e: KvsError) -> Status {
+    Status::internal(e.to_string())
+}
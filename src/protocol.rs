@@ -0,0 +1,167 @@
+//! Wire protocol spoken between `kvs-server` and its clients.
+//!
+//! Every message is sent as a frame: a big-endian `u32` length followed by that many bytes of
+//! JSON. A connection carries any number of request/response frames until the client hangs up.
+
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{ChangeBatch, KvsError, LogPosition, Result};
+
+/// Upper bound for a single frame so a bogus length prefix can't make us allocate unbounded memory
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// A request sent by a client to the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Get the value of a key
+    Get { key: String },
+    /// Get the values of several keys
+    MultiGet { keys: Vec<String> },
+    /// Set the value of a key
+    Set { key: String, value: String },
+    /// Remove a key
+    Remove { key: String },
+    /// Set the value of a key unless it already exists
+    SetIfAbsent { key: String, value: String },
+    /// Remove a key if its value is `expected`
+    RemoveIf { key: String, expected: String },
+    /// Move the value of `from` to the key `to`
+    Rename { from: String, to: String },
+    /// Copy the value of `from` to the key `to`
+    Copy { from: String, to: String },
+    /// Authenticate the connection with a user name and password, or with a token alone
+    Auth {
+        username: Option<String>,
+        password: String,
+    },
+    /// List up to `count` keys after `cursor`, or from the first key if it is `None`, and keep the
+    /// ones matching the glob `pattern`, if any. The cursor is one the server sent with a previous
+    /// page: it is sealed, so it doesn't tell the key the page ended at, and valid until the
+    /// server restarts
+    Scan {
+        cursor: Option<String>,
+        pattern: Option<String>,
+        count: usize,
+    },
+    /// Start a transaction: the sets and removals that follow are queued until `Exec`
+    Multi,
+    /// Apply the writes queued since `Multi` atomically, ending the transaction
+    Exec,
+    /// Drop the writes queued since `Multi`, ending the transaction
+    Discard,
+    /// Get up to `limit` of the changes made to the store from `from` on, for a follower that
+    /// replicated the store with UUID `store_id` up to there
+    Replicate {
+        store_id: Option<String>,
+        from: LogPosition,
+        limit: usize,
+    },
+}
+
+/// A response sent by the server for each [`Request`]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The request succeeded. Carries the value for a `Get`, `None` otherwise.
+    Ok(Option<String>),
+    /// The values for a `MultiGet`, in the order of its keys
+    Values(Vec<Option<String>>),
+    /// Whether a `SetIfAbsent` set the key, or a `RemoveIf` removed it
+    Applied(bool),
+    /// A set or removal was queued in the transaction, to be applied by `Exec`
+    Queued,
+    /// The changes for a `Replicate`
+    Changes(ChangeBatch),
+    /// The keys of a page of a `Scan`, and the cursor to pass to the next one, or `None` once
+    /// every key was listed
+    Keys {
+        keys: Vec<String>,
+        cursor: Option<String>,
+    },
+    /// The request failed
+    Err { kind: ErrorKind, message: String },
+}
+
+/// Why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The store could not execute the operation
+    Store,
+    /// The operation needs a key that doesn't exist, e.g. removing it
+    KeyNotFound,
+    /// The server requires the connection to authenticate first
+    AuthRequired,
+    /// The credentials sent with [`Request::Auth`] were rejected
+    AuthFailed,
+    /// The authenticated user may not perform the operation on the key
+    Forbidden,
+    /// The request doesn't fit the transaction of the connection, e.g. `Exec` without `Multi` or
+    /// a read queued in a transaction
+    Transaction,
+}
+
+/// Writes `message` as a single length-prefixed frame and flushes the writer.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    writer.write_all(&encode_frame(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a single frame from `reader`.
+///
+/// Returns `None` if the peer closed the connection cleanly before a new frame started.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut body = vec![0u8; frame_len(len_buf)?];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Reads a single frame from `reader` without blocking the executor, like [`read_frame`]
+#[cfg(feature = "tokio")]
+pub(crate) async fn read_frame_async<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut body = vec![0u8; frame_len(len_buf)?];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Serializes `message` as a whole frame, length prefix included
+fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(message)?;
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| KvsError::Protocol("Frame too large".to_owned()))?;
+    // assemble the frame first so it goes out in a single write (and a single TLS record)
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Length of the body of a frame from its prefix, failing if it's over [`MAX_FRAME_LEN`]
+fn frame_len(len_buf: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len_buf);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol("Frame too large".to_owned()));
+    }
+    Ok(len as usize)
+}
@@ -0,0 +1,71 @@
+//! Serving the native protocol from tokio tasks, one per connection, see
+//! [`KvsServer::with_async`].
+//!
+//! Connections only take a task while they wait for a request, instead of a thread. Requests
+//! themselves still run on the engine's blocking API, from `block_in_place` so the other tasks of
+//! the worker thread move to another one meanwhile.
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    task,
+};
+use tracing::{debug, debug_span, error};
+
+use super::{KvsServer, MAX_PENDING_REPLIES};
+use crate::{
+    protocol::{read_frame_async, write_frame, Request},
+    KvsEngine, Result,
+};
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Listens on `addr` and serves each connection from a task of its own until the listener
+    /// fails
+    pub(super) fn run_async(self, addr: SocketAddr) -> Result<()> {
+        let runtime = Runtime::new()?;
+        runtime.block_on(async {
+            let listener = TcpListener::bind(addr).await?;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let server = self.for_connection();
+                        tokio::spawn(async move {
+                            if let Err(e) = server.serve_async(stream, peer).await {
+                                error!("Error serving client: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Connection failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Serves requests from a single connection until the client hangs up, like
+    /// [`KvsServer::serve`] does on a thread
+    async fn serve_async(mut self, stream: TcpStream, peer: SocketAddr) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut user = None;
+        let mut transaction = None;
+        let mut replies = Vec::new();
+        while let Some(request) = read_frame_async::<_, Request>(&mut reader).await? {
+            let response = task::block_in_place(|| {
+                let _span = debug_span!("request", %peer).entered();
+                debug!("Request from {}: {:?}", peer, request);
+                let response = self.handle(&mut user, &mut transaction, request);
+                debug!("Response to {}: {:?}", peer, response);
+                response
+            });
+            write_frame(&mut replies, &response)?;
+            if reader.buffer().is_empty() || replies.len() >= MAX_PENDING_REPLIES {
+                writer.write_all(&replies).await?;
+                replies.clear();
+            }
+        }
+        Ok(())
+    }
+}
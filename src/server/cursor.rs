@@ -0,0 +1,71 @@
+//! The cursors of the pages of a scan, which hold the key the page before ended at.
+//!
+//! The key is sealed with ChaCha20-Poly1305 under a key only the server knows, so a client can
+//! neither read it, which would give away the names of keys its ACL rules hide, nor forge a cursor
+//! to start from a key of its choosing. Keys are padded before they are sealed, so the length of a
+//! cursor only tells the length of its key to the nearest [`PADDING`] bytes. Cursors are valid
+//! until the server restarts.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+
+use crate::{KvsError, Result};
+
+/// Multiple of bytes the keys are padded to before they are sealed
+const PADDING: usize = 64;
+
+/// Length of the nonce a cursor starts with
+const NONCE_LEN: usize = 12;
+
+/// Seals the keys pages end at into cursors, and opens the cursors clients send back
+pub(crate) struct CursorCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl CursorCipher {
+    /// Creates a cipher under a random key, which opens none of the cursors of another one
+    pub(crate) fn new() -> Self {
+        CursorCipher {
+            cipher: ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng)),
+        }
+    }
+
+    /// The cursor of a page ending at `key`: the nonce and the sealed key, in hex
+    pub(crate) fn seal(&self, key: &str) -> Result<String> {
+        let mut padded = (key.len() as u32).to_le_bytes().to_vec();
+        padded.extend_from_slice(key.as_bytes());
+        padded.resize(padded.len().div_ceil(PADDING) * PADDING, 0);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, padded.as_slice())
+            .map_err(|_| KvsError::Message("Failed to seal cursor".to_owned()))?;
+        Ok(nonce
+            .iter()
+            .chain(&sealed)
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+
+    /// The key the page before `cursor` ended at, or `None` if the cursor wasn't sealed by this
+    /// cipher
+    pub(crate) fn open(&self, cursor: &str) -> Option<String> {
+        if !cursor.len().is_multiple_of(2) {
+            return None;
+        }
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let padded = self.cipher.decrypt(Nonce::from_slice(nonce), sealed).ok()?;
+        let len = u32::from_le_bytes(padded.get(..4)?.try_into().ok()?) as usize;
+        let key = padded.get(4..4 + len)?;
+        String::from_utf8(key.to_vec()).ok()
+    }
+}
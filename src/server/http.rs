@@ -0,0 +1,197 @@
+//! A small REST front-end for the store:
+//!
+//! - `GET /keys/{key}` returns the value, or `404` if the key is missing
+//! - `PUT /keys/{key}` stores the request body as the value
+//! - `DELETE /keys/{key}` removes the key, or returns `404` if it is missing
+//! - `GET /stats` returns the server operation counters as JSON
+//! - `GET /metrics` returns the operation counters, latency histograms and engine counters in the
+//!   Prometheus text format
+//!
+//! When the server has an ACL, requests authenticate with an `Authorization: Bearer <token>`
+//! header and are rejected with `401` or `403` otherwise.
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    thread,
+};
+
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+use tracing::{debug, debug_span, error};
+
+use super::KvsServer;
+use crate::{
+    auth::{Operation, User},
+    protocol::ErrorKind,
+    KvsEngine, KvsError, Result,
+};
+
+/// Path prefix under which keys are addressed
+const KEYS_PREFIX: &str = "/keys/";
+
+/// Content type of the Prometheus text format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Serves HTTP requests one after another until the listener fails
+    pub(super) fn run_http<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(|e| KvsError::Message(e.to_string()))?;
+        for request in server.incoming_requests() {
+            if let Err(e) = self.serve_http(request) {
+                error!("Error serving client: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves `GET /metrics` on `addr` from a background thread
+    pub(super) fn serve_metrics(&self, addr: SocketAddr) -> Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(|e| KvsError::Message(e.to_string()))?;
+        let store = self.store.clone();
+        let metrics = self.metrics.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let (status, body) =
+                    if request.url() == "/metrics" && request.method() == &Method::Get {
+                        (200, metrics.render(&store.metrics()))
+                    } else {
+                        (404, "Not found".to_string())
+                    };
+                if let Err(e) = respond(request, status, body, METRICS_CONTENT_TYPE) {
+                    error!("Error serving metrics: {}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Handles a single HTTP request and sends its response
+    fn serve_http(&mut self, mut request: Request) -> Result<()> {
+        let _span =
+            debug_span!("http_request", method = %request.method(), url = request.url()).entered();
+        debug!("HTTP {} {}", request.method(), request.url());
+        let path = request
+            .url()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let (status, body) = self
+            .route_http(&path, &mut request)
+            .unwrap_or_else(|e| (500, e.to_string()));
+        let content_type = match path.as_str() {
+            "/stats" => "application/json",
+            "/metrics" => METRICS_CONTENT_TYPE,
+            _ => "text/plain; charset=utf-8",
+        };
+        respond(request, status, body, content_type)
+    }
+
+    /// Executes the operation addressed by `path` and returns the status code and body to send
+    fn route_http(&mut self, path: &str, request: &mut Request) -> Result<(u16, String)> {
+        let user = match self.http_user(request) {
+            Ok(user) => user,
+            Err(kind) => return Ok(denied(kind)),
+        };
+        let key = match path.strip_prefix(KEYS_PREFIX) {
+            Some(key) => percent_decode(key)?,
+            None if matches!(path, "/stats" | "/metrics") && request.method() == &Method::Get => {
+                if self.acl.is_some() && user.is_none() {
+                    return Ok(denied(ErrorKind::AuthRequired));
+                }
+                let body = if path == "/stats" {
+                    serde_json::to_string(&self.metrics.stats())?
+                } else {
+                    self.metrics.render(&self.store.metrics())
+                };
+                return Ok((200, body));
+            }
+            None => return Ok((404, "Not found".to_string())),
+        };
+        let op = match request.method() {
+            Method::Get => Operation::Read,
+            _ => Operation::Write,
+        };
+        if let Err(kind) = self.authorize(user.as_deref(), op, &key) {
+            return Ok(denied(kind));
+        }
+        let reply = match request.method() {
+            Method::Get => match self.get(key)? {
+                Some(value) => (200, value),
+                None => (404, "Key not found".to_string()),
+            },
+            Method::Put => {
+                let mut value = String::new();
+                request.as_reader().read_to_string(&mut value)?;
+                self.set(key, value)?;
+                (204, String::new())
+            }
+            Method::Delete => match self.get(key.clone())? {
+                Some(_) => {
+                    self.remove(key)?;
+                    (204, String::new())
+                }
+                None => (404, "Key not found".to_string()),
+            },
+            _ => (405, "Method not allowed".to_string()),
+        };
+        Ok(reply)
+    }
+
+    /// Authenticates a request from its `Authorization: Bearer <token>` header, if it has one
+    fn http_user(&self, request: &Request) -> std::result::Result<Option<Arc<User>>, ErrorKind> {
+        let header = match request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+        {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let token = header
+            .value
+            .as_str()
+            .strip_prefix("Bearer ")
+            .ok_or(ErrorKind::AuthFailed)?;
+        self.authenticate(None, token).map(Some)
+    }
+}
+
+/// Sends the response to `request`
+fn respond(request: Request, status: u16, body: String, content_type: &str) -> Result<()> {
+    let response = Response::from_string(body)
+        .with_status_code(StatusCode(status))
+        .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+    request.respond(response)?;
+    Ok(())
+}
+
+/// Builds the status code and body for a request rejected by authentication or authorization
+fn denied(kind: ErrorKind) -> (u16, String) {
+    match kind {
+        ErrorKind::Forbidden => (403, "Forbidden".to_string()),
+        _ => (401, "Unauthorized".to_string()),
+    }
+}
+
+/// Decodes `%XX` escapes in a URL path segment
+fn percent_decode(segment: &str) -> Result<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| KvsError::Message("Invalid percent-encoding in key".to_owned()))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| KvsError::Message("Key is not valid UTF-8".to_owned()))
+}
@@ -0,0 +1,184 @@
+//! Counters and latency histograms of the operations a [`KvsServer`](super::KvsServer) executes,
+//! rendered in the Prometheus text format by the `/metrics` endpoint.
+
+// they are only ever read over HTTP
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::EngineMetrics;
+
+/// Upper bounds of the latency histogram buckets, in microseconds
+const BUCKETS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// Counters of the operations a [`KvsServer`](super::KvsServer) has executed since it started
+#[derive(Debug, Serialize)]
+pub(super) struct ServerStats {
+    gets: u64,
+    sets: u64,
+    removes: u64,
+    errors: u64,
+}
+
+/// The kinds of operation the server counts
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Op {
+    Get,
+    Set,
+    Remove,
+}
+
+impl Op {
+    const ALL: [Op; 3] = [Op::Get, Op::Set, Op::Remove];
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Remove => "remove",
+        }
+    }
+}
+
+/// What the server counts of one kind of operation
+#[derive(Debug, Default)]
+struct OpMetrics {
+    /// Keys operated on; a multi-get counts once per key
+    count: AtomicU64,
+    errors: AtomicU64,
+    /// Cumulative counts of the requests that took at most each of [`BUCKETS`]
+    buckets: [AtomicU64; BUCKETS.len()],
+    /// Requests timed, and the total time they took in microseconds
+    requests: AtomicU64,
+    micros: AtomicU64,
+}
+
+/// The operations a server executed since it started. Updated and read concurrently, so a
+/// separate thread can serve them while requests are being executed.
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    ops: [OpMetrics; 3],
+}
+
+impl Metrics {
+    /// Counts a request doing `op` on `count` keys that took `elapsed` and succeeded or not
+    pub(super) fn record(&self, op: Op, count: u64, elapsed: Duration, ok: bool) {
+        let metrics = &self.ops[op as usize];
+        metrics.count.fetch_add(count, Ordering::Relaxed);
+        if !ok {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let micros = elapsed.as_micros() as u64;
+        for (bucket, &bound) in metrics.buckets.iter().zip(&BUCKETS) {
+            if micros <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        metrics.micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// The counters `/stats` reports
+    pub(super) fn stats(&self) -> ServerStats {
+        let count = |op: Op| self.ops[op as usize].count.load(Ordering::Relaxed);
+        ServerStats {
+            gets: count(Op::Get),
+            sets: count(Op::Set),
+            removes: count(Op::Remove),
+            errors: self
+                .ops
+                .iter()
+                .map(|metrics| metrics.errors.load(Ordering::Relaxed))
+                .sum(),
+        }
+    }
+
+    /// Renders the server's metrics, and those of its `engine`, in the Prometheus text format
+    pub(super) fn render(&self, engine: &EngineMetrics) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP kvs_ops_total Keys operated on, by operation\n");
+        out.push_str("# TYPE kvs_ops_total counter\n");
+        for op in Op::ALL {
+            let count = self.ops[op as usize].count.load(Ordering::Relaxed);
+            writeln!(out, "kvs_ops_total{{op=\"{}\"}} {}", op.name(), count).unwrap();
+        }
+        out.push_str("# HELP kvs_errors_total Requests that failed, by operation\n");
+        out.push_str("# TYPE kvs_errors_total counter\n");
+        for op in Op::ALL {
+            let errors = self.ops[op as usize].errors.load(Ordering::Relaxed);
+            writeln!(out, "kvs_errors_total{{op=\"{}\"}} {}", op.name(), errors).unwrap();
+        }
+        for (name, help, value) in [
+            (
+                "kvs_compactions_total",
+                "Compactions of the log that finished",
+                engine.compactions,
+            ),
+            (
+                "kvs_write_stalls_total",
+                "Writes held back while a compaction caught up",
+                engine.write_stalls,
+            ),
+            (
+                "kvs_written_bytes_total",
+                "Bytes appended to the log",
+                engine.bytes_written,
+            ),
+            (
+                "kvs_reclaimed_bytes_total",
+                "Bytes of the log freed by compactions",
+                engine.bytes_reclaimed,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        out.push_str("# HELP kvs_request_duration_seconds Time taken by requests, by operation\n");
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for op in Op::ALL {
+            let metrics = &self.ops[op as usize];
+            let requests = metrics.requests.load(Ordering::Relaxed);
+            for (bucket, &bound) in metrics.buckets.iter().zip(&BUCKETS) {
+                writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    bound as f64 / 1e6,
+                    bucket.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                op.name(),
+                requests
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                metrics.micros.load(Ordering::Relaxed) as f64 / 1e6
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                requests
+            )
+            .unwrap();
+        }
+        out
+    }
+}
@@ -0,0 +1,687 @@
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{
+    io::{BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::Instant,
+};
+
+use tracing::{debug, debug_span, error};
+
+use self::{
+    cursor::CursorCipher,
+    metrics::{Metrics, Op},
+};
+use crate::{
+    auth::{Acl, Operation, User},
+    engines::glob,
+    protocol::{read_frame, write_frame, ErrorKind, Request, Response},
+    thread_pool::ThreadPool,
+    KvsEngine, KvsError, Result, WriteBatch,
+};
+
+/// Upper bound for the changes sent in response to a single [`Request::Replicate`]
+const MAX_REPLICATION_BATCH: usize = 1000;
+
+/// Upper bound for the keys a single [`Request::Scan`] goes through
+const MAX_SCAN_COUNT: usize = 1000;
+
+/// Size past which replies held back for requests pipelined behind them are sent anyway
+pub(super) const MAX_PENDING_REPLIES: usize = 64 * 1024;
+
+/// Prefix of the keys a [`Request::Replicate`] needs to be allowed to read: all of them
+const ALL_KEYS: &str = "";
+
+#[cfg(feature = "tokio")]
+mod async_io;
+pub(crate) mod cursor;
+#[cfg(feature = "http")]
+mod http;
+mod metrics;
+mod replica;
+mod resp;
+
+/// A job serving a connection, run on the thread pool of a [`KvsServer`]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The wire protocol a [`KvsServer`] speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The native length-prefixed JSON [`protocol`](crate::protocol)
+    #[default]
+    Kvs,
+    /// RESP2, so Redis clients can issue `GET`, `MGET`, `SET`, `SETNX`, `DEL`, `RENAME`, `EXISTS`,
+    /// `SCAN` and `PING`
+    Resp,
+    /// HTTP/1.1 with a small REST API under `/keys/{key}` and `/stats` and `/metrics` endpoints
+    #[cfg(feature = "http")]
+    Http,
+    /// gRPC, see the [`grpc`](crate::grpc) module
+    #[cfg(feature = "grpc")]
+    Grpc,
+}
+
+/// A server that serves a [`KvsEngine`] over TCP.
+///
+/// The store is opened once and kept open for the lifetime of the server, so the log is replayed
+/// only at startup instead of once per command.
+pub struct KvsServer<E: KvsEngine> {
+    store: E,
+    protocol: Protocol,
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "http")]
+    metrics_addr: Option<SocketAddr>,
+    acl: Option<Arc<Acl>>,
+    /// Seals the cursors of scans, so they don't give away the keys they hold
+    cursors: Arc<CursorCipher>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Address of the leader the server follows, if any
+    follower_of: Option<SocketAddr>,
+    /// Replicates the store of the leader until the process exits, once the server runs
+    follower: Option<Box<dyn FnOnce() + Send>>,
+    /// Whether connections are served from tokio tasks
+    #[cfg(feature = "tokio")]
+    async_io: bool,
+    /// Runs a job serving a connection on the thread pool, if connections aren't served one after
+    /// another
+    pool: Option<Box<dyn Fn(Job) + Send>>,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Creates a [`KvsServer`] serving the given store with the native protocol
+    pub fn new(store: E) -> Self {
+        KvsServer {
+            store,
+            protocol: Protocol::default(),
+            metrics: Arc::default(),
+            #[cfg(feature = "http")]
+            metrics_addr: None,
+            acl: None,
+            cursors: Arc::new(CursorCipher::new()),
+            #[cfg(feature = "tls")]
+            tls: None,
+            follower_of: None,
+            follower: None,
+            #[cfg(feature = "tokio")]
+            async_io: false,
+            pool: None,
+        }
+    }
+
+    /// Sets the protocol spoken to clients
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsServer, Protocol};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let server = KvsServer::new(store).with_protocol(Protocol::Resp);
+    /// ```
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Also serves `GET /metrics` over HTTP on `addr`, in the Prometheus text format, whatever the
+    /// protocol spoken to clients
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let server = KvsServer::new(store).with_metrics_addr("127.0.0.1:9100".parse().unwrap());
+    /// ```
+    #[cfg(feature = "http")]
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Requires clients to authenticate as one of the users in `acl` and restricts each of them to
+    /// the operations and key prefixes their rules allow
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{auth::Acl, KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let server = KvsServer::new(store).with_acl(Acl::from_file("acl.json").unwrap());
+    /// ```
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Terminates TLS on every connection using the PEM certificate chain at `cert` and the PEM
+    /// private key at `key`. Only supported for the native and RESP protocols.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let server = KvsServer::new(store).with_tls("cert.pem", "key.pem").unwrap();
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        self.tls = Some(crate::tls::server_config(cert.as_ref(), key.as_ref())?);
+        Ok(self)
+    }
+
+    /// Serves every connection from a tokio task of its own instead of one after another, so a
+    /// server can hold thousands of mostly idle connections without a thread for each. Requests
+    /// are still executed one at a time per connection. Only supported for the native protocol,
+    /// without TLS.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// KvsServer::new(store).with_async().run("127.0.0.1:4000").unwrap();
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub fn with_async(mut self) -> Self {
+        self.async_io = true;
+        self
+    }
+
+    /// Serves connections concurrently from the threads of `pool` instead of one after another.
+    /// Requests are still executed one at a time per connection.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{thread_pool::{SharedQueueThreadPool, ThreadPool}, KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// let pool = SharedQueueThreadPool::new(8).unwrap();
+    /// KvsServer::new(store).with_thread_pool(pool).run("127.0.0.1:4000").unwrap();
+    /// ```
+    pub fn with_thread_pool<P: ThreadPool>(mut self, pool: P) -> Self {
+        self.pool = Some(Box::new(move |job| pool.spawn(job)));
+        self
+    }
+
+    /// Listens on `addr` and serves connections until the listener fails, one after another
+    /// unless a thread pool or the async server serve them concurrently
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsServer};
+    /// # use tempfile::TempDir;
+    ///
+    /// let store = KvStore::open(TempDir::new().unwrap().path()).unwrap();
+    /// KvsServer::new(store).run("127.0.0.1:4000").unwrap();
+    /// ```
+    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() && !matches!(self.protocol, Protocol::Kvs | Protocol::Resp) {
+            return Err(KvsError::Message(format!(
+                "TLS is not supported with the {:?} protocol",
+                self.protocol
+            )));
+        }
+        #[cfg(feature = "tokio")]
+        if self.async_io && self.protocol != Protocol::Kvs {
+            return Err(KvsError::Message(format!(
+                "The async server doesn't support the {:?} protocol",
+                self.protocol
+            )));
+        }
+        #[cfg(feature = "tokio")]
+        if self.async_io && self.pool.is_some() {
+            return Err(KvsError::Message(
+                "The async server doesn't serve connections from a thread pool".to_owned(),
+            ));
+        }
+        #[cfg(all(feature = "tokio", feature = "tls"))]
+        if self.async_io && self.tls.is_some() {
+            return Err(KvsError::Message(
+                "TLS is not supported by the async server".to_owned(),
+            ));
+        }
+        #[cfg(feature = "grpc")]
+        if self.follower.is_some() && self.protocol == Protocol::Grpc {
+            return Err(KvsError::Message(
+                "Following a leader is not supported with the gRPC protocol".to_owned(),
+            ));
+        }
+        if let Some(follower) = self.follower.take() {
+            thread::spawn(follower);
+        }
+        #[cfg(feature = "http")]
+        if let Some(metrics_addr) = self.metrics_addr {
+            self.serve_metrics(metrics_addr)?;
+        }
+        #[cfg(feature = "http")]
+        if self.protocol == Protocol::Http {
+            return self.run_http(addr);
+        }
+        #[cfg(feature = "grpc")]
+        if self.protocol == Protocol::Grpc {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| KvsError::Message("No address to listen on".to_owned()))?;
+            return crate::grpc::serve(self.store, self.acl, addr);
+        }
+        #[cfg(feature = "tokio")]
+        if self.async_io {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| KvsError::Message("No address to listen on".to_owned()))?;
+            return self.run_async(addr);
+        }
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match (stream, &self.pool) {
+                (Ok(stream), Some(spawn)) => {
+                    let mut server = self.for_connection();
+                    spawn(Box::new(move || {
+                        if let Err(e) = server.accept(stream) {
+                            error!("Error serving client: {}", e);
+                        }
+                    }));
+                }
+                (Ok(stream), None) => {
+                    if let Err(e) = self.accept(stream) {
+                        error!("Error serving client: {}", e);
+                    }
+                }
+                (Err(e), _) => error!("Connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy of the server sharing its store, metrics and settings, to serve a connection with
+    /// concurrently with the others
+    fn for_connection(&self) -> KvsServer<E> {
+        KvsServer {
+            store: self.store.clone(),
+            protocol: self.protocol,
+            metrics: self.metrics.clone(),
+            #[cfg(feature = "http")]
+            metrics_addr: self.metrics_addr,
+            acl: self.acl.clone(),
+            cursors: self.cursors.clone(),
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            follower_of: self.follower_of,
+            follower: None,
+            #[cfg(feature = "tokio")]
+            async_io: self.async_io,
+            pool: None,
+        }
+    }
+
+    /// Serves an accepted connection, terminating TLS first if it is configured
+    fn accept(&mut self, stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr()?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let connection = rustls::ServerConnection::new(config.clone())?;
+            return self.serve_stream(rustls::StreamOwned::new(connection, stream), peer);
+        }
+        self.serve_stream(stream, peer)
+    }
+
+    /// Serves a connection with the configured protocol
+    fn serve_stream<S: Read + Write>(&mut self, stream: S, peer: SocketAddr) -> Result<()> {
+        match self.protocol {
+            Protocol::Resp => self.serve_resp(stream, peer),
+            _ => self.serve(stream, peer),
+        }
+    }
+
+    /// Serves requests from a single connection until the client hangs up.
+    ///
+    /// Requests are answered in the order they came in. The responses to requests a client
+    /// pipelined are sent together once the requests already received run out.
+    fn serve<S: Read + Write>(&mut self, stream: S, peer: SocketAddr) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut user = None;
+        let mut transaction = None;
+        let mut replies = Vec::new();
+        while let Some(request) = read_frame::<_, Request>(&mut stream)? {
+            let _span = debug_span!("request", %peer).entered();
+            debug!("Request from {}: {:?}", peer, request);
+            let response = self.handle(&mut user, &mut transaction, request);
+            debug!("Response to {}: {:?}", peer, response);
+            write_frame(&mut replies, &response)?;
+            if stream.buffer().is_empty() || replies.len() >= MAX_PENDING_REPLIES {
+                stream.get_mut().write_all(&replies)?;
+                stream.get_mut().flush()?;
+                replies.clear();
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a single request on behalf of the connection's authenticated `user`, or queues it
+    /// in the connection's open `transaction`
+    fn handle(
+        &mut self,
+        user: &mut Option<Arc<User>>,
+        transaction: &mut Option<WriteBatch>,
+        request: Request,
+    ) -> Response {
+        let access: Vec<(Operation, &str)> = match &request {
+            Request::Auth { username, password } => {
+                return match self.authenticate(username.as_deref(), password) {
+                    Ok(authenticated) => {
+                        *user = Some(authenticated);
+                        Response::Ok(None)
+                    }
+                    Err(kind) => error_response(kind, None),
+                };
+            }
+            Request::Get { key } => vec![(Operation::Read, key)],
+            Request::MultiGet { keys } => {
+                keys.iter().map(|key| (Operation::Read, &key[..])).collect()
+            }
+            Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::SetIfAbsent { key, .. }
+            | Request::RemoveIf { key, .. } => vec![(Operation::Write, key)],
+            // renaming removes the source, while copying only reads it
+            Request::Rename { from, to } => vec![(Operation::Write, from), (Operation::Write, to)],
+            Request::Copy { from, to } => vec![(Operation::Read, from), (Operation::Write, to)],
+            Request::Replicate { .. } => vec![(Operation::Read, ALL_KEYS)],
+            // only the keys the user may read are listed, but the user has to be known
+            Request::Scan { .. } if self.acl.is_some() && user.is_none() => {
+                return error_response(ErrorKind::AuthRequired, None)
+            }
+            Request::Scan { .. } => vec![],
+            Request::Multi | Request::Exec | Request::Discard => vec![],
+        };
+        for (op, key) in access {
+            if let Err(kind) = self.authorize(user.as_deref(), op, key) {
+                return error_response(kind, Some(key));
+            }
+        }
+        // the writes of a transaction are authorized as they are queued, and applied together
+        let request = match (transaction.take(), request) {
+            (None, Request::Multi) => {
+                *transaction = Some(WriteBatch::new());
+                return Response::Ok(None);
+            }
+            (None, Request::Exec | Request::Discard) => {
+                return transaction_error("No transaction to end")
+            }
+            (None, request) => request,
+            (Some(mut batch), Request::Set { key, value }) => {
+                batch.set(key, value);
+                *transaction = Some(batch);
+                return Response::Queued;
+            }
+            (Some(mut batch), Request::Remove { key }) => {
+                batch.remove(key);
+                *transaction = Some(batch);
+                return Response::Queued;
+            }
+            (Some(batch), Request::Exec) => {
+                return match self.write_batch(batch) {
+                    Ok(()) => Response::Ok(None),
+                    Err(e) => store_error(e),
+                };
+            }
+            (Some(_), Request::Discard) => return Response::Ok(None),
+            (Some(batch), request) => {
+                *transaction = Some(batch);
+                return transaction_error(match request {
+                    Request::Multi => "Transactions can't be nested",
+                    _ => "Only sets and removals can be queued in a transaction",
+                });
+            }
+        };
+
+        let result = match request {
+            Request::Get { key } => self.get(key).map(Response::Ok),
+            Request::MultiGet { keys } => self.multi_get(&keys).map(Response::Values),
+            Request::Set { key, value } => self.set(key, value).map(|_| Response::Ok(None)),
+            Request::Remove { key } => self.remove(key).map(|_| Response::Ok(None)),
+            Request::SetIfAbsent { key, value } => {
+                self.set_if_absent(key, value).map(Response::Applied)
+            }
+            Request::RemoveIf { key, expected } => {
+                self.remove_if(key, expected).map(Response::Applied)
+            }
+            Request::Rename { from, to } => self.rename(from, to).map(|_| Response::Ok(None)),
+            Request::Copy { from, to } => self.copy(from, to).map(|_| Response::Ok(None)),
+            Request::Replicate {
+                store_id,
+                from,
+                limit,
+            } => self
+                .store
+                .changes(store_id.as_deref(), from, limit.min(MAX_REPLICATION_BATCH))
+                .map(Response::Changes),
+            Request::Scan {
+                cursor,
+                pattern,
+                count,
+            } => match cursor.map(|cursor| self.cursors.open(&cursor)) {
+                Some(None) => Err(KvsError::Message("Invalid cursor".to_owned())),
+                after => self
+                    .scan(
+                        user.as_deref(),
+                        after.flatten().as_deref(),
+                        pattern.as_deref(),
+                        count,
+                    )
+                    .map(|(keys, cursor)| Response::Keys { keys, cursor }),
+            },
+            Request::Auth { .. } | Request::Multi | Request::Exec | Request::Discard => {
+                unreachable!()
+            }
+        };
+        result.unwrap_or_else(store_error)
+    }
+
+    /// Checks credentials against the ACL. Fails if they don't match or auth isn't enabled.
+    fn authenticate(
+        &self,
+        username: Option<&str>,
+        password: &str,
+    ) -> std::result::Result<Arc<User>, ErrorKind> {
+        self.acl
+            .as_ref()
+            .and_then(|acl| acl.authenticate(username, password))
+            .ok_or(ErrorKind::AuthFailed)
+    }
+
+    /// Checks whether `user` may perform `op` on `key`. Everything is allowed without an ACL.
+    fn authorize(
+        &self,
+        user: Option<&User>,
+        op: Operation,
+        key: &str,
+    ) -> std::result::Result<(), ErrorKind> {
+        match (&self.acl, user) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(ErrorKind::AuthRequired),
+            (Some(_), Some(user)) if user.allows(op, key) => Ok(()),
+            (Some(_), Some(_)) => Err(ErrorKind::Forbidden),
+        }
+    }
+
+    /// Gets a key from the store, counting the operation in the server metrics
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.measure(Op::Get, 1, |store| store.get(key))
+    }
+
+    /// Gets several keys from the store, counting each in the server metrics
+    fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.measure(Op::Get, keys.len() as u64, |store| store.multi_get(keys))
+    }
+
+    /// Sets a key in the store, counting the operation in the server metrics
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.check_writable()?;
+        self.measure(Op::Set, 1, |store| {
+            store.set(key, value)?;
+            store.flush()
+        })
+    }
+
+    /// Sets a key in the store unless it exists, counting the operation in the server metrics
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.check_writable()?;
+        self.measure(Op::Set, 1, |store| {
+            let set = store.set_if_absent(key, value)?;
+            store.flush()?;
+            Ok(set)
+        })
+    }
+
+    /// Removes a key from the store, counting the operation in the server metrics
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.check_writable()?;
+        self.measure(Op::Remove, 1, |store| {
+            store.remove(key)?;
+            store.flush()
+        })
+    }
+
+    /// Moves the value of a key to another in the store, counting the operation in the server
+    /// metrics
+    fn rename(&mut self, from: String, to: String) -> Result<()> {
+        self.check_writable()?;
+        self.measure(Op::Set, 1, |store| {
+            store.rename(from, to)?;
+            store.flush()
+        })
+    }
+
+    /// Copies the value of a key to another in the store, counting the operation in the server
+    /// metrics
+    fn copy(&mut self, from: String, to: String) -> Result<()> {
+        self.check_writable()?;
+        self.measure(Op::Set, 1, |store| {
+            store.copy(from, to)?;
+            store.flush()
+        })
+    }
+
+    /// Removes a key from the store if its value is `expected`, counting the operation in the
+    /// server metrics
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.check_writable()?;
+        self.measure(Op::Remove, 1, |store| {
+            let removed = store.remove_if(key, expected)?;
+            store.flush()?;
+            Ok(removed)
+        })
+    }
+
+    /// Goes through up to `count` keys after `after`, or from the first key, and returns the ones
+    /// `user` may read that match `pattern`, along with the cursor of the next page, unless every
+    /// key was gone through.
+    ///
+    /// The cursor holds the last key gone through, which `user` may not be allowed to read, so it
+    /// is sealed by [`CursorCipher`] and has to be opened before it is passed back as `after`.
+    ///
+    /// Keys are gone through in lexical order, so a scan ends however many keys are added or
+    /// removed meanwhile, and lists every key that exists from its start to its end.
+    fn scan(
+        &self,
+        user: Option<&User>,
+        after: Option<&str>,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let count = count.clamp(1, MAX_SCAN_COUNT);
+        let scanned = self.store.keys_after(after, count)?;
+        let cursor = match scanned.last() {
+            Some(last) if scanned.len() == count => Some(self.cursors.seal(last)?),
+            _ => None,
+        };
+        let keys = scanned
+            .into_iter()
+            .filter(|key| {
+                pattern.is_none_or(|pattern| glob::matches(pattern.as_bytes(), key.as_bytes()))
+            })
+            .filter(|key| self.authorize(user, Operation::Read, key).is_ok())
+            .collect();
+        Ok((keys, cursor))
+    }
+
+    /// Applies the writes of a transaction atomically, counting each in the server metrics
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        self.measure(Op::Set, batch.len() as u64, |store| {
+            store.write_batch(batch)?;
+            store.flush()
+        })
+    }
+
+    /// Fails if the server follows a leader, whose store is the only one written to
+    fn check_writable(&self) -> Result<()> {
+        match self.follower_of {
+            Some(leader) => Err(KvsError::Message(format!(
+                "This server is a read-only follower, send writes to its leader at {}",
+                leader
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `f` against the store, counting it as `op` on `count` keys and timing it
+    fn measure<T>(&mut self, op: Op, count: u64, f: impl FnOnce(&E) -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = f(&self.store);
+        self.metrics
+            .record(op, count, start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+/// Builds the response for a request the store failed
+fn store_error(e: KvsError) -> Response {
+    let kind = match e {
+        KvsError::KeyNotFound => ErrorKind::KeyNotFound,
+        _ => ErrorKind::Store,
+    };
+    Response::Err {
+        kind,
+        message: e.to_string(),
+    }
+}
+
+/// Builds the response for a request that doesn't fit the transaction of the connection
+fn transaction_error(message: &str) -> Response {
+    Response::Err {
+        kind: ErrorKind::Transaction,
+        message: message.to_string(),
+    }
+}
+
+/// Builds the response for a request rejected by authentication or authorization
+fn error_response(kind: ErrorKind, key: Option<&str>) -> Response {
+    let message = match (kind, key) {
+        (ErrorKind::AuthRequired, _) => "Authentication required".to_string(),
+        (ErrorKind::AuthFailed, _) => "Invalid credentials".to_string(),
+        (ErrorKind::Forbidden, Some(key)) => format!("Access to key {} denied", key),
+        _ => "Access denied".to_string(),
+    };
+    Response::Err { kind, message }
+}
@@ -0,0 +1,103 @@
+//! Follower side of leader-follower replication.
+//!
+//! A follower polls its leader for the changes past the position it reached with
+//! [`KvsClient::changes`] and applies them to its own store. The position is saved in the
+//! `replication` file of the store directory, so a restarted follower picks up where it left off.
+//! When the leader can't serve that position any more (it was compacted away, or the leader's
+//! store was replaced), the leader starts over from the beginning of its log and the follower
+//! drops its keys first: this is the initial full sync.
+
+use std::{fs, net::SocketAddr, path::Path, thread, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use super::KvsServer;
+use crate::{KvStore, KvsClient, LogPosition, Result};
+
+/// Name of the file, in the store directory, holding the replication state
+const STATE_FILE: &str = "replication";
+
+/// Number of changes asked for at once
+const BATCH_SIZE: usize = 1000;
+
+/// How long a follower that caught up waits before polling the leader again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a follower waits before reconnecting to a leader it failed to replicate from
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far a follower got in the log of its leader
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ReplicationState {
+    /// UUID of the leader's store, unless nothing was replicated yet
+    store_id: Option<String>,
+    /// Where the next changes start
+    position: Option<LogPosition>,
+}
+
+impl KvsServer<KvStore> {
+    /// Makes the server a read-only follower of the server at `leader`, whose changes it applies
+    /// to its store in the background once it runs. Writes sent to the follower fail.
+    pub fn with_leader(mut self, leader: SocketAddr) -> Self {
+        let store = self.store.clone();
+        self.follower_of = Some(leader);
+        self.follower = Some(Box::new(move || loop {
+            if let Err(e) = follow(&store, leader) {
+                error!("Error replicating from {}: {}", leader, e);
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }));
+        self
+    }
+}
+
+/// Applies the changes of the leader at `leader` to `store` until an error occurs
+fn follow(store: &KvStore, leader: SocketAddr) -> Result<()> {
+    let path = store.dir().join(STATE_FILE);
+    let mut state = read_state(&path)?;
+    info!("Replicating from {}", leader);
+    loop {
+        let batch = KvsClient::connect(leader)?.changes(
+            state.store_id.clone(),
+            state.position.unwrap_or(LogPosition::START),
+            BATCH_SIZE,
+        )?;
+        if batch.reset {
+            info!("Full sync from {} (store {})", leader, batch.store_id);
+            store.clear()?;
+        }
+        let count = batch.records.len();
+        for record in batch.records {
+            store.apply_change(record)?;
+        }
+        if batch.reset || count > 0 {
+            store.sync_all()?;
+            state = ReplicationState {
+                store_id: Some(batch.store_id),
+                position: Some(batch.next),
+            };
+            write_state(&path, &state)?;
+            debug!("Replicated {} changes, up to {}", count, batch.next);
+        }
+        if count < BATCH_SIZE {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Reads the replication state, which is empty for a follower that never replicated
+fn read_state(path: &Path) -> Result<ReplicationState> {
+    if !path.exists() {
+        return Ok(ReplicationState::default());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Replaces the replication state, so that a crash leaves either the old or the new one
+fn write_state(path: &Path, state: &ReplicationState) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
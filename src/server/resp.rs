@@ -0,0 +1,379 @@
+//! A minimal implementation of the Redis serialization protocol (RESP2).
+//!
+//! Only what a server needs is implemented: reading client commands (either arrays of bulk
+//! strings or inline commands) and writing replies.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tracing::{debug, debug_span};
+
+use super::{cursor::CursorCipher, KvsServer, MAX_PENDING_REPLIES};
+use crate::{
+    auth::{Operation, User},
+    protocol::ErrorKind,
+    KvsEngine, KvsError, Result,
+};
+
+/// Upper bound for a bulk string, matching the framing limit of the native protocol
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+
+/// Upper bound for the number of arguments in a single command
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Keys a `SCAN` goes through unless it says otherwise with `COUNT`, like Redis
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+    /// `+<string>`
+    Simple(String),
+    /// `-<message>`
+    Error(String),
+    /// `:<integer>`
+    Integer(i64),
+    /// `$<len>` followed by the bytes, or `$-1` for a null bulk string
+    Bulk(Option<Vec<u8>>),
+    /// `*<len>` followed by the elements
+    Array(Vec<Value>),
+}
+
+/// Reads the next command from `reader` as a list of arguments.
+///
+/// Returns `None` if the peer closed the connection before a new command started.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        // inline command, e.g. `PING` typed into telnet
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    }
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header =
+            read_line(reader)?.ok_or_else(|| KvsError::Protocol("Unexpected EOF".to_owned()))?;
+        if header.first() != Some(&b'$') {
+            return Err(KvsError::Protocol(
+                "Protocol error: expected '$'".to_owned(),
+            ));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN)?;
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol(
+                "Protocol error: expected CRLF".to_owned(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Writes `value` to `writer`. The caller is responsible for flushing.
+fn write_value<W: Write>(writer: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+        Value::Error(message) => write!(writer, "-{}\r\n", message)?,
+        Value::Integer(i) => write!(writer, ":{}\r\n", i)?,
+        Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+        Value::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")?;
+        }
+        Value::Array(values) => {
+            write!(writer, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(writer, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a CRLF (or bare LF) terminated line without its terminator
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parses the length following a `*` or `$` type marker
+fn parse_len(bytes: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| KvsError::Protocol("Protocol error: invalid length".to_owned()))
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    /// Serves RESP commands from a single connection until the client hangs up or sends `QUIT`
+    pub(super) fn serve_resp<S: Read + Write>(
+        &mut self,
+        stream: S,
+        peer: SocketAddr,
+    ) -> Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut user = None;
+        // replies to pipelined commands are sent together, as for the native protocol
+        let mut replies = Vec::new();
+        while let Some(args) = read_command(&mut stream)? {
+            let mut quit = false;
+            if !args.is_empty() {
+                let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+                let _span = debug_span!("resp_command", %peer, command = %name).entered();
+                debug!("RESP command from {}: {}", peer, name);
+                let reply = match self.handle_resp(&mut user, &name, &args[1..]) {
+                    Ok(reply) => reply,
+                    Err(e) => Value::Error(format!("ERR {}", e)),
+                };
+                write_value(&mut replies, &reply)?;
+                quit = name == "QUIT";
+            }
+            if quit || stream.buffer().is_empty() || replies.len() >= MAX_PENDING_REPLIES {
+                stream.get_mut().write_all(&replies)?;
+                stream.get_mut().flush()?;
+                replies.clear();
+            }
+            if quit {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a single RESP command on behalf of the connection's authenticated `user`
+    fn handle_resp(
+        &mut self,
+        user: &mut Option<Arc<User>>,
+        name: &str,
+        args: &[Vec<u8>],
+    ) -> Result<Value> {
+        let wrong_args = || {
+            Ok(Value::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )))
+        };
+        let access = match name {
+            "GET" | "MGET" | "EXISTS" => Some(Operation::Read),
+            "SET" | "SETNX" | "DEL" | "RENAME" => Some(Operation::Write),
+            _ => None,
+        };
+        // only the keys the user may read are listed, but the user has to be known
+        if name == "SCAN" && self.acl.is_some() && user.is_none() {
+            return Ok(denied(ErrorKind::AuthRequired));
+        }
+        if let Some(op) = access {
+            let keys = match name {
+                "GET" | "SET" | "SETNX" => args.get(..1).unwrap_or_default(),
+                _ => args,
+            };
+            for key in keys {
+                if let Err(kind) =
+                    self.authorize(user.as_deref(), op, &String::from_utf8_lossy(key))
+                {
+                    return Ok(denied(kind));
+                }
+            }
+        }
+        let reply = match name {
+            "AUTH" => {
+                let (username, password) = match args {
+                    [password] => (None, password),
+                    [username, password] => (Some(utf8(username)?), password),
+                    _ => return wrong_args(),
+                };
+                match self.authenticate(username.as_deref(), &utf8(password)?) {
+                    Ok(authenticated) => {
+                        *user = Some(authenticated);
+                        Value::Simple("OK".to_string())
+                    }
+                    Err(kind) => denied(kind),
+                }
+            }
+            "PING" => match args {
+                [] => Value::Simple("PONG".to_string()),
+                [message] => Value::Bulk(Some(message.clone())),
+                _ => return wrong_args(),
+            },
+            "QUIT" => Value::Simple("OK".to_string()),
+            // clients such as redis-cli ask for command docs on connect; an empty reply is enough
+            "COMMAND" => Value::Array(vec![]),
+            "GET" => match args {
+                [key] => Value::Bulk(self.get(utf8(key)?)?.map(String::into_bytes)),
+                _ => return wrong_args(),
+            },
+            "MGET" if args.is_empty() => return wrong_args(),
+            "MGET" => {
+                let keys = args
+                    .iter()
+                    .map(|key| utf8(key))
+                    .collect::<Result<Vec<_>>>()?;
+                let values = self.multi_get(&keys)?;
+                Value::Array(
+                    values
+                        .into_iter()
+                        .map(|value| Value::Bulk(value.map(String::into_bytes)))
+                        .collect(),
+                )
+            }
+            "SET" => match args {
+                [key, value] => {
+                    self.set(utf8(key)?, utf8(value)?)?;
+                    Value::Simple("OK".to_string())
+                }
+                [key, value, nx] if nx.eq_ignore_ascii_case(b"NX") => {
+                    if self.set_if_absent(utf8(key)?, utf8(value)?)? {
+                        Value::Simple("OK".to_string())
+                    } else {
+                        Value::Bulk(None)
+                    }
+                }
+                [_, _, ..] => Value::Error("ERR syntax error".to_string()),
+                _ => return wrong_args(),
+            },
+            "SETNX" => match args {
+                [key, value] => {
+                    Value::Integer(self.set_if_absent(utf8(key)?, utf8(value)?)? as i64)
+                }
+                _ => return wrong_args(),
+            },
+            "RENAME" => match args {
+                [from, to] => {
+                    let from = utf8(from)?;
+                    if self.get(from.clone())?.is_none() {
+                        Value::Error("ERR no such key".to_string())
+                    } else {
+                        self.rename(from, utf8(to)?)?;
+                        Value::Simple("OK".to_string())
+                    }
+                }
+                _ => return wrong_args(),
+            },
+            "DEL" | "EXISTS" if args.is_empty() => return wrong_args(),
+            "DEL" => {
+                let mut removed = 0;
+                for key in args {
+                    let key = utf8(key)?;
+                    if self.get(key.clone())?.is_some() {
+                        self.remove(key)?;
+                        removed += 1;
+                    }
+                }
+                Value::Integer(removed)
+            }
+            "EXISTS" => {
+                let mut found = 0;
+                for key in args {
+                    if self.get(utf8(key)?)?.is_some() {
+                        found += 1;
+                    }
+                }
+                Value::Integer(found)
+            }
+            "SCAN" => {
+                let (cursor, options) = match args.split_first() {
+                    Some(args) => args,
+                    None => return wrong_args(),
+                };
+                let after = match parse_cursor(&self.cursors, cursor) {
+                    Some(cursor) => cursor,
+                    None => return Ok(Value::Error("ERR invalid cursor".to_string())),
+                };
+                let mut pattern = None;
+                let mut count = DEFAULT_SCAN_COUNT;
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    match (option.to_ascii_uppercase().as_slice(), options.next()) {
+                        (b"MATCH", Some(arg)) => pattern = Some(utf8(arg)?),
+                        (b"COUNT", Some(arg)) => {
+                            match std::str::from_utf8(arg)
+                                .ok()
+                                .and_then(|arg| arg.parse().ok())
+                                .filter(|&count| count > 0)
+                            {
+                                Some(arg) => count = arg,
+                                None => return Ok(Value::Error("ERR syntax error".to_string())),
+                            }
+                        }
+                        _ => return Ok(Value::Error("ERR syntax error".to_string())),
+                    }
+                }
+                let (keys, cursor) =
+                    self.scan(user.as_deref(), after.as_deref(), pattern.as_deref(), count)?;
+                Value::Array(vec![
+                    Value::Bulk(Some(format_cursor(cursor.as_deref()).into_bytes())),
+                    Value::Array(
+                        keys.into_iter()
+                            .map(|key| Value::Bulk(Some(key.into_bytes())))
+                            .collect(),
+                    ),
+                ])
+            }
+            _ => Value::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        };
+        Ok(reply)
+    }
+}
+
+/// Converts a RESP argument into a `String`, since the store only holds UTF-8 keys and values
+fn utf8(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| KvsError::Message("value is not valid UTF-8".to_owned()))
+}
+
+/// The cursor of the next page of a `SCAN` sent to the client, or `0` once every key was listed,
+/// like Redis
+fn format_cursor(cursor: Option<&str>) -> String {
+    cursor.unwrap_or("0").to_string()
+}
+
+/// Opens a cursor sent by the client into the key the page before ended at, or `None` for `0`,
+/// which starts a new scan. Fails if the cursor wasn't sealed by `cursors`.
+fn parse_cursor(cursors: &CursorCipher, cursor: &[u8]) -> Option<Option<String>> {
+    if cursor == b"0" {
+        return Some(None);
+    }
+    cursors.open(std::str::from_utf8(cursor).ok()?).map(Some)
+}
+
+/// Builds the Redis-style error reply for a command rejected by authentication or authorization
+fn denied(kind: ErrorKind) -> Value {
+    Value::Error(
+        match kind {
+            ErrorKind::AuthRequired => "NOAUTH Authentication required.",
+            ErrorKind::Forbidden => {
+                "NOPERM this user has no permissions to access one of the keys used as arguments"
+            }
+            _ => "WRONGPASS invalid username-password pair or user is disabled.",
+        }
+        .to_string(),
+    )
+}
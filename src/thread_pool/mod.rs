@@ -0,0 +1,28 @@
+//! Thread pools a [`KvsServer`](crate::KvsServer) can serve connections from, see
+//! [`KvsServer::with_thread_pool`](crate::KvsServer::with_thread_pool).
+
+use crate::Result;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod shared_queue;
+
+/// Runs jobs on other threads.
+///
+/// The implementations differ in how they trade the cost of starting threads against how many
+/// jobs can run at once, so a server's concurrency can be tuned and benchmarked.
+pub trait ThreadPool: Send + 'static {
+    /// Creates a pool running up to `threads` jobs at once, if it bounds them at all.
+    ///
+    /// Fails if `threads` is 0 or the threads can't be started.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on a thread of the pool. A job that panics doesn't take the pool down.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,22 @@
+use std::thread;
+
+use super::ThreadPool;
+use crate::Result;
+
+/// Not really a pool: every job gets a new thread, however many are already running.
+///
+/// The baseline the other pools are compared against.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
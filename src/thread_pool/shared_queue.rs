@@ -0,0 +1,85 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use tracing::error;
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+/// A job queued for the workers
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of worker threads taking jobs from a single queue.
+///
+/// A worker whose job panics is replaced by a new one, so the pool keeps its size. The workers
+/// exit once the pool is dropped and the jobs queued before are done.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::Message(
+                "A thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            Worker(receiver.clone()).start()?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the workers only hang up once the pool is dropped
+        let _ = self.sender.send(Box::new(job));
+    }
+}
+
+/// A worker thread, which starts a new one in its place if a job panics on it
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    /// Runs the worker on a new thread
+    fn start(self) -> Result<()> {
+        thread::Builder::new()
+            .name("kvs-worker".to_owned())
+            .spawn(move || self.run())?;
+        Ok(())
+    }
+
+    /// Runs jobs until the pool is dropped
+    fn run(&self) {
+        loop {
+            // the lock is released before the job runs, so jobs don't wait on each other
+            let job = match self.0.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = Worker(self.0.clone()).start() {
+                error!("Failed to replace a worker thread: {}", e);
+            }
+        }
+    }
+}
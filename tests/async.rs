@@ -0,0 +1,136 @@
+#![cfg(feature = "tokio")]
+
+use common::start_server;
+use kvs::{AsyncKvStore, KvStore, KvStoreOptions, KvsClient, KvsEngine, Result, SyncPolicy};
+use std::net::TcpStream;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+mod common;
+
+// Async handles should read and write the same store as the sync API.
+#[tokio::test]
+async fn async_get_set_rm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    store.remove("key2".to_owned()).await?;
+    assert!(store.remove("key2".to_owned()).await.is_err());
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Many tasks should be able to write through clones of one handle at once.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::new(KvStore::open(temp_dir.path())?);
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.set(format!("key{}", i), format!("value{}", i)).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(store.keys_with_prefix("key".to_owned()).await?.len(), 100);
+
+    Ok(())
+}
+
+// Async writes should reach the log file before they return under `SyncPolicy::Always`.
+#[tokio::test]
+async fn async_sync_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = AsyncKvStore::new(KvStore::open_with(temp_dir.path(), options)?);
+
+    store.set("key1".to_owned(), "always".to_owned()).await?;
+    let mut written = false;
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let log = std::fs::read(entry.path())?;
+            written |= log.windows(6).any(|window| window == b"always");
+        }
+    }
+    assert!(written);
+
+    Ok(())
+}
+
+// Tasks on a single thread should write through one handle at once, while earlier records are
+// still being written out and the log moves on to new generations.
+#[tokio::test]
+async fn async_interleaved_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().segment_size(16 * 1024);
+    let store = AsyncKvStore::new(KvStore::open_with(temp_dir.path(), options.clone())?);
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                store
+                    .set(format!("key{}", i), i.to_string().repeat(500))
+                    .await?;
+                store.get(format!("key{}", i)).await
+            })
+        })
+        .collect();
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await??, Some(i.to_string().repeat(500)));
+    }
+    store.flush().await?;
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(i.to_string().repeat(500))
+        );
+    }
+
+    Ok(())
+}
+
+// The async server should serve a connection while many others are open and idle.
+#[test]
+fn async_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--async"]);
+
+    let idle: Vec<TcpStream> = (0..200)
+        .map(|_| TcpStream::connect(&server.addr))
+        .collect::<std::io::Result<_>>()?;
+    let mut first = KvsClient::connect(&server.addr)?;
+    let mut second = KvsClient::connect(&server.addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    second.remove("key1".to_owned())?;
+    assert_eq!(first.get("key1".to_owned())?, None);
+    drop(idle);
+
+    Ok(())
+}
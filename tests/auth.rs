@@ -0,0 +1,262 @@
+use assert_cmd::prelude::*;
+use common::start_server;
+use kvs::protocol::{read_frame, write_frame, ErrorKind, Request, Response};
+use kvs::{KvsClient, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, PredicateStrExt};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+const ACL: &str = r#"{
+    "users": [
+        { "name": "admin", "password": "hunter2", "rules": [{ "prefix": "", "ops": ["read", "write"] }] },
+        { "name": "deployer", "token": "t0ken", "rules": [{ "prefix": "config/", "ops": ["read"] }] }
+    ]
+}"#;
+
+// Starts a server requiring authentication against `ACL`.
+fn start_auth_server(dir: &TempDir, args: &[&str]) -> common::Server {
+    let acl = dir.path().join("acl.json");
+    fs::write(&acl, ACL).unwrap();
+    let mut all_args = vec!["--acl", acl.to_str().unwrap()];
+    all_args.extend_from_slice(args);
+    start_server(dir, &all_args)
+}
+
+fn request(stream: &mut TcpStream, request: Request) -> Response {
+    write_frame(stream, &request).unwrap();
+    read_frame(stream)
+        .unwrap()
+        .expect("server closed connection")
+}
+
+// Requests should be rejected with a structured error until the connection authenticates.
+#[test]
+fn auth_required() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_auth_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    let get = || Request::Get {
+        key: "key1".to_owned(),
+    };
+
+    assert!(matches!(
+        request(&mut stream, get()),
+        Response::Err {
+            kind: ErrorKind::AuthRequired,
+            ..
+        }
+    ));
+    assert!(matches!(
+        request(
+            &mut stream,
+            Request::Auth {
+                username: Some("admin".to_owned()),
+                password: "wrong".to_owned()
+            }
+        ),
+        Response::Err {
+            kind: ErrorKind::AuthFailed,
+            ..
+        }
+    ));
+    assert!(matches!(
+        request(
+            &mut stream,
+            Request::Auth {
+                username: Some("admin".to_owned()),
+                password: "hunter2".to_owned()
+            }
+        ),
+        Response::Ok(None)
+    ));
+    assert!(matches!(request(&mut stream, get()), Response::Ok(None)));
+}
+
+// A token user should only be able to read keys under its prefix.
+#[test]
+fn auth_prefix_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_auth_server(&temp_dir, &[]);
+
+    let mut admin = KvsClient::connect(&server.addr)?;
+    admin.authenticate(Some("admin"), "hunter2")?;
+    admin.set("config/mode".to_owned(), "fast".to_owned())?;
+    admin.set("secret".to_owned(), "42".to_owned())?;
+    drop(admin);
+
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    request(
+        &mut stream,
+        Request::Auth {
+            username: None,
+            password: "t0ken".to_owned(),
+        },
+    );
+    assert!(matches!(
+        request(&mut stream, Request::Get { key: "config/mode".to_owned() }),
+        Response::Ok(Some(v)) if v == "fast"
+    ));
+    for forbidden in [
+        Request::Get {
+            key: "secret".to_owned(),
+        },
+        Request::Set {
+            key: "config/mode".to_owned(),
+            value: "slow".to_owned(),
+        },
+        Request::Remove {
+            key: "config/mode".to_owned(),
+        },
+    ] {
+        assert!(matches!(
+            request(&mut stream, forbidden),
+            Response::Err {
+                kind: ErrorKind::Forbidden,
+                ..
+            }
+        ));
+    }
+    Ok(())
+}
+
+// The cursors of a scan shouldn't give away the keys a restricted user isn't allowed to read.
+#[test]
+fn auth_scan_cursors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_auth_server(&temp_dir, &[]);
+
+    let mut admin = KvsClient::connect(&server.addr)?;
+    admin.authenticate(Some("admin"), "hunter2")?;
+    let hidden = ["alpha", "config", "secret/db-password", "zeta"];
+    for key in hidden.iter().chain(&["config/mode", "config/region"]) {
+        admin.set(key.to_string(), "value".to_owned())?;
+    }
+    drop(admin);
+
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    request(
+        &mut stream,
+        Request::Auth {
+            username: None,
+            password: "t0ken".to_owned(),
+        },
+    );
+    let mut listed = Vec::new();
+    let mut cursors = Vec::new();
+    let mut cursor = None;
+    loop {
+        let scan = Request::Scan {
+            cursor: cursor.clone(),
+            pattern: None,
+            count: 1,
+        };
+        match request(&mut stream, scan) {
+            Response::Keys { keys, cursor: next } => {
+                listed.extend(keys);
+                match next {
+                    Some(next) => cursors.push(next.clone()),
+                    None => break,
+                }
+                cursor = cursors.last().cloned();
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    assert_eq!(listed, ["config/mode", "config/region"]);
+    // a page per key, each cursor as long as the others and showing no key in plain or in hex
+    assert_eq!(cursors.len(), 6);
+    assert!(cursors
+        .iter()
+        .all(|cursor| cursor.len() == cursors[0].len()));
+    for key in hidden {
+        let hex: String = key.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        assert!(cursors
+            .iter()
+            .all(|cursor| !cursor.contains(key) && !cursor.contains(&hex)));
+    }
+
+    // nor can a cursor be forged to start after a key of the user's choosing
+    let forged: String = "secret/"
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let scan = Request::Scan {
+        cursor: Some(forged),
+        pattern: None,
+        count: 1,
+    };
+    assert!(matches!(
+        request(&mut stream, scan),
+        Response::Err {
+            kind: ErrorKind::Store,
+            ..
+        }
+    ));
+    Ok(())
+}
+
+// `kvs-client` should authenticate with --user/--password or a token from the environment.
+#[test]
+fn cli_client_auth() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_auth_server(&temp_dir, &[]);
+    let client = || {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.env_remove("KVS_PASSWORD").env_remove("KVS_TOKEN");
+        cmd
+    };
+
+    client()
+        .args(["set", "config/mode", "fast", "--addr", &server.addr])
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+    client()
+        .args(["set", "config/mode", "fast", "--addr", &server.addr])
+        .args(["--user", "admin", "--password", "hunter2"])
+        .assert()
+        .success();
+    client()
+        .args(["get", "config/mode", "--addr", &server.addr])
+        .env("KVS_TOKEN", "t0ken")
+        .assert()
+        .success()
+        .stdout(eq("fast").trim());
+    client()
+        .args(["rm", "config/mode", "--addr", &server.addr])
+        .env("KVS_TOKEN", "t0ken")
+        .assert()
+        .failure()
+        .stderr(contains("denied"));
+}
+
+// RESP clients should get Redis-style NOAUTH/NOPERM errors.
+#[test]
+fn resp_auth() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_auth_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    let mut roundtrip = |command: &str, expected: &str| {
+        stream.write_all(command.as_bytes()).unwrap();
+        let mut reply = vec![0u8; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+    };
+
+    roundtrip(
+        "GET config/mode\r\n",
+        "-NOAUTH Authentication required.\r\n",
+    );
+    roundtrip("AUTH t0ken\r\n", "+OK\r\n");
+    roundtrip("GET config/mode\r\n", "$-1\r\n");
+    roundtrip(
+        "SET config/mode slow\r\n",
+        "-NOPERM this user has no permissions to access one of the keys used as arguments\r\n",
+    );
+}
@@ -0,0 +1,299 @@
+use assert_cmd::prelude::*;
+use common::start_server;
+use kvs::{KvsClient, KvsError, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+// The library client should round-trip values through a running server.
+#[test]
+fn client_get_set_rm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+// `set_if_absent` should only set keys that don't exist yet.
+#[test]
+fn client_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    assert!(client.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!client.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `remove_if` should only remove keys holding the expected value.
+#[test]
+fn client_remove_if() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    client.set("lock".to_owned(), "owner1".to_owned())?;
+    assert!(!client.remove_if("lock".to_owned(), "owner2".to_owned())?);
+    assert_eq!(client.get("lock".to_owned())?, Some("owner1".to_owned()));
+    assert!(client.remove_if("lock".to_owned(), "owner1".to_owned())?);
+    assert_eq!(client.get("lock".to_owned())?, None);
+    assert!(!client.remove_if("lock".to_owned(), "owner1".to_owned())?);
+
+    Ok(())
+}
+
+// `rename` and `copy` should move or duplicate a value on the server.
+#[test]
+fn client_rename_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.rename("key1".to_owned(), "key2".to_owned())?;
+    client.copy("key2".to_owned(), "key3".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert!(client.rename("key1".to_owned(), "key4".to_owned()).is_err());
+    assert!(client.copy("key1".to_owned(), "key4".to_owned()).is_err());
+    assert_eq!(client.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// `scan` should list every key a page at a time, and only the ones matching a pattern.
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    for i in 0..25 {
+        client.set(format!("key{:02}", i), "value".to_owned())?;
+    }
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let (page, next) = client.scan(cursor, None, 10)?;
+        assert!(page.len() <= 10);
+        keys.extend(page);
+        pages += 1;
+        if next.is_none() {
+            break;
+        }
+        cursor = next;
+        if pages == 1 {
+            // keys added and removed meanwhile don't keep the scan from ending
+            client.remove("key15".to_owned())?;
+            client.set("key00a".to_owned(), "value".to_owned())?;
+            client.set("zzz".to_owned(), "value".to_owned())?;
+        }
+    }
+    assert_eq!(pages, 3);
+    let mut expected: Vec<String> = (0..25)
+        .filter(|i| *i != 15)
+        .map(|i| format!("key{:02}", i))
+        .collect();
+    expected.push("zzz".to_owned());
+    assert_eq!(keys, expected);
+
+    let (keys, cursor) = client.scan(None, Some("key?5"), 100)?;
+    assert_eq!(keys, vec!["key05".to_owned()]);
+    assert_eq!(cursor, None);
+
+    Ok(())
+}
+
+// A pipeline should send every request at once and return their results in order.
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    // more than fits in a single window
+    let mut pipeline = client.pipeline();
+    for i in 0..5000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    assert_eq!(pipeline.len(), 5000);
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 5000);
+    assert!(results.iter().all(|result| matches!(result, Ok(None))));
+
+    let mut pipeline = client.pipeline();
+    pipeline
+        .get("key4999".to_owned())
+        .remove("key0".to_owned())
+        .remove("key0".to_owned())
+        .get("key0".to_owned());
+    let results = pipeline.execute()?;
+    assert!(matches!(&results[0], Ok(Some(value)) if value == "value4999"));
+    assert!(matches!(results[1], Ok(None)));
+    assert!(matches!(results[2], Err(KvsError::KeyNotFound)));
+    assert!(matches!(results[3], Ok(None)));
+
+    assert!(client.pipeline().execute()?.is_empty());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A transaction should apply its writes together on commit, and none of them if one fails.
+#[test]
+fn client_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    client.set("from".to_owned(), "100".to_owned())?;
+    let mut transaction = client.transaction()?;
+    transaction.remove("from".to_owned())?;
+    transaction.set("to".to_owned(), "100".to_owned())?;
+    transaction.commit()?;
+    assert_eq!(client.get("from".to_owned())?, None);
+    assert_eq!(client.get("to".to_owned())?, Some("100".to_owned()));
+
+    // removing a missing key fails the whole transaction
+    let mut transaction = client.transaction()?;
+    transaction.set("other".to_owned(), "1".to_owned())?;
+    transaction.remove("from".to_owned())?;
+    assert!(matches!(transaction.commit(), Err(KvsError::KeyNotFound)));
+    assert_eq!(client.get("other".to_owned())?, None);
+
+    let mut transaction = client.transaction()?;
+    transaction.set("other".to_owned(), "1".to_owned())?;
+    transaction.discard()?;
+    {
+        let mut transaction = client.transaction()?;
+        transaction.set("other".to_owned(), "2".to_owned())?;
+    }
+    assert_eq!(client.get("other".to_owned())?, None);
+
+    Ok(())
+}
+
+// `multi_get` should return every value in a single round trip, in the order asked for.
+#[test]
+fn client_multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        client.multi_get(vec![
+            "key2".to_owned(),
+            "missing".to_owned(),
+            "key1".to_owned()
+        ])?,
+        vec![Some("value2".to_owned()), None, Some("value1".to_owned())]
+    );
+    assert_eq!(client.multi_get(vec![])?, vec![]);
+
+    Ok(())
+}
+
+// `kvs-client` should map get/set/rm onto the server and report missing keys.
+#[test]
+fn cli_client_get_set_rm() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let client = || {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.current_dir(&temp_dir);
+        cmd
+    };
+
+    client()
+        .args(["set", "key1", "value1", "--addr", &server.addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client()
+        .args(["get", "key1", "--addr", &server.addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client()
+        .args(["rm", "key1", "--addr", &server.addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client()
+        .args(["get", "key1", "--addr", &server.addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client()
+        .args(["rm", "key1", "--addr", &server.addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+}
+
+// `kvs-client scan` should print every key matching the pattern, going through them in pages.
+#[test]
+fn cli_client_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr).unwrap();
+    for key in ["key1", "key2", "key3", "user1"] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    // the server serves one connection at a time
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "scan",
+            "--match",
+            "key*",
+            "--count",
+            "2",
+            "--addr",
+            &server.addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1\nkey2\nkey3\n"));
+}
+
+#[test]
+fn cli_client_invalid() {
+    for args in [
+        vec!["get"],
+        vec!["get", "extra", "field"],
+        vec!["set", "missing_field"],
+        vec!["rm"],
+        vec!["unknown", "subcommand"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .assert()
+            .failure();
+    }
+}
@@ -0,0 +1,77 @@
+#![allow(dead_code)]
+
+use assert_cmd::cargo::CommandCargoExt;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Picks a free local port for the server to listen on.
+pub fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// A running `kvs-server` process that is killed when dropped.
+pub struct Server {
+    child: Child,
+    pub addr: String,
+}
+
+impl Server {
+    // Kills the server and returns what it wrote to stdout and stderr.
+    pub fn output(mut self) -> (String, String) {
+        let _ = self.child.kill();
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        if let Some(out) = self.child.stdout.as_mut() {
+            out.read_to_string(&mut stdout).unwrap();
+        }
+        if let Some(err) = self.child.stderr.as_mut() {
+            err.read_to_string(&mut stderr).unwrap();
+        }
+        (stdout, stderr)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Starts `kvs-server` in `dir` with `args` and waits until it accepts connections.
+pub fn start_server(dir: &TempDir, args: &[&str]) -> Server {
+    spawn_server(dir, args, Stdio::inherit(), Stdio::inherit())
+}
+
+// Like `start_server`, with the output of the server piped so it can be read once it exits.
+pub fn start_server_piped(dir: &TempDir, args: &[&str]) -> Server {
+    spawn_server(dir, args, Stdio::piped(), Stdio::piped())
+}
+
+fn spawn_server(dir: &TempDir, args: &[&str], stdout: Stdio, stderr: Stdio) -> Server {
+    let addr = free_addr();
+    let server = Server {
+        child: Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr])
+            .args(args)
+            .current_dir(dir)
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()
+            .unwrap(),
+        addr,
+    };
+    for _ in 0..100 {
+        if TcpStream::connect(&server.addr).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("kvs-server did not start");
+}
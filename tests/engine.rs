@@ -0,0 +1,237 @@
+use assert_cmd::prelude::*;
+use common::{free_addr, start_server};
+use kvs::{
+    engine_of, KvStore, KvsClient, KvsEngine, KvsError, LsmKvStore, LsmOptions, Result,
+    SledKvsEngine,
+};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+// The sled engine should behave like `KvStore`.
+#[test]
+fn sled_get_set_rm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.keys_with_prefix("key")?, vec!["key1".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?,
+        Err(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store
+        .rename("key2".to_owned(), "key3".to_owned())
+        .unwrap_err();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+    store.copy("key3".to_owned(), "key4".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The LSM engine should behave like `KvStore`.
+#[test]
+fn lsm_get_set_rm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(store.keys_with_prefix("key")?, vec!["key1".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?,
+        Err(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "value2".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.get_and_set("key1".to_owned(), "value3".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.remove("key1".to_owned())?;
+
+    // the write-ahead log is replayed on open
+    store.set("key4".to_owned(), "value3".to_owned())?;
+    store.rename("key4".to_owned(), "key5".to_owned())?;
+    store.copy("key5".to_owned(), "key3".to_owned())?;
+    drop(store);
+    let store = LsmKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Flushed memtables should be compacted down the levels, with the newest value of every key
+// winning and removed keys disappearing, across reopens.
+#[test]
+fn lsm_flush_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        LsmOptions::new()
+            .memtable_size(4096)
+            .table_size(8192)
+            .level0_tables(2)
+            .level1_size(32 * 1024)
+    };
+    let store = LsmKvStore::open_with(temp_dir.path(), options())?;
+    let value = |key: usize, round: usize| format!("{:0>100}", key * 10 + round);
+    for round in 0..3 {
+        for key in 0..1000 {
+            store.set(format!("key{:04}", key), value(key, round))?;
+        }
+    }
+    for key in (0..1000).step_by(2) {
+        store.remove(format!("key{:04}", key))?;
+    }
+    store.compact()?;
+    let tables = store.tables_per_level();
+    assert!(tables[0] < 2);
+    assert!(tables[2..].iter().sum::<usize>() > 0);
+
+    drop(store);
+    let store = LsmKvStore::open_with(temp_dir.path(), options())?;
+    for key in 0..1000 {
+        let expected = (key % 2 == 1).then(|| value(key, 2));
+        assert_eq!(store.get(format!("key{:04}", key))?, expected);
+    }
+    let keys = store.keys_with_prefix("key01")?;
+    assert_eq!(keys.len(), 50);
+    assert_eq!(keys[0], "key0101");
+
+    for key in (1..1000).step_by(2) {
+        store.remove(format!("key{:04}", key))?;
+    }
+    store.compact()?;
+    assert_eq!(store.keys_with_prefix("")?, Vec::<String>::new());
+
+    Ok(())
+}
+
+// A store should record its engine and fail to open with any other one.
+#[test]
+fn engine_marker() -> Result<()> {
+    let wrong_engine = |result: Result<()>, found: &str| match result {
+        Err(KvsError::WrongEngine { found: engine, .. }) => assert_eq!(engine, found),
+        result => panic!("expected a wrong engine error, got {:?}", result.err()),
+    };
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(kvs_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        std::fs::read_to_string(kvs_dir.path().join("ENGINE"))?,
+        "kvs"
+    );
+    wrong_engine(LsmKvStore::open(kvs_dir.path()).map(drop), "kvs");
+    wrong_engine(SledKvsEngine::open(kvs_dir.path()).map(drop), "kvs");
+
+    let lsm_dir = TempDir::new().expect("unable to create temporary working directory");
+    LsmKvStore::open(lsm_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    wrong_engine(KvStore::open(lsm_dir.path()).map(drop), "lsm");
+    wrong_engine(KvStore::open_read_only(lsm_dir.path()).map(drop), "lsm");
+    // stores from before the marker are recognised by their files
+    std::fs::remove_file(lsm_dir.path().join("ENGINE"))?;
+    assert_eq!(engine_of(lsm_dir.path())?.as_deref(), Some("lsm"));
+    wrong_engine(KvStore::open(lsm_dir.path()).map(drop), "lsm");
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::open(sled_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    wrong_engine(KvStore::open(sled_dir.path()).map(drop), "sled");
+    let store = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `kvs-server --engine lsm` should serve requests and keep using the LSM engine after a restart.
+#[test]
+fn server_lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--engine", "lsm"]);
+    KvsClient::connect(&server.addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    drop(server);
+
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", &free_addr()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("created by the lsm engine"));
+
+    Ok(())
+}
+
+// `kvs-server --engine sled` should serve requests and keep using sled after a restart.
+#[test]
+fn server_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--engine", "sled"]);
+    KvsClient::connect(&server.addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    drop(server);
+
+    let server = start_server(&temp_dir, &[]);
+    let mut client = KvsClient::connect(&server.addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("wal").exists());
+
+    Ok(())
+}
+
+// A store created by one engine should not be opened by the other.
+#[test]
+fn server_wrong_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", &free_addr()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("created by the kvs engine"));
+
+    let server = start_server(&temp_dir, &["--engine", "kvs"]);
+    drop(server);
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", &free_addr()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+#![cfg(feature = "grpc")]
+
+use common::start_server;
+use kvs::grpc::proto::{kvs_client::KvsClient, GetRequest, RemoveRequest, ScanRequest, SetRequest};
+use tempfile::TempDir;
+use tonic::Code;
+
+mod common;
+
+// Typed stubs should be able to get, set, remove and scan keys.
+#[tokio::test]
+async fn grpc_get_set_rm_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "grpc"]);
+    let mut client = KvsClient::connect(format!("http://{}", server.addr))
+        .await
+        .unwrap();
+
+    let get = |key: &str| GetRequest {
+        key: key.to_string(),
+    };
+    assert_eq!(
+        client.get(get("key1")).await.unwrap().into_inner().value,
+        None
+    );
+    for (key, value) in [("user:2", "b"), ("user:1", "a"), ("other", "c")] {
+        client
+            .set(SetRequest {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        client.get(get("user:1")).await.unwrap().into_inner().value,
+        Some("a".to_string())
+    );
+
+    let entries = client
+        .scan(ScanRequest {
+            prefix: "user:".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .entries;
+    let entries: Vec<_> = entries.into_iter().map(|e| (e.key, e.value)).collect();
+    assert_eq!(
+        entries,
+        vec![
+            ("user:1".to_string(), "a".to_string()),
+            ("user:2".to_string(), "b".to_string())
+        ]
+    );
+
+    let remove = || RemoveRequest {
+        key: "other".to_string(),
+    };
+    client.remove(remove()).await.unwrap();
+    assert_eq!(
+        client.remove(remove()).await.unwrap_err().code(),
+        Code::NotFound
+    );
+}
+
+// Scans should go through the keys under a prefix a page at a time, resuming from sealed cursors.
+#[tokio::test]
+async fn grpc_scan_pages() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "grpc"]);
+    let mut client = KvsClient::connect(format!("http://{}", server.addr))
+        .await
+        .unwrap();
+
+    for key in ["user", "user:1", "user:2", "user:3", "user:4", "zebra"] {
+        client
+            .set(SetRequest {
+                key: key.to_string(),
+                value: "value".to_string(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let page = client
+            .scan(ScanRequest {
+                prefix: "user".to_string(),
+                limit: 2,
+                cursor,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        pages += 1;
+        assert!(page.entries.len() <= 2);
+        keys.extend(page.entries.into_iter().map(|e| e.key));
+        if let Some(next) = &page.cursor {
+            assert!(!next.contains("user"));
+        }
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(keys, ["user", "user:1", "user:2", "user:3", "user:4"]);
+    assert_eq!(pages, 3);
+
+    let invalid = client
+        .scan(ScanRequest {
+            prefix: "user".to_string(),
+            limit: 2,
+            cursor: Some("not a cursor".to_string()),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(invalid.code(), Code::InvalidArgument);
+}
@@ -0,0 +1,136 @@
+#![cfg(feature = "http")]
+
+use common::{free_addr, start_server};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+mod common;
+
+// Sends a single HTTP/1.1 request and returns the status code and body of the response.
+fn http(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    (status, body.to_string())
+}
+
+// Keys should be readable, writable and removable through the REST API.
+#[test]
+fn http_keys() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "http"]);
+    let addr = &server.addr;
+
+    assert_eq!(http(addr, "GET", "/keys/key1", "").0, 404);
+    assert_eq!(http(addr, "PUT", "/keys/key1", "value1").0, 204);
+    assert_eq!(
+        http(addr, "GET", "/keys/key1", ""),
+        (200, "value1".to_string())
+    );
+    assert_eq!(http(addr, "PUT", "/keys/user%3A1", "a b").0, 204);
+    assert_eq!(
+        http(addr, "GET", "/keys/user:1", ""),
+        (200, "a b".to_string())
+    );
+    assert_eq!(http(addr, "DELETE", "/keys/key1", "").0, 204);
+    assert_eq!(http(addr, "DELETE", "/keys/key1", "").0, 404);
+    assert_eq!(http(addr, "POST", "/keys/key1", "").0, 405);
+}
+
+// `/stats` should report the operations executed so far.
+#[test]
+fn http_stats() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "http"]);
+    let addr = &server.addr;
+
+    http(addr, "PUT", "/keys/key1", "value1");
+    http(addr, "GET", "/keys/key1", "");
+    let (status, body) = http(addr, "GET", "/stats", "");
+    assert_eq!(status, 200);
+    let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["sets"], 1);
+    assert_eq!(stats["gets"], 1);
+}
+
+// `/metrics` should report the operations executed so far in the Prometheus text format.
+#[test]
+fn http_metrics() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "http"]);
+    let addr = &server.addr;
+
+    http(addr, "PUT", "/keys/key1", "value1");
+    http(addr, "GET", "/keys/key1", "");
+    http(addr, "GET", "/keys/key2", "");
+    let (status, body) = http(addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(body.contains("kvs_ops_total{op=\"get\"} 2\n"));
+    assert!(body.contains("kvs_ops_total{op=\"set\"} 1\n"));
+    assert!(body.contains("kvs_errors_total{op=\"set\"} 0\n"));
+    assert!(body.contains("kvs_request_duration_seconds_count{op=\"get\"} 2\n"));
+    assert!(body.contains("kvs_request_duration_seconds_bucket{op=\"set\",le=\"+Inf\"} 1\n"));
+    assert!(body.contains("kvs_compactions_total 0\n"));
+    assert!(!body.contains("kvs_written_bytes_total 0\n"));
+}
+
+// `--metrics-addr` should serve `/metrics` next to a server speaking another protocol.
+#[test]
+fn metrics_addr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics_addr = free_addr();
+    let _server = start_server(&temp_dir, &["--metrics-addr", &metrics_addr]);
+
+    let (status, body) = http(&metrics_addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    assert!(body.contains("# TYPE kvs_ops_total counter\n"));
+    assert_eq!(http(&metrics_addr, "GET", "/stats", "").0, 404);
+}
+
+// With an ACL, requests need a valid bearer token with access to the key.
+#[test]
+fn http_auth() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl = temp_dir.path().join("acl.json");
+    std::fs::write(
+        &acl,
+        r#"{ "users": [{ "name": "ci", "token": "t0ken", "rules": [{ "prefix": "ci/", "ops": ["read", "write"] }] }] }"#,
+    )
+    .unwrap();
+    let server = start_server(
+        &temp_dir,
+        &["--protocol", "http", "--acl", acl.to_str().unwrap()],
+    );
+
+    let authorized = |method: &str, path: &str, token: &str| {
+        let mut stream = TcpStream::connect(&server.addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: kvs\r\nAuthorization: Bearer {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            method, path, token
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response[9..12].parse::<u16>().unwrap()
+    };
+
+    assert_eq!(http(&server.addr, "GET", "/keys/ci/a", "").0, 401);
+    assert_eq!(authorized("GET", "/keys/ci/a", "wrong"), 401);
+    assert_eq!(authorized("PUT", "/keys/ci/a", "t0ken"), 204);
+    assert_eq!(authorized("GET", "/keys/ci/a", "t0ken"), 200);
+    assert_eq!(authorized("GET", "/keys/other", "t0ken"), 403);
+}
@@ -0,0 +1,191 @@
+use common::start_server;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+// Sends raw RESP bytes and reads back exactly `expected.len()` bytes of reply.
+fn roundtrip(stream: &mut TcpStream, command: &str, expected: &str) {
+    stream.write_all(command.as_bytes()).unwrap();
+    let mut reply = vec![0u8; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8(reply).unwrap(), expected, "{:?}", command);
+}
+
+// Sends a `SCAN` command, checks that its reply lists `keys`, and returns the cursor it sent.
+fn scan(stream: &mut TcpStream, command: &str, keys: &[&str]) -> String {
+    stream.write_all(command.as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = || {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    };
+    assert_eq!(line(), "*2", "{:?}", command);
+    line();
+    let cursor = line();
+    assert_eq!(line(), format!("*{}", keys.len()), "{:?}", command);
+    for key in keys {
+        line();
+        assert_eq!(line(), *key, "{:?}", command);
+    }
+    cursor
+}
+
+// A Redis client should be able to get, set, delete and probe keys.
+#[test]
+fn resp_commands() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(&mut stream, "*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+    roundtrip(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", "$-1\r\n");
+    roundtrip(
+        &mut stream,
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+        "+OK\r\n",
+    );
+    roundtrip(
+        &mut stream,
+        "*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n",
+        "$6\r\nvalue1\r\n",
+    );
+    roundtrip(
+        &mut stream,
+        "*3\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+        ":1\r\n",
+    );
+    roundtrip(
+        &mut stream,
+        "*3\r\n$4\r\nMGET\r\n$4\r\nkey2\r\n$4\r\nkey1\r\n",
+        "*2\r\n$-1\r\n$6\r\nvalue1\r\n",
+    );
+    roundtrip(
+        &mut stream,
+        "*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+        ":1\r\n",
+    );
+    roundtrip(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", "$-1\r\n");
+}
+
+// Commands pipelined in a single write should all be answered, in order.
+#[test]
+fn resp_pipelining() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(
+        &mut stream,
+        "SET key1 value1\r\nGET key1\r\n\r\nDEL key1\r\nGET key1\r\nPING\r\n",
+        "+OK\r\n$6\r\nvalue1\r\n:1\r\n$-1\r\n+PONG\r\n",
+    );
+    let commands = "SET key value\r\n".repeat(10_000);
+    roundtrip(&mut stream, &commands, &"+OK\r\n".repeat(10_000));
+}
+
+// `SETNX` and `SET ... NX` should only set keys that don't exist yet.
+#[test]
+fn resp_setnx() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(&mut stream, "SETNX key1 value1\r\n", ":1\r\n");
+    roundtrip(&mut stream, "SETNX key1 value2\r\n", ":0\r\n");
+    roundtrip(&mut stream, "SET key1 value3 NX\r\n", "$-1\r\n");
+    roundtrip(&mut stream, "SET key2 value2 nx\r\n", "+OK\r\n");
+    roundtrip(&mut stream, "GET key1\r\n", "$6\r\nvalue1\r\n");
+    roundtrip(
+        &mut stream,
+        "SETNX key1\r\n",
+        "-ERR wrong number of arguments for 'setnx' command\r\n",
+    );
+}
+
+// RENAME should move a value and reject missing keys.
+#[test]
+fn resp_rename() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(&mut stream, "SET key1 value1\r\n", "+OK\r\n");
+    roundtrip(&mut stream, "RENAME key1 key2\r\n", "+OK\r\n");
+    roundtrip(&mut stream, "GET key1\r\n", "$-1\r\n");
+    roundtrip(&mut stream, "GET key2\r\n", "$6\r\nvalue1\r\n");
+    roundtrip(&mut stream, "RENAME key1 key3\r\n", "-ERR no such key\r\n");
+}
+
+// SCAN should list the keys a page at a time with a cursor, and filter them with MATCH.
+#[test]
+fn resp_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(&mut stream, "SCAN 0\r\n", "*2\r\n$1\r\n0\r\n*0\r\n");
+    roundtrip(&mut stream, "SET key1 value1\r\n", "+OK\r\n");
+    roundtrip(&mut stream, "SET key2 value2\r\n", "+OK\r\n");
+    roundtrip(&mut stream, "SET user1 alice\r\n", "+OK\r\n");
+    let cursor = scan(&mut stream, "SCAN 0 COUNT 2\r\n", &["key1", "key2"]);
+    // the cursor is sealed rather than the key the page ended at
+    assert_ne!(cursor, "0");
+    assert!(!cursor.contains("6b657932"));
+    let next = scan(
+        &mut stream,
+        &format!("SCAN {} COUNT 2\r\n", cursor),
+        &["user1"],
+    );
+    assert_eq!(next, "0");
+    roundtrip(
+        &mut stream,
+        "SCAN 0 MATCH key*\r\n",
+        "*2\r\n$1\r\n0\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+    );
+    roundtrip(&mut stream, "SCAN xyz\r\n", "-ERR invalid cursor\r\n");
+    roundtrip(&mut stream, "SCAN 6b657932\r\n", "-ERR invalid cursor\r\n");
+    roundtrip(&mut stream, "SCAN 0 COUNT 0\r\n", "-ERR syntax error\r\n");
+}
+
+// Inline commands and errors should follow Redis conventions.
+#[test]
+fn resp_inline_and_errors() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    roundtrip(&mut stream, "PING hello\r\n", "$5\r\nhello\r\n");
+    roundtrip(
+        &mut stream,
+        "FLUSHALL\r\n",
+        "-ERR unknown command 'flushall'\r\n",
+    );
+    roundtrip(
+        &mut stream,
+        "GET\r\n",
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    roundtrip(&mut stream, "QUIT\r\n", "+OK\r\n");
+}
@@ -0,0 +1,251 @@
+use common::{start_server, start_server_piped};
+use kvs::protocol::{read_frame, write_frame, ErrorKind, Request, Response};
+use kvs::KvsClient;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+fn request(stream: &mut TcpStream, request: Request) -> Response {
+    write_frame(stream, &request).unwrap();
+    read_frame(stream)
+        .unwrap()
+        .expect("server closed connection")
+}
+
+// Requests sent over one connection should all be served against the same open store.
+#[test]
+fn server_get_set_rm() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+
+    let key = || "key1".to_owned();
+    assert!(matches!(
+        request(&mut stream, Request::Get { key: key() }),
+        Response::Ok(None)
+    ));
+    assert!(matches!(
+        request(
+            &mut stream,
+            Request::Set {
+                key: key(),
+                value: "value1".to_owned()
+            }
+        ),
+        Response::Ok(None)
+    ));
+    assert!(
+        matches!(request(&mut stream, Request::Get { key: key() }), Response::Ok(Some(v)) if v == "value1")
+    );
+    assert!(matches!(
+        request(&mut stream, Request::Remove { key: key() }),
+        Response::Ok(None)
+    ));
+    assert!(
+        matches!(request(&mut stream, Request::Remove { key: key() }), Response::Err { kind: ErrorKind::KeyNotFound, message } if message == "Key not found")
+    );
+
+    drop(server);
+}
+
+// Writes between MULTI and EXEC should be queued, and anything else sent meanwhile refused.
+#[test]
+fn server_transaction() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+
+    assert!(matches!(
+        request(&mut stream, Request::Exec),
+        Response::Err {
+            kind: ErrorKind::Transaction,
+            ..
+        }
+    ));
+    assert!(matches!(
+        request(&mut stream, Request::Multi),
+        Response::Ok(None)
+    ));
+    assert!(matches!(
+        request(
+            &mut stream,
+            Request::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned()
+            }
+        ),
+        Response::Queued
+    ));
+    for refused in [
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+        Request::Multi,
+    ] {
+        assert!(matches!(
+            request(&mut stream, refused),
+            Response::Err {
+                kind: ErrorKind::Transaction,
+                ..
+            }
+        ));
+    }
+    assert!(matches!(
+        request(&mut stream, Request::Exec),
+        Response::Ok(None)
+    ));
+    assert!(
+        matches!(request(&mut stream, Request::Get { key: "key1".to_owned() }), Response::Ok(Some(v)) if v == "value1")
+    );
+
+    drop(server);
+}
+
+// With a thread pool, the server should serve connections concurrently.
+#[test]
+fn server_thread_pool() {
+    for pool in ["naive", "shared-queue"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let server = start_server(&temp_dir, &["--thread-pool", pool, "--threads", "2"]);
+
+        let mut first = KvsClient::connect(&server.addr).unwrap();
+        let mut second = KvsClient::connect(&server.addr).unwrap();
+        first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        assert_eq!(
+            second.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        second.remove("key1".to_owned()).unwrap();
+        assert_eq!(first.get("key1".to_owned()).unwrap(), None);
+    }
+}
+
+// With `--log-level debug` the server should trace requests and store operations in its log, and
+// never print values to stdout.
+#[test]
+fn server_log_level() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server_piped(&temp_dir, &["--log-level", "debug"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    request(
+        &mut stream,
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    );
+    request(
+        &mut stream,
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+    );
+    drop(stream);
+
+    let (stdout, stderr) = server.output();
+    assert!(!stdout.contains("value1"));
+    assert!(stderr.contains("request; peer="));
+    assert!(stderr.contains("set; key=\"key1\""));
+    assert!(stderr.contains("get; key=\"key1\""));
+}
+
+// With `--slow-op-threshold`, store operations slower than it should be logged with their key.
+#[test]
+fn server_slow_op_threshold() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server_piped(&temp_dir, &["--slow-op-threshold", "0"]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    request(
+        &mut stream,
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    );
+    drop(stream);
+
+    let (stdout, stderr) = server.output();
+    assert!(!stdout.contains("value1"));
+    assert!(stderr.contains("Slow set of key1 took"), "{}", stderr);
+}
+
+// Data set through the server should survive a restart.
+#[test]
+fn server_persists_across_restart() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    request(
+        &mut stream,
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    );
+    drop(stream);
+    drop(server);
+
+    let server = start_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    assert!(matches!(
+        request(&mut stream, Request::Get { key: "key1".to_owned() }),
+        Response::Ok(Some(v)) if v == "value1"
+    ));
+    drop(server);
+}
+
+/// Polls `key` on the server at `addr` until it holds `value`, failing after a few seconds
+fn wait_for(addr: &str, key: &str, value: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let current = KvsClient::connect(addr)
+            .unwrap()
+            .get(key.to_owned())
+            .unwrap();
+        if current.as_deref() == value {
+            return;
+        }
+        assert!(Instant::now() < deadline, "{} is still {:?}", key, current);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// A follower should catch up with the keys its leader had, keep applying new changes and refuse
+// writes.
+#[test]
+fn server_follower() {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = start_server(&leader_dir, &[]);
+    // the server serves one connection at a time, so none may stay open while the follower polls
+    let client = || KvsClient::connect(&leader.addr).unwrap();
+    client()
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    client()
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap();
+
+    let follower = start_server(&follower_dir, &["--leader", &leader.addr]);
+    wait_for(&follower.addr, "key1", Some("value1"));
+    wait_for(&follower.addr, "key2", Some("value2"));
+
+    client()
+        .set("key3".to_owned(), "value3".to_owned())
+        .unwrap();
+    client().remove("key1".to_owned()).unwrap();
+    wait_for(&follower.addr, "key3", Some("value3"));
+    wait_for(&follower.addr, "key1", None);
+
+    let err = KvsClient::connect(&follower.addr)
+        .unwrap()
+        .set("key4".to_owned(), "value4".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("read-only follower"));
+
+    drop(follower);
+    drop(leader);
+}
@@ -1,8 +1,25 @@
+// The original CLI tests pass their arguments as borrowed arrays
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CompactionEnd, CompactionListener, CompactionStart, CompactionStrategy, CompactionTrigger,
+    Compression, CorruptRecord, ExportFormat, KeyCodec, KeyedKvStore, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, LogPosition, OnConflict, RecordFormat, RecordKind, ReplaceStrategy,
+    Result, SyncPolicy, TypedKvStore, VersionRetention, WalArchive, WatchEvent, WatchOp,
+    WriteBatch, WriteStall,
+};
+use predicates::boolean::PredicateBooleanExt;
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +82,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -89,26 +106,40 @@ fn cli_get_stored() -> Result<()> {
     Ok(())
 }
 
-// `kvs rm <KEY>` should print nothing and exit with zero.
+// `kvs clear` should only wipe the store when confirmed with --yes
 #[test]
-fn cli_rm_stored() -> Result<()> {
+fn cli_clear() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["clear"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--yes"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
-        .stdout(is_empty());
+        .stdout(eq("value1").trim());
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["clear", "--yes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -117,185 +148,3898 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs scan <PREFIX>` should print the matching keys and values in order
 #[test]
-fn cli_invalid_get() {
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    drop(store);
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["scan", "user:"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(eq("user:1\talice\nuser:2\tbob\n"));
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["scan", "nobody:"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(is_empty());
+
+    Ok(())
 }
 
+// `kvs keys <PATTERN>` should print the keys matching a glob pattern in order
 #[test]
-fn cli_invalid_set() {
+fn cli_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2:profile".to_owned(), "bob".to_owned())?;
+    store.set("user:1:profile".to_owned(), "alice".to_owned())?;
+    store.set("user:1:session".to_owned(), "1234".to_owned())?;
+    drop(store);
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["keys", "user:*:profile"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(eq("user:1:profile\nuser:2:profile\n"));
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["--output", "json", "keys"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(eq(r#"["user:1:profile","user:1:session","user:2:profile"]"#).trim());
+
+    Ok(())
+}
+
+// Glob patterns should match with `*`, `?` and escapes, backtracking over earlier stars.
+#[test]
+fn keys_matching() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a*b", "aab", "ab", "abab", "abc", "b", "xab"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    let keys = |pattern: &str| store.keys_matching(pattern).collect::<Vec<_>>();
+
+    assert_eq!(
+        keys("*"),
+        vec!["a*b", "aab", "ab", "abab", "abc", "b", "xab"]
+    );
+    assert_eq!(keys("a*b"), vec!["a*b", "aab", "ab", "abab"]);
+    assert_eq!(keys("a?b"), vec!["a*b", "aab"]);
+    assert_eq!(keys("a\\*b"), vec!["a*b"]);
+    assert_eq!(keys("*ab"), vec!["aab", "ab", "abab", "xab"]);
+    assert_eq!(
+        keys("*b*"),
+        vec!["a*b", "aab", "ab", "abab", "abc", "b", "xab"]
+    );
+    assert_eq!(keys("ab"), vec!["ab"]);
+    assert_eq!(keys("?"), vec!["b"]);
+    assert!(keys("c*").is_empty());
+
+    Ok(())
+}
+
+// `kvs bench` should report every operation it ran, without touching the data directory
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for engine in ["kvs", "sled", "lsm"] {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args([
+                "bench",
+                "--writes",
+                "200",
+                "--reads",
+                "300",
+                "--threads",
+                "3",
+            ])
+            .args(["--engine", engine, "--output", "json"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(stats["engine"], engine);
+        assert_eq!(stats["writes"], 200);
+        assert_eq!(stats["reads"], 300);
+        assert!(
+            stats["writes_p50_us"].as_u64().unwrap() <= stats["writes_max_us"].as_u64().unwrap()
+        );
+    }
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// Every get, set, removal and compaction should be counted in the latency histograms.
+#[cfg(feature = "metrics")]
+#[test]
+fn latencies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.get("key1".to_owned())?;
+    store.get("missing".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+
+    let latencies = store.latencies();
+    assert_eq!(latencies.set.count, 100);
+    assert_eq!(latencies.get.count, 2);
+    assert_eq!(latencies.remove.count, 1);
+    assert_eq!(latencies.compaction.count, 1);
+    let counted: u64 = latencies.set.buckets.iter().map(|(_, count)| count).sum();
+    assert_eq!(counted, 100);
+    assert!(latencies.set.percentile(50.0) <= latencies.set.percentile(99.0));
+    assert!(latencies.set.percentile(100.0) <= latencies.set.max);
+    assert!(latencies.set.total >= latencies.set.max);
+
+    Ok(())
+}
+
+// `kvs backup <DIR>` should write a backup that opens as a store
+#[test]
+fn cli_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["backup", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(is_empty());
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // without a directory, the backup goes into the `backups/` directory of the store
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let printed = temp_dir
+        .path()
+        .join(String::from_utf8(output.stdout).unwrap().trim());
+    assert_eq!(
+        printed.parent(),
+        Some(temp_dir.path().join("backups").as_path())
+    );
+    let backup = KvStore::open(printed)?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
 }
 
+// `kvs migrate --from json --to cbor` should rewrite the store in the current directory
 #[test]
-fn cli_invalid_rm() {
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().record_format(RecordFormat::Json);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["migrate", "--from", "json", "--to", "cbor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "bincode", "--to", "json"])
+        .current_dir(&temp_dir)
         .assert()
         .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// `kvs restore <BACKUP_DIR>` should restore a backup into the current directory
+#[test]
+fn cli_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path().join("backup"))?;
+    drop(store);
 
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args([
+            "restore",
+            backup_dir.path().join("backup").to_str().unwrap(),
+        ])
+        .current_dir(&target_dir)
         .assert()
-        .failure();
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&target_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
 }
 
+// `kvs export` should print the keys and values in the requested format
 #[test]
-fn cli_invalid_subcommand() {
+fn cli_export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    drop(store);
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            "{\"key\":\"group:1\",\"value\":\"admins\"}\n",
+            "{\"key\":\"user:1\",\"value\":\"alice\"}\n"
+        )));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key,value\r\nuser:1,alice\r\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "xml"])
+        .current_dir(&temp_dir)
         .assert()
         .failure();
+
+    Ok(())
 }
 
-// Should get previously stored value.
+// `kvs import <FILE>` should load a dump, keeping existing keys with `--on-conflict skip`
 #[test]
-fn get_stored_value() -> Result<()> {
+fn cli_import() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    drop(store);
+    std::fs::write(
+        temp_dir.path().join("dump.jsonl"),
+        "{\"key\":\"key1\",\"value\":\"new\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n",
+    )?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "dump.jsonl", "--on-conflict", "fail"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "dump.jsonl", "--on-conflict", "skip"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
 
-    // Open from disk again and check persistent data.
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("old".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
     Ok(())
 }
 
-// Should overwrite existent value.
+// `kvs load` should set the pairs read from stdin
 #[test]
-fn overwrite_value() -> Result<()> {
+fn cli_load() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["load"])
+        .current_dir(&temp_dir)
+        .write_stdin("key1\tvalue1\nkey2\tvalue2\n")
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["load"])
+        .current_dir(&temp_dir)
+        .write_stdin("key3\tvalue3\nkey4\n")
+        .assert()
+        .failure();
 
-    // Open from disk again and check persistent data.
-    drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
 
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key.
+// `--output` should print what get, scan and stats read as JSON or tables, and `--quiet` nothing.
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn cli_output() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
 
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
-
-    // Open from disk again and check persistent data.
+    store.set("key10".to_owned(), "say \"hi\"".to_owned())?;
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let kvs = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command.args(args).current_dir(&temp_dir).assert()
+    };
+    kvs(&["get", "key10", "--output", "json"])
+        .success()
+        .stdout(eq("{\"key\":\"key10\",\"value\":\"say \\\"hi\\\"\"}\n"));
+    kvs(&["get", "key2", "--output", "json"])
+        .success()
+        .stdout(eq("{\"key\":\"key2\",\"value\":null}\n"));
+    kvs(&["scan", "key", "--output", "json"])
+        .success()
+        .stdout(eq(
+            "[{\"key\":\"key1\",\"value\":\"value1\"},{\"key\":\"key10\",\"value\":\"say \\\"hi\\\"\"}]\n",
+        ));
+    kvs(&["scan", "--output", "table"])
+        .success()
+        .stdout(eq("KEY    VALUE\nkey1   value1\nkey10  say \"hi\"\n"));
+    kvs(&["stats", "--output", "json"])
+        .success()
+        .stdout(contains("\"keys\":2").and(contains("\"last_compaction\":null")));
+    kvs(&["stats", "--output", "table"])
+        .success()
+        .stdout(contains("STAT             VALUE\nkeys             2\n"));
+
+    kvs(&["get", "key1", "--quiet"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["get", "key2", "-q"]).failure().stdout(is_empty());
+    kvs(&["scan", "-q"]).success().stdout(is_empty());
 
     Ok(())
 }
 
+// `kvs shell` should run every command read from stdin, reporting failures without stopping.
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn cli_shell() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell"])
+        .current_dir(&temp_dir)
+        .write_stdin(
+            "set key1 value1\nget key1\n\nset \"key 2\" \"two words\"\nget \"key 2\"\nrm missing\n\
+             bogus\nfsck\nget \"unterminated\nrm key1\nexit\nset key3 value3\n",
+        )
+        .assert()
+        .success()
+        .stdout(eq("value1\ntwo words\nKey not found\n"))
+        .stderr(
+            contains("bogus")
+                .and(contains("fsck is not available in the shell"))
+                .and(contains("Unterminated quote")),
+        );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key 2".to_owned())?, Some("two words".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
     Ok(())
 }
 
+// `kvs log dump` should print every record with its status
 #[test]
-fn remove_key() -> Result<()> {
+fn cli_log_dump() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("gen\toffset\tlen\ttype\tkey\texpires_at\tstatus\n")
+                .and(contains("\tSET\tkey1\t-\tstale\n"))
+                .and(contains("\tSET\tkey1\t-\tlive\n")),
+        );
+
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// `kvs compact` should report how many bytes it reclaimed
 #[test]
-fn compaction() -> Result<()> {
+fn cli_compact() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), iter.to_string())?;
+    }
+    drop(store);
 
-        let new_size = dir_size();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed ").and(contains(" bytes")));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("99").trim());
+
+    Ok(())
+}
+
+// `kvs stats` should print the key count and log sizes
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("keys: 2\n")
+                .and(contains("stale_bytes: 0\n"))
+                .and(contains("segments: 1\n"))
+                .and(contains("last_compaction: never\n")),
+        );
+
+    Ok(())
+}
+
+// `kvs fsck` should fail on a damaged log until `--repair` rebuilds it.
+#[test]
+fn cli_fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    tamper_with_log(&temp_dir, "value1", "valueX")?;
+
+    let fsck = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs").unwrap();
+        command
+            .arg("fsck")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    fsck(&[])
+        .failure()
+        .stdout(contains("corrupt: 1\n").and(contains("status: damaged")));
+    fsck(&["--repair"])
+        .success()
+        .stdout(contains("status: repaired\n"));
+    fsck(&[])
+        .success()
+        .stdout(contains("live: 1\n").and(contains("status: clean\n")));
+
+    Ok(())
+}
+
+// `kvs rm <KEY>` should print nothing and exit with zero.
+#[test]
+fn cli_rm_stored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+// `kvs rename` and `kvs copy` should move or duplicate a stored value.
+#[test]
+fn cli_rename_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["copy", "key2", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rename", "key1", "key4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn cli_invalid_get() {
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "extra", "field"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_invalid_set() {
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "missing_field"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "extra", "extra", "field"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_invalid_rm() {
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "extra", "field"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_invalid_subcommand() {
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["unknown", "subcommand"])
+        .assert()
+        .failure();
+}
+
+// Should get previously stored value.
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value.
+#[test]
+fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key.
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Errors should tell what went wrong by their kind, not only their message.
+#[test]
+fn error_kinds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.rename("key2".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        store.remove("key2".to_owned()).unwrap_err().to_string(),
+        "Key not found"
+    );
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
         if new_size > current_size {
             current_size = new_size;
             continue;
         }
-        // Compaction triggered.
+        // Compaction triggered.
+
+        drop(store);
+        // reopen and check content.
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Compaction should start once the stale bytes pass the ratio of the trigger, however few the
+// stale records, but not while the log is below its minimum size.
+#[test]
+fn compaction_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let trigger = CompactionTrigger {
+        stale_ratio: 0.4,
+        min_log_bytes: 64 * 1024,
+    };
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_trigger(trigger),
+    )?;
+    // starting a compaction moves the writes to new generations right away
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), i.to_string())?;
+    }
+    assert_eq!(store.stats()?.segments, 1);
+
+    let large = |i: usize| i.to_string().repeat(40 * 1024);
+    for i in 0..3 {
+        store.set(format!("large{}", i), large(i))?;
+    }
+    store.set("large0".to_owned(), large(3))?;
+    assert_eq!(store.stats()?.segments, 1);
+    store.set("large1".to_owned(), large(4))?;
+    assert!(store.stats()?.segments > 1);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.metrics().compactions == 0 {
+        assert!(Instant::now() < deadline, "compaction didn't finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("large0".to_owned())?, Some(large(3)));
+    assert_eq!(store.get("large2".to_owned())?, Some(large(2)));
+
+    Ok(())
+}
+
+// A partial compaction should only rewrite the generations with the most garbage, keeping the
+// removals the generations it leaves alone still need.
+#[test]
+fn partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_trigger(CompactionTrigger {
+            stale_ratio: 0.4,
+            min_log_bytes: 0,
+        })
+        .compaction_strategy(CompactionStrategy::Partial { max_bytes: 2048 });
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let value = "value".repeat(10);
+    for i in 0..50 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    for i in 20..25 {
+        store.remove(format!("cold{}", i))?;
+    }
+    store.delete_prefix("cold3")?;
+    // overwrites until a few compactions went through the generations with the most garbage
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut i = 0;
+    while store.metrics().compactions < 3 {
+        assert!(Instant::now() < deadline, "compactions didn't finish");
+        store.set(format!("hot{}", i % 10), i.to_string())?;
+        i += 1;
+    }
+    drop(store);
+
+    // the first generation only holds live records
+    assert!(log_file(temp_dir.path(), 1).exists());
+    // the log is replayed instead of loading the index from its checkpoint
+    std::fs::remove_file(temp_dir.path().join("hints/checkpoint"))?;
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..50 {
+        let expected = match i {
+            20..=24 | 3 | 30..=39 => None,
+            _ => Some(value.clone()),
+        };
+        assert_eq!(store.get(format!("cold{}", i))?, expected, "cold{}", i);
+    }
+    for hot in i - 10..i {
+        assert_eq!(
+            store.get(format!("hot{}", hot % 10))?,
+            Some(hot.to_string())
+        );
+    }
+    assert_eq!(store.len(), 50 - 5 - 11 + 10);
+
+    Ok(())
+}
+
+// A compaction listener should hear about every compaction starting and ending.
+#[test]
+fn compaction_listener() -> Result<()> {
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl CompactionListener for Recorder {
+        fn on_compaction_start(&self, event: &CompactionStart) {
+            let event = format!("start {} {}", event.gen, event.stale_bytes > 0);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_compaction_end(&self, event: &CompactionEnd) {
+            let event = format!("end {} {:?}", event.gen, event.result);
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let recorder = Recorder::default();
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_listener(recorder.clone()),
+    )?;
+    for i in 0..100 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    let reclaimed = store.compact()?;
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![
+            "start 2 true".to_owned(),
+            format!("end 2 Ok({})", reclaimed)
+        ]
+    );
+
+    Ok(())
+}
+
+// Writes should wait for a compaction that lags behind the garbage they produce.
+#[test]
+fn write_stall() -> Result<()> {
+    // holds the first compaction back until told to go on
+    #[derive(Clone)]
+    struct Hold(Arc<Mutex<Option<Receiver<()>>>>);
+
+    impl CompactionListener for Hold {
+        fn on_compaction_start(&self, _: &CompactionStart) {
+            let release = self.0.lock().unwrap().take();
+            if let Some(release) = release {
+                release.recv().unwrap();
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (release, hold) = mpsc::channel();
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new()
+            .compaction_trigger(CompactionTrigger {
+                stale_ratio: 0.4,
+                min_log_bytes: 0,
+            })
+            .write_stall(WriteStall {
+                slowdown_bytes: 500,
+                delay: Duration::from_millis(1),
+                stop_bytes: 1000,
+            })
+            .compaction_listener(Hold(Arc::new(Mutex::new(Some(hold))))),
+    )?;
+
+    let written = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let store = store.clone();
+        let written = written.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                store.set("key".to_owned(), format!("value{}", i)).unwrap();
+                written.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    let stopped_at = written.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(written.load(Ordering::SeqCst), stopped_at);
+    assert!(stopped_at < 200);
+
+    release.send(()).unwrap();
+    writer.join().unwrap();
+    assert_eq!(store.get("key".to_owned())?, Some("value199".to_owned()));
+    let metrics = store.metrics();
+    assert!(metrics.write_stalls > 0);
+    assert!(metrics.write_stall_time >= Duration::from_millis(200));
+
+    Ok(())
+}
+
+// Compaction should read and write no faster than its rate limit.
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_rate_limit(500 * 1024),
+    )?;
+    let value = "v".repeat(1000);
+    for i in 0..50 {
+        store.set(format!("key{}", i), value.clone())?;
+        store.set(format!("key{}", i), value.clone())?;
+    }
+
+    // about 50 KiB of live records are read, then written
+    let start = Instant::now();
+    assert!(store.compact()? > 0);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Clones of a store should share it across threads.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A reopened store should know how much of its log is stale, and compact it when writes resume.
+#[test]
+fn stale_bytes_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let never = CompactionTrigger {
+        stale_ratio: 1.0,
+        min_log_bytes: 0,
+    };
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_trigger(never),
+    )?;
+    for i in 0..200 {
+        store.set("overwritten".to_owned(), format!("{:0>64}", i))?;
+    }
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    let before = store.stats()?;
+    drop(store);
+
+    let trigger = CompactionTrigger {
+        stale_ratio: 0.5,
+        min_log_bytes: 4096,
+    };
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_trigger(trigger),
+    )?;
+    assert_eq!(store.stats()?.stale_bytes, before.stale_bytes);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.stats()?.last_compaction.is_none() {
+        assert!(
+            Instant::now() < deadline,
+            "the reopened store never compacted"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(store.stats()?.log_bytes < before.log_bytes);
+    assert_eq!(
+        store.get("overwritten".to_owned())?,
+        Some(format!("{:0>64}", 199))
+    );
+    assert_eq!(store.get("removed".to_owned())?, None);
+
+    Ok(())
+}
+
+// A compaction interrupted by a crash should leave the store as it was before or after it, and
+// what it left behind should be cleaned up on open.
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.flush()?;
+    std::fs::copy(path("wal/1.log"), path("1.log.saved"))?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert!(!log_file(temp_dir.path(), 1).exists());
+
+    // crashed after the compacted generation replaced the old one, but before it was deleted
+    std::fs::rename(path("1.log.saved"), path("segments/1.log"))?;
+    std::fs::write(path("compaction"), "1\n")?;
+    // and in the middle of writing a later compaction and its manifest
+    std::fs::write(path("tmp/9.compacting"), "partial")?;
+    std::fs::write(path("tmp/compaction.tmp"), "")?;
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert!(path("segments/1.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    for leftover in [
+        "segments/1.log",
+        "compaction",
+        "tmp/9.compacting",
+        "tmp/compaction.tmp",
+    ] {
+        assert!(!path(leftover).exists(), "{} was left behind", leftover);
+    }
+
+    Ok(())
+}
+
+// Closing a store should sync what was written, and closing a read-only one should do nothing.
+#[test]
+fn close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+    assert!(temp_dir.path().join("hints/checkpoint").exists());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.close()?;
+
+    Ok(())
+}
+
+// Files replaced by moving the old version aside should survive a crash between the two renames.
+#[test]
+fn replace_by_moving_aside() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let options = KvStoreOptions::new().replace_strategy(ReplaceStrategy::MoveAside);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(path("hints/checkpoint").exists());
+    assert!(!path("hints/checkpoint.replaced").exists());
+
+    // crashed after moving the log aside, before its replacement took its name
+    std::fs::rename(path("wal/1.log"), path("wal/1.log.replaced"))?;
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(path("wal/1.log").exists());
+    assert!(!path("wal/1.log.replaced").exists());
+
+    Ok(())
+}
+
+// Reads from several threads should see every value while writes trigger compaction.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let trigger = CompactionTrigger {
+        stale_ratio: 0.5,
+        min_log_bytes: 4096,
+    };
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_trigger(trigger),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..1000 {
+                store.set("overwritten".to_owned(), i.to_string()).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for round in 0..5 {
+                    for i in 0..100 {
+                        assert_eq!(
+                            store.get(format!("key{}", (i + round * 7) % 100)).unwrap(),
+                            Some(format!("value{}", (i + round * 7) % 100))
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// A store written as a single `kvs.store` log should still open.
+#[test]
+fn open_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs.store"),
+        r#"{"key":"key1","value":"value1","command_type":"SET"}{"key":"key2","value":"value2","command_type":"SET"}{"key":"key2","value":null,"command_type":"RM"}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+/// Paths and sizes of the files under `dir`
+fn dir_contents(dir: &TempDir) -> Vec<(std::path::PathBuf, u64)> {
+    WalkDir::new(dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| (entry.path().to_owned(), entry.metadata().unwrap().len()))
+        .collect()
+}
+
+// A read-only store should serve the keys written before, reject every change and leave the
+// directory as it found it.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+
+    let owner = KvStore::open(temp_dir.path())?;
+    owner.set("user:1".to_owned(), "alice".to_owned())?;
+    owner.set("user:2".to_owned(), "bob".to_owned())?;
+    owner
+        .namespace("sessions")?
+        .set("key1".to_owned(), "token".to_owned())?;
+    drop(owner);
+    let before = dir_contents(&temp_dir);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(store.scan_prefix("user:").count(), 2);
+    assert_eq!(
+        store.namespace("sessions")?.get("key1".to_owned())?,
+        Some("token".to_owned())
+    );
+    assert!(store.namespace("missing").is_err());
+    assert!(store.set("user:3".to_owned(), "carol".to_owned()).is_err());
+    assert!(store.remove("user:1".to_owned()).is_err());
+    let mut batch = WriteBatch::new();
+    batch.set("user:3".to_owned(), "carol".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert!(store.clear().is_err());
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+    drop(store);
+    assert_eq!(dir_contents(&temp_dir), before);
+
+    Ok(())
+}
+
+// A store open for writing should be locked against any other open, and a read-only store only
+// against opens for writing.
+#[test]
+fn open_locks_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    // the handles share the lock, which is released with the last one
+    let handle = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(handle);
+
+    let reader1 = KvStore::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::open_read_only(temp_dir.path())?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader1);
+    drop(reader2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `--dir` and `KVS_DIR` should pick the data directory, which is created if missing.
+#[test]
+fn cli_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--dir", "data/store"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("KVS_DIR", temp_dir.path().join("data/store"))
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    let store = KvStore::open(temp_dir.path().join("data/store"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Errors should be printed to stderr, with usage errors reported by the subcommand.
+#[test]
+fn cli_errors_to_stderr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "missing.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("No such file"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["clear"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--yes"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("<value>"));
+}
+
+// The CLI should refuse a store another process has open.
+#[test]
+fn cli_store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already open"));
+    drop(store);
+
+    Ok(())
+}
+
+// Removed keys should stay removed once their records are compacted away.
+#[test]
+fn compaction_keeps_removals() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i))?;
+    }
+    for iter in 0..20 {
+        for i in 50..100 {
+            store.set(format!("key{}", i), format!("value{}", iter))?;
+        }
+    }
+
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 50..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value19".to_owned()));
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    for i in 50..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The log files of the store in `dir`, in `wal/` and `segments/`.
+fn log_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut logs = Vec::new();
+    for sub_dir in ["wal", "segments"] {
+        let sub_dir = dir.join(sub_dir);
+        if !sub_dir.exists() {
+            continue;
+        }
+        for entry in std::fs::read_dir(sub_dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("log".as_ref()) {
+                logs.push(path);
+            }
+        }
+    }
+    logs.sort();
+    Ok(logs)
+}
+
+// The log file of generation `gen` of the store in `dir`, in `wal/` while it is appended to and
+// in `segments/` after.
+fn log_file(dir: &Path, gen: u64) -> PathBuf {
+    let wal = dir.join(format!("wal/{}.log", gen));
+    if wal.exists() {
+        wal
+    } else {
+        dir.join(format!("segments/{}.log", gen))
+    }
+}
+
+// Rewrites the bytes `from` to `to` in every log file of the store in `dir`.
+fn tamper_with_log(dir: &TempDir, from: &str, to: &str) -> Result<()> {
+    assert_eq!(from.len(), to.len());
+    for path in log_files(dir.path())? {
+        let mut log = std::fs::read(&path)?;
+        if let Some(start) = log
+            .windows(from.len())
+            .position(|window| window == from.as_bytes())
+        {
+            log[start..start + to.len()].copy_from_slice(to.as_bytes());
+        }
+        std::fs::write(&path, log)?;
+    }
+    Ok(())
+}
+
+// A record altered on disk should be reported instead of returning the wrong value.
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+
+    tamper_with_log(&temp_dir, "value1", "valueX")?;
+    let err = store.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::Corruption(_)));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(err, KvsError::Corruption(_)));
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().skip_corrupt(true))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Opening a store should start from the checkpoint of its index, written when it was closed or by
+// `checkpoint`, and only replay the log written since.
+#[test]
+fn index_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint = temp_dir.path().join("hints/checkpoint");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "stale1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(checkpoint.exists());
+
+    // the stale record is before the checkpoint, so it isn't read again
+    tamper_with_log(&temp_dir, "stale1", "staleX")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!checkpoint.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a crash after a checkpoint replays the records written since
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.checkpoint()?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.flush()?;
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    for entry in WalkDir::new(temp_dir.path()).min_depth(1) {
+        let entry = entry.unwrap();
+        let copy = crashed
+            .path()
+            .join(entry.path().strip_prefix(temp_dir.path()).unwrap());
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(copy)?;
+        } else {
+            std::fs::copy(entry.path(), copy)?;
+        }
+    }
+    let copy = KvStore::open(crashed.path())?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, None);
+    assert_eq!(copy.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // without the checkpoint, the whole log is replayed
+    std::fs::remove_file(&checkpoint)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(err, KvsError::Corruption(_)));
+
+    Ok(())
+}
+
+// `fsck` should count corrupt and torn records without touching the log, and `repair` should
+// rebuild the log from the others.
+#[test]
+fn fsck_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    tamper_with_log(&temp_dir, "value2", "valueX")?;
+    let log = log_file(temp_dir.path(), 1);
+    let len = std::fs::metadata(&log)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let report = KvStore::fsck(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!((report.live, report.stale, report.corrupt), (2, 0, 1));
+    assert!(report.torn_bytes > 0);
+    assert!(!report.is_clean());
+    assert_eq!(std::fs::metadata(&log)?.len(), len - 3);
+
+    assert_eq!(
+        KvStore::repair(temp_dir.path(), KvStoreOptions::new())?,
+        report
+    );
+    let report = KvStore::fsck(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(report.is_clean());
+    assert_eq!((report.live, report.stale), (2, 0));
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().strict(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A record cut short by a crash should be truncated away on open, or refused in strict mode.
+#[test]
+fn torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log = log_file(temp_dir.path(), 1);
+    let len = std::fs::metadata(&log)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let err = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().strict(true))
+        .err()
+        .unwrap();
+    assert!(matches!(err, KvsError::Corruption(_)));
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert_eq!(std::fs::metadata(&log)?.len(), len - 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // the torn record was truncated away
+    assert!(std::fs::metadata(log_file(temp_dir.path(), 1))?.len() < len - 3);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().strict(true))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A sealed generation cut short was damaged after it was written, so opening the store should
+// fail instead of truncating it, and `repair` should recover the records before the damage.
+#[test]
+fn torn_sealed_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // the generation is full, so it is sealed
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().segment_size(1))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let log = log_file(temp_dir.path(), 1);
+    assert!(log.starts_with(temp_dir.path().join("segments")));
+    let len = std::fs::metadata(&log)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(
+        err,
+        KvsError::Corruption(CorruptRecord { gen: 1, .. })
+    ));
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    assert_eq!(std::fs::metadata(&log)?.len(), len - 3);
+
+    let report = KvStore::repair(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(report.torn_bytes > 0);
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().strict(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The active generation should be in `wal/` and the older ones in `segments/`, and a store with
+// every file in its root, as they were before, should be moved into place on open.
+#[test]
+fn data_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = |name: &str| temp_dir.path().join(name);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // every generation is full, so each write seals the one it went to
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().segment_size(1))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.backups_dir(), path("backups"));
+    drop(store);
+    assert!(path("segments/1.log").exists());
+    assert!(path("segments/2.log").exists());
+    assert!(path("wal/3.log").exists());
+    assert!(path("hints/checkpoint").exists());
+    assert!(path("LAYOUT").exists());
+
+    // lay the store out as it was before
+    for log in log_files(temp_dir.path())? {
+        std::fs::rename(&log, path(log.file_name().unwrap().to_str().unwrap()))?;
+    }
+    std::fs::rename(path("hints/checkpoint"), path("checkpoint"))?;
+    for dir in ["wal", "segments", "hints", "tmp", "backups"] {
+        std::fs::remove_dir(path(dir))?;
+    }
+    std::fs::remove_file(path("LAYOUT"))?;
+
+    // the files can't be moved by a read-only store
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(!path("1.log").exists() && !path("checkpoint").exists());
+    assert_eq!(
+        log_files(temp_dir.path())?,
+        [
+            path("segments/1.log"),
+            path("segments/2.log"),
+            path("segments/3.log"),
+            path("wal/4.log")
+        ]
+    );
+    assert!(KvStore::open_read_only(temp_dir.path()).is_ok());
+
+    Ok(())
+}
+
+// Generations replaced by compaction should be archived instead of deleted, within the retention,
+// and the archived ones should open as the store as it was.
+#[test]
+fn wal_archive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // the archived log files in `dir`, oldest first
+    let archived = |dir: &Path| -> Result<Vec<PathBuf>> {
+        let mut logs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("log".as_ref()) {
+                logs.push(path);
+            }
+        }
+        logs.sort_by_key(|log| {
+            let stem = log.file_stem().unwrap().to_str().unwrap();
+            stem.parse::<u64>().unwrap()
+        });
+        Ok(logs)
+    };
+    let options = KvStoreOptions::new().wal_archive(WalArchive::default());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    let first = archived(&temp_dir.path().join("archive"))?;
+    assert!(!first.is_empty());
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    store.clear()?;
+    drop(store);
+    let all = archived(&temp_dir.path().join("archive"))?;
+    assert!(all.len() > first.len() && all.starts_with(&first));
+
+    // the store as it was before each compaction
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore = |name: &str, logs: &[PathBuf]| -> Result<KvStore> {
+        let segments = restored_dir.path().join(name).join("segments");
+        std::fs::create_dir_all(&segments)?;
+        for log in logs {
+            std::fs::copy(log, segments.join(log.file_name().unwrap()))?;
+        }
+        KvStore::open(restored_dir.path().join(name))
+    };
+    let store = restore("first", &first)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // the last one archived holds the marker written by `clear`
+    let store = restore("second", &all[..all.len() - 1])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(restore("cleared", &all)?.is_empty());
+
+    // only the newest archived generations are kept, and namespaces archive theirs apart
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().wal_archive(WalArchive {
+        dir: Some(archive_dir.path().to_owned()),
+        max_segments: Some(2),
+        ..WalArchive::default()
+    });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let users = store.namespace("users")?;
+    for i in 0..5 {
+        store.set("key1".to_owned(), i.to_string())?;
+        store.compact()?;
+        users.set("alice".to_owned(), i.to_string())?;
+        users.compact()?;
+    }
+    let kept = archived(archive_dir.path())?;
+    assert_eq!(kept.len(), 2);
+    assert_eq!(
+        archived(&archive_dir.path().join("namespaces/users"))?.len(),
+        2
+    );
+    assert!(!temp_dir.path().join("archive").exists());
+
+    Ok(())
+}
+
+// Files that aren't logs of this store should be refused instead of being read as records.
+#[test]
+fn open_validates_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), "just some log output")?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("is not a kvs log file"));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    KvStore::open(other_dir.path())?.set("key2".to_owned(), "value2".to_owned())?;
+    std::fs::copy(
+        log_file(other_dir.path(), 1),
+        temp_dir.path().join("segments/100.log"),
+    )?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("belongs to another store"));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let mut log = std::fs::read(log_file(temp_dir.path(), 1))?;
+    log[8..12].copy_from_slice(&99u32.to_le_bytes());
+    std::fs::write(log_file(temp_dir.path(), 1), log)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("format version 99"));
+
+    Ok(())
+}
+
+// Compressed records should shrink the log and read back whatever options the store is opened with.
+#[test]
+fn compression() -> Result<()> {
+    let log_size = |dir: &TempDir| -> u64 {
+        log_files(dir.path())
+            .unwrap()
+            .iter()
+            .map(|log| std::fs::metadata(log).unwrap().len())
+            .sum()
+    };
+    let value = "a fairly repetitive value ".repeat(1000);
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(plain_dir.path())?.set("key1".to_owned(), value.clone())?;
+
+    for compression in [Compression::Snappy, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(
+            temp_dir.path(),
+            KvStoreOptions::new().compression(compression),
+        )?;
+        store.set("key1".to_owned(), value.clone())?;
+        store.set("key2".to_owned(), "tiny".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        drop(store);
+        assert!(log_size(&temp_dir) * 4 < log_size(&plain_dir));
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        assert_eq!(store.get("key2".to_owned())?, Some("tiny".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Encrypted records shouldn't be readable from disk or without the key, and rotating keys should
+// re-encrypt everything under the new one.
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = [1; 32];
+    let new_key = [2; 32];
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(old_key),
+    )?;
+    store.set("key1".to_owned(), "a secret value".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a secret value".to_owned())
+    );
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let log = std::fs::read(entry.path())?;
+            assert!(!log.windows(6).any(|window| window == b"secret"));
+        }
+    }
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(new_key)
+    )
+    .is_err());
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new()
+            .encryption_key(new_key)
+            .previous_encryption_key(old_key),
+    )?;
+    store.set("key2".to_owned(), "another secret".to_owned())?;
+    store.reencrypt()?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(new_key),
+    )?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("a secret value".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("another secret".to_owned())
+    );
+
+    Ok(())
+}
+
+// Whether any file of the store in `dir` contains `needle`.
+fn log_contains(dir: &TempDir, needle: &[u8]) -> Result<bool> {
+    for entry in WalkDir::new(dir.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let log = std::fs::read(entry.path())?;
+            if log.windows(needle.len()).any(|window| window == needle) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Buffered records should be readable right away and reach the log file once flushed.
+#[test]
+fn flush_and_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "buffered1".to_owned())?;
+    assert_eq!(
+        store.clone().get("key1".to_owned())?,
+        Some("buffered1".to_owned())
+    );
+    store.set("key2".to_owned(), "buffered2".to_owned())?;
+    assert!(!log_contains(&temp_dir, b"buffered2")?);
+    store.flush()?;
+    assert!(log_contains(&temp_dir, b"buffered2")?);
+
+    store.set("key3".to_owned(), "synced".to_owned())?;
+    store.sync_all()?;
+    assert!(log_contains(&temp_dir, b"synced")?);
+
+    Ok(())
+}
+
+// Each sync policy should get writes to disk at the point it promises.
+#[test]
+fn sync_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "always".to_owned())?;
+    assert!(log_contains(&temp_dir, b"always")?);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryNWrites(3));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "every1".to_owned())?;
+    store.set("key2".to_owned(), "every2".to_owned())?;
+    assert!(!log_contains(&temp_dir, b"every")?);
+    store.set("key3".to_owned(), "every3".to_owned())?;
+    assert!(log_contains(&temp_dir, b"every1")?);
+    assert!(log_contains(&temp_dir, b"every3")?);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options =
+        KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_millis(50)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "interval".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !log_contains(&temp_dir, b"interval")? {
+        assert!(Instant::now() < deadline, "the log was never synced");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+// Opening a store over and over, e.g. for one command each, should keep appending to its newest
+// generation until it is full instead of leaving a new one behind every time.
+#[test]
+fn reopen_appends_to_newest_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    for _ in 0..20 {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("value1").trim());
+    }
+    assert_eq!(log_files(temp_dir.path())?.len(), 1);
+
+    let options = KvStoreOptions::new().segment_size(1024);
+    for i in 0..50 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", i), "value".repeat(10))?;
+    }
+    // the 50 records take a few generations of 1 KiB, not one each
+    let logs = log_files(temp_dir.path())?.len();
+    assert!(logs <= 8, "{} log files", logs);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key49".to_owned())?, Some("value".repeat(10)));
+
+    Ok(())
+}
+
+// Writes should move on to a new generation whenever the active one reaches the segment size,
+// and every generation should be replayed and tailed.
+#[test]
+fn segment_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".repeat(10))?;
+    }
+    let segments = store.stats()?.segments;
+    assert!(segments >= 5, "{} segments", segments);
+    assert_eq!(store.tail(LogPosition::START)?.take(100).count(), 100);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".repeat(10)));
+    }
+    store.compact()?;
+    assert_eq!(store.stats()?.segments, 2);
+    assert_eq!(store.get("key99".to_owned())?, Some("value".repeat(10)));
+
+    Ok(())
+}
+
+// A batch should be applied whole, and not at all if its end didn't make it to disk.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.remove("missing".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key0".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // read-only, so the log stays the newest generation, which a crash can tear
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // tear the commit marker off the end of the log, as a crash while writing it would
+    let log = log_file(temp_dir.path(), 1);
+    let len = std::fs::metadata(&log)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 1)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Increments through compare-and-swap from several threads shouldn't lose any updates.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "counter".to_owned();
+
+    assert_eq!(
+        store.compare_and_swap(key(), Some("1".to_owned()), Some("2".to_owned()))?,
+        Err(None)
+    );
+    assert_eq!(
+        store.compare_and_swap(key(), None, Some("0".to_owned()))?,
+        Ok(())
+    );
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut current = store.get(key()).unwrap();
+                    loop {
+                        let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                        match store
+                            .compare_and_swap(key(), current, Some(next.to_string()))
+                            .unwrap()
+                        {
+                            Ok(()) => break,
+                            Err(actual) => current = actual,
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get(key())?, Some("400".to_owned()));
+
+    assert_eq!(
+        store.compare_and_swap(key(), Some("400".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(store.get(key())?, None);
+
+    Ok(())
+}
+
+// Values swapped in from several threads should each be returned to exactly one caller.
+#[test]
+fn get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.get_and_set("token".to_owned(), "0".to_owned())?, None);
+    let handles: Vec<_> = (1..=4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                (0..50)
+                    .map(|j| {
+                        store
+                            .get_and_set("token".to_owned(), (i * 100 + j).to_string())
+                            .unwrap()
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let mut returned: Vec<_> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    returned.push(store.get("token".to_owned())?.unwrap());
+    returned.sort();
+    returned.dedup();
+    assert_eq!(returned.len(), 201);
+
+    Ok(())
+}
+
+// Renamed and copied keys should keep their value and expiry, and survive a reopen.
+#[test]
+fn rename_and_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    store.copy("key2".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    store.rename("key3".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert!(store.rename("key1".to_owned(), "key4".to_owned()).is_err());
+    assert!(store.copy("key1".to_owned(), "key4".to_owned()).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "ephemeral".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.rename("short".to_owned(), "moved".to_owned())?;
+    assert!(store.ttl("moved".to_owned())?.is_some());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(store.ttl("moved".to_owned())?.is_some());
+
+    Ok(())
+}
+
+// A key created from several threads at once should be set once, to the value all of them get.
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let set = store
+                    .set_if_absent("key2".to_owned(), i.to_string())
+                    .unwrap();
+                let value = store
+                    .get_or_insert_with("key3".to_owned(), || i.to_string())
+                    .unwrap();
+                (set, value)
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+    assert_eq!(results.iter().filter(|(set, _)| *set).count(), 1);
+    let value = store.get("key3".to_owned())?.unwrap();
+    assert!(results.iter().all(|(_, returned)| *returned == value));
+    assert_eq!(
+        store.get_or_insert_with("key3".to_owned(), || unreachable!())?,
+        value
+    );
+
+    Ok(())
+}
+
+// Keys set with a TTL should vanish once it elapses, and their records with the next compaction.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "ephemeral".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set_with_ttl(
+        "kept".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.persist("kept".to_owned())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get("short".to_owned())?, Some("ephemeral".to_owned()));
+    assert!(store.ttl("short".to_owned())?.unwrap() <= Duration::from_millis(100));
+    assert!(store.ttl("long".to_owned())?.unwrap() > Duration::from_secs(3500));
+    assert_eq!(store.ttl("kept".to_owned())?, None);
+    assert_eq!(store.ttl("plain".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(store.ttl("short".to_owned()).is_err());
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.keys_with_prefix("")?,
+        vec!["kept".to_owned(), "long".to_owned(), "plain".to_owned()]
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert!(store.ttl("long".to_owned())?.is_some());
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+
+    // rewriting the log compacts it
+    store.reencrypt()?;
+    drop(store);
+    assert!(!log_contains(&temp_dir, b"ephemeral")?);
+    assert!(log_contains(&temp_dir, b"long")?);
+
+    Ok(())
+}
+
+// Expired keys should get removal records, on demand or from the background sweeper.
+#[test]
+fn sweep_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.sweep_expired()?, 0);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.sweep_expired()?, 1);
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().expiry_sweep_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.flush()?;
+    let log = log_file(temp_dir.path(), 1);
+    let len = std::fs::metadata(&log)?.len();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        store.flush()?;
+        if std::fs::metadata(&log)?.len() > len {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the expired key was never removed"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.sweep_expired()?, 0);
+
+    Ok(())
+}
+
+// Iterating should list every live key in order, with its current value.
+#[test]
+fn keys_and_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().count(), 0);
+    for i in (0..10).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key3".to_owned(), "updated".to_owned())?;
+    store.remove("key5".to_owned())?;
+    store.set_with_ttl("key7".to_owned(), "gone".to_owned(), Duration::ZERO)?;
+
+    let expected: Vec<_> = (0..10)
+        .filter(|i| *i != 5 && *i != 7)
+        .map(|i| {
+            let value = match i {
+                3 => "updated".to_owned(),
+                _ => format!("value{}", i),
+            };
+            (format!("key{}", i), value)
+        })
+        .collect();
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        expected
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    Ok(())
+}
+
+// Range scans should return the keys within their bounds, in order.
+#[test]
+fn range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for id in [3, 1, 4, 15, 9, 2, 6] {
+        store.set(format!("user:{}", id), id.to_string())?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let keys = |entries: Vec<(String, String)>| -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    let start = "user:2".to_owned();
+    let entries = store
+        .range(start.as_str().."user:6")
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries[0], ("user:2".to_owned(), "2".to_owned()));
+    assert_eq!(keys(entries), vec!["user:2", "user:3", "user:4"]);
+    let entries = store.range("user:4"..).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["user:4", "user:6", "user:9"]);
+    let entries = store.range(..="user:15").collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(entries), vec!["other", "user:1", "user:15"]);
+    assert_eq!(store.range("user:7".."user:8").count(), 0);
+
+    Ok(())
+}
+
+// Prefix scans should return exactly the keys sharing the prefix.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["user", "user:1", "user:2", "user;", "users:1", "group:1"] {
+        store.set(key.to_owned(), format!("{} value", key))?;
+    }
+    store.remove("user:2".to_owned())?;
+
+    let entries = store.scan_prefix("user:").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![("user:1".to_owned(), "user:1 value".to_owned())]
+    );
+    assert_eq!(store.scan_prefix("user").count(), 4);
+    assert_eq!(store.scan_prefix("").count(), 5);
+    assert_eq!(store.scan_prefix("nobody").count(), 0);
+
+    Ok(())
+}
+
+// `multi_get` should return the values in the order of the keys, wherever the records live.
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    // these records land in a newer generation than the others
+    store.set("key3".to_owned(), "updated".to_owned())?;
+    store.remove("key5".to_owned())?;
+
+    let keys: Vec<_> = [7, 3, 5, 0, 42]
+        .iter()
+        .map(|i| format!("key{}", i))
+        .collect();
+    assert_eq!(
+        store.multi_get(&keys)?,
+        vec![
+            Some("value7".to_owned()),
+            Some("updated".to_owned()),
+            None,
+            Some("value0".to_owned()),
+            None
+        ]
+    );
+
+    Ok(())
+}
+
+// The index-backed accessors should agree with the values in the store.
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+    assert!(!store.contains_key("key1"));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+    assert!(!store.is_empty());
+    assert_eq!(store.len(), 2);
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+    assert_eq!(store.len(), 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert!(store.contains_key("key2"));
+
+    Ok(())
+}
+
+// Keys deleted by prefix or range should stay deleted across reopens and compaction, with a single
+// record written for each deletion.
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("session:{}", i), "alice".to_owned())?;
+        store.set(format!("user:{}", i), "alice".to_owned())?;
+    }
+    let events = store.watch("session:1");
+
+    assert_eq!(store.delete_prefix("session:")?, 10);
+    assert_eq!(store.delete_prefix("session:")?, 0);
+    assert_eq!(store.delete_range("user:2".."user:5")?, 3);
+    assert_eq!(store.delete_range("user:6"..="user:7")?, 2);
+    assert_eq!(store.delete_range::<&str, _>(..)?, 5);
+    assert!(store.is_empty());
+    let event = events.try_recv().unwrap();
+    assert_eq!(event.op, WatchOp::Remove);
+    assert_eq!(event.old_value, Some(b"alice".to_vec()));
+    assert_eq!(
+        store
+            .log_records()?
+            .iter()
+            .filter(|record| record.kind == RecordKind::RemoveRange)
+            .count(),
+        4
+    );
+
+    // keys set after a deletion are left alone by it on replay
+    store.set("session:1".to_owned(), "bob".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["session:1".to_owned()]
+    );
+    assert_eq!(store.history("session:1")?.len(), 3);
+    store.compact()?;
+    assert_eq!(store.log_records()?.len(), 1);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session:1".to_owned())?, Some("bob".to_owned()));
+
+    // the older versions compaction keeps still see the deletion
+    drop(store);
+    let options = KvStoreOptions::new().version_retention(VersionRetention::All);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.delete_prefix("session:")?;
+    store.set("session:2".to_owned(), "carol".to_owned())?;
+    store.compact()?;
+    let values: Vec<_> = store
+        .history("session:1")?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(values, vec![Some(b"bob".to_vec()), None]);
+    assert_eq!(store.get("session:2".to_owned())?, Some("carol".to_owned()));
+
+    Ok(())
+}
+
+// Clearing should drop every key for good, and the store should stay usable afterwards.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i % 100), format!("value{}", i))?;
+    }
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!log_contains(&temp_dir, b"value")?);
+
+    store.set("key1".to_owned(), "after".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+
+    Ok(())
+}
+
+// Binary values should round-trip byte for byte, and string values should read as bytes too.
+#[test]
+fn bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let binary: Vec<u8> = (0..=255).collect();
+    store.set_bytes("binary".to_owned(), binary.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get_bytes("binary".to_owned())?, Some(binary.clone()));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+    assert!(store.get("binary".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("binary".to_owned())?, Some(binary));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Keys of arbitrary bytes should iterate in byte order and survive a reopen, while the string
+// views leave out the ones that aren't valid UTF-8.
+#[test]
+fn raw_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = |id: u32| [b"id\xff".as_slice(), &id.to_be_bytes()].concat();
+    for id in [300, 2, 256, 1] {
+        store.set_raw(key(id), id.to_string().into_bytes())?;
+    }
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get_raw(&key(256))?, Some(b"256".to_vec()));
+    assert_eq!(store.get_raw(b"text")?, Some(b"value".to_vec()));
+    let ids: Vec<_> = store
+        .range_raw(key(2)..key(301))
+        .map(|entry| entry.map(|(_, value)| value))
+        .collect::<Result<_>>()?;
+    assert_eq!(ids, vec![b"2".to_vec(), b"256".to_vec(), b"300".to_vec()]);
+    assert_eq!(store.scan_prefix_raw(b"id\xff").count(), 4);
+    assert_eq!(store.len(), 5);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["text"]);
+    assert_eq!(store.iter().count(), 1);
+
+    store.remove_raw(&key(1))?;
+    assert!(store.remove_raw(&key(1)).is_err());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_raw(&key(1))?, None);
+    assert_eq!(store.get_raw(&key(300))?, Some(b"300".to_vec()));
+    assert_eq!(store.keys_raw().count(), 4);
+
+    Ok(())
+}
+
+// Namespaces should keep their keys apart from the store and from each other, across a reopen.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let sessions = store.namespace("sessions")?;
+    let users = store.namespace("users")?;
+    store.set("key1".to_owned(), "store".to_owned())?;
+    sessions.set("key1".to_owned(), "session".to_owned())?;
+    users.set("key2".to_owned(), "user".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("store".to_owned()));
+    assert_eq!(sessions.get("key1".to_owned())?, Some("session".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert_eq!(store.len(), 1);
+    // every handle to a namespace sees the same keys
+    assert_eq!(
+        store.namespace("users")?.get("key2".to_owned())?,
+        Some("user".to_owned())
+    );
+    sessions.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("store".to_owned()));
+    assert!(store.namespace("../escape").is_err());
+    assert!(store.namespace("").is_err());
+    drop((store, sessions, users));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, vec!["sessions", "users"]);
+    assert_eq!(store.namespace("sessions")?.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.namespace("users")?.get("key2".to_owned())?,
+        Some("user".to_owned())
+    );
+
+    Ok(())
+}
+
+// A backup should hold the store as it was when it was taken, namespaces included, even while the
+// store keeps being written and compacted.
+#[test]
+fn backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..600 {
+        store.set(format!("key{}", iter % 100), iter.to_string())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl("gone".to_owned(), "soon".to_owned(), Duration::ZERO)?;
+    store
+        .namespace("sessions")?
+        .set("key1".to_owned(), "session".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 0..1000 {
+                store.set("busy".to_owned(), iter.to_string()).unwrap();
+            }
+        })
+    };
+    store.backup(backup_dir.path().join("full"))?;
+    writer.join().unwrap();
+    assert!(store.backup(backup_dir.path().join("full")).is_err());
+    assert!(backup_dir.path().join("full").join("MANIFEST").exists());
+
+    let backup = KvStore::open(backup_dir.path().join("full"))?;
+    assert_eq!(backup.get("key0".to_owned())?, None);
+    assert_eq!(backup.get("key99".to_owned())?, Some("599".to_owned()));
+    assert_eq!(backup.get("gone".to_owned())?, None);
+    assert_eq!(
+        backup.keys().filter(|key| key.starts_with("key")).count(),
+        99
+    );
+    assert_eq!(
+        backup.namespace("sessions")?.get("key1".to_owned())?,
+        Some("session".to_owned())
+    );
+
+    Ok(())
+}
+
+// Restoring should bring back a backup, and refuse one that is incomplete or was tampered with.
+#[test]
+fn restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store
+        .namespace("sessions")?
+        .set("key2".to_owned(), "session".to_owned())?;
+    store.backup(backup_dir.path().join("backup"))?;
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::restore(backup_dir.path().join("backup"), restored_dir.path())?;
+    let restored = KvStore::open(restored_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        restored.namespace("sessions")?.get("key2".to_owned())?,
+        Some("session".to_owned())
+    );
+    // the restored store isn't empty anymore
+    assert!(KvStore::restore(backup_dir.path().join("backup"), restored_dir.path()).is_err());
+
+    // a flipped byte in a namespace fails the whole restore before anything is written
+    let log = backup_dir
+        .path()
+        .join("backup/namespaces/sessions/segments/1.log");
+    let mut data = std::fs::read(&log)?;
+    *data.last_mut().unwrap() ^= 1;
+    std::fs::write(&log, data)?;
+    let target = backup_dir.path().join("target");
+    assert!(KvStore::restore(backup_dir.path().join("backup"), &target).is_err());
+    assert!(!target.exists());
+
+    // a backup without its manifest didn't complete
+    assert!(KvStore::restore(temp_dir.path(), &target).is_err());
+
+    Ok(())
+}
+
+// Incremental backups should only hold the changes since the previous backup, and restore to the
+// store as it was when each of them was made.
+#[test]
+fn incremental_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backups = backup_dir.path();
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), "full".to_owned())?;
+    }
+    store.backup(backups.join("full"))?;
+
+    store.set("key1".to_owned(), "first".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let sessions = store.namespace("sessions")?;
+    sessions.set("key1".to_owned(), "session".to_owned())?;
+    store.backup_incremental(backups.join("full"), backups.join("1"))?;
+    let full_len = std::fs::metadata(backups.join("full/segments/1.log"))?.len();
+    assert!(std::fs::metadata(backups.join("1/segments/1.log"))?.len() < full_len / 10);
+
+    // compaction moves the records, which are copied again, but must not bring key2 back
+    for iter in 0..600 {
+        store.set("key3".to_owned(), iter.to_string())?;
+    }
+    store.remove("key4".to_owned())?;
+    sessions.remove("key1".to_owned())?;
+    store.backup_incremental(backups.join("1"), backups.join("2"))?;
+
+    let restored = |backup: &str| -> Result<KvStore> {
+        let target = backups.join(format!("restored-{}", backup));
+        KvStore::restore(backups.join(backup), &target)?;
+        KvStore::open(target)
+    };
+    let first = restored("1")?;
+    assert_eq!(first.get("key1".to_owned())?, Some("first".to_owned()));
+    assert_eq!(first.get("key2".to_owned())?, None);
+    assert_eq!(first.get("key4".to_owned())?, Some("full".to_owned()));
+    assert_eq!(first.len(), 99);
+    assert_eq!(
+        first.namespace("sessions")?.get("key1".to_owned())?,
+        Some("session".to_owned())
+    );
+    let second = restored("2")?;
+    assert_eq!(second.get("key2".to_owned())?, None);
+    assert_eq!(second.get("key3".to_owned())?, Some("599".to_owned()));
+    assert_eq!(second.get("key4".to_owned())?, None);
+    assert_eq!(second.len(), 98);
+    assert_eq!(second.namespace("sessions")?.get("key1".to_owned())?, None);
+
+    // a restored store is a store of its own
+    assert!(second
+        .backup_incremental(backups.join("2"), backups.join("3"))
+        .is_err());
+
+    Ok(())
+}
+
+// Exports should escape whatever the keys and values hold.
+#[test]
+fn export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("quoted".to_owned(), "say \"hi\", twice\nplease".to_owned())?;
+    store.set_raw(vec![0xff], b"skipped".to_vec())?;
+
+    let mut jsonl = Vec::new();
+    assert_eq!(store.export("", ExportFormat::JsonLines, &mut jsonl)?, 2);
+    let lines: Vec<serde_json::Value> = String::from_utf8(jsonl)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<_, _>>()?;
+    assert_eq!(lines[1]["key"], "quoted");
+    assert_eq!(lines[1]["value"], "say \"hi\", twice\nplease");
+
+    let mut csv = Vec::new();
+    assert_eq!(store.export("q", ExportFormat::Csv, &mut csv)?, 1);
+    assert_eq!(
+        String::from_utf8(csv)?,
+        "key,value\r\nquoted,\"say \"\"hi\"\", twice\nplease\"\r\n"
+    );
+
+    store.set_bytes("binary".to_owned(), vec![0xff])?;
+    assert!(store.export("", ExportFormat::Csv, Vec::new()).is_err());
+
+    Ok(())
+}
+
+// Importing an export should recreate the store, following the conflict policy for keys that
+// already exist.
+#[test]
+fn import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2500 {
+        store.set(format!("key{}", iter), format!("value \"{}\"", iter))?;
+    }
+    let mut dump = Vec::new();
+    store.export("", ExportFormat::JsonLines, &mut dump)?;
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target = KvStore::open(target_dir.path())?;
+    target.set("key7".to_owned(), "kept".to_owned())?;
+    assert_eq!(
+        target.import_from_reader(&dump[..], OnConflict::Skip)?,
+        2499
+    );
+    assert_eq!(target.len(), 2500);
+    assert_eq!(target.get("key7".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(
+        target.get("key2499".to_owned())?,
+        Some("value \"2499\"".to_owned())
+    );
+
+    assert_eq!(
+        target.import_from_reader(&dump[..], OnConflict::Overwrite)?,
+        2500
+    );
+    assert_eq!(
+        target.get("key7".to_owned())?,
+        Some("value \"7\"".to_owned())
+    );
+
+    // everything before the conflicting key is imported
+    let fresh_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fresh = KvStore::open(fresh_dir.path())?;
+    let input = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"a\",\"value\":\"2\"}\n";
+    assert!(fresh
+        .import_from_reader(input.as_bytes(), OnConflict::Fail)
+        .is_err());
+    assert_eq!(fresh.get("a".to_owned())?, Some("1".to_owned()));
+    assert!(fresh
+        .import_from_reader("not json\n".as_bytes(), OnConflict::Overwrite)
+        .is_err());
+
+    Ok(())
+}
+
+// Loading should set every pair in one batch, or none of them if a line is malformed.
+#[test]
+fn load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let input = "key1\tvalue1\r\n\nkey2\tvalue\twith tabs\n{\"key\":\"key3\",\"value\":\"value3\"}\nkey1\tvalue4\n";
+    assert_eq!(store.load(input.as_bytes())?, 4);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(
+        store.get("key2".to_owned())?,
+        Some("value\twith tabs".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    assert!(store
+        .load("key4\tvalue4\nno tab here\n".as_bytes())
+        .is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The log records should be reported in order, with only the current value of each key live.
+#[test]
+fn log_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "batched".to_owned());
+    store.write_batch(batch)?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+
+    let records = store.log_records()?;
+    let summary: Vec<_> = records
+        .iter()
+        .map(|record| (record.kind, record.key.as_slice(), record.live))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (RecordKind::Set, &b"key1"[..], false),
+            (RecordKind::Set, &b"key2"[..], false),
+            (RecordKind::Remove, &b"key1"[..], false),
+            (RecordKind::Begin, &b""[..], false),
+            (RecordKind::Set, &b"key2"[..], true),
+            (RecordKind::Commit, &b""[..], false),
+            (RecordKind::Set, &b"key3"[..], false),
+        ]
+    );
+    assert!(records[6].expires_at.is_some());
+    assert_eq!(records[1].offset, records[0].offset + records[0].len);
+
+    Ok(())
+}
+
+// Compacting on demand should shrink the log to the live records and keep every value.
+#[test]
+fn compact_on_demand() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10), iter.to_string())?;
+    }
+    for iter in 0..5 {
+        store.remove(format!("key{}", iter))?;
+    }
+    let log_size = || -> u64 {
+        log_files(temp_dir.path())
+            .unwrap()
+            .iter()
+            .map(|log| std::fs::metadata(log).unwrap().len())
+            .sum()
+    };
+    store.flush()?;
+    let before = log_size();
+
+    // the new active generation has a header of its own
+    let reclaimed = store.compact()?;
+    assert!(reclaimed > 0 && reclaimed < before);
+    assert!(log_size() < before);
+    assert_eq!(store.len(), 5);
+    assert_eq!(store.get("key9".to_owned())?, Some("199".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert!(store.compact()? < reclaimed);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("195".to_owned()));
+
+    Ok(())
+}
+
+// Stats should account for every byte of the log, and show compaction reclaiming the stale ones.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.live_bytes, stats.stale_bytes), (0, 0, 0));
+    assert_eq!(stats.segments, 1);
+
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), iter.to_string())?;
+    }
+    store.remove("key0".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 9);
+    assert!(stats.stale_bytes > stats.live_bytes);
+    assert_eq!(stats.last_compaction, None);
+    let on_disk: u64 = log_files(temp_dir.path())?
+        .iter()
+        .map(|log| std::fs::metadata(log).unwrap().len())
+        .sum();
+    assert_eq!(stats.log_bytes, on_disk);
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 9);
+    assert_eq!(compacted.live_bytes, stats.live_bytes);
+    assert_eq!(compacted.stale_bytes, 0);
+    assert!(compacted.last_compaction.is_some());
+
+    Ok(())
+}
+
+/// Options with a merge operator adding the operands to a counter
+fn counter_options() -> KvStoreOptions {
+    KvStoreOptions::new().merge_operator(|_key, existing, operand| {
+        let count = |value: &[u8]| String::from_utf8_lossy(value).parse::<i64>().unwrap();
+        (existing.map_or(0, count) + count(operand))
+            .to_string()
+            .into_bytes()
+    })
+}
+
+// Merges should be folded into the value of their key when it is read, after reopening the store
+// with or without a checkpoint, and after compaction replaced them with a set.
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.merge("key1".to_owned(), "1".to_owned()).is_err());
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), counter_options())?;
+    let events = store.watch("key");
+    store.merge("key1".to_owned(), "1".to_owned())?;
+    store.merge("key1".to_owned(), "2".to_owned())?;
+    store.set("key2".to_owned(), "10".to_owned())?;
+    store.merge("key2".to_owned(), "5".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("15".to_owned()));
+    let event = events.try_iter().last().unwrap();
+    assert_eq!(event.op, WatchOp::Merge);
+    assert_eq!(event.old_value, Some(b"10".to_vec()));
+    assert_eq!(event.new_value, Some(b"15".to_vec()));
+    let records = store.log_records()?;
+    assert_eq!(
+        records
+            .iter()
+            .filter(|record| record.kind == RecordKind::Merge && record.live)
+            .count(),
+        3
+    );
+
+    // the merges are replayed, as recovering ignores the checkpoint, then taken from the checkpoint
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), counter_options().skip_corrupt(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("3".to_owned()));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), counter_options())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("15".to_owned()));
+
+    store.compact()?;
+    assert!(store
+        .log_records()?
+        .iter()
+        .all(|record| record.kind == RecordKind::Set));
+    store.merge("key1".to_owned(), "4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("15".to_owned()));
+
+    // overwriting or removing a key drops its merges
+    store.set("key1".to_owned(), "100".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("100".to_owned()));
+    store.remove("key2".to_owned())?;
+    store.merge("key2".to_owned(), "1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("1".to_owned()));
+
+    // a backup holds the values the merges result in
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path())?;
+    KvStore::restore(backup_dir.path(), target_dir.path())?;
+    let restored = KvStore::open(target_dir.path())?;
+    assert_eq!(restored.get("key2".to_owned())?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+// Appends should extend values without a merge operator, across reopens and compaction.
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.watch("key");
+    store.append("key1".to_owned(), "a".to_owned())?;
+    store.append("key1".to_owned(), "b".to_owned())?;
+    store.set("key2".to_owned(), "x".to_owned())?;
+    store.append("key2".to_owned(), "y".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("ab".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("xy".to_owned()));
+    let event = events.try_iter().last().unwrap();
+    assert_eq!(event.op, WatchOp::Append);
+    assert_eq!(event.new_value, Some(b"xy".to_vec()));
+    assert_eq!(
+        store
+            .log_records()?
+            .iter()
+            .filter(|record| record.kind == RecordKind::Append && record.live)
+            .count(),
+        3
+    );
+    let mut tail = store.tail(LogPosition::START)?;
+    let change = tail.next().unwrap()?;
+    assert_eq!(change.op, WatchOp::Append);
+    assert_eq!(change.value, Some(b"a".to_vec()));
+
+    drop(tail);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("ab".to_owned()));
+    store.append("key2".to_owned(), "z".to_owned())?;
+    store.compact()?;
+    assert!(store
+        .log_records()?
+        .iter()
+        .all(|record| record.kind == RecordKind::Set));
+    assert_eq!(store.get("key2".to_owned())?, Some("xyz".to_owned()));
+
+    // removing a key drops its appends
+    store.remove("key1".to_owned())?;
+    store.append("key1".to_owned(), "c".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("c".to_owned()));
+
+    Ok(())
+}
+
+// Older versions of a key can be read until compaction drops them, as the retention policy allows.
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), counter_options())?;
+    store.set("key1".to_owned(), "1".to_owned())?;
+    let first = store.version();
+    store.merge("key1".to_owned(), "2".to_owned())?;
+    let second = store.version();
+    assert!(second > first);
+    store.remove("key1".to_owned())?;
+    let removed = store.version();
+    store.set("key1".to_owned(), "10".to_owned())?;
+
+    assert_eq!(store.get_at("key1", first - 1)?, None);
+    assert_eq!(store.get_at("key1", first)?, Some("1".to_owned()));
+    assert_eq!(store.get_at("key1", second)?, Some("3".to_owned()));
+    assert_eq!(store.get_at("key1", removed)?, None);
+    assert_eq!(
+        store.get_at("key1", store.version())?,
+        Some("10".to_owned())
+    );
+    let values = |store: &KvStore| -> Result<Vec<Option<Vec<u8>>>> {
+        Ok(store
+            .history("key1")?
+            .into_iter()
+            .map(|version| version.value)
+            .collect())
+    };
+    let all = vec![
+        Some(b"1".to_vec()),
+        Some(b"3".to_vec()),
+        None,
+        Some(b"10".to_vec()),
+    ];
+    assert_eq!(values(&store)?, all);
+
+    // versions survive reopening
+    drop(store);
+    let options = counter_options().version_retention(VersionRetention::All);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.history("key1")?[1].version, second);
+    assert!(store.version() >= removed);
+
+    // every version is kept
+    store.compact()?;
+    assert_eq!(values(&store)?, all);
+    assert_eq!(store.get_at("key1", second)?, Some("3".to_owned()));
+    drop(store);
+
+    // versions written in the last hour are kept
+    let options =
+        counter_options().version_retention(VersionRetention::Age(Duration::from_secs(3600)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact()?;
+    assert_eq!(values(&store)?, all);
+    drop(store);
+
+    // only the current values are kept by default
+    let store = KvStore::open_with(temp_dir.path(), counter_options())?;
+    store.compact()?;
+    assert_eq!(values(&store)?, vec![Some(b"10".to_vec())]);
+    assert_eq!(store.get_at("key1", second)?, None);
+
+    Ok(())
+}
+
+// The read cache should serve repeated reads of a key, and never a value that was overwritten,
+// removed or moved by compaction.
+#[test]
+fn read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_capacity(64))?;
+    let counters = |store: &KvStore| -> Result<(u64, u64)> {
+        let stats = store.stats()?;
+        Ok((stats.cache_hits, stats.cache_misses))
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(counters(&store)?, (1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(counters(&store)?, (2, 3));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // values past the capacity evict the least recently used ones
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+        store.get(format!("key{}", iter))?;
+    }
+    let (hits, misses) = counters(&store)?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(counters(&store)?, (hits + 1, misses + 1));
+
+    // without a capacity, nothing is cached
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.get("key9".to_owned())?;
+    store.get("key9".to_owned())?;
+    assert_eq!(counters(&store)?, (0, 0));
+
+    Ok(())
+}
+
+// A secondary index should find the keys by a value pulled out of theirs, through sets, removes,
+// batches, compaction and clears.
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let user = |name: &str, email: &str| format!(r#"{{"name":"{}","email":"{}"}}"#, name, email);
+    store.set("user:1".to_owned(), user("alice", "a@b.com"))?;
+    store.set("user:2".to_owned(), "not json".to_owned())?;
+    store.add_index("email", |value| {
+        let user: serde_json::Value = serde_json::from_slice(value).ok()?;
+        Some(user.get("email")?.as_str()?.as_bytes().to_vec())
+    })?;
+    assert_eq!(store.get_by_index("email", "a@b.com")?, vec!["user:1"]);
+    assert!(store.get_by_index("name", "alice").is_err());
+
+    store.set("user:3".to_owned(), user("carol", "a@b.com"))?;
+    store.set("user:1".to_owned(), user("alice", "alice@b.com"))?;
+    assert_eq!(store.get_by_index("email", "a@b.com")?, vec!["user:3"]);
+    assert_eq!(store.get_by_index("email", "alice@b.com")?, vec!["user:1"]);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .remove("user:3".to_owned())
+        .set("user:2".to_owned(), user("bob", "a@b.com"));
+    store.write_batch(batch)?;
+    store.remove("user:1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get_by_index("email", "a@b.com")?, vec!["user:2"]);
+    assert!(store.get_by_index("email", "alice@b.com")?.is_empty());
+
+    store.clear()?;
+    assert!(store.get_by_index("email", "a@b.com")?.is_empty());
+
+    Ok(())
+}
+
+// Watchers should receive every change to the keys under their prefix, in order, with the values
+// before and after it.
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.watch("config:");
+    let event = |op, key: &str, old: Option<&str>, new: Option<&str>| WatchEvent {
+        op,
+        key: key.as_bytes().to_vec(),
+        old_value: old.map(|value| value.as_bytes().to_vec()),
+        new_value: new.map(|value| value.as_bytes().to_vec()),
+    };
+
+    store.set("config:level".to_owned(), "info".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("config:level".to_owned(), "debug".to_owned())?;
+    store.remove("config:level".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("config:a".to_owned(), "1".to_owned());
+    batch.set("config:a".to_owned(), "2".to_owned());
+    batch.set("other".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    store.clear()?;
+
+    let received: Vec<WatchEvent> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            event(WatchOp::Set, "config:level", None, Some("info")),
+            event(WatchOp::Set, "config:level", Some("info"), Some("debug")),
+            event(WatchOp::Remove, "config:level", Some("debug"), None),
+            event(WatchOp::Set, "config:a", None, Some("1")),
+            event(WatchOp::Set, "config:a", Some("1"), Some("2")),
+            event(WatchOp::Clear, "", None, None),
+        ]
+    );
+
+    // writes go on after the receiver is dropped
+    drop(events);
+    store.set("config:level".to_owned(), "info".to_owned())?;
+    assert_eq!(
+        store.get("config:level".to_owned())?,
+        Some("info".to_owned())
+    );
+
+    Ok(())
+}
+
+// A snapshot should keep reading the keys as they were when it was taken, even once compaction
+// deleted their records from the log.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .merge_operator(|_key, existing, operand| [existing.unwrap_or_default(), operand].concat());
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.merge("key3".to_owned(), "a".to_owned())?;
+    store.merge("key3".to_owned(), "b".to_owned())?;
+    let snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.merge("key3".to_owned(), "c".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3")?, Some("ab".to_owned()));
+    assert_eq!(snapshot.get("key4")?, None);
+    assert_eq!(snapshot.len()?, 3);
+    let entries = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "ab".to_owned()),
+        ]
+    );
+    assert_eq!(snapshot.scan_prefix("key2").count(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.snapshot()?.get("key3")?, Some("abc".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should read the keys written since it was taken from their history, and the others
+// from the store, even once the store is cleared.
+#[test]
+fn snapshot_written_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(snapshot.get("key4")?, None);
+    assert!(snapshot.contains_key("key2")?);
+    assert!(!snapshot.contains_key("key4")?);
+
+    store.clear()?;
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key3")?, Some("value3".to_owned()));
+    let keys = snapshot
+        .iter()
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["key1", "key2", "key3"]);
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert!(store.snapshot()?.is_empty()?);
+
+    Ok(())
+}
+
+// Looking a key up through the log should skip the sealed generations whose bloom filter doesn't
+// hold it, with the filters kept in `hints/` and rebuilt once they no longer match.
+#[test]
+fn generation_bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every write seals the generation it went to
+    let options = KvStoreOptions::new().segment_size(1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    // a range removal is about keys its filter can't name
+    store.delete_prefix("key1")?;
+    assert_eq!(store.history("key1")?.len(), 3);
+    for gen in 1..=4 {
+        assert!(temp_dir
+            .path()
+            .join(format!("hints/{}.bloom", gen))
+            .exists());
+    }
+    assert!(!temp_dir.path().join("hints/5.bloom").exists());
+
+    // damage the record of key2 without changing the length of its generation
+    let log = temp_dir.path().join("segments/2.log");
+    let healthy = std::fs::read(&log)?;
+    let mut damaged = healthy.clone();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 0xff;
+    std::fs::write(&log, damaged)?;
+    let history = store.history("key1")?;
+    assert_eq!(history[0].value, Some(b"value1".to_vec()));
+    assert_eq!(history[1].value, Some(b"value3".to_vec()));
+    assert_eq!(history[2].value, None);
+    assert!(matches!(
+        store.history("key2"),
+        Err(KvsError::Corruption(_))
+    ));
+    std::fs::write(&log, healthy)?;
+    drop(store);
+
+    // a damaged filter is rebuilt, and the filters of compacted generations are deleted
+    std::fs::write(temp_dir.path().join("hints/3.bloom"), b"damaged")?;
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.history("key1")?.len(), 3);
+    assert_ne!(
+        std::fs::read(temp_dir.path().join("hints/3.bloom"))?,
+        b"damaged"
+    );
+    store.compact()?;
+    drop(store);
+    KvStore::open_with(temp_dir.path(), options)?;
+    assert!(!temp_dir.path().join("hints/1.bloom").exists());
+
+    Ok(())
+}
+
+// A tail should yield the committed records in order, pick up new ones as they are written and
+// resume from its position.
+#[test]
+fn tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let change = |record: Result<kvs::ChangeRecord>| {
+        let record = record.unwrap();
+        (
+            record.op,
+            String::from_utf8(record.key).unwrap(),
+            record.value.map(|value| String::from_utf8(value).unwrap()),
+        )
+    };
+    let set = |key: &str, value: &str| (WatchOp::Set, key.to_owned(), Some(value.to_owned()));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+
+    let mut tail = store.tail(LogPosition::START)?;
+    assert_eq!(
+        tail.by_ref().map(change).collect::<Vec<_>>(),
+        vec![
+            set("key1", "value1"),
+            (WatchOp::Remove, "key1".to_owned(), None),
+            set("key2", "value2"),
+            set("key3", "value3"),
+        ]
+    );
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(tail.next().map(change), Some(set("key4", "value4")));
+    assert!(tail.next().is_none());
+    let position = tail.position();
+
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    let mut resumed = store.tail(position)?;
+    assert_eq!(resumed.next().map(change), Some(set("key5", "value5")));
+    assert!(resumed.next().is_none());
+
+    Ok(())
+}
+
+// A tail should read through compaction, and a position compacted away should be rejected.
+#[test]
+fn tail_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let mut tail = store.tail(LogPosition::START)?;
+    assert_eq!(tail.next().unwrap()?.value, Some(b"value1".to_vec()));
+    let position = tail.position();
+
+    store.compact()?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let values: Vec<Vec<u8>> = tail.map(|record| record.unwrap().value.unwrap()).collect();
+    // the copy compaction made of the live record comes again
+    assert_eq!(
+        values,
+        vec![b"value2".to_vec(), b"value2".to_vec(), b"value3".to_vec()]
+    );
+
+    assert!(store.tail(position).is_err());
+    let keys = store
+        .tail(LogPosition::START)?
+        .map(|record| record.unwrap().key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+
+    Ok(())
+}
+
+// A follower applying the batches of `changes` should end up with the keys of the leader, and start
+// over with a reset when it asks for a position of another store.
+#[test]
+fn replicate_changes() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    let follower = KvStore::open(follower_dir.path())?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    follower.set("key3".to_owned(), "stale".to_owned())?;
+
+    let batch = leader.changes(None, LogPosition::START, 2)?;
+    assert!(batch.reset);
+    assert_eq!(batch.records.len(), 2);
+    follower.clear()?;
+    for record in batch.records {
+        follower.apply_change(record)?;
+    }
+    let batch = leader.changes(Some(&batch.store_id), batch.next, 2)?;
+    assert!(!batch.reset);
+    assert_eq!(batch.records.len(), 1);
+    for record in batch.records {
+        follower.apply_change(record)?;
+    }
+    assert_eq!(follower.get("key1".to_owned())?, None);
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(follower.get("key3".to_owned())?, None);
+
+    let next = batch.next;
+    let batch = leader.changes(Some(&batch.store_id), next, 2)?;
+    assert!(!batch.reset);
+    assert!(batch.records.is_empty());
+    assert_eq!(batch.next, next);
+    assert!(leader.changes(Some("another store"), next, 2)?.reset);
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    roles: Vec<String>,
+}
+
+// Keys encoded with their codec should round-trip and be ranged over in the order of their values.
+#[test]
+fn keyed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let orders = KeyedKvStore::<u64>::new(store.namespace("orders")?);
+    for id in (0..300).rev() {
+        orders.set(&id, format!("order{}", id))?;
+    }
+    let ids = orders
+        .range(95..105)
+        .map(|entry| entry.map(|(id, _)| id))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(ids, (95..105).collect::<Vec<_>>());
+    assert_eq!(orders.get(&250)?, Some("order250".to_owned()));
+    orders.remove(&250)?;
+    assert_eq!(orders.get(&250)?, None);
+    assert_eq!(orders.iter().count(), 299);
+
+    let readings = KeyedKvStore::<i64>::new(store.namespace("readings")?);
+    for reading in [3, -20, 0, i64::MIN, -1, i64::MAX] {
+        readings.set(&reading, reading.to_string())?;
+    }
+    let sorted = readings
+        .iter()
+        .map(|entry| entry.map(|(reading, _)| reading))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(sorted, vec![i64::MIN, -20, -1, 0, 3, i64::MAX]);
+
+    // a user's events, whatever the length of the user names and the zero bytes in them
+    let events = KeyedKvStore::<(String, u64)>::new(store.namespace("events")?);
+    for user in ["bo", "bob", "bo\0b"] {
+        for seq in 0..5 {
+            events.set(&(user.to_owned(), seq), format!("{}:{}", user, seq))?;
+        }
+    }
+    let bob = events
+        .range(("bob".to_owned(), 1)..("bob".to_owned(), u64::MAX))
+        .map(|entry| entry.map(|(_, value)| value))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(bob, vec!["bob:1", "bob:2", "bob:3", "bob:4"]);
+    assert_eq!(
+        events
+            .range(("bo".to_owned(), 0)..("bob".to_owned(), 0))
+            .count(),
+        10
+    );
 
-        drop(store);
-        // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    let id = uuid::Uuid::new_v4();
+    assert_eq!(uuid::Uuid::from_key_bytes(&id.to_key_bytes())?, id);
+    assert!(u32::from_key_bytes(&[0, 1]).is_err());
+
+    Ok(())
+}
+
+// Typed handles should round-trip structs and refuse values of another shape.
+#[test]
+fn typed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = TypedKvStore::<User>::new(store.clone());
+    let alice = User {
+        name: "alice".to_owned(),
+        roles: vec!["admin".to_owned()],
+    };
+    users.set("user:1".to_owned(), &alice)?;
+    assert_eq!(users.get("user:1".to_owned())?, Some(alice.clone()));
+    assert_eq!(users.get("user:2".to_owned())?, None);
+
+    store.set("user:2".to_owned(), "not a user".to_owned())?;
+    assert!(users.get("user:2".to_owned()).is_err());
+    users.remove("user:2".to_owned())?;
+    drop(users);
+    drop(store);
+
+    let users = TypedKvStore::<User>::new(KvStore::open(temp_dir.path())?);
+    assert_eq!(users.get("user:1".to_owned())?, Some(alice));
+
+    Ok(())
+}
+
+// Records of every format should be read back on reopen, whatever format the reopening asks for.
+#[test]
+fn record_formats() -> Result<()> {
+    let formats = [
+        RecordFormat::Bincode,
+        RecordFormat::Json,
+        RecordFormat::MessagePack,
+        RecordFormat::Cbor,
+    ];
+    let options = |format| {
+        KvStoreOptions::new()
+            .record_format(format)
+            .merge_operator(|_key, existing, operand| match existing {
+                Some(existing) => [existing, b",", operand].concat(),
+                None => operand.to_vec(),
+            })
+    };
+    for format in formats {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), options(format))?;
+        for i in 0..100 {
+            store.set(format!("key{:02}", i), "x".repeat(i * 5))?;
         }
-        return Ok(());
+        store.set_raw(vec![0, 0xff], Vec::new())?;
+        store.remove("key00".to_owned())?;
+        assert_eq!(store.delete_range("key10".."key20")?, 10);
+        store.merge("list".to_owned(), "a".to_owned())?;
+        store.merge("list".to_owned(), "b".to_owned())?;
+        store.set_with_ttl(
+            "session".to_owned(),
+            "s".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        drop(store);
+        std::fs::remove_file(temp_dir.path().join("hints/checkpoint"))?;
+
+        // the store keeps the format it was created with
+        let other = formats[(formats.iter().position(|&f| f == format).unwrap() + 1) % 4];
+        let store = KvStore::open_with(temp_dir.path(), options(other))?;
+        assert_eq!(store.get("key00".to_owned())?, None);
+        assert_eq!(store.get("key15".to_owned())?, None);
+        assert_eq!(store.get("key99".to_owned())?, Some("x".repeat(495)));
+        assert_eq!(store.get_raw(&[0, 0xff])?, Some(Vec::new()));
+        assert_eq!(store.get("list".to_owned())?, Some("a,b".to_owned()));
+        assert!(store.ttl("session".to_owned())?.is_some());
+        store.set("key00".to_owned(), "back".to_owned())?;
+        store.compact()?;
+        drop(store);
+        std::fs::remove_file(temp_dir.path().join("hints/checkpoint"))?;
+
+        let store = KvStore::open_with(temp_dir.path(), options(format))?;
+        assert_eq!(store.get("key00".to_owned())?, Some("back".to_owned()));
+        assert_eq!(store.get("key50".to_owned())?, Some("x".repeat(250)));
+        assert_eq!(store.get("list".to_owned())?, Some("a,b".to_owned()));
+        assert_eq!(store.stats()?.keys, 93);
     }
 
-    panic!("No compaction detected");
+    Ok(())
+}
+
+// Migrating a store should rewrite every log file in the new format, keep its data and finish
+// on open if a crash interrupted the swap.
+#[test]
+fn migrate_record_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json = KvStoreOptions::new().record_format(RecordFormat::Json);
+    let store = KvStore::open_with(temp_dir.path(), json.clone())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "s".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store
+        .namespace("users")?
+        .set("alice".to_owned(), "admin".to_owned())?;
+    drop(store);
+
+    // a copy of the store before the migration, to interrupt one later
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    // laid out as stores were before `wal/` and `segments/`, which opening it moves them into
+    for log in log_files(temp_dir.path())? {
+        std::fs::copy(&log, copy_dir.path().join(log.file_name().unwrap()))?;
+    }
+
+    let (from, to) = (RecordFormat::Json, RecordFormat::Bincode);
+    // the store doesn't hold CBOR records
+    assert!(KvStore::migrate(
+        temp_dir.path(),
+        RecordFormat::Cbor,
+        to,
+        KvStoreOptions::new()
+    )
+    .is_err());
+    KvStore::migrate(temp_dir.path(), from, to, KvStoreOptions::new())?;
+    // the header of every file names bincode records now
+    for log in log_files(temp_dir.path())? {
+        assert_eq!(std::fs::read(log)?[10..12], [0, 0]);
+    }
+    // running it again does nothing
+    KvStore::migrate(temp_dir.path(), from, to, KvStoreOptions::new())?;
+
+    let store = KvStore::open_with(temp_dir.path(), json.clone())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    assert!(store.ttl("session".to_owned())?.is_some());
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("alice".to_owned())?, Some("admin".to_owned()));
+    drop((users, store));
+
+    // a crash after the manifest was written leaves the swap for the next open to finish
+    let mut gens = Vec::new();
+    for log in log_files(temp_dir.path())? {
+        let gen = log.file_stem().unwrap().to_str().unwrap().to_owned();
+        std::fs::copy(&log, copy_dir.path().join(format!("{}.migrating", gen)))?;
+        gens.push(gen);
+    }
+    std::fs::write(copy_dir.path().join("migration"), gens.join("\n"))?;
+    assert!(KvStore::open_read_only(copy_dir.path()).is_err());
+    let store = KvStore::open_with(copy_dir.path(), json)?;
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    assert!(!copy_dir.path().join("migration").exists());
+    for log in log_files(copy_dir.path())? {
+        assert_eq!(std::fs::read(log)?[10..12], [0, 0]);
+    }
+
+    Ok(())
 }
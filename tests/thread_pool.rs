@@ -0,0 +1,55 @@
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Runs `jobs` jobs on `pool` and waits until every one of them has run.
+fn run_jobs<P: ThreadPool>(pool: &P, jobs: usize) {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..jobs {
+        let counter = counter.clone();
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..jobs {
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), jobs);
+}
+
+// Every job spawned on a naive pool should run.
+#[test]
+fn naive_thread_pool() -> Result<()> {
+    run_jobs(&NaiveThreadPool::new(4)?, 100);
+    Ok(())
+}
+
+// Every job spawned on a shared queue pool should run, even with fewer threads than jobs.
+#[test]
+fn shared_queue_thread_pool() -> Result<()> {
+    run_jobs(&SharedQueueThreadPool::new(4)?, 100);
+    Ok(())
+}
+
+// Jobs that panic shouldn't leave a shared queue pool without workers.
+#[test]
+fn shared_queue_thread_pool_panic() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..8 {
+        pool.spawn(|| panic!("job failed on purpose"));
+    }
+    run_jobs(&pool, 100);
+    Ok(())
+}
+
+// A pool without threads can't run anything.
+#[test]
+fn shared_queue_thread_pool_empty() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "tls")]
+
+use assert_cmd::prelude::*;
+use common::start_server;
+use kvs::{KvsClient, Result};
+use predicates::ord::eq;
+use predicates::str::PredicateStrExt;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+// Writes a CA certificate and a `localhost` certificate signed by it into `dir`.
+// Returns the paths of the CA certificate, the server certificate and the server key.
+fn write_certs(dir: &Path) -> (PathBuf, PathBuf, PathBuf) {
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&server_key, &ca_cert, &ca_key)
+        .unwrap();
+
+    let paths = (
+        dir.join("ca.pem"),
+        dir.join("cert.pem"),
+        dir.join("key.pem"),
+    );
+    fs::write(&paths.0, ca_cert.pem()).unwrap();
+    fs::write(&paths.1, server_cert.pem()).unwrap();
+    fs::write(&paths.2, server_key.serialize_pem()).unwrap();
+    paths
+}
+
+// The library client should talk to a TLS-terminating server.
+#[test]
+fn tls_client_get_set_rm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (ca, cert, key) = write_certs(certs_dir.path());
+    let server = start_server(
+        &temp_dir,
+        &[
+            "--cert",
+            cert.to_str().unwrap(),
+            "--key",
+            key.to_str().unwrap(),
+        ],
+    );
+
+    let mut client = KvsClient::connect_tls(&server.addr, "localhost", &ca)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    // a TLS client can't talk to a plaintext server
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain_server = start_server(&plain_dir, &[]);
+    assert!(
+        KvsClient::connect_tls(&plain_server.addr, "localhost", &ca)?
+            .get("key1".to_owned())
+            .is_err()
+    );
+
+    Ok(())
+}
+
+// `kvs-client --ca` should connect over TLS.
+#[test]
+fn cli_client_tls() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (ca, cert, key) = write_certs(certs_dir.path());
+    let server = start_server(
+        &temp_dir,
+        &[
+            "--cert",
+            cert.to_str().unwrap(),
+            "--key",
+            key.to_str().unwrap(),
+        ],
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &server.addr])
+        .args(["--ca", ca.to_str().unwrap()])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &server.addr])
+        .args(["--ca", ca.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
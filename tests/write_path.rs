@@ -0,0 +1,122 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Records appended by each write path
+const RECORDS: usize = 100_000;
+
+/// A log file counting the calls that reach it, each of which is a system call
+struct CountingFile {
+    file: File,
+    syscalls: usize,
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.syscalls += 1;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for CountingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.syscalls += 1;
+        self.file.seek(pos)
+    }
+}
+
+fn open_log(dir: &TempDir, name: &str) -> Result<CountingFile> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(dir.path().join(name))?;
+    Ok(CountingFile { file, syscalls: 0 })
+}
+
+fn record(i: usize) -> Vec<u8> {
+    format!(
+        r#"{{"key":"key{:010}","value":"{}","command_type":"SET"}}"#,
+        i,
+        "x".repeat(100)
+    )
+    .into_bytes()
+}
+
+fn report(name: &str, syscalls: usize, elapsed: Duration) {
+    println!(
+        "{:<28} {:>9} syscalls {:>6.2}/write {:>10.0} writes/s",
+        name,
+        syscalls,
+        syscalls as f64 / RECORDS as f64,
+        RECORDS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+// Compares appending records the way the store first did, seeking to the end before every write
+// and back to the start after it, with keeping the offset in memory, unbuffered and buffered like
+// the log writer of `KvStore`. Run with `cargo test --release --test write_path -- --ignored
+// --nocapture`.
+#[test]
+#[ignore]
+fn bench_write_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let records: Vec<Vec<u8>> = (0..RECORDS).map(record).collect();
+
+    let mut log = open_log(&temp_dir, "seeking.log")?;
+    let start = Instant::now();
+    let mut offsets = Vec::with_capacity(RECORDS);
+    for record in &records {
+        offsets.push(log.seek(SeekFrom::End(0))?);
+        log.write_all(record)?;
+        log.seek(SeekFrom::Start(0))?;
+    }
+    report("seek before and after", log.syscalls, start.elapsed());
+
+    let mut log = open_log(&temp_dir, "offset.log")?;
+    let start = Instant::now();
+    let mut offset = 0;
+    offsets.clear();
+    for record in &records {
+        offsets.push(offset);
+        log.write_all(record)?;
+        offset += record.len() as u64;
+    }
+    report("tracked offset", log.syscalls, start.elapsed());
+
+    let mut log = BufWriter::with_capacity(8 * 1024, open_log(&temp_dir, "buffered.log")?);
+    let start = Instant::now();
+    let mut offset = 0;
+    offsets.clear();
+    for record in &records {
+        offsets.push(offset);
+        log.write_all(record)?;
+        offset += record.len() as u64;
+    }
+    log.flush()?;
+    let elapsed = start.elapsed();
+    report("tracked offset, buffered", log.get_ref().syscalls, elapsed);
+
+    // the same writes through the store, for reference
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    let value = "x".repeat(100);
+    let start = Instant::now();
+    for i in 0..RECORDS {
+        store.set(format!("key{:010}", i), value.clone())?;
+    }
+    store.flush()?;
+    let elapsed = start.elapsed();
+    println!(
+        "{:<28} {:>35.0} writes/s",
+        "KvStore::set",
+        RECORDS as f64 / elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}